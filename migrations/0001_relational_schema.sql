@@ -0,0 +1,22 @@
+-- Applied with `wrangler d1 migrations apply <database>`; see src/storage.rs.
+--
+-- Posts keep their full JSON in `body`, the way KV stores them, so fields can be added without a
+-- migration. The other columns copy what queries filter and sort by. Users, likes and comments
+-- stay where they are (KV, and the like counters' Durable Objects) until something moves them,
+-- so they get tables then.
+
+CREATE TABLE IF NOT EXISTS posts (
+  id TEXT PRIMARY KEY,
+  username TEXT NOT NULL,
+  title TEXT,
+  content TEXT,
+  community TEXT,
+  time TEXT,
+  likes INTEGER NOT NULL DEFAULT 0,
+  archived INTEGER NOT NULL DEFAULT 0,
+  body TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS posts_by_username ON posts (username, time);
+CREATE INDEX IF NOT EXISTS posts_by_community ON posts (community, time);
+CREATE INDEX IF NOT EXISTS posts_by_time ON posts (time);
@@ -0,0 +1,104 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::error::{self, ApiResult};
+use crate::session::Session;
+
+/// Keys in the `activity` namespace:
+///
+/// - `day/<username>/<yyyy-mm-dd>`: [`Day`], the user's counts for one UTC day. The counts are
+///   also the key's metadata, so a year of them comes from a single list.
+const ACTIVITY_KV: &str = "activity";
+
+/// How many days `GET /users/:username/activity` covers.
+const DAYS: i64 = 365;
+
+/// Counters outlive the window they are shown in by a little, then KV drops them.
+const DAY_TTL: u64 = 60 * 60 * 24 * 400;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+struct Day {
+    posts: u64,
+    comments: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Post,
+    Comment,
+}
+
+async fn increment(ctx: &RouteContext<Session>, username: &str, kind: Kind) -> Result<()> {
+    let kv = ctx.kv(ACTIVITY_KV)?;
+    let key = format!("day/{}/{}", username, Utc::now().format("%Y-%m-%d"));
+    let mut day = match kv.get(&key).await? {
+        Some(v) => v.as_json::<Day>()?,
+        None => Day::default(),
+    };
+    match kind {
+        Kind::Post => day.posts += 1,
+        Kind::Comment => day.comments += 1,
+    }
+    kv.put(&key, day)?
+        .metadata(day)?
+        .expiration_ttl(DAY_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Counts a post or comment `username` just made towards today. Counts are read-modify-write,
+/// so two writes in the same instant may count once; good enough for a histogram. Failures are
+/// logged, never returned.
+pub async fn record(ctx: &RouteContext<Session>, username: &str, kind: Kind) {
+    if let Err(e) = increment(ctx, username, kind).await {
+        console_log!("activity for {} failed: {}", username, e);
+    }
+}
+
+/// `GET /users/:username/activity`: posts and comments per day over the last year, oldest
+/// first, with every day present so clients can lay out a contribution graph directly.
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let prefix = format!("day/{}/", username);
+    let keys = ctx
+        .kv(ACTIVITY_KV)?
+        .list()
+        .prefix(prefix.clone())
+        .execute()
+        .await?
+        .keys;
+    let counted: HashMap<String, Day> = keys
+        .into_iter()
+        .filter_map(|key| {
+            let day = serde_json::from_value::<Day>(key.metadata?).ok()?;
+            Some((key.name[prefix.len()..].to_string(), day))
+        })
+        .collect();
+
+    let today = Utc::now().date_naive();
+    let mut days = vec![];
+    let (mut total_posts, mut total_comments) = (0, 0);
+    for offset in (0..DAYS).rev() {
+        let date = (today - Duration::days(offset))
+            .format("%Y-%m-%d")
+            .to_string();
+        let day = counted.get(&date).copied().unwrap_or_default();
+        total_posts += day.posts;
+        total_comments += day.comments;
+        days.push(json!({
+            "date": date,
+            "posts": day.posts,
+            "comments": day.comments,
+        }));
+    }
+    Ok(Response::from_json(&json!({
+        "username": username,
+        "total_posts": total_posts,
+        "total_comments": total_comments,
+        "days": days,
+    }))?)
+}
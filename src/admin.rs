@@ -0,0 +1,135 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{moderation, posts, session, storage, users};
+
+/// Paths under `/admin/` that aren't admin routes: `/admin/login` is a decoy for scanners (see
+/// `bots`) and has to answer them like the other decoys do.
+const DECOYS: &[&str] = &["/admin/login"];
+
+#[derive(Deserialize, Debug, Default)]
+struct NewBan {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Whether `username` is one of `admins`, the comma-separated `ADMINS` var.
+pub fn listed(admins: &str, username: &str) -> bool {
+    admins.split(',').any(|admin| admin.trim() == username)
+}
+
+/// The check every `/admin/` route sits behind, run by `main` before routing: anyone but an
+/// admin is refused with 403, whichever route they asked for. Routes needing the admin's name
+/// still take it from the session.
+pub fn gate(path: &str, env: &Env, session: &Session) -> ApiResult<()> {
+    if !path.starts_with("/admin/") || DECOYS.contains(&path) {
+        return Ok(());
+    }
+    match session.username() {
+        Some(username) if listed(&env.var("ADMINS")?.to_string(), username) => Ok(()),
+        _ => Err(ApiError::Forbidden("Forbidden".to_string())),
+    }
+}
+
+/// `DELETE /admin/posts/:id`: deletes anyone's post, as its author could. It can be restored
+/// within the restore window like any deleted post.
+pub async fn delete_post(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let admin = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let store = storage::posts(&ctx)?;
+    let post = posts::load(&*store, &id).await?;
+    posts::soft_delete(&ctx, &*store, &id, post, &admin).await?;
+    console_log!("admin: {} deleted post {}", admin, id);
+    Ok(Response::empty()?)
+}
+
+/// `POST /admin/users/:username/ban`, with an optional `{"reason": "..."}`: the user's sessions
+/// stop working, so they can no longer act as themselves. `DELETE` lifts the ban. Admins can't
+/// be banned; take them out of `ADMINS` first.
+pub async fn ban(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let admin = session::authed(&ctx)?.username;
+    let username = error::param(&ctx, "username")?;
+    let banning = req.method() == Method::Post;
+    let text = req.text().await?;
+    let body = if !banning || text.trim().is_empty() {
+        NewBan::default()
+    } else {
+        serde_json::from_str::<NewBan>(&text).map_err(|e| ApiError::BadRequest(e.to_string()))?
+    };
+    if banning && moderation::is_admin(&ctx, &username)? {
+        return Err(ApiError::Conflict("Admins can't be banned".to_string()));
+    }
+    let kv = ctx.kv(users::USERS_KV)?;
+    let mut profile = users::load(&kv, &username)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    profile.banned = if banning {
+        Some(users::Ban {
+            reason: body.reason.filter(|reason| !reason.trim().is_empty()),
+            by: admin.clone(),
+            at: Utc::now().to_rfc3339(),
+        })
+    } else {
+        None
+    };
+    kv.put(&username, &profile)?.execute().await?;
+    users::forget_ban(&username);
+    console_log!(
+        "admin: {} {} {}",
+        admin,
+        if banning { "banned" } else { "unbanned" },
+        username
+    );
+    Ok(Response::from_json(&json!({
+        "username": username,
+        "banned": profile.banned,
+    }))?)
+}
+
+/// `GET /admin/stats`: every user and post the instance stores, by state, including what
+/// `GET /about/stats` leaves out.
+pub async fn stats(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(users::USERS_KV)?;
+    let usernames = kv.list().execute().await?.keys;
+    let mut banned = 0;
+    for key in &usernames {
+        if users::load(&kv, &key.name)
+            .await?
+            .is_some_and(|profile| profile.banned.is_some())
+        {
+            banned += 1;
+        }
+    }
+
+    let (mut public, mut archived, mut moderated, mut deleted, mut total) = (0, 0, 0, 0, 0);
+    for (_, stored) in storage::posts(&ctx)?.list("", ctx.data().trace()).await? {
+        let post = match serde_json::from_str::<Value>(&stored) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        total += 1;
+        if posts::is_deleted(&post) {
+            deleted += 1;
+        } else if posts::is_moderated(&post) {
+            moderated += 1;
+        } else if posts::is_archived(&post) {
+            archived += 1;
+        } else {
+            public += 1;
+        }
+    }
+    Ok(Response::from_json(&json!({
+        "users": { "total": usernames.len(), "banned": banned },
+        "posts": {
+            "total": total,
+            "public": public,
+            "archived": archived,
+            "moderated": moderated,
+            "deleted": deleted,
+        },
+    }))?)
+}
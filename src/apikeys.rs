@@ -0,0 +1,235 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{moderation, session, users, utils, validation};
+
+/// Keys in the `api_keys` namespace:
+///
+/// - `key/<id>`: [`ApiKey`], where `id` is the SHA-256 of the key. The key itself is only ever
+///   shown once, when it is issued.
+/// - `service/<username>`: [`ServiceAccount`]
+pub const API_KEYS_KV: &str = "api_keys";
+
+pub const HEADER: &str = "X-Api-Key";
+
+/// What a key may be used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Reading `GET /firehose`.
+    Firehose,
+    /// Posting the daily digest as the key's owner, `POST /bot/digest`.
+    Digest,
+    /// Creating posts as the key's owner through `POST /posts`.
+    Post,
+    /// Reading the owner's `GET /feed`.
+    Read,
+    /// Polling the RSS bridge, `POST /bot/rss`.
+    Bridge,
+    /// Running the retention sweep, `POST /bot/retention`, and purging deleted posts,
+    /// `POST /bot/purge`.
+    Retention,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub owner: String,
+    pub label: String,
+    pub scopes: Vec<Scope>,
+    /// When set, the key can only post into this community.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub community: Option<String>,
+    pub created_at: String,
+}
+
+impl ApiKey {
+    pub fn allows_community(&self, community: Option<&str>) -> bool {
+        self.community.is_none() || self.community.as_deref() == community
+    }
+}
+
+/// An account that can't sign in and only acts through API keys issued to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceAccount {
+    pub username: String,
+    pub description: String,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewKey {
+    owner: String,
+    #[serde(default)]
+    label: String,
+    scopes: Vec<Scope>,
+    community: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewServiceAccount {
+    username: String,
+    #[serde(default)]
+    description: String,
+}
+
+/// The key a request carries in [`HEADER`], if it exists and its owner isn't banned.
+pub async fn lookup(req: &Request, ctx: &RouteContext<Session>) -> Result<Option<ApiKey>> {
+    let key = match req.headers().get(HEADER)? {
+        Some(key) if !key.trim().is_empty() => key,
+        _ => return Ok(None),
+    };
+    let kv = ctx.kv(API_KEYS_KV)?;
+    let api_key = match kv
+        .get(&format!("key/{}", utils::sha256_hex(key.trim())))
+        .await?
+    {
+        Some(v) => v.as_json::<ApiKey>()?,
+        None => return Ok(None),
+    };
+    if users::is_banned(&ctx.kv(users::USERS_KV)?, &api_key.owner).await? {
+        return Ok(None);
+    }
+    Ok(Some(api_key))
+}
+
+/// The key a request carries in [`HEADER`], provided it exists and grants `scope`.
+pub async fn authorize(
+    req: &Request,
+    ctx: &RouteContext<Session>,
+    scope: Scope,
+) -> Result<Option<ApiKey>> {
+    Ok(lookup(req, ctx)
+        .await?
+        .filter(|api_key| api_key.scopes.contains(&scope)))
+}
+
+fn admin(ctx: &RouteContext<Session>) -> Result<Option<String>> {
+    Ok(match session::current_user(ctx) {
+        Some(username) if moderation::is_admin(ctx, &username)? => Some(username),
+        _ => None,
+    })
+}
+
+/// `POST /admin/api_keys`
+pub async fn issue(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if admin(&ctx)?.is_none() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let body = match req.json::<NewKey>().await {
+        Ok(body) if !body.owner.is_empty() && !body.scopes.is_empty() => body,
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    if !users::exists(&ctx.kv(users::USERS_KV)?, &body.owner).await? {
+        return Err(ApiError::BadRequest(
+            "`owner` is not a registered user".to_string(),
+        ));
+    }
+    let now = Utc::now();
+    // Nobody without SESSION_SECRET can produce or guess one of these.
+    let key = session::seal(
+        &format!(
+            "{}|{}",
+            body.owner,
+            now.timestamp_nanos_opt().unwrap_or_default()
+        ),
+        &format!("api_key:{}", ctx.secret("SESSION_SECRET")?.to_string()),
+    );
+    let api_key = ApiKey {
+        id: utils::sha256_hex(&key),
+        owner: body.owner,
+        label: body.label,
+        scopes: body.scopes,
+        community: body.community,
+        created_at: now.to_rfc3339(),
+    };
+    ctx.kv(API_KEYS_KV)?
+        .put(&format!("key/{}", api_key.id), &api_key)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(
+        &json!({ "key": key, "api_key": api_key }),
+    )?)
+}
+
+/// `DELETE /admin/api_keys/:id`
+pub async fn revoke(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if admin(&ctx)?.is_none() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(API_KEYS_KV)?;
+    if kv.get(&format!("key/{}", id)).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    kv.delete(&format!("key/{}", id)).await?;
+    Ok(Response::empty()?)
+}
+
+/// Whether `username` is a service account; `kv` is the [`API_KEYS_KV`] namespace.
+pub async fn is_service_account(kv: &kv::KvStore, username: &str) -> Result<bool> {
+    Ok(kv.get(&format!("service/{}", username)).await?.is_some())
+}
+
+/// `POST /admin/service_accounts`
+///
+/// Registers the username like any other user, so nobody can sign up under it, but never hands
+/// out a session for it. It can then be given keys through `POST /admin/api_keys`.
+pub async fn create_service_account(
+    mut req: Request,
+    ctx: RouteContext<Session>,
+) -> ApiResult<Response> {
+    let admin = match admin(&ctx)? {
+        Some(admin) => admin,
+        None => return Err(ApiError::Forbidden("Forbidden".to_string())),
+    };
+    let body = match req.json::<NewServiceAccount>().await {
+        Ok(body) if validation::username(&body.username).is_ok() => body,
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let users = ctx.kv(users::USERS_KV)?;
+    if users::exists(&users, &body.username).await? {
+        return Err(ApiError::Conflict("Username is taken".to_string()));
+    }
+    let profile = users::register(&users, &body.username).await?;
+    let account = ServiceAccount {
+        username: body.username,
+        description: body.description,
+        created_by: admin,
+        created_at: profile.created_at,
+    };
+    ctx.kv(API_KEYS_KV)?
+        .put(&format!("service/{}", account.username), &account)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&account)?)
+}
+
+/// `GET /admin/service_accounts`
+pub async fn list_service_accounts(
+    _req: Request,
+    ctx: RouteContext<Session>,
+) -> ApiResult<Response> {
+    if admin(&ctx)?.is_none() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let kv = ctx.kv(API_KEYS_KV)?;
+    let mut accounts = vec![];
+    for key in kv
+        .list()
+        .prefix("service/".to_string())
+        .execute()
+        .await?
+        .keys
+    {
+        if let Some(v) = kv.get(&key.name).await? {
+            accounts.push(v.as_json::<ServiceAccount>()?);
+        }
+    }
+    Ok(Response::from_json(&accounts)?)
+}
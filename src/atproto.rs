@@ -0,0 +1,76 @@
+use chrono::DateTime;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiResult};
+use crate::session::Session;
+use crate::{posts, storage};
+
+/// Bluesky rejects post records longer than this many graphemes; we count characters.
+const MAX_TEXT_CHARS: usize = 300;
+
+const TID_ALPHABET: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+
+/// An AT Protocol record key (TID) for a post made at `micros` since the epoch: 53 bits of
+/// timestamp and 10 bits of clock id, base32-sortable encoded.
+fn tid(micros: i64, clock_id: u64) -> String {
+    let value = ((micros as u64 & ((1 << 53) - 1)) << 10) | (clock_id & 0x3ff);
+    (0..13)
+        .rev()
+        .map(|i| TID_ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+fn post_text(title: &str, content: &str) -> String {
+    let text = if title.is_empty() {
+        content.to_string()
+    } else {
+        format!("{}\n\n{}", title, content)
+    };
+    if text.chars().count() <= MAX_TEXT_CHARS {
+        return text;
+    }
+    let mut cut: String = text.chars().take(MAX_TEXT_CHARS - 1).collect();
+    cut.push('…');
+    cut
+}
+
+/// `GET /users/:username/atproto-export`
+///
+/// The user's public posts as `app.bsky.feed.post` records, each with a TID record key derived
+/// from when it was posted, ready to be written into a repo with `com.atproto.repo.applyWrites`.
+pub async fn export(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let store = storage::posts(&ctx)?;
+    let mut records = vec![];
+    for (clock_id, post) in posts::list_public(&*store, ctx.data().trace())
+        .await?
+        .into_iter()
+        .filter(|post| post.username == username)
+        .enumerate()
+    {
+        let created_at = match post.time.as_deref().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(time)) => time,
+            _ => continue,
+        };
+        let mut record = json!({
+            "$type": "app.bsky.feed.post",
+            "text": post_text(&post.title, &post.content),
+            "createdAt": created_at.to_rfc3339(),
+        });
+        if let (Some(lang), Some(record_obj)) = (
+            post.extra.get("lang").and_then(Value::as_str),
+            record.as_object_mut(),
+        ) {
+            record_obj.insert("langs".to_string(), json!([lang]));
+        }
+        records.push(json!({
+            "collection": "app.bsky.feed.post",
+            "rkey": tid(created_at.timestamp_micros(), clock_id as u64),
+            "value": record,
+        }));
+    }
+    Ok(Response::from_json(
+        &json!({ "username": username, "records": records }),
+    )?)
+}
@@ -0,0 +1,272 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::*;
+
+use crate::outbound;
+use crate::trace::Trace;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keys in the `auth` namespace:
+///
+/// - `jwks`: the auth server's [`Jwks`], cached for [`JWKS_TTL`] seconds
+const AUTH_KV: &str = "auth";
+
+const JWKS_TTL: u64 = 60 * 60;
+
+/// Seconds of clock skew allowed between the auth server and the worker.
+const LEEWAY: i64 = 60;
+
+/// What local verification made of a token.
+pub enum Verdict {
+    /// Signed by the auth server and current, for this username.
+    Valid(String),
+    /// A token we can check, and it doesn't check out.
+    Invalid,
+    /// Not something we can check here, e.g. not a JWT or signed with an algorithm we don't
+    /// implement; only the auth server can tell.
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    exp: i64,
+    #[serde(default)]
+    nbf: Option<i64>,
+}
+
+/// The auth server's published keys. Only symmetric (`oct`) keys can be used here; the rest are
+/// kept so a `kid` naming one is recognised as unknown rather than invalid.
+#[derive(Serialize, Deserialize, Debug)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    /// The key itself, base64url, for `oct` keys.
+    #[serde(default)]
+    k: Option<String>,
+}
+
+async fn fetch_jwks(env: &Env, trace: &Trace) -> Result<Jwks> {
+    let auth_server = env.var("AUTH_SERVER_URL")?.to_string();
+    let url = Url::parse(&format!(
+        "{}/.well-known/jwks.json",
+        auth_server.trim_end_matches('/')
+    ))?;
+    let policy = outbound::Policy::allow_only(url.host_str().unwrap_or_default());
+    let res = outbound::get(url.as_str(), &Headers::new(), &policy, trace).await?;
+    if !(200..300).contains(&res.status) {
+        return Err(format!("auth server answered {} for its JWKS", res.status).into());
+    }
+    Ok(serde_json::from_slice(&res.body)?)
+}
+
+/// The auth server's keys, from KV when they were fetched within the last hour.
+async fn jwks(env: &Env, trace: &Trace) -> Result<Jwks> {
+    let kv = env.kv(AUTH_KV)?;
+    if let Some(v) = kv.get("jwks").await? {
+        return Ok(v.as_json::<Jwks>()?);
+    }
+    let jwks = fetch_jwks(env, trace).await?;
+    kv.put("jwks", &jwks)?
+        .expiration_ttl(JWKS_TTL)
+        .execute()
+        .await?;
+    Ok(jwks)
+}
+
+/// The HMAC key for a token: the JWKS key its `kid` names, or else the `AUTH_JWT_SECRET` shared
+/// with the auth server. `None` when neither is there.
+async fn key(env: &Env, kid: Option<&str>, trace: &Trace) -> Result<Option<Vec<u8>>> {
+    if let Some(kid) = kid {
+        let jwks = match jwks(env, trace).await {
+            Ok(jwks) => jwks,
+            Err(e) => {
+                console_log!("auth: JWKS unavailable: {}", e);
+                return Ok(None);
+            }
+        };
+        return Ok(jwks
+            .keys
+            .into_iter()
+            .find(|jwk| jwk.kty == "oct" && jwk.kid.as_deref() == Some(kid))
+            .and_then(|jwk| URL_SAFE_NO_PAD.decode(jwk.k?).ok()));
+    }
+    Ok(env
+        .secret("AUTH_JWT_SECRET")
+        .ok()
+        .map(|secret| secret.to_string().into_bytes()))
+}
+
+/// The header, claims and signature of `token`, if it is shaped like a JWT.
+fn parts(token: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = token.split('.');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+            Some((header, claims, signature))
+        }
+        _ => None,
+    }
+}
+
+/// The header of `token`, if it is a JWT that can be checked here: only HS256 is.
+fn header(token: &str) -> Option<Header> {
+    let (header, _, _) = parts(token)?;
+    URL_SAFE_NO_PAD
+        .decode(header)
+        .ok()
+        .and_then(|header| serde_json::from_slice::<Header>(&header).ok())
+        .filter(|header| header.alg == "HS256")
+}
+
+/// Checks the signature of `token` with `key`, then that its claims name someone and hold at
+/// `now`, in seconds since the epoch.
+fn check(token: &str, key: &[u8], now: i64) -> Verdict {
+    let (header, claims, signature) = match parts(token) {
+        Some(parts) => parts,
+        None => return Verdict::Unknown,
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", header, claims).as_bytes());
+    let signed = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .is_some_and(|signature| mac.verify_slice(&signature).is_ok());
+    if !signed {
+        return Verdict::Invalid;
+    }
+    let claims = match URL_SAFE_NO_PAD
+        .decode(claims)
+        .ok()
+        .and_then(|claims| serde_json::from_slice::<Claims>(&claims).ok())
+    {
+        Some(claims) => claims,
+        None => return Verdict::Invalid,
+    };
+    if claims.exp + LEEWAY <= now || claims.nbf.is_some_and(|nbf| nbf - LEEWAY > now) {
+        return Verdict::Invalid;
+    }
+    if claims.sub.trim().is_empty() {
+        return Verdict::Invalid;
+    }
+    Verdict::Valid(claims.sub)
+}
+
+/// Checks a JWT issued by the auth server without calling it. Only HS256 is checked locally.
+pub async fn verify_jwt(env: &Env, token: &str, trace: &Trace) -> Result<Verdict> {
+    let parsed = match header(token) {
+        Some(parsed) => parsed,
+        None => return Ok(Verdict::Unknown),
+    };
+    let key = match key(env, parsed.kid.as_deref(), trace).await? {
+        Some(key) => key,
+        None => return Ok(Verdict::Unknown),
+    };
+    Ok(check(token, &key, Utc::now().timestamp()))
+}
+
+/// Looks for the auth server's JWT among the cookies of a `Cookie` header. Cookies that can't
+/// be checked here are skipped; the first one that can decides.
+pub async fn verify_cookies(env: &Env, cookie_header: &str, trace: &Trace) -> Result<Verdict> {
+    for (_, value) in cookie_header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+    {
+        if value.matches('.').count() != 2 {
+            continue;
+        }
+        match verify_jwt(env, value.trim(), trace).await? {
+            Verdict::Unknown => continue,
+            verdict => return Ok(verdict),
+        }
+    }
+    Ok(Verdict::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const KEY: &[u8] = b"shared with the auth server";
+    const NOW: i64 = 1_700_000_000;
+
+    fn token(alg: &str, claims: Value, key: &[u8]) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let mut mac = HmacSha256::new_from_slice(key).unwrap();
+        mac.update(format!("{}.{}", header, claims).as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}.{}", header, claims, signature)
+    }
+
+    fn checked(claims: Value) -> Verdict {
+        check(&token("HS256", claims, KEY), KEY, NOW)
+    }
+
+    #[test]
+    fn only_hs256_jwts_are_checked_here() {
+        let claims = json!({ "sub": "alice", "exp": NOW + 60 });
+        assert!(header(&token("HS256", claims.clone(), KEY)).is_some());
+        assert!(header(&token("RS256", claims, KEY)).is_none());
+        assert!(header("not-a-jwt").is_none());
+        assert!(header("a.b.c.d").is_none());
+    }
+
+    #[test]
+    fn a_signed_current_token_names_its_subject() {
+        let verdict = checked(json!({ "sub": "alice", "exp": NOW + 60 }));
+        assert!(matches!(verdict, Verdict::Valid(username) if username == "alice"));
+    }
+
+    #[test]
+    fn a_wrong_key_or_tampered_claims_are_invalid() {
+        let signed = token("HS256", json!({ "sub": "alice", "exp": NOW + 60 }), KEY);
+        assert!(matches!(check(&signed, b"other", NOW), Verdict::Invalid));
+
+        let (header, _, signature) = parts(&signed).unwrap();
+        let claims =
+            URL_SAFE_NO_PAD.encode(json!({ "sub": "mallory", "exp": NOW + 60 }).to_string());
+        let tampered = format!("{}.{}.{}", header, claims, signature);
+        assert!(matches!(check(&tampered, KEY, NOW), Verdict::Invalid));
+    }
+
+    #[test]
+    fn expiry_and_not_before_allow_for_clock_skew() {
+        let expired_within_leeway = json!({ "sub": "alice", "exp": NOW - LEEWAY + 1 });
+        let expired = json!({ "sub": "alice", "exp": NOW - LEEWAY });
+        let early = json!({ "sub": "alice", "exp": NOW + 600, "nbf": NOW + LEEWAY + 1 });
+        assert!(matches!(checked(expired_within_leeway), Verdict::Valid(_)));
+        assert!(matches!(checked(expired), Verdict::Invalid));
+        assert!(matches!(checked(early), Verdict::Invalid));
+    }
+
+    #[test]
+    fn a_token_naming_no_one_is_invalid() {
+        assert!(matches!(
+            checked(json!({ "sub": " ", "exp": NOW + 60 })),
+            Verdict::Invalid
+        ));
+        assert!(matches!(
+            checked(json!({ "exp": NOW + 60 })),
+            Verdict::Invalid
+        ));
+    }
+}
@@ -0,0 +1,434 @@
+use chrono::{DateTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::moderation::{self, Action, ReasonCode};
+use crate::session::Session;
+use crate::{communities, mod_notes, models, posts, storage, users, webhooks};
+
+/// Most rules one community may have.
+const MAX_RULES: usize = 25;
+
+/// Compiled size a rule's regex may grow to, so one rule can't make every post slow to write.
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RuleAction {
+    /// Take the post down, as a moderator's `remove` would.
+    Remove,
+    /// Keep the post out of listings until a moderator restores it.
+    Hold,
+    /// Publish the post but list it for the moderators.
+    Flag,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Title,
+    Content,
+    #[default]
+    Any,
+}
+
+/// One rule. Every condition it sets has to hold for it to match.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Rule {
+    name: String,
+    /// Words or phrases, any of which matches, ignoring case.
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    regex: Option<String>,
+    /// What `keywords` and `regex` are matched against.
+    #[serde(default)]
+    field: Field,
+    /// Matches authors whose account is younger than this.
+    #[serde(default)]
+    max_account_age_days: Option<i64>,
+    /// Matches authors with fewer likes than this across their posts.
+    #[serde(default)]
+    karma_below: Option<i64>,
+    action: RuleAction,
+    #[serde(default = "default_reason_code")]
+    reason_code: ReasonCode,
+    /// Shown to the author of a removed or held post.
+    #[serde(default)]
+    message: String,
+}
+
+fn default_reason_code() -> ReasonCode {
+    ReasonCode::Other
+}
+
+impl Rule {
+    fn compiled_regex(&self) -> std::result::Result<Option<Regex>, regex::Error> {
+        self.regex
+            .as_deref()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+            })
+            .transpose()
+    }
+
+    /// Whether the post's text meets `keywords` and `regex`. The author conditions are checked
+    /// apart, as they need lookups.
+    fn text_matches(&self, post: &Value) -> std::result::Result<bool, regex::Error> {
+        let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or_default();
+        let text = match self.field {
+            Field::Title => field("title").to_string(),
+            Field::Content => field("content").to_string(),
+            Field::Any => format!("{}\n{}", field("title"), field("content")),
+        };
+        if !self.keywords.is_empty() {
+            let text = text.to_lowercase();
+            if !self
+                .keywords
+                .iter()
+                .any(|keyword| text.contains(&keyword.to_lowercase()))
+            {
+                return Ok(false);
+            }
+        }
+        if let Some(regex) = self.compiled_regex()? {
+            if !regex.is_match(&text) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn has_conditions(&self) -> bool {
+        !self.keywords.is_empty()
+            || self.regex.is_some()
+            || self.max_account_age_days.is_some()
+            || self.karma_below.is_some()
+    }
+}
+
+/// What is known about the author, looked up only when a rule asks for it.
+#[derive(Default)]
+struct Author {
+    age_days: Option<i64>,
+    karma: Option<i64>,
+}
+
+impl Author {
+    async fn age_days(&mut self, ctx: &RouteContext<Session>, username: &str) -> Result<i64> {
+        if let Some(age_days) = self.age_days {
+            return Ok(age_days);
+        }
+        // Anyone unknown counts as brand new.
+        let registered = users::load_replicated(ctx, username)
+            .await?
+            .and_then(|profile| DateTime::parse_from_rfc3339(&profile.created_at).ok());
+        let age_days = registered
+            .map(|registered| (Utc::now() - registered.with_timezone(&Utc)).num_days())
+            .unwrap_or(0);
+        self.age_days = Some(age_days);
+        Ok(age_days)
+    }
+
+    async fn karma(&mut self, ctx: &RouteContext<Session>, username: &str) -> Result<i64> {
+        if let Some(karma) = self.karma {
+            return Ok(karma);
+        }
+        let karma = posts::list_public(&*storage::posts(ctx)?, ctx.data().trace())
+            .await?
+            .iter()
+            .filter(|post| post.username == username)
+            .filter_map(|post| post.extra.get("likes").and_then(Value::as_i64))
+            .sum();
+        self.karma = Some(karma);
+        Ok(karma)
+    }
+}
+
+fn rules_key(community: &str) -> String {
+    format!("automod/{}", community)
+}
+
+async fn load(ctx: &RouteContext<Session>, community: &str) -> Result<Vec<Rule>> {
+    let kv = ctx.kv(communities::COMMUNITIES_KV)?;
+    match kv.get(&rules_key(community)).await? {
+        Some(v) => Ok(v.as_json::<Vec<Rule>>()?),
+        None => Ok(vec![]),
+    }
+}
+
+async fn rule_matches(
+    ctx: &RouteContext<Session>,
+    rule: &Rule,
+    post: &Value,
+    author: &mut Author,
+) -> Result<bool> {
+    if !rule.text_matches(post).map_err(|e| e.to_string())? {
+        return Ok(false);
+    }
+    let username = post
+        .get("username")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if let Some(max_age_days) = rule.max_account_age_days {
+        if author.age_days(ctx, username).await? >= max_age_days {
+            return Ok(false);
+        }
+    }
+    if let Some(karma_below) = rule.karma_below {
+        if author.karma(ctx, username).await? >= karma_below {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The first of the community's rules that `post` matches, in the order the moderators listed
+/// them. A rule that can't be evaluated is logged and lets the post through.
+async fn first_match(ctx: &RouteContext<Session>, post: &Value) -> Option<Rule> {
+    let community = post.get("community").and_then(Value::as_str)?;
+    let rules = match load(ctx, community).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            console_log!("loading automod rules for {} failed: {}", community, e);
+            return None;
+        }
+    };
+    let mut author = Author::default();
+    for rule in rules {
+        match rule_matches(ctx, &rule, post, &mut author).await {
+            Ok(true) => return Some(rule),
+            Ok(false) => {}
+            Err(e) => console_log!(
+                "automod rule `{}` in {} failed: {}",
+                rule.name,
+                community,
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Runs a new post, before it is stored, through its community's rules. A `remove` or `hold`
+/// marks it moderated; the matching rule is to be handed to [`report`] once the post is stored.
+pub async fn screen(ctx: &RouteContext<Session>, post: &mut Value) -> Option<Rule> {
+    let rule = first_match(ctx, post).await?;
+    match rule.action {
+        RuleAction::Remove => moderation::mark(post, Action::Remove, rule.reason_code),
+        RuleAction::Hold => moderation::mark(post, Action::Hold, rule.reason_code),
+        RuleAction::Flag => {}
+    }
+    Some(rule)
+}
+
+async fn flag(
+    ctx: &RouteContext<Session>,
+    community: &str,
+    id: &str,
+    post: &Value,
+    rule: &Rule,
+) -> Result<()> {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or_default();
+    ctx.kv(moderation::MODERATION_KV)?
+        .put(
+            &format!("flag/{}/{}", community, id),
+            json!({
+                "post_id": id,
+                "title": field("title"),
+                "username": field("username"),
+                "rule": rule.name,
+                "flagged_at": Utc::now().to_rfc3339(),
+            }),
+        )?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Records what `rule` did to the stored post: a case for its author, the mod queue webhook for
+/// a hold, or an entry in the community's flags. Failures are logged, never returned.
+pub async fn report(ctx: &RouteContext<Session>, id: &str, post: &Value, rule: &Rule) {
+    let community = post
+        .get("community")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    console_log!(
+        "automod: rule `{}` in {} matched post {}",
+        rule.name,
+        community,
+        id
+    );
+    let action = match rule.action {
+        RuleAction::Remove => Action::Remove,
+        RuleAction::Hold => Action::Hold,
+        RuleAction::Flag => {
+            if let Err(e) = flag(ctx, community, id, post, rule).await {
+                console_log!("flagging {} failed: {}", id, e);
+            }
+            return;
+        }
+    };
+    let opened = moderation::open_case(
+        ctx,
+        id,
+        post,
+        action,
+        rule.reason_code,
+        rule.message.clone(),
+    );
+    if let Err(e) = opened.await {
+        console_log!("automod case for {} failed: {}", id, e);
+    }
+    if action == Action::Hold {
+        webhooks::notify(ctx, community, webhooks::Event::ModQueue, post).await;
+    }
+}
+
+/// `GET /c/:name/automod`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(&load(&ctx, &community).await?)?)
+}
+
+/// `PUT /c/:name/automod`, replacing the community's whole rule list.
+pub async fn replace(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let rules = models::from_body::<Vec<Rule>>(&mut req).await?;
+    if rules.len() > MAX_RULES {
+        return Err(ApiError::BadRequest(format!("At most {} rules", MAX_RULES)));
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.name.trim().is_empty() {
+            return Err(ApiError::BadRequest(format!("rule {} needs a `name`", i)));
+        }
+        // A rule without conditions would catch every post.
+        if !rule.has_conditions() {
+            return Err(ApiError::BadRequest(format!(
+                "rule `{}` has no conditions",
+                rule.name
+            )));
+        }
+        if let Err(e) = rule.compiled_regex() {
+            return Err(ApiError::BadRequest(format!("rule `{}`: {}", rule.name, e)));
+        }
+    }
+    ctx.kv(communities::COMMUNITIES_KV)?
+        .put(&rules_key(&community), &rules)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&rules)?)
+}
+
+/// `GET /c/:name/automod/flags`: posts flagged by a rule and not yet dismissed, each with the
+/// moderators' notes on its author.
+pub async fn flags(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let kv = ctx.kv(moderation::MODERATION_KV)?;
+    let prefix = format!("flag/{}/", community);
+    let mut flags = vec![];
+    for key in kv.list().prefix(prefix).execute().await?.keys {
+        let mut flag = match kv.get(&key.name).await? {
+            Some(v) => v.as_json::<Value>()?,
+            None => continue,
+        };
+        let username = flag
+            .get("username")
+            .and_then(Value::as_str)
+            .map(String::from);
+        if let (Some(username), Some(flag_obj)) = (username, flag.as_object_mut()) {
+            let notes = mod_notes::about(&ctx, &community, &username).await?;
+            flag_obj.insert("author_notes".to_string(), json!(notes));
+        }
+        flags.push(flag);
+    }
+    Ok(Response::from_json(&flags)?)
+}
+
+/// `DELETE /c/:name/automod/flags/:id`, once a moderator has looked at the post.
+pub async fn dismiss_flag(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (community, id) = match (ctx.param("name"), ctx.param("id")) {
+        (Some(name), Some(id)) => (name.clone(), id.clone()),
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    ctx.kv(moderation::MODERATION_KV)?
+        .delete(&format!("flag/{}/{}", community, id))
+        .await?;
+    Ok(Response::empty()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: Value) -> Rule {
+        let mut rule_obj = json!({ "name": "test", "action": "hold" });
+        rule_obj
+            .as_object_mut()
+            .unwrap()
+            .extend(rule.as_object().unwrap().clone());
+        serde_json::from_value(rule_obj).unwrap()
+    }
+
+    #[test]
+    fn keywords_match_any_ignoring_case() {
+        let post = json!({ "title": "Cheap WATCHES", "content": "click here" });
+        assert!(rule(json!({ "keywords": ["rolex", "watches"] }))
+            .text_matches(&post)
+            .unwrap());
+        assert!(!rule(json!({ "keywords": ["rolex"] }))
+            .text_matches(&post)
+            .unwrap());
+    }
+
+    #[test]
+    fn keywords_only_look_at_their_field() {
+        let post = json!({ "title": "Cheap watches", "content": "click here" });
+        let in_title = json!({ "keywords": ["click"], "field": "title" });
+        let in_content = json!({ "keywords": ["click"], "field": "content" });
+        assert!(!rule(in_title).text_matches(&post).unwrap());
+        assert!(rule(in_content).text_matches(&post).unwrap());
+    }
+
+    #[test]
+    fn keywords_and_regex_both_have_to_hold() {
+        let post = json!({ "title": "Buy now", "content": "call 555-0100" });
+        let both = json!({ "keywords": ["buy"], "regex": r"\d{3}-\d{4}" });
+        let regex_fails = json!({ "keywords": ["buy"], "regex": r"^\d+$" });
+        assert!(rule(both).text_matches(&post).unwrap());
+        assert!(!rule(regex_fails).text_matches(&post).unwrap());
+    }
+
+    #[test]
+    fn author_only_rules_leave_the_text_alone() {
+        let author_only = rule(json!({ "karma_below": 5 }));
+        assert!(author_only.text_matches(&json!({})).unwrap());
+        assert!(author_only.has_conditions());
+        assert!(!rule(json!({})).has_conditions());
+    }
+
+    #[test]
+    fn oversized_or_broken_regexes_are_refused() {
+        assert!(rule(json!({ "regex": "(" })).compiled_regex().is_err());
+        assert!(rule(json!({ "regex": r"\w{1000}{1000}" }))
+            .compiled_regex()
+            .is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{bots, cache};
+
+/// Var naming the largest request body, in bytes, a JSON route accepts.
+const MAX_BODY_VAR: &str = "MAX_BODY_BYTES";
+
+const DEFAULT_MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// Routes whose bodies aren't JSON, which check what they are sent themselves.
+const NOT_JSON: &[&str] = &["/media", "/form/:field"];
+
+/// The largest body the JSON routes accept: `MAX_BODY_BYTES`, or
+/// [`DEFAULT_MAX_BODY_BYTES`] when it is unset or not a number.
+fn max_body_bytes(env: &Env) -> usize {
+    env.var(MAX_BODY_VAR)
+        .ok()
+        .and_then(|var| var.to_string().parse().ok())
+        .filter(|&max| max > 0)
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// Whether a `Content-Type` is JSON, e.g. `application/json; charset=utf-8` or
+/// `application/merge-patch+json`.
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json"
+        || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+/// The check every request body goes through, run by `main` before routing and so before
+/// anything is read or parsed: a body sent to a JSON route has to declare its length, be at most
+/// `MAX_BODY_BYTES` long (413) and be `application/json` (415). Requests without a body, such as
+/// `POST /c/:name/join`, pass as they are.
+pub fn gate(req: &Request, path: &str, env: &Env) -> ApiResult<()> {
+    if matches!(req.method(), Method::Get | Method::Head | Method::Options)
+        || NOT_JSON
+            .iter()
+            .chain(bots::DECOYS)
+            .any(|pattern| cache::matches(pattern, path))
+    {
+        return Ok(());
+    }
+    let not_a_number = |_| ApiError::BadRequest("`Content-Length` is not a number".to_string());
+    let length = req
+        .headers()
+        .get("Content-Length")?
+        .map(|length| length.trim().parse::<usize>())
+        .transpose()
+        .map_err(not_a_number)?;
+    let length = match (length, req.inner().body().is_some()) {
+        (Some(0), _) | (None, false) => return Ok(()),
+        (Some(length), _) => length,
+        (None, true) => return Err(ApiError::LengthRequired),
+    };
+    let max = max_body_bytes(env);
+    if length > max {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "request bodies can be at most {} bytes",
+            max
+        )));
+    }
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    if !is_json(&content_type) {
+        return Err(ApiError::UnsupportedMediaType(
+            "request bodies have to be `application/json`".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::utils;
+
+/// Keys in the `bots` namespace:
+///
+/// - `score/<ip>`: [`Score`], how bot-like requests from `ip` have looked lately
+/// - `slowed/<ip>`: present while a likely bot at `ip` waits before posting again
+const BOTS_KV: &str = "bots";
+
+/// A score stops counting a week after its last signal.
+const SCORE_TTL: u64 = 60 * 60 * 24 * 7;
+
+/// Score from which an address is treated as a bot.
+pub const LIKELY_BOT: u32 = 50;
+
+/// How long a likely bot waits between posts, in seconds.
+const SLOW_DOWN: u64 = 60 * 10;
+
+/// Something a person using a real client wouldn't do.
+#[derive(Debug, Clone, Copy)]
+pub enum Signal {
+    /// Filled in a honeypot field of a post.
+    Honeypot,
+    /// Requested a decoy path, see [`decoy`].
+    Decoy,
+}
+
+impl Signal {
+    fn points(self) -> u32 {
+        match self {
+            Signal::Honeypot => 50,
+            Signal::Decoy => 25,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Score {
+    points: u32,
+    /// The signals that added up to `points`, most recent last.
+    signals: Vec<String>,
+    updated_at: String,
+}
+
+async fn load(kv: &kv::KvStore, ip: &str) -> Result<Score> {
+    match kv.get(&format!("score/{}", ip)).await? {
+        Some(v) => Ok(v.as_json::<Score>()?),
+        None => Ok(Score::default()),
+    }
+}
+
+async fn add(req: &Request, ctx: &RouteContext<Session>, signal: Signal) -> Result<()> {
+    let ip = utils::client_ip(req)?;
+    let kv = ctx.kv(BOTS_KV)?;
+    let mut score = load(&kv, &ip).await?;
+    score.points = score.points.saturating_add(signal.points());
+    score.signals.push(format!("{:?}", signal).to_lowercase());
+    score.updated_at = Utc::now().to_rfc3339();
+    kv.put(&format!("score/{}", ip), &score)?
+        .expiration_ttl(SCORE_TTL)
+        .execute()
+        .await?;
+    console_log!(
+        "bots: {} flagged ({:?}), score {}",
+        ip,
+        signal,
+        score.points
+    );
+    Ok(())
+}
+
+/// Adds `signal` to the score of the address `req` came from. The request goes on as if
+/// nothing happened; failures are logged, never returned.
+pub async fn flag(req: &Request, ctx: &RouteContext<Session>, signal: Signal) {
+    if let Err(e) = add(req, ctx, signal).await {
+        console_log!("bot flag failed: {}", e);
+    }
+}
+
+/// The bot-likelihood score of the address `req` came from, 0 for anything not flagged.
+pub async fn score(req: &Request, ctx: &RouteContext<Session>) -> Result<u32> {
+    let ip = utils::client_ip(req)?;
+    Ok(load(&ctx.kv(BOTS_KV)?, &ip).await?.points)
+}
+
+/// Lets a likely bot post once every [`SLOW_DOWN`] seconds; everyone else passes untouched.
+pub async fn throttle(req: &Request, ctx: &RouteContext<Session>) -> ApiResult<()> {
+    if score(req, ctx).await? < LIKELY_BOT {
+        return Ok(());
+    }
+    let kv = ctx.kv(BOTS_KV)?;
+    let slowed = format!("slowed/{}", utils::client_ip(req)?);
+    if kv.get(&slowed).await?.is_some() {
+        return Err(ApiError::TooManyRequests(
+            "You're posting too fast, try again later".to_string(),
+        ));
+    }
+    kv.put(&slowed, "")?
+        .expiration_ttl(SLOW_DOWN)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Routes answered by [`decoy`].
+pub const DECOYS: &[&str] = &[
+    "/wp-login.php",
+    "/xmlrpc.php",
+    "/wp-admin/post-new.php",
+    "/.env",
+    "/admin/login",
+];
+
+/// Paths nobody has a reason to visit here, which scanners and spam kits try anyway
+/// (`/wp-login.php`, `/xmlrpc.php`, `/.env`, ...): flags the caller and answers like any page
+/// that isn't there.
+pub async fn decoy(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    flag(&req, &ctx, Signal::Decoy).await;
+    Err(ApiError::NotFound)
+}
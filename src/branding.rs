@@ -0,0 +1,150 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::error::ApiResult;
+use crate::session::Session;
+use crate::validation::Problems;
+use crate::{isolate, media, models, settings, tenants};
+
+/// Kept in the `settings` namespace under `branding`, so each tenant has its own.
+const BRANDING_KEY: &str = "branding";
+
+const BRANDING_CACHE_KEY: &str = "branding";
+
+/// How long an isolate keeps the branding, in milliseconds.
+const BRANDING_TTL_MS: i64 = 60 * 1000;
+
+const MAX_NAME_CHARS: usize = 64;
+
+/// Colors a frontend themes itself with, each `#rgb` or `#rrggbb`. Unset ones are left to the
+/// frontend.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Colors {
+    #[serde(default)]
+    pub primary: Option<String>,
+    #[serde(default)]
+    pub accent: Option<String>,
+    #[serde(default)]
+    pub background: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// How the instance presents itself, set by its admins.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Branding {
+    /// Name of the instance. The software's name until set.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Media id of the logo, uploaded through `POST /media` like any image.
+    #[serde(default)]
+    pub logo: Option<String>,
+    #[serde(default)]
+    pub colors: Colors,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+fn cache_key(ctx: &RouteContext<Session>) -> String {
+    tenants::isolate_key(ctx.data().bindings(), BRANDING_CACHE_KEY)
+}
+
+/// The instance's branding; the defaults until an admin sets it. Read by every page of a
+/// frontend, so kept in the isolate for [`BRANDING_TTL_MS`].
+pub async fn get(ctx: &RouteContext<Session>) -> Result<Branding> {
+    let cache_key = cache_key(ctx);
+    if let Some(branding) = isolate::get::<Branding>(&cache_key) {
+        return Ok(branding);
+    }
+    let branding = match ctx.kv(settings::SETTINGS_KV)?.get(BRANDING_KEY).await? {
+        Some(v) => v.as_json::<Branding>()?,
+        None => Branding::default(),
+    };
+    isolate::put(&cache_key, &branding, BRANDING_TTL_MS);
+    Ok(branding)
+}
+
+/// The instance's name, as `branding` has it.
+pub fn name(branding: &Branding) -> &str {
+    branding.name.as_deref().unwrap_or(env!("CARGO_PKG_NAME"))
+}
+
+fn is_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn answer(req: &Request, branding: &Branding) -> ApiResult<Response> {
+    let logo_url = match &branding.logo {
+        Some(logo) => {
+            let mut url = req.url()?;
+            url.set_path(&format!("/media/{}", logo));
+            url.set_query(None);
+            Some(url.to_string())
+        }
+        None => None,
+    };
+    Ok(Response::from_json(&json!({
+        "name": name(branding),
+        "logo": branding.logo,
+        "logo_url": logo_url,
+        "colors": branding.colors,
+        "updated_at": branding.updated_at,
+    }))?)
+}
+
+/// `GET /branding` and `GET /admin/branding`: the instance's name, logo and colors, for
+/// frontends to present it with.
+pub async fn show(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    answer(&req, &get(&ctx).await?)
+}
+
+/// `PUT /admin/branding` with `{"name": "...", "logo": "<media id>", "colors": {"primary":
+/// "#336699", "accent": "#f90", "background": "#fff", "text": "#111"}}` replaces the instance's
+/// branding; anything left out goes back to its default.
+pub async fn update(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let cache_key = cache_key(&ctx);
+    let kv = ctx.kv(settings::SETTINGS_KV)?;
+    let mut branding = models::from_body::<Branding>(&mut req).await?;
+    branding.name = branding
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+
+    let mut problems = Problems::default();
+    if branding
+        .name
+        .as_ref()
+        .is_some_and(|name| name.chars().count() > MAX_NAME_CHARS)
+    {
+        problems.add(
+            "name",
+            format!("can be at most {} characters", MAX_NAME_CHARS),
+        );
+    }
+    if let Some(logo) = &branding.logo {
+        if !media::exists(&ctx.get_env(), logo).await? {
+            problems.add("logo", "has to be the id of an uploaded image");
+        }
+    }
+    let colors = &branding.colors;
+    for (field, color) in [
+        ("colors.primary", &colors.primary),
+        ("colors.accent", &colors.accent),
+        ("colors.background", &colors.background),
+        ("colors.text", &colors.text),
+    ] {
+        if color.as_deref().is_some_and(|color| !is_color(color)) {
+            problems.add(field, "has to be a `#rgb` or `#rrggbb` color");
+        }
+    }
+    problems.finish()?;
+
+    branding.updated_at = Some(Utc::now().to_rfc3339());
+    kv.put(BRANDING_KEY, &branding)?.execute().await?;
+    isolate::put(&cache_key, &branding, BRANDING_TTL_MS);
+    answer(&req, &branding)
+}
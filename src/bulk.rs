@@ -0,0 +1,329 @@
+use chrono::Utc;
+use js_sys::Reflect;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::{self, Session};
+use crate::storage::PostStore;
+use crate::{firehose, models, posts, queues, storage, utils};
+
+/// Keys in the `bulk` namespace:
+///
+/// - `job/<id>`: a [`Job`], where `id` is `<millis, zero-padded>-<hash of the request>`, kept
+///   [`JOB_TTL`] seconds after it was last worked on
+pub const BULK_KV: &str = "bulk";
+
+/// Binding of the queue that finishes bulk jobs a request left items over from.
+const BULK_QUEUE: &str = "BULK_QUEUE";
+
+/// Name of that queue in `wrangler.toml`, which `queues::queue` hands its batches here by.
+pub const BULK_QUEUE_NAME: &str = "bulk-jobs";
+
+/// Most posts a single bulk delete may name.
+const MAX_BULK_DELETE: usize = 1000;
+
+/// Posts handled per request or queue message. Each one costs a load, a write and the journal,
+/// cache and firehose entries that follow it, so this stays well under a worker's subrequests.
+const CHUNK: usize = 20;
+
+/// How long a job is kept after it was last worked on.
+const JOB_TTL: u64 = 60 * 60 * 24 * 7;
+
+/// `POST /posts/bulk_delete`, either starting a job or continuing one.
+#[derive(Deserialize, Debug)]
+struct BulkDelete {
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default)]
+    continuation: Option<String>,
+}
+
+/// A bulk delete and how far it got.
+#[derive(Serialize, Deserialize, Debug)]
+struct Job {
+    id: String,
+    username: String,
+    /// Posts not handled yet, in the order they were named.
+    remaining: Vec<String>,
+    deleted: Vec<String>,
+    failed: Vec<Value>,
+    /// Whether [`BULK_QUEUE`] is finishing the job, rather than the client.
+    queued: bool,
+    updated_at: String,
+}
+
+/// What is queued to handle the next [`CHUNK`] of a job. `step` counts the job's messages, so
+/// each one's idempotency key is its own and a redelivery of it is skipped (see `queues`).
+#[derive(Serialize, Deserialize, Debug)]
+struct NextChunk {
+    job: String,
+    step: u32,
+}
+
+impl NextChunk {
+    fn idempotency_key(&self) -> String {
+        format!("bulk/{}/{}", self.job, self.step)
+    }
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+fn job_key(id: &str) -> String {
+    format!("job/{}", id)
+}
+
+async fn load(kv: &kv::KvStore, id: &str) -> Result<Option<Job>> {
+    match kv.get(&job_key(id)).await? {
+        Some(v) => Ok(Some(v.as_json::<Job>()?)),
+        None => Ok(None),
+    }
+}
+
+async fn save(kv: &kv::KvStore, job: &mut Job) -> Result<()> {
+    job.updated_at = Utc::now().to_rfc3339();
+    kv.put(&job_key(&job.id), &*job)?
+        .expiration_ttl(JOB_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// [`BULK_QUEUE`], if the worker has it bound.
+fn bulk_queue(bindings: &JsValue) -> Result<Option<JsValue>> {
+    let queue = Reflect::get(bindings, &JsValue::from(BULK_QUEUE)).map_err(js_error)?;
+    Ok(if queue.is_undefined() {
+        None
+    } else {
+        Some(queue)
+    })
+}
+
+async fn send(queue: &JsValue, next: &NextChunk) -> Result<()> {
+    queues::send(queue, &serde_json::to_string(next)?).await
+}
+
+/// Deletes the next [`CHUNK`] of `job`'s posts, answering what became of them. A post that
+/// can't be deleted is reported as failed, so one bad post doesn't hold up the rest.
+async fn work(
+    store: &dyn PostStore,
+    firehose: &kv::KvStore,
+    bindings: &JsValue,
+    job: &mut Job,
+) -> (Vec<String>, Vec<Value>) {
+    let chunk: Vec<String> = job
+        .remaining
+        .drain(..CHUNK.min(job.remaining.len()))
+        .collect();
+    let mut deleted = vec![];
+    let mut failed = vec![];
+    for id in chunk {
+        let post = match posts::load(store, &id).await {
+            Ok(post) => post,
+            Err(ApiError::NotFound) => {
+                failed.push(json!({ "id": id, "error": "not found" }));
+                continue;
+            }
+            Err(e) => {
+                failed.push(json!({ "id": id, "error": e.to_string() }));
+                continue;
+            }
+        };
+        if post.get("username").and_then(Value::as_str) != Some(job.username.as_str()) {
+            failed.push(json!({ "id": id, "error": "not the author of this post" }));
+            continue;
+        }
+        match posts::soft_delete_in(firehose, bindings, store, &id, post, &job.username).await {
+            Ok(_) => deleted.push(id),
+            Err(e) => failed.push(json!({ "id": id, "error": e.to_string() })),
+        }
+    }
+    job.deleted.extend(deleted.iter().cloned());
+    job.failed.extend(failed.iter().cloned());
+    (deleted, failed)
+}
+
+/// `POST /posts/bulk_delete` with `{"ids": [...]}` deletes up to [`MAX_BULK_DELETE`] of the
+/// signed-in user's posts, [`CHUNK`] of them right away. It answers with those,
+/// `{"deleted": [...], "failed": [...], "remaining": 80, "continuation": "...", "queued": true}`.
+/// With [`BULK_QUEUE`] bound the rest is deleted in the background, which
+/// `GET /posts/bulk_delete/:job` follows, passing the `continuation`. Without it, the client sends
+/// `{"continuation": "..."}` for each next chunk until `continuation` comes back `null`. Only the
+/// user who started a job may continue it.
+pub async fn delete(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let body = models::from_body::<BulkDelete>(&mut req).await?;
+    let kv = ctx.kv(BULK_KV)?;
+    let queue = bulk_queue(ctx.data().bindings())?;
+    let mut job = match &body.continuation {
+        Some(id) => {
+            let job = load(&kv, id).await?.ok_or(ApiError::NotFound)?;
+            if job.username != username {
+                return Err(ApiError::Forbidden("Forbidden".to_string()));
+            }
+            if job.queued {
+                return Err(ApiError::Conflict(
+                    "This job is being finished in the background".to_string(),
+                ));
+            }
+            job
+        }
+        None => {
+            if body.ids.len() > MAX_BULK_DELETE {
+                return Err(ApiError::BadRequest(format!(
+                    "at most {} posts can be deleted at once",
+                    MAX_BULK_DELETE
+                )));
+            }
+            let now = Utc::now();
+            let request = format!("{}|{}", username, body.ids.join(","));
+            Job {
+                id: format!(
+                    "{:013}-{}",
+                    now.timestamp_millis(),
+                    &utils::sha256_hex(&request)[..16]
+                ),
+                username,
+                remaining: body.ids.clone(),
+                deleted: vec![],
+                failed: vec![],
+                queued: false,
+                updated_at: now.to_rfc3339(),
+            }
+        }
+    };
+
+    let store = storage::posts(&ctx)?;
+    let (deleted, failed) = work(
+        &*store,
+        &ctx.kv(firehose::FIREHOSE_KV)?,
+        ctx.data().bindings(),
+        &mut job,
+    )
+    .await;
+    job.queued = !job.remaining.is_empty() && queue.is_some();
+    save(&kv, &mut job).await?;
+    if let (true, Some(queue)) = (job.queued, &queue) {
+        let next = NextChunk {
+            job: job.id.clone(),
+            step: 0,
+        };
+        // The job is saved either way; the client can carry on with the continuation.
+        if let Err(e) = send(queue, &next).await {
+            console_log!("queueing bulk job {} failed: {}", job.id, e);
+            job.queued = false;
+            save(&kv, &mut job).await?;
+        }
+    }
+    let continuation = if job.remaining.is_empty() {
+        None
+    } else {
+        Some(&job.id)
+    };
+    Ok(Response::from_json(&json!({
+        "deleted": deleted,
+        "failed": failed,
+        "remaining": job.remaining.len(),
+        "continuation": continuation,
+        "queued": job.queued,
+    }))?)
+}
+
+/// `GET /posts/bulk_delete/:job`: how far one of the signed-in user's bulk deletes got, all of it
+/// so far.
+pub async fn progress(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "job")?;
+    let job = load(&ctx.kv(BULK_KV)?, &id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if job.username != username {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(&json!({
+        "id": job.id,
+        "deleted": job.deleted,
+        "failed": job.failed,
+        "remaining": job.remaining.len(),
+        "queued": job.queued,
+        "updated_at": job.updated_at,
+    }))?)
+}
+
+/// Consumer of [`BULK_QUEUE`]: each message deletes the next [`CHUNK`] of a job and queues the
+/// one after, until the job is done. Messages that keep failing are dead-lettered, and a
+/// redelivery of one already handled is skipped (see `queues`).
+pub async fn consume(messages: Vec<queues::Message>, env: &Env) -> Result<()> {
+    let kv = env.kv(BULK_KV)?;
+    let firehose = env.kv(firehose::FIREHOSE_KV)?;
+    let dead_letters = env.kv(queues::QUEUES_KV)?;
+    let store = storage::posts_in(env)?;
+    let queue = bulk_queue(env)?;
+    for message in messages {
+        let next = match message
+            .body()
+            .and_then(|text| serde_json::from_str::<NextChunk>(&text).ok())
+        {
+            Some(next) => next,
+            None => {
+                let malformed = "malformed bulk message".to_string();
+                queues::failed(&dead_letters, BULK_QUEUE, &message, malformed, true).await?;
+                continue;
+            }
+        };
+        let key = next.idempotency_key();
+        match queues::handled(&dead_letters, BULK_QUEUE, &key).await {
+            Ok(true) => {
+                message.ack()?;
+                continue;
+            }
+            Ok(false) => {}
+            // Handling it again would queue the job's next chunk twice, so wait and see.
+            Err(e) => {
+                queues::failed(&dead_letters, BULK_QUEUE, &message, e.to_string(), false).await?;
+                continue;
+            }
+        }
+        let outcome: Result<()> = async {
+            // A job that expired, or was already finished, has nothing left to do.
+            let mut job = match load(&kv, &next.job).await? {
+                Some(job) if job.queued && !job.remaining.is_empty() => job,
+                _ => return Ok(()),
+            };
+            work(&*store, &firehose, env, &mut job).await;
+            job.queued = !job.remaining.is_empty() && queue.is_some();
+            save(&kv, &mut job).await?;
+            if let (true, Some(queue)) = (job.queued, &queue) {
+                let after = NextChunk {
+                    job: job.id.clone(),
+                    step: next.step + 1,
+                };
+                send(queue, &after).await?;
+            }
+            console_log!(
+                "bulk job {}: {} deleted, {} remaining",
+                job.id,
+                job.deleted.len(),
+                job.remaining.len()
+            );
+            Ok(())
+        }
+        .await;
+        match outcome {
+            Ok(()) => {
+                if let Err(e) = queues::mark_handled(&dead_letters, &key).await {
+                    console_log!("marking {} handled failed: {}", key, e);
+                }
+                message.ack()?;
+            }
+            Err(e) => {
+                queues::failed(&dead_letters, BULK_QUEUE, &message, e.to_string(), false).await?
+            }
+        }
+    }
+    Ok(())
+}
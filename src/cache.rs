@@ -0,0 +1,322 @@
+use chrono::Utc;
+use js_sys::{Function, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::utils;
+
+/// How long a colo serves the `GET /posts` list it built from its cache. Writes to posts drop it
+/// sooner, see [`invalidate_posts`].
+const POSTS_TTL: u32 = 15;
+
+/// Cache key of the current version of the `GET /posts` list. Every cached list is keyed by the
+/// version it was built under, so bumping this drops all of them at once.
+const POSTS_VERSION_KEY: &str = "https://posts.cache/version";
+
+/// How long a colo keeps [`POSTS_VERSION_KEY`]; when it is evicted, lists are keyed afresh.
+const POSTS_VERSION_TTL: u32 = 86400;
+
+/// Who may keep a copy of a response.
+pub enum Visibility {
+    /// Browsers and shared caches (Cloudflare's edge included).
+    Public,
+    /// Only the requesting browser, e.g. anything tied to a user.
+    Private,
+    NoStore,
+}
+
+pub struct CachePolicy {
+    pub visibility: Visibility,
+    pub max_age: u32,
+    pub vary: &'static [&'static str],
+}
+
+const NO_STORE: CachePolicy = CachePolicy {
+    visibility: Visibility::NoStore,
+    max_age: 0,
+    vary: &[],
+};
+
+/// For routes that leave out posts withheld in the reader's country. Cloudflare's edge cache
+/// doesn't vary on country, so only the reader's browser may keep a copy.
+const PER_COUNTRY: CachePolicy = CachePolicy {
+    visibility: Visibility::Private,
+    max_age: 10,
+    vary: &["Accept-Encoding"],
+};
+
+/// Cache policy per `GET` route, using the same `:param` patterns as the router. Anything not
+/// listed here, and every non-`GET` response, is sent with `no-store`.
+const POLICIES: &[(&str, CachePolicy)] = &[
+    ("/posts", PER_COUNTRY),
+    (
+        "/users",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 60,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    (
+        "/worker-version",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 3600,
+            vary: &[],
+        },
+    ),
+    ("/threads/:id", PER_COUNTRY),
+    ("/posts/:id", PER_COUNTRY),
+    ("/search", PER_COUNTRY),
+    (
+        "/tags/trending",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 300,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    ("/tags/:tag", PER_COUNTRY),
+    // A media id is the hash of its bytes, so what it serves never changes.
+    (
+        "/media/:id",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 60 * 60 * 24 * 365,
+            vary: &[],
+        },
+    ),
+    (
+        "/posts/:id/comments",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 10,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    ("/c/:name", PER_COUNTRY),
+    ("/c/:name/posts", PER_COUNTRY),
+    (
+        "/communities/discover",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 300,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    (
+        "/about/stats",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 300,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    (
+        "/.well-known/nodeinfo",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 3600,
+            vary: &[],
+        },
+    ),
+    (
+        "/nodeinfo/2.0",
+        CachePolicy {
+            visibility: Visibility::Public,
+            max_age: 300,
+            vary: &["Accept-Encoding"],
+        },
+    ),
+    (
+        "/feed",
+        CachePolicy {
+            visibility: Visibility::Private,
+            max_age: 0,
+            vary: &["Cookie"],
+        },
+    ),
+    (
+        "/drafts/:id/revisions",
+        CachePolicy {
+            visibility: Visibility::Private,
+            max_age: 0,
+            vary: &["Cookie"],
+        },
+    ),
+];
+
+/// Whether `path` fits a router pattern, where `:name` stands for any one segment.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_end_matches('/').split('/');
+    let path = path.trim_end_matches('/').split('/');
+    pattern.clone().count() == path.clone().count()
+        && pattern
+            .zip(path)
+            .all(|(want, got)| want.starts_with(':') || want == got)
+}
+
+pub fn policy_for(method: &Method, path: &str) -> &'static CachePolicy {
+    if *method != Method::Get && *method != Method::Head {
+        return &NO_STORE;
+    }
+    POLICIES
+        .iter()
+        .find(|(pattern, _)| matches(pattern, path))
+        .map(|(_, policy)| policy)
+        .unwrap_or(&NO_STORE)
+}
+
+/// Sets `Cache-Control` and `Vary` on a finished response according to its route's policy.
+pub fn apply(method: &Method, path: &str, res: &mut Response) -> Result<()> {
+    // Errors are never worth caching, whatever the route.
+    let policy = if res.status_code() >= 400 {
+        &NO_STORE
+    } else {
+        policy_for(method, path)
+    };
+    let cache_control = match policy.visibility {
+        Visibility::Public => format!("public, max-age={}", policy.max_age),
+        Visibility::Private => format!("private, max-age={}", policy.max_age),
+        Visibility::NoStore => "no-store".to_string(),
+    };
+    let headers = res.headers_mut();
+    headers.set("Cache-Control", &cache_control)?;
+    if !policy.vary.is_empty() {
+        headers.set("Vary", &policy.vary.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Whether the request's `If-None-Match` names `etag`, i.e. the client's copy is current.
+/// Weak tags match their strong form.
+pub fn fresh(req: &Request, etag: &str) -> Result<bool> {
+    Ok(req.headers().get("If-None-Match")?.is_some_and(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    }))
+}
+
+/// `304 Not Modified` for a client whose copy is still `etag`.
+pub fn not_modified(etag: &str) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("ETag", etag)?;
+    Ok(Response::empty()?.with_status(304).with_headers(headers))
+}
+
+/// `json` as the body with an `ETag` of its hash, or [`not_modified`] if the client already has
+/// it. Lists that are built on every request can't tell whether they changed any cheaper, and a
+/// 304 still spares the client the download.
+pub fn tagged_json(req: &Request, json: String) -> Result<Response> {
+    let etag = format!("\"{}\"", &utils::sha256_hex(&json)[..32]);
+    if fresh(req, &etag)? {
+        return not_modified(&etag);
+    }
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("ETag", &etag)?;
+    Ok(Response::from_bytes(json.into_bytes())?.with_headers(headers))
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// Calls `caches.default.<method>(args)` and waits for it. `worker` 0.0.7 predates the Cache
+/// API, so the runtime's cache is called directly. It belongs to the colo serving the request.
+async fn call_cache(method: &str, args: &[JsValue]) -> Result<JsValue> {
+    let caches = Reflect::get(&js_sys::global(), &JsValue::from("caches")).map_err(js_error)?;
+    let cache = Reflect::get(&caches, &JsValue::from("default")).map_err(js_error)?;
+    let function: Function = Reflect::get(&cache, &JsValue::from(method))
+        .map_err(js_error)?
+        .unchecked_into();
+    let args = args.iter().collect::<js_sys::Array>();
+    let promise: Promise = function
+        .apply(&cache, &args)
+        .map_err(js_error)?
+        .unchecked_into();
+    JsFuture::from(promise).await.map_err(js_error)
+}
+
+/// The text cached under `key` in this colo, if any.
+async fn lookup(key: &str) -> Result<Option<String>> {
+    let found = call_cache("match", &[JsValue::from(key)]).await?;
+    if found.is_undefined() {
+        return Ok(None);
+    }
+    let mut res = Response::from(found.unchecked_into::<worker_sys::Response>());
+    Ok(Some(res.text().await?))
+}
+
+/// Caches `text` under `key` in this colo for `ttl` seconds.
+async fn store(key: &str, text: String, ttl: u32) -> Result<()> {
+    let mut headers = Headers::new();
+    headers.set("Cache-Control", &format!("public, max-age={}", ttl))?;
+    let res: worker_sys::Response = Response::ok(text)?.with_headers(headers).into();
+    call_cache("put", &[JsValue::from(key), res.into()]).await?;
+    Ok(())
+}
+
+/// Cache key of the `GET /posts` list `req` asks for under `version`: its host, which tells
+/// tenants apart, its `legacy`, `license` and `v` parameters and the country it comes from, since
+/// withheld posts differ by country.
+fn posts_key(req: &Request, version: &str) -> Result<String> {
+    let url = req.url()?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default()
+    };
+    let mut key = Url::parse(&format!("https://posts.cache/{}/posts", version))?;
+    key.query_pairs_mut()
+        .append_pair("host", url.host_str().unwrap_or_default())
+        .append_pair("legacy", &query("legacy"))
+        .append_pair("license", &query("license"))
+        .append_pair("v", &query("v"))
+        .append_pair("country", &req.cf().country().unwrap_or_default());
+    Ok(key.to_string())
+}
+
+async fn lookup_posts(req: &Request) -> Result<(String, Option<String>)> {
+    let version = lookup(POSTS_VERSION_KEY)
+        .await?
+        .unwrap_or_else(|| "0".to_string());
+    let key = posts_key(req, &version)?;
+    let cached = lookup(&key).await?;
+    Ok((key, cached))
+}
+
+/// The `GET /posts` list for `req`, as JSON, if this colo cached it under the current version,
+/// alongside the key to [`store_posts`] it under otherwise. A cache that can't be read is logged
+/// and counts as a miss, with no key to store under.
+pub async fn cached_posts(req: &Request) -> (Option<String>, Option<String>) {
+    match lookup_posts(req).await {
+        Ok((key, cached)) => (Some(key), cached),
+        Err(e) => {
+            console_log!("posts cache lookup failed: {}", e);
+            (None, None)
+        }
+    }
+}
+
+/// Caches the `GET /posts` list `json` under `key` from [`cached_posts`], for [`POSTS_TTL`]. A
+/// failure is logged; the list is answered either way.
+pub async fn store_posts(key: &str, json: &str) {
+    if let Err(e) = store(key, json.to_string(), POSTS_TTL).await {
+        console_log!("posts cache store failed: {}", e);
+    }
+}
+
+/// Drops every `GET /posts` list this colo cached, by moving it to a new version. Other colos
+/// keep theirs until [`POSTS_TTL`] runs out. A failure is logged: the write it follows already
+/// happened, and the list is only stale until then too.
+pub async fn invalidate_posts() {
+    let version = Utc::now().timestamp_millis().to_string();
+    if let Err(e) = store(POSTS_VERSION_KEY, version, POSTS_VERSION_TTL).await {
+        console_log!("posts cache invalidation failed: {}", e);
+    }
+}
@@ -0,0 +1,287 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{activity, automod, likes, moderation, notifications, posts, session, storage, utils};
+
+/// Keys in the `comments` namespace:
+///
+/// - `<post id>:<millis, zero-padded>:<username>`: one comment, which is also its id, so a post's
+///   comments list in the order they were made.
+///
+/// Keys of removed or held comments carry `{"hidden": true}` as KV metadata, so counting a
+/// post's comments needs no reads.
+const COMMENTS_KV: &str = "comments";
+
+/// Keys in the `likes` namespace (see `likes::LIKES_KV`):
+///
+/// - `comment/<comment id>/<username>`: present while `username` likes the comment. The comment
+///   itself carries a copy of the count as `likes`, recounted from these on every change.
+fn likers_prefix(comment_id: &str) -> String {
+    format!("comment/{}/", comment_id)
+}
+
+/// Longest comment accepted.
+const MAX_COMMENT_CHARS: usize = 10_000;
+
+#[derive(Deserialize, Debug)]
+struct NewComment {
+    content: String,
+    /// The comment this one replies to, if any.
+    parent_id: Option<String>,
+}
+
+fn comments_prefix(post_id: &str) -> String {
+    format!("{}:", post_id)
+}
+
+/// The post a comment id belongs to. Post ids can hold `:` themselves, so the id is split from
+/// the right.
+fn post_id_of(comment_id: &str) -> Option<&str> {
+    let mut parts = comment_id.rsplitn(3, ':');
+    let (_username, _millis) = (parts.next()?, parts.next()?);
+    parts.next()
+}
+
+/// How many visible comments each of `post_ids` has. The posts are listed concurrently, up to
+/// `storage::kv_concurrency` at a time.
+pub async fn counts(ctx: &RouteContext<Session>, post_ids: &[String]) -> Result<Vec<usize>> {
+    let kv = ctx.kv(COMMENTS_KV)?;
+    let kv = &kv;
+    let lists = post_ids.iter().map(|post_id| async move {
+        let started_at = Utc::now().timestamp_millis();
+        let keys = kv.list().prefix(comments_prefix(post_id)).execute().await;
+        ctx.data().trace().time("kv-list", started_at);
+        keys.map(|keys| {
+            keys.keys
+                .iter()
+                .filter(|key| {
+                    key.metadata
+                        .as_ref()
+                        .and_then(|metadata| metadata.get("hidden"))
+                        .is_none()
+                })
+                .count()
+        })
+    });
+    let concurrency = storage::kv_concurrency(ctx.data().bindings());
+    let mut counts = vec![];
+    for count in utils::bounded(lists, concurrency).await {
+        counts.push(count?);
+    }
+    Ok(counts)
+}
+
+/// Most liked first; comments liked as often stay oldest first.
+fn sort_top(comments: &mut [Value]) {
+    comments.sort_by_key(|comment| std::cmp::Reverse(likes_of(comment)));
+}
+
+fn likes_of(comment: &Value) -> i64 {
+    comment.get("likes").and_then(Value::as_i64).unwrap_or(0)
+}
+
+/// `GET /posts/:id/comments`, oldest first, or most liked first with `?sort=top`. Removed and
+/// held comments are left out.
+pub async fn list(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let top = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "sort" && value == "top");
+    let post_id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(COMMENTS_KV)?;
+    let mut comments = vec![];
+    for key in kv
+        .list()
+        .prefix(comments_prefix(&post_id))
+        .execute()
+        .await?
+        .keys
+    {
+        if let Some(v) = kv.get(&key.name).await? {
+            let comment = v.as_json::<Value>()?;
+            if !posts::is_moderated(&comment) {
+                comments.push(comment);
+            }
+        }
+    }
+    if top {
+        sort_top(&mut comments);
+    }
+    Ok(Response::from_json(&comments)?)
+}
+
+/// `POST /posts/:id/comments`. Comments are rendered like posts and go through the automod rules
+/// of the post's community.
+pub async fn create(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let post_id = error::param(&ctx, "id")?;
+    let body = match req.json::<NewComment>().await {
+        Ok(body) if !body.content.trim().is_empty() => body,
+        _ => return Err(ApiError::BadRequest("`content` is required".to_string())),
+    };
+    if body.content.chars().count() > MAX_COMMENT_CHARS {
+        return Err(ApiError::BadRequest(format!(
+            "a comment can be at most {} characters",
+            MAX_COMMENT_CHARS
+        )));
+    }
+    let post = posts::load(&*storage::posts(&ctx)?, &post_id).await?;
+    if posts::is_archived(&post) || posts::is_moderated(&post) {
+        return Err(ApiError::Forbidden(
+            "This post can't be commented on".to_string(),
+        ));
+    }
+    let kv = ctx.kv(COMMENTS_KV)?;
+    if let Some(parent_id) = &body.parent_id {
+        if post_id_of(parent_id) != Some(post_id.as_str()) || kv.get(parent_id).await?.is_none() {
+            return Err(ApiError::BadRequest(
+                "`parent_id` is not a comment on this post".to_string(),
+            ));
+        }
+    }
+
+    let now = Utc::now();
+    let id = format!(
+        "{}{:013}:{}",
+        comments_prefix(&post_id),
+        now.timestamp_millis(),
+        username
+    );
+    let mut comment = json!({
+        "id": id,
+        "post_id": post_id,
+        "parent_id": body.parent_id,
+        "username": &username,
+        "content": body.content,
+        "time": now.to_rfc3339(),
+    });
+    if let (Some(community), Some(comment_obj)) = (post.get("community"), comment.as_object_mut()) {
+        comment_obj.insert("community".to_string(), community.clone());
+    }
+    posts::render_content(&mut comment);
+    let rule = automod::screen(&ctx, &mut comment).await;
+    let put = kv.put(&id, comment.to_string())?;
+    let put = if posts::is_moderated(&comment) {
+        put.metadata(json!({ "hidden": true }))?
+    } else {
+        put
+    };
+    put.execute().await?;
+    if let Some(rule) = &rule {
+        automod::report(&ctx, &id, &comment, rule).await;
+    }
+    if !posts::is_moderated(&comment) {
+        activity::record(&ctx, &username, activity::Kind::Comment).await;
+        notifications::mentioned(&ctx, &post_id, Some(&id), &comment).await;
+    }
+    Ok(Response::from_json(&comment)?)
+}
+
+/// Every key under `prefix`, however many pages they take.
+async fn names(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        names.extend(page.keys.into_iter().map(|key| key.name));
+        if page.list_complete || page.cursor.is_none() {
+            return Ok(names);
+        }
+        cursor = page.cursor;
+    }
+}
+
+/// Likes or unlikes the comment named in the route for the signed-in user, then copies the
+/// recount onto the comment. Recounting the likers rather than adding one to the stored count
+/// means two likes landing together can't lose one for good: the next change counts both.
+async fn react(ctx: RouteContext<Session>, like: bool) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(COMMENTS_KV)?;
+    let mut comment: Value = match kv.get(&id).await? {
+        Some(v) => v.as_json()?,
+        None => return Err(ApiError::NotFound),
+    };
+    if posts::is_moderated(&comment) {
+        return Err(ApiError::NotFound);
+    }
+    let likers = ctx.kv(likes::LIKES_KV)?;
+    let key = format!("{}{}", likers_prefix(&id), username);
+    if like {
+        likers.put(&key, "")?.execute().await?;
+    } else {
+        likers.delete(&key).await?;
+    }
+    let count = names(&likers, likers_prefix(&id)).await?.len();
+    if let Some(comment_obj) = comment.as_object_mut() {
+        comment_obj.insert("likes".to_string(), json!(count));
+    }
+    kv.put(&id, comment.to_string())?.execute().await?;
+    Ok(Response::from_json(
+        &json!({ "likes": count, "liked": like }),
+    )?)
+}
+
+/// `POST /comments/:id/like`
+pub async fn like(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    react(ctx, true).await
+}
+
+/// `POST /comments/:id/unlike`
+pub async fn unlike(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    react(ctx, false).await
+}
+
+/// `DELETE /comments/:id`, for the comment's author and the moderators of its community.
+pub async fn delete(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(COMMENTS_KV)?;
+    let comment: Value = match kv.get(&id).await? {
+        Some(v) => v.as_json()?,
+        None => return Err(ApiError::NotFound),
+    };
+    let field = |name: &str| comment.get(name).and_then(Value::as_str);
+    let allowed = field("username") == Some(username.as_str())
+        || match field("community") {
+            Some(community) => moderation::may_moderate(&ctx, community).await?,
+            None => moderation::is_admin(&ctx, &username)?,
+        };
+    if !allowed {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    kv.delete(&id).await?;
+    Ok(Response::empty()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_comments_come_first_and_ties_stay_oldest_first() {
+        let mut comments = vec![
+            json!({ "id": "a" }),
+            json!({ "id": "b", "likes": 3 }),
+            json!({ "id": "c", "likes": 1 }),
+            json!({ "id": "d", "likes": 3 }),
+        ];
+        sort_top(&mut comments);
+        let ids: Vec<&str> = comments.iter().map(|c| c["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["b", "d", "c", "a"]);
+    }
+
+    #[test]
+    fn comment_ids_name_their_post() {
+        assert_eq!(post_id_of("p:1:0000000000001:alice"), Some("p:1"));
+        assert_eq!(post_id_of("alice"), None);
+    }
+}
@@ -0,0 +1,500 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::withholding::Withheld;
+use crate::{
+    apikeys, feeds, follows, isolate, models, moderation, posts, replica, session, settings,
+    storage, tenants, users,
+};
+
+/// Keys in the `communities` namespace:
+///
+/// - `member/<community>/<username>`: membership, listed per community
+/// - `joined/<username>/<community>`: the same membership, listed per user
+/// - `count/<community>`: number of members
+/// - `meta/<community>`: [`Meta`], written when the community is founded, see [`create`]
+/// - `webhooks/<community>`: the community's outgoing webhooks, see `webhooks`
+/// - `templates/<community>`: post templates of the community, see `templates`
+/// - `quarantine/<community>`: [`Quarantine`], while an admin has the community quarantined
+///
+/// Member keys carry `{"joined_at": <rfc3339>}` as KV metadata so growth can be read off a
+/// listing without fetching every value.
+pub const COMMUNITIES_KV: &str = "communities";
+
+/// How far back discovery looks for new members and posts.
+const DISCOVER_WINDOW_DAYS: i64 = 7;
+const DISCOVER_LIMIT: usize = 20;
+const MAX_TAGS: usize = 5;
+
+/// Query parameter with which a reader accepts a quarantined community's interstitial.
+const QUARANTINE_ACK: &str = "acknowledge_quarantine";
+
+/// How long an isolate keeps whether a community is quarantined, in milliseconds.
+const QUARANTINED_TTL_MS: i64 = 30 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Meta {
+    created_by: String,
+    created_at: String,
+    /// Category tags, lowercased. Only `created_by` can change them.
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// A community kept out of everything instance-wide (listings, discovery, search alerts and the
+/// firehose) whose posts are only shown to readers who explicitly ask for them.
+#[derive(Serialize, Deserialize, Debug)]
+struct Quarantine {
+    reason: String,
+    by: String,
+    at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewQuarantine {
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tags {
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Discovered {
+    name: String,
+    tags: Vec<String>,
+    members: u64,
+    new_members: usize,
+    recent_posts: usize,
+}
+
+async fn meta(kv: &kv::KvStore, community: &str) -> Result<Option<Meta>> {
+    match kv.get(&format!("meta/{}", community)).await? {
+        Some(v) => Ok(Some(v.as_json::<Meta>()?)),
+        None => Ok(None),
+    }
+}
+
+/// Like [`meta`], but from the nearest edge cache, so up to [`replica::COMMUNITY_META_TTL`]
+/// seconds stale. Only for reading: updates go through [`meta`].
+async fn meta_replicated(ctx: &RouteContext<Session>, community: &str) -> Result<Option<Meta>> {
+    let key = format!("meta/{}", community);
+    match replica::get(ctx, COMMUNITIES_KV, &key, replica::COMMUNITY_META_TTL).await? {
+        Some(stored) => Ok(Some(serde_json::from_str::<Meta>(&stored)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether `community` has been founded (see [`create`]).
+pub async fn exists(ctx: &RouteContext<Session>, community: &str) -> Result<bool> {
+    Ok(meta_replicated(ctx, community).await?.is_some())
+}
+
+async fn quarantine(kv: &kv::KvStore, community: &str) -> Result<Option<Quarantine>> {
+    match kv.get(&format!("quarantine/{}", community)).await? {
+        Some(v) => Ok(Some(v.as_json::<Quarantine>()?)),
+        None => Ok(None),
+    }
+}
+
+/// Every community quarantined in `kv`.
+pub async fn quarantined_in(kv: &kv::KvStore) -> Result<HashSet<String>> {
+    let prefix = "quarantine/";
+    let keys = kv.list().prefix(prefix.to_string()).execute().await?.keys;
+    Ok(keys
+        .into_iter()
+        .map(|key| key.name[prefix.len()..].to_string())
+        .collect())
+}
+
+/// [`quarantined_in`] the worker's communities.
+pub async fn quarantined(ctx: &RouteContext<Session>) -> Result<HashSet<String>> {
+    quarantined_in(&ctx.kv(COMMUNITIES_KV)?).await
+}
+
+fn quarantined_key(ctx: &RouteContext<Session>, community: &str) -> String {
+    let key = format!("communities/quarantined/{}", community);
+    tenants::isolate_key(ctx.data().bindings(), &key)
+}
+
+/// Checked for every new post and listed post, so the answer is kept in the isolate for
+/// [`QUARANTINED_TTL_MS`]; a quarantine set elsewhere takes at most that long to apply here.
+pub async fn is_quarantined(ctx: &RouteContext<Session>, community: &str) -> Result<bool> {
+    if let Some(quarantined) = isolate::get::<bool>(&quarantined_key(ctx, community)) {
+        return Ok(quarantined);
+    }
+    let quarantined = quarantine(&ctx.kv(COMMUNITIES_KV)?, community)
+        .await?
+        .is_some();
+    isolate::put(
+        &quarantined_key(ctx, community),
+        &quarantined,
+        QUARANTINED_TTL_MS,
+    );
+    Ok(quarantined)
+}
+
+/// `posts` without those from quarantined communities, for instance-wide listings.
+pub async fn without_quarantined(
+    ctx: &RouteContext<Session>,
+    posts: Vec<models::Post>,
+) -> Result<Vec<models::Post>> {
+    let quarantined = quarantined(ctx).await?;
+    Ok(posts
+        .into_iter()
+        .filter(|post| {
+            !post
+                .extra
+                .get("community")
+                .and_then(Value::as_str)
+                .is_some_and(|community| quarantined.contains(community))
+        })
+        .collect())
+}
+
+/// Whether `username` moderates `community`. For now that is whoever founded it.
+pub async fn is_moderator(
+    ctx: &RouteContext<Session>,
+    community: &str,
+    username: &str,
+) -> Result<bool> {
+    Ok(meta_replicated(ctx, community)
+        .await?
+        .is_some_and(|meta| meta.created_by == username))
+}
+
+fn is_recent(time: &str, since: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(time).is_ok_and(|time| time >= since)
+}
+
+async fn member_count(kv: &kv::KvStore, community: &str) -> Result<u64> {
+    Ok(match kv.get(&format!("count/{}", community)).await? {
+        Some(v) => v.as_string().parse().unwrap_or(0),
+        None => 0,
+    })
+}
+
+/// Communities `username` has joined.
+pub async fn joined(kv: &kv::KvStore, username: &str) -> Result<HashSet<String>> {
+    let prefix = format!("joined/{}/", username);
+    let keys = kv.list().prefix(prefix.clone()).execute().await?.keys;
+    Ok(keys
+        .into_iter()
+        .map(|key| key.name[prefix.len()..].to_string())
+        .collect())
+}
+
+/// `GET /c/:name`
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let name = error::param(&ctx, "name")?;
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+    let members = member_count(&kv, &name).await?;
+    let tags = meta_replicated(&ctx, &name).await?.unwrap_or_default().tags;
+    let quarantine = quarantine(&kv, &name).await?;
+    Ok(Response::from_json(&json!({
+        "name": name,
+        "members": members,
+        "tags": tags,
+        "quarantined": quarantine.is_some(),
+        "quarantine_reason": quarantine.map(|quarantine| quarantine.reason),
+    }))?)
+}
+
+/// `GET /c/:name/posts`, newest first. A quarantined community answers with its interstitial
+/// (403) unless the reader passes `?acknowledge_quarantine=true`.
+pub async fn posts(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let name = error::param(&ctx, "name")?;
+    if let Some(quarantine) = quarantine(&ctx.kv(COMMUNITIES_KV)?, &name).await? {
+        let acknowledged = req
+            .url()?
+            .query_pairs()
+            .any(|(key, value)| key == QUARANTINE_ACK && value == "true");
+        if !acknowledged {
+            let interstitial = json!({
+                "quarantined": true,
+                "reason": quarantine.reason,
+                "acknowledge_with": format!("?{}=true", QUARANTINE_ACK),
+            });
+            return Ok(Response::from_json(&interstitial)?.with_status(403));
+        }
+    }
+    let mut posts: Vec<models::Post> =
+        posts::list_public(&*storage::posts(&ctx)?, ctx.data().trace())
+            .await?
+            .into_iter()
+            .filter(|post| {
+                post.extra.get("community").and_then(Value::as_str) == Some(name.as_str())
+            })
+            .collect();
+    posts.sort_by(|a, b| b.time.cmp(&a.time));
+    Withheld::for_request(&req, &ctx)
+        .await?
+        .apply_to_posts(&mut posts);
+    Ok(Response::from_json(&posts)?)
+}
+
+/// `PUT /c/:name/quarantine` (with a `reason`) and `DELETE /c/:name/quarantine`, for admins.
+pub async fn set_quarantine(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let admin = match session::current_user(&ctx) {
+        Some(username) if moderation::is_admin(&ctx, &username)? => username,
+        _ => return Err(ApiError::Forbidden("Forbidden".to_string())),
+    };
+    let name = match ctx.param("name") {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+    let key = format!("quarantine/{}", name);
+    isolate::forget(&quarantined_key(&ctx, &name));
+    if req.method() == Method::Delete {
+        kv.delete(&key).await?;
+        return Ok(Response::from_json(
+            &json!({ "name": name, "quarantined": false }),
+        )?);
+    }
+    let body = match req.json::<NewQuarantine>().await {
+        Ok(body) if !body.reason.trim().is_empty() => body,
+        _ => return Err(ApiError::BadRequest("`reason` is required".to_string())),
+    };
+    let quarantine = Quarantine {
+        reason: body.reason,
+        by: admin,
+        at: Utc::now().to_rfc3339(),
+    };
+    kv.put(&key, &quarantine)?.execute().await?;
+    console_log!("quarantine: {} quarantined c/{}", quarantine.by, name);
+    Ok(Response::from_json(
+        &json!({ "name": name, "quarantined": true, "reason": quarantine.reason }),
+    )?)
+}
+
+/// `POST /c/:name`: founds a community, making the signed-in user its moderator. A name that
+/// already has posts, from before communities were founded, can only be founded by an admin, who
+/// may hand it to its rightful founder with `?founder=<username>`.
+pub async fn create(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let name = match ctx.param("name") {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+    if meta(&kv, &name).await?.is_some() {
+        return Err(ApiError::Conflict(
+            "This community has already been founded".to_string(),
+        ));
+    }
+    let founder = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "founder")
+        .map(|(_, founder)| founder.into_owned());
+    let is_admin = moderation::is_admin(&ctx, &username)?;
+    let founder = match founder {
+        Some(_) if !is_admin => return Err(ApiError::Forbidden("Forbidden".to_string())),
+        Some(founder) => {
+            if !users::exists(&ctx.kv(users::USERS_KV)?, &founder).await? {
+                return Err(ApiError::BadRequest(
+                    "`founder` is not a registered user".to_string(),
+                ));
+            }
+            founder
+        }
+        None => username,
+    };
+    if !is_admin {
+        let has_posts = posts::list_public(&*storage::posts(&ctx)?, ctx.data().trace())
+            .await?
+            .iter()
+            .any(|post| post.extra.get("community").and_then(Value::as_str) == Some(name.as_str()));
+        if has_posts {
+            return Err(ApiError::Forbidden(
+                "This community already has posts; ask an admin to found it".to_string(),
+            ));
+        }
+    }
+    let meta = Meta {
+        created_by: founder,
+        created_at: Utc::now().to_rfc3339(),
+        tags: vec![],
+    };
+    kv.put(&format!("meta/{}", name), &meta)?.execute().await?;
+    Ok(Response::from_json(&json!({
+        "name": name,
+        "created_by": meta.created_by,
+        "created_at": meta.created_at,
+    }))?
+    .with_status(201))
+}
+
+/// `POST /c/:name/join` and `DELETE /c/:name/join`
+pub async fn join(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let name = match ctx.param("name") {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let joining = req.method() == Method::Post;
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+    let member_key = format!("member/{}/{}", name, username);
+    let is_member = kv.get(&member_key).await?.is_some();
+
+    let mut members = member_count(&kv, &name).await?;
+    if joining && !is_member {
+        let now = Utc::now().to_rfc3339();
+        kv.put(&member_key, "")?
+            .metadata(json!({ "joined_at": now }))?
+            .execute()
+            .await?;
+        kv.put(&format!("joined/{}/{}", username, name), "")?
+            .execute()
+            .await?;
+        members += 1;
+    } else if !joining && is_member {
+        kv.delete(&member_key).await?;
+        kv.delete(&format!("joined/{}/{}", username, name)).await?;
+        members = members.saturating_sub(1);
+    }
+    if joining != is_member {
+        feeds::invalidate(&ctx, &username).await;
+    }
+    kv.put(&format!("count/{}", name), members.to_string())?
+        .execute()
+        .await?;
+
+    Ok(Response::from_json(
+        &json!({ "name": name, "members": members, "joined": joining }),
+    )?)
+}
+
+/// Posts from the communities `username` has joined and by the users they follow, in the
+/// languages they asked for.
+pub async fn home(ctx: &RouteContext<Session>, username: &str) -> Result<Vec<models::Post>> {
+    let joined = joined(&ctx.kv(COMMUNITIES_KV)?, username).await?;
+    let following = follows::following(ctx, username).await?;
+    let filtered = follows::filtered(ctx, username).await?;
+    let languages = settings::languages(ctx, username).await?;
+    Ok(feeds::home(ctx, username, &following, &joined, &filtered)
+        .await?
+        .into_iter()
+        .filter(|post| languages.wants(post.extra.get("lang").and_then(Value::as_str)))
+        .collect())
+}
+
+/// `GET /feed`, see [`home`]. Also readable with a `read` API key, as the key's owner.
+pub async fn feed(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = match session::current_user(&ctx) {
+        Some(username) => username,
+        None => match apikeys::authorize(&req, &ctx, apikeys::Scope::Read).await? {
+            Some(api_key) => api_key.owner,
+            None => return Err(ApiError::Unauthorized),
+        },
+    };
+    let mut posts = home(&ctx, &username).await?;
+    Withheld::for_request(&req, &ctx)
+        .await?
+        .apply_to_posts(&mut posts);
+    Ok(Response::from_json(&posts)?)
+}
+
+/// `PUT /c/:name/tags`, for whoever founded the community.
+pub async fn set_tags(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let name = error::param(&ctx, "name")?;
+    let body = models::from_body::<Tags>(&mut req).await?;
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+    let mut meta = match meta(&kv, &name).await? {
+        Some(meta) => meta,
+        None => return Err(ApiError::NotFound),
+    };
+    if meta.created_by != username {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+
+    let mut tags: Vec<String> = body
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::BadRequest(format!("At most {} tags", MAX_TAGS)));
+    }
+    meta.tags = tags;
+    kv.put(&format!("meta/{}", name), &meta)?.execute().await?;
+    Ok(Response::from_json(
+        &json!({ "name": name, "tags": meta.tags }),
+    )?)
+}
+
+/// `GET /communities/discover[?tag=<tag>]`
+///
+/// Ranks communities by what happened in the last [`DISCOVER_WINDOW_DAYS`] days: posts made in
+/// them and members who joined. Ties go to the larger community.
+pub async fn discover(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let tag = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "tag")
+        .map(|(_, value)| value.to_lowercase());
+    let since = Utc::now() - Duration::days(DISCOVER_WINDOW_DAYS);
+    let kv = ctx.kv(COMMUNITIES_KV)?;
+
+    let mut recent_posts: HashMap<String, usize> = HashMap::new();
+    for post in posts::list_public(&*storage::posts(&ctx)?, ctx.data().trace()).await? {
+        let community = post.extra.get("community").and_then(Value::as_str);
+        if let (Some(community), Some(time)) = (community, post.time.as_deref()) {
+            if is_recent(time, since) {
+                *recent_posts.entry(community.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    let quarantined = quarantined(&ctx).await?;
+    let mut found = vec![];
+    for key in kv.list().prefix("count/".to_string()).execute().await?.keys {
+        let name = key.name["count/".len()..].to_string();
+        if quarantined.contains(&name) {
+            continue;
+        }
+        let tags = meta_replicated(&ctx, &name).await?.unwrap_or_default().tags;
+        if tag.as_ref().is_some_and(|tag| !tags.contains(tag)) {
+            continue;
+        }
+        let members = member_count(&kv, &name).await?;
+        if members == 0 {
+            continue;
+        }
+        let new_members = kv
+            .list()
+            .prefix(format!("member/{}/", name))
+            .execute()
+            .await?
+            .keys
+            .iter()
+            .filter_map(|key| key.metadata.as_ref()?.get("joined_at")?.as_str())
+            .filter(|joined_at| is_recent(joined_at, since))
+            .count();
+        found.push(Discovered {
+            recent_posts: recent_posts.get(&name).copied().unwrap_or(0),
+            name,
+            tags,
+            members,
+            new_members,
+        });
+    }
+
+    found.sort_by(|a, b| {
+        (b.recent_posts + b.new_members, b.members)
+            .cmp(&(a.recent_posts + a.new_members, a.members))
+    });
+    found.truncate(DISCOVER_LIMIT);
+    Ok(Response::from_json(&found)?)
+}
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{apikeys, communities, models, posts, storage};
+
+/// How many posts the digest lists unless `?n=` says otherwise.
+const DEFAULT_TOP: usize = 10;
+const MAX_TOP: usize = 50;
+
+/// `POST /bot/digest[?n=<count>]`, for a key with the `digest` scope.
+///
+/// Posts a roundup of the most-liked posts of the last 24 hours as the key's owner. Meant to be
+/// called once a day by whatever scheduler the deployment has (workers-rs doesn't hand bindings
+/// to cron handlers); a second call on the same day is refused with 409.
+pub async fn post(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let bot = match apikeys::authorize(&req, &ctx, apikeys::Scope::Digest).await? {
+        Some(api_key) => api_key.owner,
+        None => return Err(ApiError::Unauthorized),
+    };
+    let top = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "n")
+        .and_then(|(_, n)| n.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_TOP)
+        .clamp(1, MAX_TOP);
+
+    let now = Utc::now();
+    let id = format!("digest-{}-{}", now.format("%Y-%m-%d"), bot);
+    let store = storage::posts(&ctx)?;
+    if store.get(&id).await?.is_some() {
+        return Err(ApiError::Conflict(
+            "Today's digest has already been posted".to_string(),
+        ));
+    }
+
+    let since = now - Duration::days(1);
+    let listed = posts::list_public(&*store, ctx.data().trace()).await?;
+    let mut candidates: Vec<(i64, models::Post)> = communities::without_quarantined(&ctx, listed)
+        .await?
+        .into_iter()
+        .filter(|post| post.username != bot)
+        .filter(|post| {
+            post.time
+                .as_deref()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .is_some_and(|time| time >= since)
+        })
+        .map(|post| {
+            let likes = ["total_likes", "likes"]
+                .iter()
+                .find_map(|field| post.extra.get(*field).and_then(Value::as_i64))
+                .unwrap_or(0);
+            (likes, post)
+        })
+        .collect();
+    candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+    candidates.truncate(top);
+    if candidates.is_empty() {
+        return Err(ApiError::Conflict(
+            "Nothing was posted in the last 24 hours".to_string(),
+        ));
+    }
+
+    let content = candidates
+        .iter()
+        .enumerate()
+        .map(|(rank, (likes, post))| {
+            let post_id = post
+                .extra
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            format!(
+                "{}. {} by @{} ({} likes) [{}]",
+                rank + 1,
+                post.title,
+                post.username,
+                likes,
+                post_id
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut digest = json!({
+        "id": id,
+        "username": bot,
+        "title": format!("Top posts for {}", now.format("%B %-d, %Y")),
+        "content": content,
+        "time": now.to_rfc3339(),
+        "digest": true,
+    });
+    posts::normalize_license(&mut digest)?;
+    posts::insert(&ctx, &id, &mut digest).await?;
+    Ok(Response::from_json(&digest)?)
+}
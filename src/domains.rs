@@ -0,0 +1,337 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use wasm_bindgen::JsCast;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::{self, Session};
+use crate::validation::Problems;
+use crate::{communities, events, isolate, models, outbound, tenants};
+
+/// Keys in the `domains` namespace, which like `tenants` belongs to the deployment and is never
+/// scoped:
+///
+/// - `domain/<hostname>`: the [`Domain`] claimed on `hostname`. Kept for [`CLAIM_TTL`] seconds
+///   until it is verified, then for good
+/// - `site/<tenant, or "-">/<root>`: the hostname claimed for a community or profile, by the
+///   path its routes hang off, e.g. `site/-/c/rust`
+pub const DOMAINS_KV: &str = "domains";
+
+/// How long an unverified claim holds a hostname, so one nobody verifies doesn't squat on it.
+const CLAIM_TTL: u64 = 60 * 60 * 24 * 7;
+
+/// How long an isolate keeps what a host resolves to, in milliseconds, as for tenants.
+const HOST_TTL_MS: i64 = 60 * 1000;
+
+/// Label under the claimed hostname whose TXT record verifies a claim.
+const VERIFY_LABEL: &str = "_social-verify";
+
+/// DNS-over-HTTPS resolver claims are verified with.
+const RESOLVER: &str = "cloudflare-dns.com";
+
+const MAX_HOST_CHARS: usize = 253;
+
+/// What a custom domain serves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum Site {
+    Community(String),
+    Profile(String),
+}
+
+impl Site {
+    /// The path the site's routes hang off.
+    fn root(&self) -> String {
+        match self {
+            Site::Community(name) => format!("/c/{}", name),
+            Site::Profile(username) => format!("/users/{}", username),
+        }
+    }
+}
+
+/// A hostname claimed for a community or profile.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Domain {
+    pub host: String,
+    pub site: Site,
+    /// Tenant the site belongs to; `None` for the deployment's own instance.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub claimed_by: String,
+    /// What the `_social-verify.<host>` TXT record has to say.
+    pub token: String,
+    #[serde(default)]
+    pub verified_at: Option<String>,
+    pub created_at: String,
+}
+
+/// Body of `PUT /c/:name/domain` and `PUT /users/:username/domain`.
+#[derive(Deserialize, Debug)]
+struct Claim {
+    host: String,
+}
+
+fn domain_key(host: &str) -> String {
+    format!("domain/{}", host)
+}
+
+fn site_key(tenant: Option<&str>, site: &Site) -> String {
+    format!("site/{}{}", tenant.unwrap_or("-"), site.root())
+}
+
+fn host_cache_key(host: &str) -> String {
+    format!("domains/host/{}", host)
+}
+
+/// The namespace, or `None` on a deployment that doesn't bind it and so serves no domains.
+fn domains_kv(env: &Env) -> Option<kv::KvStore> {
+    env.kv(DOMAINS_KV).ok()
+}
+
+async fn load(kv: &kv::KvStore, host: &str) -> Result<Option<Domain>> {
+    match kv.get(&domain_key(host)).await? {
+        Some(v) => Ok(Some(v.as_json::<Domain>()?)),
+        None => Ok(None),
+    }
+}
+
+/// The verified domain `req`'s host is, if any.
+pub async fn of(req: &Request, env: &Env) -> Result<Option<Domain>> {
+    let host = req.url()?.host_str().unwrap_or_default().to_lowercase();
+    if let Some(domain) = isolate::get::<Option<Domain>>(&host_cache_key(&host)) {
+        return Ok(domain);
+    }
+    let kv = match domains_kv(env) {
+        Some(kv) => kv,
+        None => return Ok(None),
+    };
+    let domain = load(&kv, &host)
+        .await?
+        .filter(|domain| domain.verified_at.is_some());
+    isolate::put(&host_cache_key(&host), &domain, HOST_TTL_MS);
+    Ok(domain)
+}
+
+/// `req` as the API sees it on `domain`: a read of a path the site has a route for is routed
+/// there, so `/` is the community or profile and `/posts` its posts. Everything else, signing in
+/// and writing included, goes through as it is.
+pub fn rewrite(req: Request, domain: &Domain) -> Result<Request> {
+    if !matches!(req.method(), Method::Get | Method::Head) {
+        return Ok(req);
+    }
+    let root = domain.site.root();
+    let path = match req.path().as_str() {
+        "/" => root,
+        path => format!("{}{}", root, path),
+    };
+    if !events::is_route(&path) {
+        return Ok(req);
+    }
+    let mut url = req.url()?;
+    url.set_path(&path);
+    // `new Request(url, req)` keeps everything of `req` but its URL.
+    let init = req.inner().unchecked_ref::<worker_sys::RequestInit>();
+    let rewritten = worker_sys::Request::new_with_str_and_init(url.as_str(), init)
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    Ok(Request::from(rewritten))
+}
+
+/// The site `ctx`'s route is about, if the user may manage its domain: a community's moderator,
+/// or the user themselves.
+async fn manageable(ctx: &RouteContext<Session>) -> ApiResult<(Site, String)> {
+    let current = session::authed(ctx)?.username;
+    let site = match ctx.param("name") {
+        Some(name) => {
+            if !communities::is_moderator(ctx, name, &current).await? {
+                return Err(ApiError::Forbidden("Forbidden".to_string()));
+            }
+            Site::Community(name.clone())
+        }
+        None => {
+            let username = error::param(ctx, "username")?;
+            if username != current {
+                return Err(ApiError::Forbidden("Forbidden".to_string()));
+            }
+            Site::Profile(username)
+        }
+    };
+    Ok((site, current))
+}
+
+fn valid_host(host: &str) -> bool {
+    host.len() <= MAX_HOST_CHARS
+        && host.contains('.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+fn answer(domain: &Domain) -> ApiResult<Response> {
+    Ok(Response::from_json(&json!({
+        "host": domain.host,
+        "verified": domain.verified_at.is_some(),
+        "verified_at": domain.verified_at,
+        "txt_record": format!("{}.{}", VERIFY_LABEL, domain.host),
+        "txt_value": domain.token,
+        "created_at": domain.created_at,
+    }))?)
+}
+
+/// The domain claimed for the site, as long as the claim still stands.
+async fn claimed(kv: &kv::KvStore, tenant: Option<&str>, site: &Site) -> Result<Option<Domain>> {
+    let host = match kv.get(&site_key(tenant, site)).await? {
+        Some(host) => host.as_string(),
+        None => return Ok(None),
+    };
+    Ok(load(kv, &host)
+        .await?
+        .filter(|domain| domain.site == *site && domain.tenant.as_deref() == tenant))
+}
+
+/// `GET /c/:name/domain` and `GET /users/:username/domain`: the domain claimed for the
+/// community or profile, and the TXT record that verifies it.
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (site, _) = manageable(&ctx).await?;
+    let tenant = tenants::id(ctx.data().bindings());
+    let domain = claimed(&ctx.kv(DOMAINS_KV)?, tenant.as_deref(), &site)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    answer(&domain)
+}
+
+/// `PUT /c/:name/domain` and `PUT /users/:username/domain` with `{"host": "blog.example.com"}`
+/// claim a hostname for a community or profile, in place of any it had. The answer names a TXT
+/// record to publish, after which `POST .../domain/verify` puts the domain to use. The hostname
+/// has to be routed to the worker in Cloudflare as well.
+pub async fn claim(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (site, current) = manageable(&ctx).await?;
+    let host = models::from_body::<Claim>(&mut req)
+        .await?
+        .host
+        .trim()
+        .to_lowercase();
+    let mut problems = Problems::default();
+    if !valid_host(&host) {
+        problems.add("host", "has to be a hostname, without a scheme or port");
+    } else if req.url()?.host_str() == Some(host.as_str()) {
+        problems.add("host", "is the host this API is served on");
+    }
+    problems.finish()?;
+
+    let kv = ctx.kv(DOMAINS_KV)?;
+    let tenant = tenants::id(ctx.data().bindings());
+    if let Some(other) = load(&kv, &host).await? {
+        if other.site != site || other.tenant != tenant {
+            return Err(ApiError::Conflict(format!(
+                "{} is already claimed for another site",
+                host
+            )));
+        }
+        return answer(&other);
+    }
+    if let Some(previous) = claimed(&kv, tenant.as_deref(), &site).await? {
+        kv.delete(&domain_key(&previous.host)).await?;
+        isolate::forget(&host_cache_key(&previous.host));
+    }
+
+    let secret = format!("domain:{}", ctx.secret("SESSION_SECRET")?.to_string());
+    let proof = format!(
+        "{}|{}|{}",
+        host,
+        tenant.as_deref().unwrap_or("-"),
+        site.root()
+    );
+    let domain = Domain {
+        host: host.clone(),
+        site,
+        tenant,
+        claimed_by: current,
+        token: session::keyed_hash(&proof, &secret)[..32].to_string(),
+        verified_at: None,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    kv.put(&domain_key(&host), &domain)?
+        .expiration_ttl(CLAIM_TTL)
+        .execute()
+        .await?;
+    kv.put(&site_key(domain.tenant.as_deref(), &domain.site), &host)?
+        .execute()
+        .await?;
+    Ok(answer(&domain)?.with_status(201))
+}
+
+/// The TXT records published for `name`, through [`RESOLVER`]'s JSON API.
+async fn txt_records(ctx: &RouteContext<Session>, name: &str) -> ApiResult<Vec<String>> {
+    // `name` is a checked hostname, so it goes into the query as it is.
+    let url = format!("https://{}/dns-query?name={}&type=TXT", RESOLVER, name);
+    let mut headers = Headers::new();
+    headers.set("Accept", "application/dns-json")?;
+    let policy = outbound::Policy::allow_only(RESOLVER);
+    let fetched = outbound::get(&url, &headers, &policy, ctx.data().trace()).await?;
+    if fetched.status != 200 {
+        return Err(ApiError::Upstream(format!(
+            "looking up {} failed with {}",
+            name, fetched.status
+        )));
+    }
+    let answer: Value = serde_json::from_slice(&fetched.body)?;
+    Ok(answer
+        .get("Answer")
+        .and_then(Value::as_array)
+        .map(|records| {
+            records
+                .iter()
+                .filter_map(|record| record.get("data").and_then(Value::as_str))
+                .map(|data| data.trim_matches('"').to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// `POST /c/:name/domain/verify` and `POST /users/:username/domain/verify`: checks the claimed
+/// domain's TXT record and, once it says what it should, serves the site there.
+pub async fn verify(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (site, _) = manageable(&ctx).await?;
+    let kv = ctx.kv(DOMAINS_KV)?;
+    let tenant = tenants::id(ctx.data().bindings());
+    let mut domain = claimed(&kv, tenant.as_deref(), &site)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if domain.verified_at.is_none() {
+        let record = format!("{}.{}", VERIFY_LABEL, domain.host);
+        if !txt_records(&ctx, &record).await?.contains(&domain.token) {
+            return Err(ApiError::Unprocessable(format!(
+                "{} doesn't have a TXT record of {} yet",
+                record, domain.token
+            )));
+        }
+        domain.verified_at = Some(Utc::now().to_rfc3339());
+        kv.put(&domain_key(&domain.host), &domain)?
+            .execute()
+            .await?;
+        isolate::forget(&host_cache_key(&domain.host));
+        console_log!("domains: {} verified for {}", domain.host, site.root());
+    }
+    answer(&domain)
+}
+
+/// `DELETE /c/:name/domain` and `DELETE /users/:username/domain`: stops serving the site on its
+/// domain and gives up the claim.
+pub async fn release(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (site, _) = manageable(&ctx).await?;
+    let kv = ctx.kv(DOMAINS_KV)?;
+    let tenant = tenants::id(ctx.data().bindings());
+    let domain = claimed(&kv, tenant.as_deref(), &site)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    kv.delete(&domain_key(&domain.host)).await?;
+    kv.delete(&site_key(tenant.as_deref(), &site)).await?;
+    isolate::forget(&host_cache_key(&domain.host));
+    Ok(Response::empty()?)
+}
@@ -0,0 +1,111 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::models;
+use crate::session::{self, Session};
+
+const DRAFTS_KV: &str = "drafts";
+
+/// How many autosaved revisions are kept per draft. Older ones are overwritten in place.
+const DRAFT_REVISIONS: u64 = 10;
+
+/// Head record stored under the draft id; `next_seq` is the sequence number the next
+/// autosave will be written with.
+#[derive(Serialize, Deserialize, Debug)]
+struct Draft {
+    id: String,
+    username: String,
+    next_seq: u64,
+    updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Revision {
+    seq: u64,
+    title: String,
+    content: String,
+    saved_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Autosave {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    content: String,
+}
+
+/// Revisions live in a fixed set of `DRAFT_REVISIONS` slots, so the newest write always
+/// replaces the oldest one.
+fn revision_key(id: &str, seq: u64) -> String {
+    format!("{}/rev/{}", id, seq % DRAFT_REVISIONS)
+}
+
+async fn load_draft(kv: &kv::KvStore, id: &str) -> Result<Option<Draft>> {
+    match kv.get(id).await? {
+        Some(v) => Ok(Some(v.as_json()?)),
+        None => Ok(None),
+    }
+}
+
+/// `PUT /drafts/:id/autosave`, saving into the signed-in user's draft.
+pub async fn autosave(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let body = models::from_body::<Autosave>(&mut req).await?;
+    let kv = ctx.kv(DRAFTS_KV)?;
+    let now = Utc::now().to_rfc3339();
+    let mut draft = match load_draft(&kv, &id).await? {
+        Some(draft) if draft.username != username => {
+            return Err(ApiError::Forbidden("Forbidden".to_string()))
+        }
+        Some(draft) => draft,
+        None => Draft {
+            id: id.clone(),
+            username,
+            next_seq: 0,
+            updated_at: now.clone(),
+        },
+    };
+
+    let revision = Revision {
+        seq: draft.next_seq,
+        title: body.title,
+        content: body.content,
+        saved_at: now.clone(),
+    };
+    kv.put(&revision_key(&id, revision.seq), &revision)?
+        .execute()
+        .await?;
+    draft.next_seq += 1;
+    draft.updated_at = now;
+    kv.put(&id, &draft)?.execute().await?;
+
+    Ok(Response::from_json(&revision)?)
+}
+
+/// `GET /drafts/:id/revisions`, newest first, for the draft's owner only.
+pub async fn revisions(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(DRAFTS_KV)?;
+    let draft = match load_draft(&kv, &id).await? {
+        Some(draft) => draft,
+        None => return Err(ApiError::NotFound),
+    };
+    if draft.username != username {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+
+    let oldest = draft.next_seq.saturating_sub(DRAFT_REVISIONS);
+    let mut revisions = vec![];
+    for seq in (oldest..draft.next_seq).rev() {
+        if let Some(v) = kv.get(&revision_key(&id, seq)).await? {
+            revisions.push(v.as_json::<Revision>()?);
+        }
+    }
+
+    Ok(Response::from_json(&revisions)?)
+}
@@ -0,0 +1,142 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use worker::*;
+
+use crate::session::Session;
+
+/// What a handler can fail with. Handlers return [`ApiResult`] and use `?`; [`api`] answers the
+/// error as a JSON body `{"error": "...", "code": 404}` with the same status, to which `main`
+/// adds the request's `"trace_id"`.
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400, saying what is wrong with the request.
+    BadRequest(String),
+    /// 400 with what is wrong per field, answered as `"fields": {"title": "..."}`.
+    Invalid(BTreeMap<String, String>),
+    /// 401: no session or API key, or one that doesn't check out.
+    Unauthorized,
+    /// 403, saying why where there is more to say than "Forbidden".
+    Forbidden(String),
+    NotFound,
+    /// 409: the request clashes with what is stored, e.g. a username that is taken.
+    Conflict(String),
+    /// 410, saying what the client has to do instead, e.g. resync from scratch.
+    Gone(String),
+    /// 411: a body sent without a `Content-Length`, so its size can't be checked up front.
+    LengthRequired,
+    /// 413, saying how large a body may be.
+    PayloadTooLarge(String),
+    /// 415, saying what a body has to be.
+    UnsupportedMediaType(String),
+    /// 422: a well-formed request that can't be acted on.
+    Unprocessable(String),
+    /// 429, saying when or how to try again.
+    TooManyRequests(String),
+    /// 503, saying why the request can't be served right now.
+    Unavailable(String),
+    /// 502: a service the worker called failed. The detail is logged, not sent.
+    Upstream(String),
+    /// 500. The detail is logged, not sent.
+    Internal(String),
+}
+
+pub type ApiResult<T> = std::result::Result<T, ApiError>;
+
+impl ApiError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) | ApiError::Invalid(_) => 400,
+            ApiError::Unauthorized => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::NotFound => 404,
+            ApiError::Conflict(_) => 409,
+            ApiError::Gone(_) => 410,
+            ApiError::LengthRequired => 411,
+            ApiError::PayloadTooLarge(_) => 413,
+            ApiError::UnsupportedMediaType(_) => 415,
+            ApiError::Unprocessable(_) => 422,
+            ApiError::TooManyRequests(_) => 429,
+            ApiError::Unavailable(_) => 503,
+            ApiError::Upstream(_) => 502,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// What the client is told.
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::Conflict(message)
+            | ApiError::Gone(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::Unavailable(message)
+            | ApiError::Unprocessable(message)
+            | ApiError::TooManyRequests(message) => message,
+            ApiError::Invalid(_) => "Some fields are invalid",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::NotFound => "Not Found",
+            ApiError::LengthRequired => "Length Required",
+            ApiError::Upstream(_) => "Bad Gateway",
+            ApiError::Internal(_) => "Internal Server Error",
+        }
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        if let ApiError::Upstream(detail) | ApiError::Internal(detail) = &self {
+            console_log!("{}: {}", self.message(), detail);
+        }
+        let mut body = json!({ "error": self.message(), "code": self.code() });
+        if let (ApiError::Invalid(fields), Some(body_obj)) = (&self, body.as_object_mut()) {
+            body_obj.insert("fields".to_string(), json!(fields));
+        }
+        Ok(Response::from_json(&body)?.with_status(self.code()))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Upstream(detail) | ApiError::Internal(detail) => write!(f, "{}", detail),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<kv::KvError> for ApiError {
+    fn from(e: kv::KvError) -> Self {
+        ApiError::from(Error::from(e))
+    }
+}
+
+/// Request bodies are read with [`crate::models::from_body`], which answers with a 400, so a
+/// serde error that gets this far is about something stored.
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+/// Runs a handler for the router, answering its error, if any.
+pub async fn api(handler: impl Future<Output = ApiResult<Response>>) -> Result<Response> {
+    match handler.await {
+        Ok(res) => Ok(res),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The route parameter `name`.
+pub fn param(ctx: &RouteContext<Session>, name: &str) -> ApiResult<String> {
+    ctx.param(name)
+        .cloned()
+        .ok_or_else(|| ApiError::BadRequest(format!("`{}` is required", name)))
+}
@@ -0,0 +1,229 @@
+use chrono::Utc;
+use serde::Serialize;
+use worker::*;
+
+use crate::session::Session;
+use crate::trace::{Span, Trace};
+use crate::{cache, utils};
+
+/// Bumped whenever a field of [`RequestEvent`] changes meaning or goes away, so a tail worker
+/// can tell which shape it is reading.
+const SCHEMA_VERSION: u32 = 1;
+
+/// The router's patterns, literal paths before the patterns they would also match. Keep in sync
+/// with the routes in `main`; a path matching none of them is reported as `"unmatched"`.
+const ROUTES: &[&str] = &[
+    "/",
+    "/form/:field",
+    "/worker-version",
+    "/posts",
+    "/posts/bulk_delete",
+    "/posts/bulk_delete/:job",
+    "/posts/:id",
+    "/posts/:id/archive",
+    "/posts/:id/restore",
+    "/posts/:id/like",
+    "/posts/:id/unlike",
+    "/posts/:id/comments",
+    "/posts/:id/co_authors/:action",
+    "/posts/:id/crosspost",
+    "/posts/:id/moderation",
+    "/posts/:id/report",
+    "/posts/:id/share_link",
+    "/comments/:id",
+    "/comments/:id/like",
+    "/comments/:id/unlike",
+    "/me/moderation",
+    "/me/referrals",
+    "/me/filters",
+    "/me/archived",
+    "/me/profile_views",
+    "/threads",
+    "/threads/:id",
+    "/media",
+    "/media/:id",
+    "/notifications",
+    "/notifications/read",
+    "/search",
+    "/tags/trending",
+    "/tags/:tag",
+    "/searches",
+    "/searches/alerts",
+    "/searches/:id",
+    "/communities/discover",
+    "/c/:name",
+    "/c/:name/join",
+    "/c/:name/tags",
+    "/c/:name/webhooks",
+    "/c/:name/automod",
+    "/c/:name/automod/flags",
+    "/c/:name/automod/flags/:id",
+    "/c/:name/users/:username/notes",
+    "/c/:name/users/:username/notes/:id",
+    "/c/:name/posts",
+    "/c/:name/quarantine",
+    "/c/:name/templates",
+    "/c/:name/domain",
+    "/c/:name/domain/verify",
+    "/about/stats",
+    "/branding",
+    "/openapi.json",
+    "/docs",
+    "/.well-known/nodeinfo",
+    "/nodeinfo/2.0",
+    "/feed",
+    "/feed/global",
+    "/feed/for_you",
+    "/explore",
+    "/ws",
+    "/events",
+    "/api/v1/accounts/verify_credentials",
+    "/api/v1/timelines/home",
+    "/api/v1/statuses",
+    "/api/v1/statuses/:id",
+    "/firehose",
+    "/triggers/me",
+    "/triggers/new_posts",
+    "/actions/create_post",
+    "/bot/digest",
+    "/bot/rss",
+    "/bot/retention",
+    "/bot/purge",
+    "/admin/api_keys",
+    "/admin/api_keys/:id",
+    "/admin/service_accounts",
+    "/admin/rss_feeds",
+    "/admin/rss_feeds/:id",
+    "/admin/signup_limits",
+    "/admin/branding",
+    "/admin/maintenance",
+    "/admin/storage/migrate",
+    "/admin/posts/:id",
+    "/admin/users/:username/ban",
+    "/admin/stats",
+    "/admin/reports",
+    "/admin/journal/replay",
+    "/admin/tenants",
+    "/admin/tenants/:id",
+    "/admin/queue_stats",
+    "/admin/dead_letters",
+    "/admin/dead_letters/:id/replay",
+    "/admin/dead_letters/:id",
+    "/admin/withholdings",
+    "/admin/withholdings/:id",
+    "/admin/surveys",
+    "/admin/login",
+    "/surveys",
+    "/surveys/:id",
+    "/surveys/:id/responses",
+    "/surveys/:id/results",
+    "/settings/languages",
+    "/settings/retention",
+    "/settings/profile_views",
+    "/drafts/:id/autosave",
+    "/drafts/:id/revisions",
+    "/users",
+    "/users/:username",
+    "/users/:username/follow",
+    "/users/:username/block",
+    "/users/:username/mute",
+    "/users/:username/followers",
+    "/users/:username/following",
+    "/users/:username/mutuals",
+    "/users/:username/activity",
+    "/users/:username/atproto-export",
+    "/users/:username/domain",
+    "/users/:username/domain/verify",
+    "/wp-login.php",
+    "/xmlrpc.php",
+    "/wp-admin/post-new.php",
+    "/.env",
+];
+
+/// The pattern `path` was routed by, so events about `/posts/a` and `/posts/b` add up.
+fn route(path: &str) -> &'static str {
+    ROUTES
+        .iter()
+        .find(|pattern| cache::matches(pattern, path))
+        .copied()
+        .unwrap_or("unmatched")
+}
+
+/// Whether the router has a route for `path`, whatever its method.
+pub fn is_route(path: &str) -> bool {
+    route(path) != "unmatched"
+}
+
+/// One line per request, written to the console as JSON for a tail worker to pick up:
+///
+/// ```json
+/// {"event": "request", "schema": 1, "trace_id": "4bf9...", "method": "GET",
+///  "route": "/posts/:id", "status": 200, "outcome": "ok", "duration_ms": 12,
+///  "user": "9f86d081884c7d65", "dependencies": [{"host": "auth.example.com",
+///  "duration_ms": 8, "status": 200}]}
+/// ```
+///
+/// `outcome` is `ok`, `client_error` (4xx) or `server_error` (5xx), which is what error-rate
+/// alerts should count. `user` is a keyed hash of the username, the same for every request by
+/// that user and useless to anyone without `SESSION_SECRET`; `null` when nobody is signed in.
+#[derive(Serialize, Debug)]
+pub struct RequestEvent {
+    event: &'static str,
+    schema: u32,
+    trace_id: String,
+    method: String,
+    route: &'static str,
+    status: u16,
+    outcome: &'static str,
+    duration_ms: i64,
+    user: Option<String>,
+    dependencies: Vec<Span>,
+    #[serde(skip)]
+    started_at: i64,
+}
+
+impl RequestEvent {
+    /// Starts the event for `req`, as the first thing the worker does with it.
+    pub fn start(req: &Request, trace: &Trace) -> RequestEvent {
+        RequestEvent {
+            event: "request",
+            schema: SCHEMA_VERSION,
+            trace_id: trace.id().to_string(),
+            method: req.method().to_string(),
+            route: route(&req.path()),
+            status: 0,
+            outcome: "",
+            duration_ms: 0,
+            user: None,
+            dependencies: vec![],
+            started_at: Utc::now().timestamp_millis(),
+        }
+    }
+
+    /// Notes who the request is from, once the session is checked and before routing takes it.
+    pub fn identify(&mut self, env: &Env, session: &Session) {
+        self.user = match (session.username(), env.secret("SESSION_SECRET")) {
+            (Some(username), Ok(secret)) => Some(
+                utils::sha256_hex(&format!("user:{}:{}", secret.to_string(), username))[..16]
+                    .to_string(),
+            ),
+            _ => None,
+        };
+    }
+
+    /// Fills in how the request went and writes the event.
+    pub fn finish(mut self, res: &Response, trace: &Trace) {
+        self.status = res.status_code();
+        self.outcome = match self.status {
+            500.. => "server_error",
+            400..=499 => "client_error",
+            _ => "ok",
+        };
+        self.duration_ms = Utc::now().timestamp_millis() - self.started_at;
+        self.dependencies = trace.spans();
+        match serde_json::to_string(&self) {
+            Ok(line) => console_log!("{}", line),
+            Err(e) => console_log!("request event failed: {}", e),
+        }
+    }
+}
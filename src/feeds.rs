@@ -0,0 +1,484 @@
+use chrono::{DateTime, Duration, Utc};
+use js_sys::Reflect;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use wasm_bindgen::prelude::*;
+use worker::*;
+
+use crate::error::ApiResult;
+use crate::models::Post;
+use crate::session::Session;
+use crate::withholding::Withheld;
+use crate::{comments, communities, follows, mirror, posts, queues, seen, session, storage};
+
+/// Keys in the `feeds` namespace:
+///
+/// - `feed/<username>`: the ids of the newest posts in `username`'s home feed, oldest first,
+///   which new posts are added to as they are made. Only heavy readers have one.
+/// - `global/<yyyy-mm-ddThh>/<post id>`: a post made in that UTC hour, for [`global`], kept
+///   [`GLOBAL_TTL`] seconds
+/// - `media/<yyyy-mm-ddThh>/<post id>`: the same, for posts with `media`, which [`explore`] reads
+/// - `seen/<username>`: the posts [`for_you`] already served `username`, see `seen`
+///
+/// The global index is sharded by hour, one key per post: a single index key would take the
+/// write of every post on the instance, well past the one write a second KV allows a key. Reads
+/// merge the hours' shards, newest first.
+const FEEDS_KV: &str = "feeds";
+
+/// Binding of the queue new posts are fanned out through; the worker consumes it in [`consume`].
+const FEED_QUEUE: &str = "FEED_QUEUE";
+
+/// Name of that queue in `wrangler.toml`, which `queues::queue` hands its batches here by.
+pub const FEED_QUEUE_NAME: &str = "feed-fan-out";
+
+/// Readers who follow at least this many users and communities between them get a materialized
+/// feed. Picking everyone else's feed out of all posts on read is cheap enough, and spares every
+/// new post a write per follower.
+const HEAVY_READER_SOURCES: usize = 100;
+
+/// Posts a materialized feed keeps; older ones fall off as new ones arrive.
+const FEED_LENGTH: usize = 300;
+
+/// How far back [`global`] looks, and how many posts it answers with.
+const GLOBAL_HOURS: i64 = 48;
+const GLOBAL_LIMIT: usize = 50;
+
+/// How far back For You looks, how many posts it weighs, and how many it serves.
+const FOR_YOU_HOURS: i64 = 24;
+const FOR_YOU_CANDIDATES: usize = 200;
+const FOR_YOU_LIMIT: usize = 20;
+
+/// How far back Explore looks, how many media posts it weighs, and how many it serves.
+const EXPLORE_HOURS: i64 = 48;
+const EXPLORE_CANDIDATES: usize = 200;
+const EXPLORE_LIMIT: usize = 30;
+
+/// Global index entries are kept a little past [`GLOBAL_HOURS`], then KV drops them.
+const GLOBAL_TTL: u64 = 60 * 60 * (GLOBAL_HOURS as u64 + 1);
+
+/// What is queued for a new post.
+#[derive(Serialize, Deserialize, Debug)]
+struct NewPost {
+    /// Names this fan-out, so the consumer delivers it once however often the queue does. Absent
+    /// from messages queued before it existed, which fall back to [`NewPost::default_key`].
+    #[serde(default)]
+    key: Option<String>,
+    id: String,
+    username: String,
+    #[serde(default)]
+    community: Option<String>,
+}
+
+impl NewPost {
+    fn default_key(id: &str) -> String {
+        format!("fan_out/{}", id)
+    }
+
+    fn idempotency_key(&self) -> String {
+        self.key
+            .clone()
+            .unwrap_or_else(|| NewPost::default_key(&self.id))
+    }
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+fn feed_key(username: &str) -> String {
+    format!("feed/{}", username)
+}
+
+/// Every name after `prefix` in `kv`, following the listing's cursor past its first page.
+async fn names(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        names.extend(
+            page.keys
+                .into_iter()
+                .map(|key| key.name[prefix.len()..].to_string()),
+        );
+        if page.list_complete || page.cursor.is_none() {
+            return Ok(names);
+        }
+        cursor = page.cursor;
+    }
+}
+
+/// The posts of `username`'s home feed: those by users in `following` or in communities in
+/// `joined`, but not by users in `filtered`, oldest first.
+///
+/// Heavy readers (see [`HEAVY_READER_SOURCES`]) read the newest [`FEED_LENGTH`] of them off
+/// their materialized feed, which their first read builds and [`fan_out`] keeps up to date.
+/// Everyone else's are picked out of every public post on each read. A materialized feed is
+/// filtered as it is built and as posts are fanned out, never on read.
+pub async fn home(
+    ctx: &RouteContext<Session>,
+    username: &str,
+    following: &HashSet<String>,
+    joined: &HashSet<String>,
+    filtered: &HashSet<String>,
+) -> Result<Vec<Post>> {
+    let store = storage::posts(ctx)?;
+    let heavy = following.len() + joined.len() >= HEAVY_READER_SOURCES;
+    let kv = ctx.kv(FEEDS_KV)?;
+    if heavy {
+        if let Some(v) = kv.get(&feed_key(username)).await? {
+            return posts::load_public(&*store, &v.as_json::<Vec<String>>()?).await;
+        }
+    }
+
+    let posts: Vec<Post> = posts::list_public(&*store, ctx.data().trace())
+        .await?
+        .into_iter()
+        .filter(|post| !filtered.contains(&post.username))
+        .filter(|post| {
+            following.contains(&post.username)
+                || post
+                    .extra
+                    .get("community")
+                    .and_then(Value::as_str)
+                    .is_some_and(|community| joined.contains(community))
+        })
+        .collect();
+    if heavy {
+        let ids: Vec<&str> = posts
+            .iter()
+            .filter_map(|post| post.extra.get("id").and_then(Value::as_str))
+            .collect();
+        let newest = &ids[ids.len().saturating_sub(FEED_LENGTH)..];
+        let built = match kv.put(&feed_key(username), newest) {
+            Ok(put) => put.execute().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = built {
+            console_log!("materializing the feed of {} failed: {}", username, e);
+        }
+    }
+    Ok(posts)
+}
+
+/// Whether `post` has any `media` attached.
+fn has_media(post: &Value) -> bool {
+    post.get("media")
+        .and_then(Value::as_array)
+        .is_some_and(|media| !media.is_empty())
+}
+
+/// Adds a new post to the hour's shard of the global index, and of the media index if it has
+/// media.
+pub async fn index(ctx: &RouteContext<Session>, id: &str, post: &Value) -> Result<()> {
+    let hour = Utc::now().format("%Y-%m-%dT%H");
+    let kv = ctx.kv(FEEDS_KV)?;
+    kv.put(&format!("global/{}/{}", hour, id), "")?
+        .expiration_ttl(GLOBAL_TTL)
+        .execute()
+        .await?;
+    if has_media(post) {
+        kv.put(&format!("media/{}/{}", hour, id), "")?
+            .expiration_ttl(GLOBAL_TTL)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+/// The newest `limit` posts of the last `hours` hours in `index` (`global` or `media`) whose
+/// ids are `wanted`, newest first, leaving out what `posts::load_listed` leaves out.
+async fn recent(
+    ctx: &RouteContext<Session>,
+    kv: &kv::KvStore,
+    withheld: &Withheld,
+    index: &str,
+    hours: i64,
+    limit: usize,
+    wanted: impl Fn(&str) -> bool,
+) -> ApiResult<Vec<Value>> {
+    let now = Utc::now();
+    let mut found = vec![];
+    for hours_ago in 0..hours {
+        if found.len() >= limit {
+            break;
+        }
+        let hour = (now - Duration::hours(hours_ago)).format("%Y-%m-%dT%H");
+        let mut ids = names(kv, format!("{}/{}/", index, hour)).await?;
+        ids.retain(|id| wanted(id));
+        // Ids start with the time the post was made.
+        ids.sort_by(|a, b| b.cmp(a));
+        let mut ids = ids.into_iter();
+        while found.len() < limit {
+            let batch: Vec<String> = ids.by_ref().take(limit - found.len()).collect();
+            if batch.is_empty() {
+                break;
+            }
+            found.extend(posts::load_listed(ctx, withheld, batch).await?);
+        }
+    }
+    Ok(found)
+}
+
+/// `GET /feed/global`: the newest [`GLOBAL_LIMIT`] posts of the last [`GLOBAL_HOURS`] hours
+/// across the instance, newest first.
+pub async fn global(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(FEEDS_KV)?;
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    match recent(
+        &ctx,
+        &kv,
+        &withheld,
+        "global",
+        GLOBAL_HOURS,
+        GLOBAL_LIMIT,
+        |_| true,
+    )
+    .await
+    {
+        Ok(found) => Ok(Response::from_json(&found)?),
+        // Posts that can't be read are served from the latest snapshot, if there is one.
+        Err(e) => mirror::fallback(&req, ctx.data().bindings(), e).await,
+    }
+}
+
+/// How a post ranks in For You: its likes, discounted by its age in hours.
+fn rank(post: &Value, now: DateTime<Utc>) -> f64 {
+    let likes = post.get("likes").and_then(Value::as_f64).unwrap_or(0.0);
+    let age_hours = post
+        .get("time")
+        .and_then(Value::as_str)
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map_or(FOR_YOU_HOURS as f64, |time| {
+            (now - time.with_timezone(&Utc)).num_minutes() as f64 / 60.0
+        })
+        .max(0.0);
+    (likes + 1.0) / (age_hours + 2.0).powf(1.5)
+}
+
+/// `GET /feed/for_you`, signed in: the [`FOR_YOU_LIMIT`] best ranked (see [`rank`]) of the
+/// newest [`FOR_YOU_CANDIDATES`] posts by others that the reader wasn't served before. What it
+/// serves goes into the reader's `seen` filter, so the next call moves on to other posts.
+pub async fn for_you(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(FEEDS_KV)?;
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    let mut seen = seen::load(&kv, &username).await?;
+    let mut candidates = recent(
+        &ctx,
+        &kv,
+        &withheld,
+        "global",
+        FOR_YOU_HOURS,
+        FOR_YOU_CANDIDATES,
+        |id| !seen.contains(id),
+    )
+    .await?;
+    candidates.retain(|post| post.get("username").and_then(Value::as_str) != Some(&username));
+
+    let now = Utc::now();
+    candidates.sort_by(|a, b| rank(b, now).total_cmp(&rank(a, now)));
+    candidates.truncate(FOR_YOU_LIMIT);
+    for post in &candidates {
+        if let Some(id) = post.get("id").and_then(Value::as_str) {
+            seen.insert(id);
+        }
+    }
+    if !candidates.is_empty() {
+        // Serving the same posts again is better than failing the feed.
+        if let Err(e) = seen::save(&kv, &username, &seen).await {
+            console_log!("seen filter of {} not saved: {}", username, e);
+        }
+    }
+    Ok(Response::from_json(&candidates)?)
+}
+
+/// How much a post was engaged with: its likes and comments.
+fn engagement(post: &Value, comments: usize) -> i64 {
+    post.get("likes").and_then(Value::as_i64).unwrap_or(0) + comments as i64
+}
+
+/// `GET /explore`: the [`EXPLORE_LIMIT`] most engaged with (see [`engagement`]) of the newest
+/// [`EXPLORE_CANDIDATES`] posts with media of the last [`EXPLORE_HOURS`] hours, each with its
+/// `comment_count`. Posts engaged with alike stay newest first.
+pub async fn explore(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(FEEDS_KV)?;
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    let candidates = recent(
+        &ctx,
+        &kv,
+        &withheld,
+        "media",
+        EXPLORE_HOURS,
+        EXPLORE_CANDIDATES,
+        |_| true,
+    )
+    .await?;
+    let ids: Vec<String> = candidates
+        .iter()
+        .map(|post| {
+            post.get("id")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+    let counts = comments::counts(&ctx, &ids).await?;
+    let mut ranked: Vec<(i64, Value)> = candidates
+        .into_iter()
+        .zip(counts)
+        .map(|(mut post, count)| {
+            let score = engagement(&post, count);
+            if let Some(post_obj) = post.as_object_mut() {
+                post_obj.insert("comment_count".to_string(), count.into());
+            }
+            (score, post)
+        })
+        .collect();
+    ranked.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    let posts: Vec<Value> = ranked
+        .into_iter()
+        .take(EXPLORE_LIMIT)
+        .map(|(_, post)| post)
+        .collect();
+    Ok(Response::from_json(&posts)?)
+}
+
+/// Drops `username`'s materialized feed once they follow, unfollow, join or leave, so their next
+/// read rebuilds it from what they follow then, or stops keeping one if they are no longer a
+/// heavy reader. Failures are logged, never returned.
+pub async fn invalidate(ctx: &RouteContext<Session>, username: &str) {
+    let deleted = match ctx.kv(FEEDS_KV) {
+        Ok(kv) => kv.delete(&feed_key(username)).await.map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = deleted {
+        console_log!("dropping the feed of {} failed: {}", username, e);
+    }
+}
+
+/// Adds `new_post` to the materialized feed of every heavy reader following its author or
+/// community, save those who block or mute the author, answering how many feeds it went into.
+/// Adding a post a feed already has does nothing, so a delivery can be retried.
+async fn deliver(
+    follows: &kv::KvStore,
+    communities: &kv::KvStore,
+    feeds: &kv::KvStore,
+    new_post: &NewPost,
+) -> Result<usize> {
+    let readers: HashSet<String> = names(feeds, "feed/".to_string())
+        .await?
+        .into_iter()
+        .collect();
+    if readers.is_empty() {
+        return Ok(0);
+    }
+    let mut audience: HashSet<String> = names(follows, format!("follower/{}/", new_post.username))
+        .await?
+        .into_iter()
+        .collect();
+    if let Some(community) = &new_post.community {
+        audience.extend(names(communities, format!("member/{}/", community)).await?);
+    }
+    // Readers who block or mute the author never get the post written into their feed.
+    for reader in follows::filtering(follows, &new_post.username).await? {
+        audience.remove(&reader);
+    }
+
+    let mut delivered = 0;
+    for reader in audience.intersection(&readers) {
+        let key = feed_key(reader);
+        let mut ids = match feeds.get(&key).await? {
+            Some(v) => v.as_json::<Vec<String>>()?,
+            None => continue,
+        };
+        if ids.contains(&new_post.id) {
+            continue;
+        }
+        ids.push(new_post.id.clone());
+        ids.sort();
+        let excess = ids.len().saturating_sub(FEED_LENGTH);
+        ids.drain(..excess);
+        feeds.put(&key, &ids)?.execute().await?;
+        delivered += 1;
+    }
+    Ok(delivered)
+}
+
+async fn try_fan_out(ctx: &RouteContext<Session>, new_post: &NewPost) -> Result<()> {
+    let queue =
+        Reflect::get(ctx.data().bindings(), &JsValue::from(FEED_QUEUE)).map_err(js_error)?;
+    if !queue.is_undefined() {
+        return queues::send(&queue, &serde_json::to_string(new_post)?).await;
+    }
+    let follows = ctx.kv(follows::FOLLOWS_KV)?;
+    let communities = ctx.kv(communities::COMMUNITIES_KV)?;
+    deliver(&follows, &communities, &ctx.kv(FEEDS_KV)?, new_post).await?;
+    Ok(())
+}
+
+/// Queues a new post for the materialized feeds of the heavy readers following its author or
+/// community, so its author doesn't wait on the writes. A worker without [`FEED_QUEUE`] bound
+/// delivers it right away instead. Failures are logged, never returned.
+pub async fn fan_out(ctx: &RouteContext<Session>, id: &str, post: &Value) {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).map(String::from);
+    let new_post = NewPost {
+        key: Some(NewPost::default_key(id)),
+        id: id.to_string(),
+        username: field("username").unwrap_or_default(),
+        community: field("community"),
+    };
+    if let Err(e) = try_fan_out(ctx, &new_post).await {
+        console_log!("fanning out {} failed: {}", id, e);
+    }
+}
+
+/// Consumer of [`FEED_QUEUE`]. Each message is acked or retried on its own; one that keeps
+/// failing, or doesn't parse, is dead-lettered (see `queues`) rather than blocking the queue. A
+/// redelivery of a message already handled is acked and skipped.
+pub async fn consume(messages: Vec<queues::Message>, env: &Env) -> Result<()> {
+    let follows = env.kv(follows::FOLLOWS_KV)?;
+    let communities = env.kv(communities::COMMUNITIES_KV)?;
+    let feeds = env.kv(FEEDS_KV)?;
+    let dead_letters = env.kv(queues::QUEUES_KV)?;
+    for message in messages {
+        let new_post = match message
+            .body()
+            .and_then(|text| serde_json::from_str::<NewPost>(&text).ok())
+        {
+            Some(new_post) => new_post,
+            None => {
+                let malformed = "malformed feed message".to_string();
+                queues::failed(&dead_letters, FEED_QUEUE, &message, malformed, true).await?;
+                continue;
+            }
+        };
+        let key = new_post.idempotency_key();
+        match queues::handled(&dead_letters, FEED_QUEUE, &key).await {
+            Ok(true) => {
+                message.ack()?;
+                continue;
+            }
+            Ok(false) => {}
+            // Delivering twice only repeats writes a feed already has, so go ahead.
+            Err(e) => console_log!("dedupe check for {} failed: {}", key, e),
+        }
+        match deliver(&follows, &communities, &feeds, &new_post).await {
+            Ok(delivered) => {
+                console_log!("fanned {} out to {} feeds", new_post.id, delivered);
+                if let Err(e) = queues::mark_handled(&dead_letters, &key).await {
+                    console_log!("marking {} handled failed: {}", key, e);
+                }
+                message.ack()?;
+            }
+            Err(e) => {
+                queues::failed(&dead_letters, FEED_QUEUE, &message, e.to_string(), false).await?
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{apikeys, communities, live, posts};
+
+/// Keys in the `firehose` namespace:
+///
+/// - `event/<minute>/<cursor>`: one [`Event`], where `minute` is minutes since the epoch and
+///   `cursor` is `<millis, zero-padded>-<kind>-<post id>`, so keys sort in the order events
+///   happened.
+pub const FIREHOSE_KV: &str = "firehose";
+
+/// Events are dropped from KV after a day; consumers further behind than that have to resync.
+const EVENT_TTL: u64 = 60 * 60 * 24;
+
+/// How many minutes of events one `GET /firehose` reads at most.
+const MAX_MINUTES: i64 = 60;
+
+/// How many events one `GET /firehose` answers at most. Each is a KV read, and a worker only
+/// gets so many per request.
+const MAX_EVENTS: usize = 500;
+
+/// Response header with the cursor to pass next, for when no event was answered to take it from.
+const CURSOR_HEADER: &str = "Firehose-Cursor";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Event {
+    cursor: String,
+    kind: Kind,
+    id: String,
+    /// The post as it now reads publicly. Absent for deletes.
+    post: Option<Value>,
+    at: String,
+}
+
+async fn write(kv: &kv::KvStore, key: &str, event: &Event) -> Result<()> {
+    kv.put(key, event)?
+        .expiration_ttl(EVENT_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Records in `kv` that a public post was created, changed or went away (deleted, archived or
+/// moderated), and relays it to the consumers streaming `GET /firehose` through the hub in
+/// `bindings`. A failure is logged rather than returned: the write it reports on already
+/// happened.
+pub async fn publish_in(
+    kv: &kv::KvStore,
+    bindings: &JsValue,
+    kind: Kind,
+    id: &str,
+    post: Option<&Value>,
+) {
+    let now = Utc::now();
+    let millis = now.timestamp_millis();
+    let kind_name = serde_json::to_value(kind)
+        .ok()
+        .and_then(|kind| kind.as_str().map(String::from))
+        .unwrap_or_default();
+    let event = Event {
+        cursor: format!("{:013}-{}-{}", millis, kind_name, id),
+        kind,
+        id: id.to_string(),
+        post: post.cloned(),
+        at: now.to_rfc3339(),
+    };
+    let key = format!("event/{}/{}", millis / 60_000, event.cursor);
+    if let Err(e) = write(kv, &key, &event).await {
+        console_log!("firehose event for {} failed: {}", id, e);
+    }
+    let relayed = match serde_json::to_string(&event) {
+        Ok(line) => live::relay(bindings, live::FIREHOSE_HUB, &line).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = relayed {
+        console_log!("streaming the firehose event for {} failed: {}", id, e);
+    }
+}
+
+/// [`publish_in`] the worker's firehose.
+pub async fn publish(ctx: &RouteContext<Session>, kind: Kind, id: &str, post: Option<&Value>) {
+    match ctx.kv(FIREHOSE_KV) {
+        Ok(kv) => publish_in(&kv, ctx.data().bindings(), kind, id, post).await,
+        Err(e) => console_log!("firehose event for {} failed: {}", id, e),
+    }
+}
+
+/// Whether `post` is in a quarantined community. If that can't be told, it is assumed not.
+async fn in_quarantine(ctx: &RouteContext<Session>, post: &Value) -> bool {
+    let community = match post.get("community").and_then(Value::as_str) {
+        Some(community) => community,
+        None => return false,
+    };
+    communities::is_quarantined(ctx, community)
+        .await
+        .unwrap_or_else(|e| {
+            console_log!("quarantine check for {} failed: {}", community, e);
+            false
+        })
+}
+
+/// Publishes `post` as it now reads publicly, or a delete if it is no longer public (or sits in
+/// a quarantined community, which the firehose leaves out).
+pub async fn post_changed(ctx: &RouteContext<Session>, kind: Kind, id: &str, post: &Value) {
+    if posts::is_archived(post) || posts::is_moderated(post) || in_quarantine(ctx, post).await {
+        return publish(ctx, Kind::Delete, id, None).await;
+    }
+    let mut public = post.clone();
+    posts::hide_pending_co_authors(&mut public);
+    publish(ctx, kind, id, Some(&public)).await
+}
+
+/// `GET /firehose[?cursor=<cursor>]` or `GET /firehose?stream=true`, for holders of a
+/// `firehose` API key.
+///
+/// With `stream=true`, or `Upgrade: websocket`, the response stays open and every event is sent
+/// as it happens: one JSON object per line (empty lines are heartbeats), or one per WebSocket
+/// message. A stream only carries what happens while it is open, so a consumer that went away
+/// catches up from the last `cursor` it saw before streaming again.
+///
+/// Otherwise returns the events after `cursor` (or from the last minute, without one) as NDJSON,
+/// oldest first: up to [`MAX_EVENTS`] of them, from at most [`MAX_MINUTES`] minutes. Consumers
+/// catching up pass the `cursor` of the last event they saw, or [`CURSOR_HEADER`] when there
+/// was none, which also moves them past quiet stretches. A cursor older than [`EVENT_TTL`] is
+/// answered with a 410: events after it are gone, and the consumer has to resync.
+pub async fn read(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if apikeys::authorize(&req, &ctx, apikeys::Scope::Firehose)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    let url = req.url()?;
+    let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
+    if upgrade.eq_ignore_ascii_case("websocket")
+        || url
+            .query_pairs()
+            .any(|(key, value)| key == "stream" && value == "true")
+    {
+        return Ok(live::forward(&req, ctx.data().bindings(), live::FIREHOSE_HUB).await?);
+    }
+    let now = Utc::now();
+    let after = url
+        .query_pairs()
+        .find(|(key, _)| key == "cursor")
+        .map(|(_, cursor)| cursor.into_owned())
+        .unwrap_or_else(|| format!("{:013}", (now - Duration::minutes(1)).timestamp_millis()));
+    let now_minute = now.timestamp_millis() / 60_000;
+    let (first_minute, last_minute) = span(&after, now_minute)?;
+
+    let kv = ctx.kv(FIREHOSE_KV)?;
+    let mut body = String::new();
+    let mut events = 0;
+    let mut next = None;
+    'minutes: for minute in first_minute..=last_minute {
+        let prefix = format!("event/{}/", minute);
+        for name in names(&kv, prefix.clone()).await? {
+            let cursor = &name[prefix.len()..];
+            if *cursor <= *after {
+                continue;
+            }
+            if let Some(v) = kv.get(&name).await? {
+                body.push_str(&v.as_string());
+                body.push('\n');
+                events += 1;
+                next = Some(cursor.to_string());
+            }
+            if events >= MAX_EVENTS {
+                break 'minutes;
+            }
+        }
+    }
+    let next = next.unwrap_or_else(|| skip(&after, last_minute, now_minute));
+    let mut res = Response::ok(body)?;
+    res.headers_mut()
+        .set("Content-Type", "application/x-ndjson")?;
+    res.headers_mut().set(CURSOR_HEADER, &next)?;
+    Ok(res)
+}
+
+/// The minutes, first and last, a read of the events after cursor `after` covers.
+fn span(after: &str, now_minute: i64) -> ApiResult<(i64, i64)> {
+    let first_minute = match after
+        .get(..13)
+        .and_then(|millis| millis.parse::<i64>().ok())
+    {
+        Some(millis) => millis / 60_000,
+        None => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    if first_minute < now_minute - EVENT_TTL as i64 / 60 {
+        return Err(ApiError::Gone(
+            "Events after this cursor have expired; resync and start without a cursor".to_string(),
+        ));
+    }
+    Ok((first_minute, now_minute.min(first_minute + MAX_MINUTES - 1)))
+}
+
+/// The cursor to pass next when a read up to `last_minute` found no events. A consumer that is
+/// behind skips to the end of what was read; one that is caught up keeps its cursor, as more
+/// events may land in this minute.
+fn skip(after: &str, last_minute: i64, now_minute: i64) -> String {
+    if last_minute < now_minute {
+        format!("{:013}", (last_minute + 1) * 60_000 - 1)
+    } else {
+        after.to_string()
+    }
+}
+
+/// Every key name in `kv` under `prefix`, following the listing's cursor past its first page.
+async fn names(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        names.extend(page.keys.into_iter().map(|key| key.name));
+        if page.list_complete || page.cursor.is_none() {
+            return Ok(names);
+        }
+        cursor = page.cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_MINUTE: i64 = 28_000_000;
+
+    fn cursor(minute: i64) -> String {
+        format!("{:013}-create-01HXYZ", minute * 60_000 + 1_234)
+    }
+
+    #[test]
+    fn reads_from_the_cursors_minute_for_at_most_an_hour() {
+        assert_eq!(
+            span(&cursor(NOW_MINUTE - 1), NOW_MINUTE).unwrap(),
+            (NOW_MINUTE - 1, NOW_MINUTE)
+        );
+        assert_eq!(
+            span(&cursor(NOW_MINUTE - 600), NOW_MINUTE).unwrap(),
+            (NOW_MINUTE - 600, NOW_MINUTE - 600 + MAX_MINUTES - 1)
+        );
+    }
+
+    #[test]
+    fn expired_or_malformed_cursors_are_refused() {
+        let day = EVENT_TTL as i64 / 60;
+        assert!(span(&cursor(NOW_MINUTE - day), NOW_MINUTE).is_ok());
+        assert!(matches!(
+            span(&cursor(NOW_MINUTE - day - 1), NOW_MINUTE),
+            Err(ApiError::Gone(_))
+        ));
+        assert!(matches!(
+            span("yesterday", NOW_MINUTE),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn quiet_stretches_are_skipped_only_when_behind() {
+        let behind = cursor(NOW_MINUTE - 600);
+        let (_, last_minute) = span(&behind, NOW_MINUTE).unwrap();
+        let next = skip(&behind, last_minute, NOW_MINUTE);
+        assert!(next > behind);
+        assert!(next < cursor(last_minute + 1));
+        assert_eq!(span(&next, NOW_MINUTE).unwrap().0, last_minute);
+
+        let caught_up = cursor(NOW_MINUTE);
+        assert_eq!(skip(&caught_up, NOW_MINUTE, NOW_MINUTE), caught_up);
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{feeds, journal, session, users};
+
+/// Keys in the `follows` namespace:
+///
+/// - `following/<follower>/<username>`: `follower` follows `username`, listed per follower
+/// - `follower/<username>/<follower>`: the same follow, listed per followed user
+/// - `blocking/<username>/<blocked>` and `muting/<username>/<muted>`: who `username` blocks and
+///   mutes
+/// - `filtered/<author>/<username>`: `username` blocks or mutes `author`, listed per author so
+///   fan-out and notifications can leave `username` out when `author` writes
+///
+/// Follow keys carry `{"followed_at": <rfc3339>}` as KV metadata.
+pub const FOLLOWS_KV: &str = "follows";
+
+/// The two ways of filtering someone out. Both keep their posts out of your home feed and their
+/// mentions out of your notifications; a block also ends follows either way and keeps them from
+/// following you again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filter {
+    Block,
+    Mute,
+}
+
+impl Filter {
+    fn verb(self) -> &'static str {
+        match self {
+            Filter::Block => "block",
+            Filter::Mute => "mute",
+        }
+    }
+
+    /// What the user is doing to those they filter, which their keys start with.
+    fn prefix(self) -> &'static str {
+        match self {
+            Filter::Block => "blocking",
+            Filter::Mute => "muting",
+        }
+    }
+}
+
+/// The usernames after `prefix` in the namespace, following the listing's cursor past its
+/// first page.
+async fn names(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        names.extend(
+            page.keys
+                .into_iter()
+                .map(|key| key.name[prefix.len()..].to_string()),
+        );
+        if page.list_complete || page.cursor.is_none() {
+            return Ok(names);
+        }
+        cursor = page.cursor;
+    }
+}
+
+/// Everyone `username` follows.
+pub async fn following(ctx: &RouteContext<Session>, username: &str) -> Result<HashSet<String>> {
+    let kv = ctx.kv(FOLLOWS_KV)?;
+    Ok(names(&kv, format!("following/{}/", username))
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// Everyone `username` blocks or mutes.
+pub async fn filtered(ctx: &RouteContext<Session>, username: &str) -> Result<HashSet<String>> {
+    let kv = ctx.kv(FOLLOWS_KV)?;
+    let mut filtered: HashSet<String> = names(&kv, format!("blocking/{}/", username))
+        .await?
+        .into_iter()
+        .collect();
+    filtered.extend(names(&kv, format!("muting/{}/", username)).await?);
+    Ok(filtered)
+}
+
+/// Everyone who blocks or mutes `author`.
+pub async fn filtering(kv: &kv::KvStore, author: &str) -> Result<Vec<String>> {
+    names(kv, format!("filtered/{}/", author)).await
+}
+
+/// Whether `username` blocks or mutes `author`.
+pub async fn filters(kv: &kv::KvStore, username: &str, author: &str) -> Result<bool> {
+    Ok(kv
+        .get(&format!("filtered/{}/{}", author, username))
+        .await?
+        .is_some())
+}
+
+/// Makes `follower` follow `username`, or stop following them.
+pub async fn set(kv: &kv::KvStore, follower: &str, username: &str, following: bool) -> Result<()> {
+    let following_key = format!("following/{}/{}", follower, username);
+    let follower_key = format!("follower/{}/{}", username, follower);
+    if following {
+        let metadata = json!({ "followed_at": Utc::now().to_rfc3339() });
+        kv.put(&following_key, "")?
+            .metadata(&metadata)?
+            .execute()
+            .await?;
+        kv.put(&follower_key, "")?
+            .metadata(&metadata)?
+            .execute()
+            .await?;
+    } else {
+        kv.delete(&following_key).await?;
+        kv.delete(&follower_key).await?;
+    }
+    Ok(())
+}
+
+/// `POST /users/:username/follow` follows, `DELETE` unfollows.
+pub async fn follow(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let follower = session::authed(&ctx)?.username;
+    let username = error::param(&ctx, "username")?;
+    if username == follower {
+        return Err(ApiError::BadRequest(
+            "You can't follow yourself".to_string(),
+        ));
+    }
+    if !users::exists(&ctx.kv(users::USERS_KV)?, &username).await? {
+        return Err(ApiError::NotFound);
+    }
+    let following = req.method() == Method::Post;
+    let kv = ctx.kv(FOLLOWS_KV)?;
+    let blocked_key = format!("blocking/{}/{}", username, follower);
+    if following && kv.get(&blocked_key).await?.is_some() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    set(&kv, &follower, &username, following).await?;
+    let mutation = if following {
+        journal::Mutation::UserFollowed {
+            follower: follower.clone(),
+            username: username.clone(),
+        }
+    } else {
+        journal::Mutation::UserUnfollowed {
+            follower: follower.clone(),
+            username: username.clone(),
+        }
+    };
+    journal::record(&ctx, mutation).await;
+    feeds::invalidate(&ctx, &follower).await;
+    Ok(Response::from_json(
+        &json!({ "username": username, "following": following }),
+    )?)
+}
+
+/// `names` as `{"username": ..., "mutual": ...}` objects, `mutual` when the name is also in
+/// `other`: for a user's followers, those the user follows back, and the other way round.
+fn flag_mutuals(names: Vec<String>, other: &HashSet<String>) -> Vec<Value> {
+    names
+        .into_iter()
+        .map(|name| {
+            let mutual = other.contains(&name);
+            json!({ "username": name, "mutual": mutual })
+        })
+        .collect()
+}
+
+/// Who `username` follows and who follows them.
+async fn both_ways(kv: &kv::KvStore, username: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let following = names(kv, format!("following/{}/", username)).await?;
+    let followers = names(kv, format!("follower/{}/", username)).await?;
+    Ok((following, followers))
+}
+
+/// `GET /users/:username/followers`, each `mutual` when `username` follows them back.
+pub async fn followers(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let (following, followers) = both_ways(&ctx.kv(FOLLOWS_KV)?, &username).await?;
+    let following: HashSet<String> = following.into_iter().collect();
+    Ok(Response::from_json(&flag_mutuals(followers, &following))?)
+}
+
+/// `GET /users/:username/following`, each `mutual` when they follow `username` back.
+pub async fn list_following(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let (following, followers) = both_ways(&ctx.kv(FOLLOWS_KV)?, &username).await?;
+    let followers: HashSet<String> = followers.into_iter().collect();
+    Ok(Response::from_json(&flag_mutuals(following, &followers))?)
+}
+
+/// `GET /users/:username/mutuals`: those `username` follows who follow them back.
+pub async fn mutuals(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let (following, followers) = both_ways(&ctx.kv(FOLLOWS_KV)?, &username).await?;
+    let followers: HashSet<String> = followers.into_iter().collect();
+    let mutuals: Vec<String> = following
+        .into_iter()
+        .filter(|name| followers.contains(name))
+        .collect();
+    Ok(Response::from_json(&mutuals)?)
+}
+
+/// Adds or removes one of `username`'s filters, keeping the `filtered/` key while either of
+/// them stands.
+async fn set_filter(
+    kv: &kv::KvStore,
+    username: &str,
+    author: &str,
+    filter: Filter,
+    on: bool,
+) -> Result<()> {
+    let key = format!("{}/{}/{}", filter.prefix(), username, author);
+    let filtered_key = format!("filtered/{}/{}", author, username);
+    if on {
+        kv.put(&key, "")?.execute().await?;
+        kv.put(&filtered_key, "")?.execute().await?;
+        return Ok(());
+    }
+    kv.delete(&key).await?;
+    let other = match filter {
+        Filter::Block => Filter::Mute,
+        Filter::Mute => Filter::Block,
+    };
+    let other_key = format!("{}/{}/{}", other.prefix(), username, author);
+    if kv.get(&other_key).await?.is_none() {
+        kv.delete(&filtered_key).await?;
+    }
+    Ok(())
+}
+
+async fn filter(req: Request, ctx: RouteContext<Session>, filter: Filter) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let author = error::param(&ctx, "username")?;
+    if author == username {
+        return Err(ApiError::BadRequest(format!(
+            "You can't {} yourself",
+            filter.verb()
+        )));
+    }
+    if !users::exists(&ctx.kv(users::USERS_KV)?, &author).await? {
+        return Err(ApiError::NotFound);
+    }
+    let on = req.method() == Method::Post;
+    let kv = ctx.kv(FOLLOWS_KV)?;
+    set_filter(&kv, &username, &author, filter, on).await?;
+    if on && filter == Filter::Block {
+        for (follower, followed) in [(&username, &author), (&author, &username)] {
+            set(&kv, follower, followed, false).await?;
+            let mutation = journal::Mutation::UserUnfollowed {
+                follower: follower.clone(),
+                username: followed.clone(),
+            };
+            journal::record(&ctx, mutation).await;
+        }
+        feeds::invalidate(&ctx, &author).await;
+    }
+    // A materialized feed may hold posts from before; the rebuild leaves them out.
+    feeds::invalidate(&ctx, &username).await;
+    Ok(Response::from_json(
+        &json!({ "username": author, filter.prefix(): on }),
+    )?)
+}
+
+/// `POST /users/:username/block` blocks, `DELETE` unblocks.
+pub async fn block(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    filter(req, ctx, Filter::Block).await
+}
+
+/// `POST /users/:username/mute` mutes, `DELETE` unmutes.
+pub async fn mute(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    filter(req, ctx, Filter::Mute).await
+}
+
+/// `GET /me/filters`: who the signed-in user blocks and mutes.
+pub async fn mine(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(FOLLOWS_KV)?;
+    let blocking = names(&kv, format!("blocking/{}/", username)).await?;
+    let muting = names(&kv, format!("muting/{}/", username)).await?;
+    Ok(Response::from_json(
+        &json!({ "blocking": blocking, "muting": muting }),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_those_followed_back() {
+        let other: HashSet<String> = ["bob".to_string()].into_iter().collect();
+        let flagged = flag_mutuals(vec!["alice".to_string(), "bob".to_string()], &other);
+        assert_eq!(
+            flagged,
+            [
+                json!({ "username": "alice", "mutual": false }),
+                json!({ "username": "bob", "mutual": true }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Entries past this many are dropped, expired ones first, so a busy isolate can't grow the
+/// cache without bound.
+const MAX_ENTRIES: usize = 10_000;
+
+thread_local! {
+    /// Key → (expiry in milliseconds since the epoch, value).
+    static ENTRIES: RefCell<HashMap<String, (i64, Value)>> = RefCell::new(HashMap::new());
+}
+
+/// A cache that lives as long as the isolate, for KV values read on most requests.
+///
+/// Every isolate has its own copy and nothing tells it about writes made elsewhere, so a value
+/// may be up to its TTL out of date. Only cache what is fine to be that stale, and have the
+/// route that changes it call [`forget`] so at least its own isolate sees the change at once.
+pub fn get<T: DeserializeOwned>(key: &str) -> Option<T> {
+    let now = Utc::now().timestamp_millis();
+    ENTRIES.with(|entries| {
+        let entries = entries.borrow();
+        match entries.get(key) {
+            Some((expires_at, value)) if *expires_at > now => {
+                serde_json::from_value(value.clone()).ok()
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Keeps `value` under `key` for `ttl_ms` milliseconds.
+pub fn put<T: Serialize>(key: &str, value: &T, ttl_ms: i64) {
+    let value = match serde_json::to_value(value) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let now = Utc::now().timestamp_millis();
+    ENTRIES.with(|entries| {
+        let mut entries = entries.borrow_mut();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (expires_at, _)| *expires_at > now);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(key.to_string(), (now + ttl_ms, value));
+    });
+}
+
+pub fn forget(key: &str) {
+    ENTRIES.with(|entries| {
+        entries.borrow_mut().remove(key);
+    });
+}
@@ -0,0 +1,110 @@
+use chrono::Utc;
+use js_sys::Reflect;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use wasm_bindgen::prelude::*;
+use worker::*;
+
+use crate::tenants;
+use crate::trace::Trace;
+
+mod backup;
+mod likes;
+mod purge;
+mod trending;
+
+/// The cron triggers in `wrangler.toml`. Trending tags are recomputed often; the jobs that walk
+/// every post run once a day, when traffic is lowest. The backup runs hourly, so the snapshot
+/// `mirror` falls back on is never more than an hour old.
+const EVERY_TEN_MINUTES: &str = "*/10 * * * *";
+const HOURLY: &str = "30 * * * *";
+const DAILY: &str = "0 4 * * *";
+
+/// One line per job run, written to the console as JSON next to `events::RequestEvent`:
+///
+/// ```json
+/// {"event": "job", "job": "purge", "cron": "0 4 * * *", "trace_id": "4bf9...",
+///  "outcome": "ok", "duration_ms": 840, "result": {"purged": 3}}
+/// ```
+///
+/// `result` is what the job did, or `{"error": "..."}` when `outcome` is `error`. Jobs run for
+/// each tenant too (see `tenants`), whose runs carry `"tenant": "<id>"`.
+#[derive(Serialize, Debug)]
+struct JobEvent<'a> {
+    event: &'static str,
+    job: &'static str,
+    cron: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    trace_id: &'a str,
+    outcome: &'static str,
+    duration_ms: i64,
+    result: Value,
+}
+
+/// Runs one job and logs its [`JobEvent`]. A failing job is logged, never returned, so the jobs
+/// after it still run.
+async fn run(
+    job: &'static str,
+    cron: &str,
+    tenant: Option<&str>,
+    trace: &Trace,
+    work: impl Future<Output = Result<Value>>,
+) {
+    let started_at = Utc::now().timestamp_millis();
+    let (outcome, result) = match work.await {
+        Ok(result) => ("ok", result),
+        Err(e) => ("error", json!({ "error": e.to_string() })),
+    };
+    let event = JobEvent {
+        event: "job",
+        job,
+        cron,
+        tenant,
+        trace_id: trace.id(),
+        outcome,
+        duration_ms: Utc::now().timestamp_millis() - started_at,
+        result,
+    };
+    match serde_json::to_string(&event) {
+        Ok(line) => console_log!("{}", line),
+        Err(e) => console_log!("job event failed: {}", e),
+    }
+}
+
+/// The worker's `scheduled` handler, run by the cron triggers. `#[event(scheduled)]` expands to
+/// a `worker::Schedule` that `worker` 0.0.7 doesn't have, so the handler is exported directly
+/// and reads the cron off the runtime's event.
+#[wasm_bindgen]
+pub async fn scheduled(event: JsValue, env: Env) {
+    let cron = Reflect::get(&event, &JsValue::from("cron"))
+        .ok()
+        .and_then(|cron| cron.as_string())
+        .unwrap_or_default();
+    let trace = Trace::detached();
+    let envs = match tenants::every_env(&env).await {
+        Ok(envs) => envs,
+        Err(e) => {
+            console_log!(
+                "listing tenants failed, running jobs for the instance only: {}",
+                e
+            );
+            vec![env]
+        }
+    };
+    for env in &envs {
+        let tenant = tenants::id(env);
+        let tenant = tenant.as_deref();
+        match cron.as_str() {
+            EVERY_TEN_MINUTES => run("trending", &cron, tenant, &trace, trending::run(env)).await,
+            HOURLY => run("backup", &cron, tenant, &trace, backup::run(env, &trace)).await,
+            DAILY => {
+                run("purge", &cron, tenant, &trace, purge::run(env, &trace)).await;
+                // After the purge, so the counters of the posts it removed go the same day.
+                run("likes", &cron, tenant, &trace, likes::run(env)).await;
+            }
+            _ => console_log!("no jobs run on the cron {:?}", cron),
+        }
+    }
+}
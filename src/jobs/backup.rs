@@ -0,0 +1,11 @@
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::mirror;
+use crate::trace::Trace;
+
+/// Snapshots the public posts into R2, for `mirror` to serve when KV can't be.
+pub async fn run(env: &Env, trace: &Trace) -> Result<Value> {
+    let posts = mirror::snapshot(env, trace).await?;
+    Ok(json!({ "posts": posts }))
+}
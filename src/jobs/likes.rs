@@ -0,0 +1,11 @@
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::likes;
+
+/// Clears the like counters of posts removed for good, whose Durable Object storage would
+/// otherwise be kept forever.
+pub async fn run(env: &Env) -> Result<Value> {
+    let cleared = likes::compact(env).await?;
+    Ok(json!({ "counters_cleared": cleared }))
+}
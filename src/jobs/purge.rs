@@ -0,0 +1,17 @@
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::trace::Trace;
+use crate::{likes, posts, retention, storage};
+
+/// Removes for good the deleted posts past their restore window, as `POST /bot/purge` does.
+pub async fn run(env: &Env, trace: &Trace) -> Result<Value> {
+    let purged = retention::purge_expired(
+        &*storage::posts_in(env)?,
+        &env.kv(likes::LIKES_KV)?,
+        posts::restore_window_in(env),
+        trace,
+    )
+    .await?;
+    Ok(json!({ "purged": purged }))
+}
@@ -0,0 +1,10 @@
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::tags;
+
+/// Recomputes the trending tags `GET /tags/trending` answers with.
+pub async fn run(env: &Env) -> Result<Value> {
+    let trending = tags::recompute(&env.kv(tags::TAGS_KV)?).await?;
+    Ok(json!({ "tags": trending }))
+}
@@ -0,0 +1,205 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{follows, posts, search, storage, utils};
+
+/// Keys in the `journal` namespace:
+///
+/// - `entry/<yyyy-mm-ddThh>/<millis, zero-padded>-<hash of the entry>`: one [`Entry`], in a
+///   segment per UTC hour, so keys list in the order the mutations happened
+///
+/// Entries are only ever added, one key each, so writers never contend for a key.
+pub const JOURNAL_KV: &str = "journal";
+
+/// Entries `POST /admin/journal/replay` applies per call, well within a worker's subrequests.
+const REPLAY_BATCH: u64 = 100;
+
+/// A change to the instance's data, as recorded in the journal.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mutation {
+    /// A post was stored: made, edited, liked, moderated or soft-deleted. `post` is all of it as
+    /// stored, so replaying the last of these rebuilds the post.
+    PostWritten {
+        id: String,
+        post: Value,
+    },
+    /// A post was removed from the store for good.
+    PostDeleted {
+        id: String,
+    },
+    PostLiked {
+        id: String,
+        username: String,
+        likes: i64,
+    },
+    PostUnliked {
+        id: String,
+        username: String,
+        likes: i64,
+    },
+    UserFollowed {
+        follower: String,
+        username: String,
+    },
+    UserUnfollowed {
+        follower: String,
+        username: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    at: String,
+    #[serde(flatten)]
+    mutation: Mutation,
+}
+
+async fn append(kv: &kv::KvStore, mutation: Mutation) -> Result<()> {
+    let now = Utc::now();
+    let entry = Entry {
+        at: now.to_rfc3339(),
+        mutation,
+    };
+    let text = serde_json::to_string(&entry)?;
+    let key = format!(
+        "entry/{}/{:013}-{}",
+        now.format("%Y-%m-%dT%H"),
+        now.timestamp_millis(),
+        &utils::sha256_hex(&text)[..16]
+    );
+    kv.put(&key, text)?.execute().await?;
+    Ok(())
+}
+
+/// Appends `mutation` to the journal in `kv`. A failure is logged rather than returned: the
+/// mutation it records already happened.
+pub async fn record_in(kv: &kv::KvStore, mutation: Mutation) {
+    if let Err(e) = append(kv, mutation).await {
+        console_log!("journal entry failed: {}", e);
+    }
+}
+
+/// [`record_in`] the worker's journal.
+pub async fn record(ctx: &RouteContext<Session>, mutation: Mutation) {
+    match ctx.kv(JOURNAL_KV) {
+        Ok(kv) => record_in(&kv, mutation).await,
+        Err(e) => console_log!("journal entry failed: {}", e),
+    }
+}
+
+/// Where a replay applies entries, and which entries it applies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    /// Posts, into the KV store.
+    Kv,
+    /// Posts, into D1, e.g. to fill it before `POSTS_STORAGE` is switched over.
+    D1,
+    /// Follows, into the `follows` namespace.
+    Follows,
+    /// Public posts, into the search index.
+    Search,
+}
+
+impl Target {
+    fn parse(name: &str) -> Option<Target> {
+        match name {
+            "kv" => Some(Target::Kv),
+            "d1" => Some(Target::D1),
+            "follows" => Some(Target::Follows),
+            "search" => Some(Target::Search),
+            _ => None,
+        }
+    }
+}
+
+/// `POST /admin/journal/replay?target=<kv|d1|follows|search>[&cursor=...]`, for admins:
+/// applies the next entries of the journal to `target` and answers `{"replayed": 80,
+/// "skipped": 20, "cursor": "..."}`. Call it again with the `cursor` until it comes back
+/// `null`. Every entry applies the same however often it is replayed, so a replay that failed
+/// halfway can be started over. Replays write around the journal, so they don't add to it.
+pub async fn replay(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let url = req.url()?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let target = match query("target").as_deref().and_then(Target::parse) {
+        Some(target) => target,
+        None => {
+            return Err(ApiError::BadRequest(
+                "`target` must be one of kv, d1, follows or search".to_string(),
+            ))
+        }
+    };
+    let backend = match target {
+        Target::Kv => Some("kv"),
+        Target::D1 => Some("d1"),
+        Target::Follows | Target::Search => None,
+    };
+    let store = backend
+        .map(|backend| storage::unjournaled(Some(backend.to_string()), ctx.data().bindings()))
+        .transpose()?;
+    let follows_kv = ctx.kv(follows::FOLLOWS_KV)?;
+
+    let kv = ctx.kv(JOURNAL_KV)?;
+    let mut list = kv.list().prefix("entry/".to_string()).limit(REPLAY_BATCH);
+    if let Some(cursor) = query("cursor") {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+    let (mut replayed, mut skipped) = (0, 0);
+    for key in &page.keys {
+        let entry = match kv.get(&key.name).await? {
+            Some(v) => match v.as_json::<Entry>() {
+                Ok(entry) => entry,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            },
+            None => continue,
+        };
+        match (target, entry.mutation, &store) {
+            (Target::Kv | Target::D1, Mutation::PostWritten { id, post }, Some(store)) => {
+                store.put(&id, &post).await?
+            }
+            (Target::Kv | Target::D1, Mutation::PostDeleted { id }, Some(store)) => {
+                store.delete(&id).await?
+            }
+            (Target::Follows, Mutation::UserFollowed { follower, username }, _) => {
+                follows::set(&follows_kv, &follower, &username, true).await?
+            }
+            (Target::Follows, Mutation::UserUnfollowed { follower, username }, _) => {
+                follows::set(&follows_kv, &follower, &username, false).await?
+            }
+            (Target::Search, Mutation::PostWritten { id, post }, _)
+                if !posts::is_deleted(&post)
+                    && !posts::is_moderated(&post)
+                    && !posts::is_archived(&post) =>
+            {
+                search::index(&ctx, &id, &post).await?
+            }
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        }
+        replayed += 1;
+    }
+    let cursor = if page.list_complete {
+        None
+    } else {
+        page.cursor
+    };
+    Ok(Response::from_json(&json!({
+        "replayed": replayed,
+        "skipped": skipped,
+        "cursor": cursor,
+    }))?)
+}
@@ -1,16 +1,137 @@
 use chrono::{Datelike, Timelike, Utc};
 use serde::*;
 use serde_json::{json, Value};
-use std::fmt;
 use worker::*;
 
+mod activity;
+mod admin;
+mod apikeys;
+mod atproto;
+mod auth;
+mod automod;
+mod bodies;
+mod bots;
+mod branding;
+mod bulk;
+mod cache;
+mod comments;
+mod communities;
+mod digest;
+mod domains;
+mod drafts;
+mod error;
+mod events;
+mod feeds;
+mod firehose;
+mod follows;
+mod isolate;
+mod jobs;
+mod journal;
+mod likes;
+mod live;
+mod mastodon;
+mod math;
+mod media;
+mod mirror;
+mod mod_notes;
+mod models;
+mod moderation;
+mod notifications;
+mod openapi;
+mod outbound;
+mod posts;
+mod profile_views;
+mod queues;
+mod referrals;
+mod render;
+mod replica;
+mod reports;
+mod retention;
+mod rss;
+mod search;
+mod searches;
+mod seen;
+mod session;
+mod settings;
+mod signups;
+mod stats;
+mod storage;
+mod surveys;
+mod tags;
+mod templates;
+mod tenants;
+mod threads;
+mod trace;
+mod triggers;
+mod users;
 mod utils;
+mod validation;
+mod webhooks;
+mod withholding;
 
-fn log_request(req: &Request) {
+use error::{api, ApiError};
+
+#[derive(Deserialize, Debug)]
+struct ArchiveToggle {
+    archived: bool,
+}
+
+/// Who `POST /posts` lets post under a username.
+#[derive(Debug, PartialEq)]
+enum Poster {
+    /// Whoever holds the API key the request carries, which is checked against the name next.
+    ApiKey,
+    /// The user signed in under that name.
+    SignedIn,
+    /// Nobody has the name yet; it is registered to whoever posts under it.
+    Newcomer,
+}
+
+/// Decides who may post as `username`: existing users have to prove who they are, with a
+/// session that is theirs (`session_user`) or a `post` API key. A brand new username is
+/// registered on its first post.
+fn poster(
+    has_api_key: bool,
+    username_taken: bool,
+    session_user: Option<&str>,
+    username: &str,
+) -> error::ApiResult<Poster> {
+    if has_api_key {
+        Ok(Poster::ApiKey)
+    } else if !username_taken {
+        Ok(Poster::Newcomer)
+    } else if session_user == Some(username) {
+        Ok(Poster::SignedIn)
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
+/// CORS headers sent with every response, so browsers can call any route from the frontend.
+fn set_cors_headers(headers: &mut Headers) -> Result<()> {
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set(
+        "Access-Control-Allow-Methods",
+        "GET,HEAD,POST,PUT,DELETE,OPTIONS",
+    )?;
+    headers.set(
+        "Access-Control-Allow-Headers",
+        "Content-Type, Authorization, X-Api-Key, traceparent, tracestate",
+    )?;
+    // Lets the frontend's own timing code read `Server-Timing` too, not just devtools.
+    headers.set("Timing-Allow-Origin", "*")?;
+    headers.set("Access-Control-Expose-Headers", "X-Max-Staleness, ETag")?;
+    Ok(())
+}
+
+/// Workers keep console output with the request that wrote it, so the trace ID logged here ties
+/// every later line of the request to its trace.
+fn log_request(req: &Request, trace: &trace::Trace) {
     console_log!(
-        "{} - [{}], located at: {:?}, within: {}",
+        "{} - [{}], trace: {}, located at: {:?}, within: {}",
         Date::now().to_string(),
         req.path(),
+        trace.id(),
         req.cf().coordinates().unwrap_or_default(),
         req.cf().region().unwrap_or("unknown region".into())
     );
@@ -18,32 +139,71 @@ fn log_request(req: &Request) {
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env) -> Result<Response> {
-    log_request(&req);
+    let trace = trace::Trace::of(&req);
+    let mut event = events::RequestEvent::start(&req, &trace);
+    log_request(&req, &trace);
 
     // Optionally, get more helpful error messages written to the console in the case of a panic.
     utils::set_panic_hook();
 
     // Optionally, use the Router to handle matching endpoints, use ":name" placeholders, or "*name"
-    // catch-alls to match on specific patterns. Alternatively, use `Router::with_data(D)` to
-    // provide arbitrary data that will be accessible in each route via the `ctx.data()` method.
-    let router = Router::new();
-
-    #[derive(Serialize, Deserialize, Debug)]
-    struct Post {
-        title: String,
-        username: String,
-        content: String,
+    // catch-alls to match on specific patterns. Every route gets the request's checked session
+    // as its data, through `session::authed` and `session::current_user`; a route acting for a
+    // user takes them from there, never from the body or query.
+    let method = req.method();
+    let path = req.path();
+    // A host provisioned as a tenant is served from its own slice of every namespace, with its
+    // own config; see `tenants`. A community's or profile's own domain is served from the
+    // instance it belongs to, with reads routed to it; see `domains`.
+    let resolved = async {
+        let domain = domains::of(&req, &env).await?;
+        let tenant = match &domain {
+            Some(domain) => match &domain.tenant {
+                Some(id) => tenants::get(&env, id).await?,
+                None => None,
+            },
+            None => tenants::of(&req, &env).await?,
+        };
+        Ok::<_, Error>((domain, tenant))
+    };
+    let (domain, tenant) = match resolved.await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            let res = ApiError::from(e).into_response()?;
+            return finish(res, &method, &path, &trace, event, None).await;
+        }
+    };
+    let req = match &domain {
+        Some(domain) => domains::rewrite(req, domain)?,
+        None => req,
+    };
+    let path = req.path();
+    let env = match &tenant {
+        Some(tenant) => tenants::scoped(&env, tenant)?,
+        None => env,
+    };
+    let frontend = tenant.and_then(|tenant| tenant.frontend_url);
+    let session = session::Session::of(&req, &env, trace.clone()).await;
+    event.identify(&env, &session);
+    if let Err(e) = admin::gate(&path, &env, &session).and_then(|_| bodies::gate(&req, &path, &env))
+    {
+        let res = e.into_response()?;
+        return finish(res, &method, &path, &trace, event, frontend.as_deref()).await;
     }
-
-    impl fmt::Display for Post {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "{{ \"title\": {}, \"username\": {}, \"content\": {} }}",
-                self.title, self.username, self.content
-            )
+    // While a maintenance flag is set, writes are refused and the feeds may be served from a
+    // snapshot instead; see `mirror`.
+    match mirror::intercept(&req, &path, &env).await {
+        Ok(None) => {}
+        Ok(Some(res)) => {
+            return finish(res, &method, &path, &trace, event, frontend.as_deref()).await;
+        }
+        Err(e) => {
+            let res = e.into_response()?;
+            return finish(res, &method, &path, &trace, event, frontend.as_deref()).await;
         }
     }
+    let router = Router::with_data(session);
+
     struct Wrapper<Value>(Vec<Value>);
     impl From<Vec<Value>> for Wrapper<Value> {
         fn from(v: Vec<Value>) -> Self {
@@ -69,153 +229,647 @@ pub async fn main(req: Request, env: Env) -> Result<Response> {
     // Add as many routes as your Worker needs! Each route will get a `Request` for handling HTTP
     // functionality and a `RouteContext` which you can use to  and get route parameters and
     // Environment bindings like KV Stores, Durable Objects, Secrets, and Variables.
-    router
+    // New routes also go in `events::ROUTES`, so their request events are grouped by pattern.
+    let res = router
         .get("/", |_, _| Response::ok("Hello from Workers!"))
-        .post_async("/form/:field", |mut req, ctx| async move {
-            if let Some(name) = ctx.param("field") {
-                let form = req.form_data().await?;
-                match form.get(name) {
-                    Some(FormEntry::Field(value)) => {
-                        return Response::from_json(&json!({ name: value }))
+        .post_async("/form/:field", |mut req, ctx| {
+            api(async move {
+                if let Some(name) = ctx.param("field") {
+                    let form = req.form_data().await?;
+                    match form.get(name) {
+                        Some(FormEntry::Field(value)) => {
+                            return Ok(Response::from_json(&json!({ name: value }))?)
+                        }
+                        Some(FormEntry::File(_)) => {
+                            return Err(ApiError::Unprocessable(
+                                "`field` param in form shouldn't be a File".to_string(),
+                            ));
+                        }
+                        None => return Err(ApiError::BadRequest("Bad Request".to_string())),
                     }
-                    Some(FormEntry::File(_)) => {
-                        return Response::error("`field` param in form shouldn't be a File", 422);
-                    }
-                    None => return Response::error("Bad Request", 400),
                 }
-            }
 
-            Response::error("Bad Request", 400)
+                Err(ApiError::BadRequest("Bad Request".to_string()))
+            })
         })
         .get("/worker-version", |_, ctx| {
             let version = ctx.var("WORKERS_RS_VERSION")?.to_string();
             Response::ok(version)
         })
-        .get_async("/posts", |mut req, ctx| async move {
-            let kv = ctx.kv("my-app-general_posts_preview")?;
-            let keys = kv.list().execute().await?.keys;
-            let mut posts: Vec<Value> = vec![];
-            for key in keys {
-                let mut value = kv.get(&key.name).await.unwrap().unwrap().as_string();
-                let j = json!(value);
-                posts.push(j);
-            }
-            console_log!("{:#?}", posts);
-            let mut res = Response::from_json(&posts)?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Headers::set(headers, "transfer-encoding", "chunked")?;
-            Headers::set(headers, "vary", "Accept-Encoding")?;
-            Headers::set(headers, "connection", "keep-alive")?;
-            Ok(res)
-        })
-        .post_async("/posts", |mut req, ctx| async move {
-            let mut new_post: Value = req.json::<serde_json::Value>().await?;
-            let now = Utc::now().to_rfc3339().to_string();
-            if let Some(new_post_obj) = new_post.as_object_mut() {
-                new_post_obj.insert("time".to_string(), serde_json::Value::String(now.clone()));
-            }
-            let name_not_found = "name not found".to_string();
-            let mut new_post_name = match new_post.get("username") {
-                Some(n) => n.to_string(),
-                None => name_not_found.to_string(),
-            };
-            let new_post_string = new_post.to_string();
-            let kv = ctx.kv("my-app-general_posts_preview")?;
-            new_post_name.pop();
-            new_post_name.remove(0);
-            let kv_value = match kv.get(&new_post_name).await? {
-                Some(n) => n.as_string(),
-                None => name_not_found.to_string(),
-            };
-            kv.put(&(now + "-" + &new_post_name), &new_post_string)?
-                .execute()
-                .await?;
-
-            let mut res = Response::ok(format!("{}", new_post))?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Headers::set(
-                headers,
-                "Access-Control-Allow-Methods",
-                "GET,HEAD,POST,OPTIONS",
-            )?;
-            Headers::set(headers, "Access-Control-Allow-Headers", "Content-Type")?;
-            Headers::set(headers, "Allow", "GET,HEAD,POST,OPTIONS")?;
-            Ok(res)
-        })
-        .options_async("/posts", |_, _| async {
-            let mut res = Response::ok("success")?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Ok(res)
-        })
-        .post_async("/updatelikes", |mut req, ctx| async move {
-            // get value <username>-<time>
-            let mut new_post: Value = req.json::<serde_json::Value>().await?;
-            let kv = ctx.kv("my-app-general_posts_preview")?;
-            let new_post_obj = new_post.as_object_mut().unwrap();
-            let mut username = new_post_obj.get("username").unwrap().to_string();
-            username.pop();
-            username.remove(0);
-            let mut time = new_post_obj.get("time").unwrap().to_string();
-            time.pop();
-            time.remove(0);
-            let key = time + "-" + &username;
-            kv.delete(&key).await?;
-            let new_post_string = new_post.to_string();
-            kv.put(&key, new_post_string)?.execute().await?;
-            let mut res = Response::ok(format!("{}", new_post))?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Headers::set(
-                headers,
-                "Access-Control-Allow-Methods",
-                "GET,HEAD,POST,OPTIONS",
-            )?;
-            Headers::set(headers, "Access-Control-Allow-Headers", "Content-Type")?;
-            Headers::set(headers, "Allow", "GET,HEAD,POST,OPTIONS")?;
-            Ok(res)
-        })
-        .get_async("/users", |_, ctx| async move {
-            let kv = ctx.kv("users")?;
-            let keys = kv.list().execute().await?.keys;
-            let mut users = vec![];
-            for key in keys {
-                // let mut value = kv.get(&key.name).await.unwrap().unwrap().as_string();
-                // let j = json!(value);
-                users.push(key.name);
-            }
-            console_log!("{:#?}", users);
-            let mut res = Response::from_json(&users)?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Headers::set(headers, "transfer-encoding", "chunked")?;
-            Headers::set(headers, "vary", "Accept-Encoding")?;
-            Headers::set(headers, "connection", "keep-alive")?;
-            Ok(res)
-        })
-        .post_async("/users", |mut req, ctx| async move {
-            let mut new_user: Value = req.json::<serde_json::Value>().await?;
-            let now = Utc::now().to_rfc3339().to_string();
-            let new_user_obj = new_user.as_object_mut().unwrap();
-            let mut username = new_user_obj.get("username").unwrap().to_string();
-            username.pop();
-            username.remove(0);
-            let kv = ctx.kv("users")?;
-            kv.put(&username, &now)?.execute().await?;
-            let mut res = Response::ok(format!("{}", new_user))?;
-            let headers = Response::headers_mut(&mut res);
-            Headers::set(headers, "Access-Control-Allow-Origin", "*")?;
-            Headers::set(
-                headers,
-                "Access-Control-Allow-Methods",
-                "GET,HEAD,POST,OPTIONS",
-            )?;
-            Headers::set(headers, "Access-Control-Allow-Headers", "Content-Type")?;
-            Headers::set(headers, "Allow", "GET,HEAD,POST,OPTIONS")?;
-            Ok(res)
+        .get_async("/posts", |req, ctx| {
+            api(async move {
+                // Clients written against the old shape expect every post as a JSON-encoded string.
+                let url = req.url()?;
+                let legacy = url.query_pairs().any(|(k, v)| k == "legacy" && v == "true");
+                // `?v=2` answers a `models::PostList`; without it the bare array is kept.
+                let version = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "v")
+                    .map(|(_, v)| v.into_owned());
+                let enveloped = match version.as_deref() {
+                    None | Some("1") => false,
+                    Some("2") if !legacy => true,
+                    Some("2") => {
+                        return Err(ApiError::BadRequest(
+                            "`legacy` posts only come as a bare array".to_string(),
+                        ))
+                    }
+                    Some(_) => return Err(ApiError::BadRequest("`v` must be 1 or 2".to_string())),
+                };
+                // `?license=`, see `posts::License::matches`.
+                let license = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "license")
+                    .map(|(_, v)| v.into_owned());
+                // Each colo keeps the list it built for a few seconds; writes to posts drop it.
+                let (cache_key, cached) = cache::cached_posts(&req).await;
+                if let Some(json) = cached {
+                    return Ok(cache::tagged_json(&req, json)?);
+                }
+                let store = storage::posts(&ctx)?;
+                // Posts that can't be read are served from the latest snapshot, if there is one.
+                let listed = match posts::list_public(&*store, ctx.data().trace()).await {
+                    Ok(listed) => listed,
+                    Err(e) => return mirror::fallback(&req, ctx.data().bindings(), e.into()).await,
+                };
+                let mut kept = vec![];
+                for post in communities::without_quarantined(&ctx, listed).await? {
+                    let keep = license
+                        .as_deref()
+                        .is_none_or(|wanted| posts::License::of(&post).matches(wanted));
+                    if keep {
+                        kept.push(post);
+                    }
+                }
+                withholding::Withheld::for_request(&req, &ctx)
+                    .await?
+                    .apply_to_posts(&mut kept);
+                // Posts stored before ids existed are keyed by `<time>-<username>`.
+                let ids: Vec<String> = kept
+                    .iter()
+                    .map(|post| match post.extra.get("id").and_then(Value::as_str) {
+                        Some(id) => id.to_string(),
+                        None => format!(
+                            "{}-{}",
+                            post.time.as_deref().unwrap_or_default(),
+                            post.username
+                        ),
+                    })
+                    .collect();
+                let comment_counts = comments::counts(&ctx, &ids).await?;
+                let mut posts: Vec<Value> = vec![];
+                for (mut post, comment_count) in kept.into_iter().zip(comment_counts) {
+                    post.extra
+                        .insert("comment_count".to_string(), json!(comment_count));
+                    if legacy {
+                        posts.push(json!(serde_json::to_string(&post)?));
+                    } else {
+                        posts.push(serde_json::to_value(&post)?);
+                    }
+                }
+                let json = if enveloped {
+                    serde_json::to_string(&models::PostList::new(&posts))?
+                } else {
+                    serde_json::to_string(&posts)?
+                };
+                if let Some(key) = cache_key {
+                    cache::store_posts(&key, &json).await;
+                }
+                // Clients poll this; one whose copy is current gets a 304 instead of the list.
+                Ok(cache::tagged_json(&req, json)?)
+            })
+        })
+        .post_async("/posts", |mut req, ctx| {
+            api(async move {
+                let mut new_post = models::from_body::<models::Post>(&mut req).await?;
+                new_post.strip_server_fields();
+                if new_post.touched_honeypot() {
+                    bots::flag(&req, &ctx, bots::Signal::Honeypot).await;
+                }
+                bots::throttle(&req, &ctx).await?;
+                let new_post_name = new_post.username.clone();
+                let mut new_post = serde_json::to_value(&new_post)?;
+                validation::post(&mut new_post)?;
+                // The timestamp and the id are always assigned here; whatever the client's clock
+                // said is ignored.
+                let now = Utc::now().to_rfc3339();
+                let id = utils::ulid()?;
+                if let Some(new_post_obj) = new_post.as_object_mut() {
+                    new_post_obj.insert("time".to_string(), Value::String(now));
+                    new_post_obj.insert("id".to_string(), Value::String(id.clone()));
+                }
+                posts::prepare(&ctx, &mut new_post).await?;
+                // A brand new username is handed a session for its next post.
+                let users = ctx.kv(users::USERS_KV)?;
+                let mut set_cookie = None;
+                let has_api_key = req.headers().get(apikeys::HEADER)?.is_some();
+                // The key's owner is checked below, so the name's owner only matters without one.
+                let username_taken = !has_api_key && users::exists(&users, &new_post_name).await?;
+                let session_user = session::current_user(&ctx);
+                let poster = poster(
+                    has_api_key,
+                    username_taken,
+                    session_user.as_deref(),
+                    &new_post_name,
+                )?;
+                if poster == Poster::ApiKey {
+                    let api_key = match apikeys::authorize(&req, &ctx, apikeys::Scope::Post).await?
+                    {
+                        Some(api_key) if api_key.owner == new_post_name => api_key,
+                        _ => return Err(ApiError::Unauthorized),
+                    };
+                    let community = new_post.get("community").and_then(Value::as_str);
+                    if !api_key.allows_community(community) {
+                        return Err(ApiError::Forbidden(
+                            "This key can't post into that community".to_string(),
+                        ));
+                    }
+                } else if poster == Poster::Newcomer {
+                    signups::check(&req, &ctx).await?;
+                    users::register(&users, &new_post_name).await?;
+                    signups::record(&req, &ctx).await;
+                    if let Err(e) = referrals::attribute(&req, &ctx, &new_post_name).await {
+                        console_log!("referral for {} failed: {}", new_post_name, e);
+                    }
+                    let secret = ctx.secret("SESSION_SECRET")?.to_string();
+                    set_cookie = Some(session::mint(&new_post_name, &secret));
+                }
+                posts::insert(&ctx, &id, &mut new_post).await?;
+
+                let mut res = Response::ok(format!("{}", new_post))?;
+                if let Some(cookie) = set_cookie {
+                    res.headers_mut().set("Set-Cookie", &cookie)?;
+                }
+                Ok(res)
+            })
+        })
+        .options_async("/posts", |_, _| async { Response::ok("success") })
+        .post_async("/posts/bulk_delete", |req, ctx| api(bulk::delete(req, ctx)))
+        .get_async("/posts/bulk_delete/:job", |req, ctx| {
+            api(bulk::progress(req, ctx))
+        })
+        .put_async("/posts/:id/archive", |mut req, ctx| {
+            api(async move {
+                let username = session::authed(&ctx)?.username;
+                let id = error::param(&ctx, "id")?;
+                let body = models::from_body::<ArchiveToggle>(&mut req).await?;
+                let store = storage::posts(&ctx)?;
+                let mut post = posts::load(&*store, &id).await?;
+                if post.get("username").and_then(Value::as_str) != Some(username.as_str()) {
+                    return Err(ApiError::Forbidden("Forbidden".to_string()));
+                }
+                if let Some(post_obj) = post.as_object_mut() {
+                    post_obj.insert("archived".to_string(), Value::Bool(body.archived));
+                }
+                store.put(&id, &post).await?;
+                firehose::post_changed(&ctx, firehose::Kind::Create, &id, &post).await;
+                Ok(Response::from_json(&post)?)
+            })
+        })
+        .get_async("/posts/:id", |req, ctx| api(posts::show(req, ctx)))
+        .put_async("/posts/:id", |req, ctx| api(posts::edit(req, ctx)))
+        .delete_async("/posts/:id", |req, ctx| api(posts::delete(req, ctx)))
+        .post_async("/posts/:id/restore", |req, ctx| {
+            api(posts::restore(req, ctx))
+        })
+        .post_async("/posts/:id/like", |req, ctx| api(likes::like(req, ctx)))
+        .post_async("/posts/:id/unlike", |req, ctx| api(likes::unlike(req, ctx)))
+        .get_async("/posts/:id/comments", |req, ctx| {
+            api(comments::list(req, ctx))
+        })
+        .post_async("/posts/:id/comments", |req, ctx| {
+            api(comments::create(req, ctx))
+        })
+        .delete_async("/comments/:id", |req, ctx| api(comments::delete(req, ctx)))
+        .post_async("/comments/:id/like", |req, ctx| {
+            api(comments::like(req, ctx))
+        })
+        .post_async("/comments/:id/unlike", |req, ctx| {
+            api(comments::unlike(req, ctx))
+        })
+        .post_async("/posts/:id/co_authors/:action", |req, ctx| {
+            api(posts::respond_to_invite(req, ctx))
+        })
+        .post_async("/posts/:id/crosspost", |req, ctx| {
+            api(posts::crosspost(req, ctx))
+        })
+        .post_async("/posts/:id/moderation", |req, ctx| {
+            api(moderation::decide(req, ctx))
+        })
+        .post_async("/posts/:id/report", |req, ctx| {
+            api(reports::report(req, ctx))
+        })
+        .get_async("/posts/:id/share_link", |req, ctx| {
+            api(referrals::share_link(req, ctx))
+        })
+        .get_async("/me/moderation", |req, ctx| api(moderation::mine(req, ctx)))
+        .get_async("/me/referrals", |req, ctx| api(referrals::mine(req, ctx)))
+        .get_async("/me/filters", |req, ctx| api(follows::mine(req, ctx)))
+        .get_async("/me/archived", |req, ctx| api(posts::archived(req, ctx)))
+        .get_async("/me/profile_views", |req, ctx| {
+            api(profile_views::mine(req, ctx))
+        })
+        .post_async("/threads", |req, ctx| api(threads::create(req, ctx)))
+        .get_async("/threads/:id", |req, ctx| api(threads::show(req, ctx)))
+        .post_async("/media", |req, ctx| api(media::upload(req, ctx)))
+        .get_async("/media/:id", |req, ctx| api(media::show(req, ctx)))
+        .get_async("/notifications", |req, ctx| {
+            api(notifications::list(req, ctx))
+        })
+        .post_async("/notifications/read", |req, ctx| {
+            api(notifications::mark_read(req, ctx))
+        })
+        .get_async("/search", |req, ctx| api(search::search(req, ctx)))
+        .get_async("/tags/trending", |req, ctx| api(tags::trending(req, ctx)))
+        .get_async("/tags/:tag", |req, ctx| api(tags::list(req, ctx)))
+        .post_async("/searches", |req, ctx| api(searches::create(req, ctx)))
+        .get_async("/searches", |req, ctx| api(searches::list(req, ctx)))
+        .get_async("/searches/alerts", |req, ctx| {
+            api(searches::alerts(req, ctx))
+        })
+        .delete_async("/searches/:id", |req, ctx| api(searches::delete(req, ctx)))
+        .get_async("/c/:name", |req, ctx| api(communities::show(req, ctx)))
+        .post_async("/c/:name", |req, ctx| api(communities::create(req, ctx)))
+        .post_async("/c/:name/join", |req, ctx| api(communities::join(req, ctx)))
+        .delete_async("/c/:name/join", |req, ctx| api(communities::join(req, ctx)))
+        .put_async("/c/:name/tags", |req, ctx| {
+            api(communities::set_tags(req, ctx))
+        })
+        .get_async("/c/:name/webhooks", |req, ctx| {
+            api(webhooks::list(req, ctx))
+        })
+        .put_async("/c/:name/webhooks", |req, ctx| {
+            api(webhooks::replace(req, ctx))
+        })
+        .get_async("/c/:name/automod", |req, ctx| api(automod::list(req, ctx)))
+        .put_async("/c/:name/automod", |req, ctx| {
+            api(automod::replace(req, ctx))
+        })
+        .get_async("/c/:name/automod/flags", |req, ctx| {
+            api(automod::flags(req, ctx))
+        })
+        .delete_async("/c/:name/automod/flags/:id", |req, ctx| {
+            api(automod::dismiss_flag(req, ctx))
+        })
+        .get_async("/c/:name/users/:username/notes", |req, ctx| {
+            api(mod_notes::list(req, ctx))
+        })
+        .post_async("/c/:name/users/:username/notes", |req, ctx| {
+            api(mod_notes::create(req, ctx))
+        })
+        .delete_async("/c/:name/users/:username/notes/:id", |req, ctx| {
+            api(mod_notes::delete(req, ctx))
+        })
+        .get_async("/c/:name/posts", |req, ctx| {
+            api(communities::posts(req, ctx))
+        })
+        .put_async("/c/:name/quarantine", |req, ctx| {
+            api(communities::set_quarantine(req, ctx))
+        })
+        .delete_async("/c/:name/quarantine", |req, ctx| {
+            api(communities::set_quarantine(req, ctx))
+        })
+        .get_async("/c/:name/templates", |req, ctx| {
+            api(templates::list(req, ctx))
+        })
+        .put_async("/c/:name/templates", |req, ctx| {
+            api(templates::replace(req, ctx))
+        })
+        .get_async("/c/:name/domain", |req, ctx| api(domains::show(req, ctx)))
+        .put_async("/c/:name/domain", |req, ctx| api(domains::claim(req, ctx)))
+        .delete_async("/c/:name/domain", |req, ctx| {
+            api(domains::release(req, ctx))
+        })
+        .post_async("/c/:name/domain/verify", |req, ctx| {
+            api(domains::verify(req, ctx))
+        })
+        .get_async("/communities/discover", |req, ctx| {
+            api(communities::discover(req, ctx))
+        })
+        .get_async("/about/stats", |req, ctx| api(stats::about(req, ctx)))
+        .get_async("/branding", |req, ctx| api(branding::show(req, ctx)))
+        .get_async("/openapi.json", |req, ctx| api(openapi::spec(req, ctx)))
+        .get_async("/docs", |req, ctx| api(openapi::docs(req, ctx)))
+        .get_async("/.well-known/nodeinfo", |req, ctx| {
+            api(stats::nodeinfo_links(req, ctx))
+        })
+        .get_async("/nodeinfo/2.0", |req, ctx| api(stats::nodeinfo(req, ctx)))
+        .get_async("/feed", |req, ctx| api(communities::feed(req, ctx)))
+        .get_async("/feed/global", |req, ctx| api(feeds::global(req, ctx)))
+        .get_async("/feed/for_you", |req, ctx| api(feeds::for_you(req, ctx)))
+        .get_async("/explore", |req, ctx| api(feeds::explore(req, ctx)))
+        .get_async("/ws", |req, ctx| api(live::connect(req, ctx)))
+        .get_async("/events", |req, ctx| api(live::events(req, ctx)))
+        .get_async("/api/v1/accounts/verify_credentials", |req, ctx| {
+            api(mastodon::verify_credentials(req, ctx))
+        })
+        .get_async("/api/v1/timelines/home", |req, ctx| {
+            api(mastodon::home_timeline(req, ctx))
+        })
+        .post_async("/api/v1/statuses", |req, ctx| {
+            api(mastodon::create_status(req, ctx))
+        })
+        .get_async("/api/v1/statuses/:id", |req, ctx| {
+            api(mastodon::show_status(req, ctx))
+        })
+        .get_async("/firehose", |req, ctx| api(firehose::read(req, ctx)))
+        .get_async("/triggers/me", |req, ctx| api(triggers::me(req, ctx)))
+        .get_async("/triggers/new_posts", |req, ctx| {
+            api(triggers::new_posts(req, ctx))
+        })
+        .post_async("/actions/create_post", |req, ctx| {
+            api(triggers::create_post(req, ctx))
         })
+        .post_async("/bot/digest", |req, ctx| api(digest::post(req, ctx)))
+        .post_async("/bot/rss", |req, ctx| api(rss::poll_all(req, ctx)))
+        .post_async("/bot/retention", |req, ctx| api(retention::sweep(req, ctx)))
+        .post_async("/bot/purge", |req, ctx| api(retention::purge(req, ctx)))
+        .post_async("/admin/api_keys", |req, ctx| api(apikeys::issue(req, ctx)))
+        .post_async("/admin/service_accounts", |req, ctx| {
+            api(apikeys::create_service_account(req, ctx))
+        })
+        .get_async("/admin/service_accounts", |req, ctx| {
+            api(apikeys::list_service_accounts(req, ctx))
+        })
+        .post_async("/admin/rss_feeds", |req, ctx| api(rss::add_feed(req, ctx)))
+        .get_async("/admin/rss_feeds", |req, ctx| {
+            api(rss::list_feeds(req, ctx))
+        })
+        .delete_async("/admin/rss_feeds/:id", |req, ctx| {
+            api(rss::remove_feed(req, ctx))
+        })
+        .get_async("/admin/signup_limits", |req, ctx| {
+            api(signups::get_limits(req, ctx))
+        })
+        .put_async("/admin/signup_limits", |req, ctx| {
+            api(signups::put_limits(req, ctx))
+        })
+        .get_async("/admin/branding", |req, ctx| api(branding::show(req, ctx)))
+        .get_async("/admin/maintenance", |req, ctx| api(mirror::show(req, ctx)))
+        .put_async("/admin/maintenance", |req, ctx| api(mirror::set(req, ctx)))
+        .delete_async("/admin/maintenance", |req, ctx| api(mirror::set(req, ctx)))
+        .put_async("/admin/branding", |req, ctx| {
+            api(branding::update(req, ctx))
+        })
+        .post_async("/admin/storage/migrate", |req, ctx| {
+            api(storage::migrate(req, ctx))
+        })
+        .delete_async("/admin/posts/:id", |req, ctx| {
+            api(admin::delete_post(req, ctx))
+        })
+        .post_async("/admin/users/:username/ban", |req, ctx| {
+            api(admin::ban(req, ctx))
+        })
+        .delete_async("/admin/users/:username/ban", |req, ctx| {
+            api(admin::ban(req, ctx))
+        })
+        .get_async("/admin/stats", |req, ctx| api(admin::stats(req, ctx)))
+        .get_async("/admin/reports", |req, ctx| api(reports::queue(req, ctx)))
+        .post_async("/admin/journal/replay", |req, ctx| {
+            api(journal::replay(req, ctx))
+        })
+        .get_async("/admin/tenants", |req, ctx| api(tenants::list(req, ctx)))
+        .put_async("/admin/tenants/:id", |req, ctx| {
+            api(tenants::provision(req, ctx))
+        })
+        .delete_async("/admin/tenants/:id", |req, ctx| {
+            api(tenants::remove(req, ctx))
+        })
+        .get_async("/admin/queue_stats", |req, ctx| {
+            api(queues::stats(req, ctx))
+        })
+        .get_async("/admin/dead_letters", |req, ctx| {
+            api(queues::dead_letters(req, ctx))
+        })
+        .post_async("/admin/dead_letters/:id/replay", |req, ctx| {
+            api(queues::dead_letter(req, ctx))
+        })
+        .delete_async("/admin/dead_letters/:id", |req, ctx| {
+            api(queues::dead_letter(req, ctx))
+        })
+        .get_async("/admin/withholdings", |req, ctx| {
+            api(withholding::list(req, ctx))
+        })
+        .put_async("/admin/withholdings/:id", |req, ctx| {
+            api(withholding::put(req, ctx))
+        })
+        .delete_async("/admin/withholdings/:id", |req, ctx| {
+            api(withholding::delete(req, ctx))
+        })
+        .delete_async("/admin/api_keys/:id", |req, ctx| {
+            api(apikeys::revoke(req, ctx))
+        })
+        .post_async("/admin/surveys", |req, ctx| api(surveys::create(req, ctx)))
+        .get_async("/surveys", |req, ctx| api(surveys::list(req, ctx)))
+        .get_async("/surveys/:id", |req, ctx| api(surveys::show(req, ctx)))
+        .post_async("/surveys/:id/responses", |req, ctx| {
+            api(surveys::respond(req, ctx))
+        })
+        .get_async("/surveys/:id/results", |req, ctx| {
+            api(surveys::results(req, ctx))
+        })
+        .get_async("/settings/languages", |req, ctx| {
+            api(settings::get_languages(req, ctx))
+        })
+        .put_async("/settings/languages", |req, ctx| {
+            api(settings::put_languages(req, ctx))
+        })
+        .get_async("/settings/retention", |req, ctx| {
+            api(settings::get_retention(req, ctx))
+        })
+        .put_async("/settings/retention", |req, ctx| {
+            api(settings::put_retention(req, ctx))
+        })
+        .get_async("/settings/profile_views", |req, ctx| {
+            api(settings::get_profile_views(req, ctx))
+        })
+        .put_async("/settings/profile_views", |req, ctx| {
+            api(settings::put_profile_views(req, ctx))
+        })
+        .put_async("/drafts/:id/autosave", |req, ctx| {
+            api(drafts::autosave(req, ctx))
+        })
+        .get_async("/drafts/:id/revisions", |req, ctx| {
+            api(drafts::revisions(req, ctx))
+        })
+        .get_async("/users", |_, ctx| {
+            api(async move {
+                let kv = ctx.kv(users::USERS_KV)?;
+                let keys = kv.list().execute().await?.keys;
+                let mut users = vec![];
+                for key in keys {
+                    // let mut value = kv.get(&key.name).await.unwrap().unwrap().as_string();
+                    // let j = json!(value);
+                    users.push(key.name);
+                }
+                Ok(Response::from_json(&users)?)
+            })
+        })
+        .get_async("/users/:username", |req, ctx| api(users::show(req, ctx)))
+        .patch_async("/users/:username", |req, ctx| api(users::update(req, ctx)))
+        .post_async("/users/:username/follow", |req, ctx| {
+            api(follows::follow(req, ctx))
+        })
+        .delete_async("/users/:username/follow", |req, ctx| {
+            api(follows::follow(req, ctx))
+        })
+        .post_async("/users/:username/block", |req, ctx| {
+            api(follows::block(req, ctx))
+        })
+        .delete_async("/users/:username/block", |req, ctx| {
+            api(follows::block(req, ctx))
+        })
+        .post_async("/users/:username/mute", |req, ctx| {
+            api(follows::mute(req, ctx))
+        })
+        .delete_async("/users/:username/mute", |req, ctx| {
+            api(follows::mute(req, ctx))
+        })
+        .get_async("/users/:username/followers", |req, ctx| {
+            api(follows::followers(req, ctx))
+        })
+        .get_async("/users/:username/following", |req, ctx| {
+            api(follows::list_following(req, ctx))
+        })
+        .get_async("/users/:username/mutuals", |req, ctx| {
+            api(follows::mutuals(req, ctx))
+        })
+        .get_async("/users/:username/activity", |req, ctx| {
+            api(activity::show(req, ctx))
+        })
+        .get_async("/users/:username/atproto-export", |req, ctx| {
+            api(atproto::export(req, ctx))
+        })
+        .get_async("/users/:username/domain", |req, ctx| {
+            api(domains::show(req, ctx))
+        })
+        .put_async("/users/:username/domain", |req, ctx| {
+            api(domains::claim(req, ctx))
+        })
+        .delete_async("/users/:username/domain", |req, ctx| {
+            api(domains::release(req, ctx))
+        })
+        .post_async("/users/:username/domain/verify", |req, ctx| {
+            api(domains::verify(req, ctx))
+        })
+        .post_async("/users", |mut req, ctx| {
+            api(async move {
+                let new_user = models::from_body::<models::User>(&mut req).await?;
+                let username = new_user.username.clone();
+                let mut problems = validation::Problems::default();
+                problems.check("username", validation::username(&username));
+                problems.finish()?;
+                let kv = ctx.kv(users::USERS_KV)?;
+                // Signing up hands out a session, so an existing name must never be re-registered.
+                if users::exists(&kv, &username).await? {
+                    return Err(ApiError::Conflict("Username is taken".to_string()));
+                }
+                signups::check(&req, &ctx).await?;
+                users::register(&kv, &username).await?;
+                signups::record(&req, &ctx).await;
+                if let Err(e) = referrals::attribute(&req, &ctx, &username).await {
+                    console_log!("referral for {} failed: {}", username, e);
+                }
+                let secret = ctx.secret("SESSION_SECRET")?.to_string();
+                let mut res = Response::ok(serde_json::to_string(&new_user)?)?;
+                res.headers_mut()
+                    .set("Set-Cookie", &session::mint(&username, &secret))?;
+                Ok(res)
+            })
+        })
+        .on_async("/wp-login.php", |req, ctx| api(bots::decoy(req, ctx)))
+        .on_async("/xmlrpc.php", |req, ctx| api(bots::decoy(req, ctx)))
+        .on_async("/wp-admin/post-new.php", |req, ctx| {
+            api(bots::decoy(req, ctx))
+        })
+        .on_async("/.env", |req, ctx| api(bots::decoy(req, ctx)))
+        .on_async("/admin/login", |req, ctx| api(bots::decoy(req, ctx)))
         .run(req, env)
-        .await
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => ApiError::from(e).into_response()?,
+    };
+    finish(res, &method, &path, &trace, event, frontend.as_deref()).await
+}
+
+/// What every response gets on its way out, whether a route or the admin gate answered.
+async fn finish(
+    res: Response,
+    method: &Method,
+    path: &str,
+    trace: &trace::Trace,
+    event: events::RequestEvent,
+    frontend: Option<&str>,
+) -> Result<Response> {
+    let mut res = trace.stamp(res).await?;
+
+    cache::apply(method, path, &mut res)?;
+    res.headers_mut().set("traceparent", &trace.traceparent())?;
+    if let Some(server_timing) = trace.server_timing() {
+        res.headers_mut().set("Server-Timing", &server_timing)?;
+    }
+    if let Some(seconds) = trace.max_staleness() {
+        res.headers_mut()
+            .set("X-Max-Staleness", &seconds.to_string())?;
+    }
+    event.finish(&res, trace);
+    set_cors_headers(res.headers_mut())?;
+    // A tenant with a frontend of its own only lets that origin call it.
+    if let Some(frontend) = frontend {
+        res.headers_mut()
+            .set("Access-Control-Allow-Origin", frontend)?;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    #[test]
+    fn existing_users_need_their_own_session() {
+        let cookie = session::mint("alice", SECRET);
+        let alice = session::verify(&cookie, SECRET);
+        assert_eq!(
+            poster(false, true, alice.as_deref(), "alice").unwrap(),
+            Poster::SignedIn
+        );
+    }
+
+    #[test]
+    fn existing_users_without_a_session_are_unauthorized() {
+        for cookie in ["", "session=", "theme=dark"] {
+            let nobody = session::verify(cookie, SECRET);
+            assert!(matches!(
+                poster(false, true, nobody.as_deref(), "alice"),
+                Err(ApiError::Unauthorized)
+            ));
+        }
+        assert!(matches!(
+            poster(false, true, None, "alice"),
+            Err(ApiError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn someone_elses_session_is_unauthorized() {
+        let bob = session::verify(&session::mint("bob", SECRET), SECRET);
+        assert_eq!(bob.as_deref(), Some("bob"));
+        assert!(matches!(
+            poster(false, true, bob.as_deref(), "alice"),
+            Err(ApiError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn free_names_are_registered_and_api_keys_checked_later() {
+        assert_eq!(
+            poster(false, false, None, "carol").unwrap(),
+            Poster::Newcomer
+        );
+        assert_eq!(poster(true, true, None, "alice").unwrap(), Poster::ApiKey);
+    }
 }
@@ -0,0 +1,440 @@
+use chrono::Utc;
+use js_sys::{Function, Promise, Reflect};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{firehose, journal, live, notifications, posts, session, storage, tenants};
+
+/// Binding of the [`LikeCounter`] namespace; there is one counter per post id.
+const LIKES_DO: &str = "LIKES";
+
+/// Keys in the `likes` namespace:
+///
+/// - `purged/<post id>`: the post was removed for good, and its counter still holds storage
+///   until [`compact`] clears it
+pub const LIKES_KV: &str = "likes";
+
+/// Counters [`compact`] clears per run, well within a worker's subrequests.
+const COMPACT_BATCH: usize = 100;
+
+/// The stored post gets a copy of its count at most this often, in milliseconds, however many
+/// likes arrive in between.
+const FLUSH_INTERVAL_MS: i64 = 5_000;
+
+/// Like counts a post's author is told about when the post reaches them, unless the
+/// `LIKE_MILESTONES` var lists others, comma-separated.
+const DEFAULT_MILESTONES: &[i64] = &[10, 100, 1000];
+
+/// The milestones `LIKE_MILESTONES` lists, or [`DEFAULT_MILESTONES`] when unset or unreadable.
+fn milestones(var: Option<String>) -> Vec<i64> {
+    let listed: Option<Vec<i64>> = var.and_then(|var| {
+        var.split(',')
+            .map(|milestone| milestone.trim().parse().ok())
+            .collect()
+    });
+    match listed {
+        Some(listed) if !listed.is_empty() => listed,
+        _ => DEFAULT_MILESTONES.to_vec(),
+    }
+}
+
+/// The highest of `milestones` a count going from `before` to `after` reached.
+fn crossed(milestones: &[i64], before: i64, after: i64) -> Option<i64> {
+    milestones
+        .iter()
+        .copied()
+        .filter(|&milestone| before < milestone && milestone <= after)
+        .max()
+}
+
+/// What the worker sends a counter.
+#[derive(Serialize, Deserialize, Debug)]
+struct Change {
+    post_id: String,
+    username: String,
+    /// The post's `likes` before it had a counter, which the counter starts from.
+    base: i64,
+    /// The tenant the post belongs to, whose store the counter copies its count to (see
+    /// `tenants`). Absent for the deployment's own instance.
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// What the worker sends a counter once it copied `likes` onto the post.
+#[derive(Serialize, Deserialize, Debug)]
+struct Flushed {
+    likes: i64,
+}
+
+/// What a counter answers with.
+#[derive(Serialize, Deserialize, Debug)]
+struct Counted {
+    likes: i64,
+    liked: bool,
+    /// Whether the worker should copy `likes` onto the post now, and report back through
+    /// `/flushed` once it did. When not, the counter has an alarm set to do it once the flush
+    /// interval is up.
+    flush: bool,
+    /// A milestone (see [`DEFAULT_MILESTONES`]) this like took the post to for the first time.
+    #[serde(default)]
+    milestone: Option<i64>,
+}
+
+/// A post's like count and who it counts, in Durable Object storage:
+///
+/// - `count`: the number of likes.
+/// - `liker/<username>`: present while `username` likes the post.
+/// - `post_id`, `tenant`: the post counted and the tenant it belongs to, for the alarm.
+/// - `flushed`, `flushed_at`: the count last copied onto the stored post, and when. Only set once
+///   the copy was written, so a failed write is retried by the alarm.
+/// - `milestone/<likes>`: the post's author was told it reached `likes`, which they are only
+///   ever told once, however often it drops below and climbs back.
+///
+/// A Durable Object handles one request at a time, so two likes landing together are both
+/// counted, which the read-modify-write of a KV post could not promise. Copying the count onto
+/// the post is coalesced: the first like after a quiet [`FLUSH_INTERVAL_MS`] is copied at once,
+/// and the rest of a burst by a single write when the interval is up, so a viral post costs a
+/// write every few seconds rather than one per like.
+#[durable_object]
+pub struct LikeCounter {
+    state: State,
+    env: Env,
+    /// The runtime's `state`, for the alarm API `worker` 0.0.7 doesn't wrap.
+    raw_state: JsValue,
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+impl LikeCounter {
+    /// Has the runtime call [`LikeCounter::alarm`] at `at`, milliseconds since the epoch. Setting
+    /// it again replaces the one pending.
+    async fn set_alarm(&self, at: i64) -> Result<()> {
+        let storage = Reflect::get(&self.raw_state, &JsValue::from("storage")).map_err(js_error)?;
+        let set_alarm: Function = Reflect::get(&storage, &JsValue::from("setAlarm"))
+            .map_err(js_error)?
+            .unchecked_into();
+        let promise: Promise = set_alarm
+            .call1(&storage, &JsValue::from(at as f64))
+            .map_err(js_error)?
+            .unchecked_into();
+        JsFuture::from(promise).await.map_err(js_error)?;
+        Ok(())
+    }
+
+    /// Copies the count onto the stored post, unless it is there already.
+    async fn flush(&mut self) -> Result<()> {
+        let mut storage = self.state.storage();
+        // Storage reports a missing key as an error; a counter without a count was cleared.
+        let count = match storage.get::<i64>("count").await {
+            Ok(count) => count,
+            Err(_) => return Ok(()),
+        };
+        if storage.get::<i64>("flushed").await.ok() == Some(count) {
+            return Ok(());
+        }
+        let post_id = storage.get::<String>("post_id").await?;
+        let tenant = storage.get::<String>("tenant").await.ok();
+        let env = tenants::scoped_by_id(&self.env, tenant.as_deref()).await?;
+        let store = storage::posts_in(&env)?;
+        let mut post = match posts::load(&*store, &post_id).await {
+            Ok(post) => post,
+            // Deleted since; there is nothing to copy the count onto.
+            Err(ApiError::NotFound) => return Ok(()),
+            Err(e) => return Err(Error::RustError(e.to_string())),
+        };
+        if let Some(post_obj) = post.as_object_mut() {
+            post_obj.insert("likes".to_string(), json!(count));
+        }
+        store.put(&post_id, &post).await?;
+        storage.put("flushed", count).await?;
+        storage
+            .put("flushed_at", Utc::now().timestamp_millis())
+            .await
+    }
+}
+
+#[wasm_bindgen]
+impl LikeCounter {
+    /// Called by the runtime when the alarm [`LikeCounter::set_alarm`] set goes off.
+    #[wasm_bindgen(js_name = alarm)]
+    pub fn alarm(&mut self) -> Promise {
+        // SAFETY: the same as for the `fetch` that `#[durable_object]` generates: the runtime
+        // never drops a Durable Object while a promise it returned is still running.
+        let this: &'static mut Self = unsafe { &mut *(self as *mut _) };
+        future_to_promise(async move {
+            this.flush()
+                .await
+                .map(|_| JsValue::UNDEFINED)
+                .map_err(JsValue::from)
+        })
+    }
+}
+
+#[durable_object]
+impl DurableObject for LikeCounter {
+    fn new(state: State, env: Env) -> Self {
+        let state = state._inner();
+        let raw_state = JsValue::from(&state);
+        Self {
+            state: State::from(state),
+            env,
+            raw_state,
+        }
+    }
+
+    /// `POST /increment` or `POST /decrement` with a [`Change`]. Liking twice, or unliking a
+    /// post one doesn't like, leaves the count as it was. `POST /flushed` with [`Flushed`] notes
+    /// a copy the worker wrote. `POST /clear` drops everything the counter stored, once its
+    /// post is gone.
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let increment = match req.path().as_str() {
+            "/increment" => true,
+            "/decrement" => false,
+            "/clear" => {
+                self.state.storage().delete_all().await?;
+                return Response::empty();
+            }
+            "/flushed" => {
+                let flushed = req.json::<Flushed>().await?;
+                let mut storage = self.state.storage();
+                storage.put("flushed", flushed.likes).await?;
+                storage
+                    .put("flushed_at", Utc::now().timestamp_millis())
+                    .await?;
+                return Response::empty();
+            }
+            _ => return Response::error("Not Found", 404),
+        };
+        let change = req.json::<Change>().await?;
+        let mut storage = self.state.storage();
+        let liker = format!("liker/{}", change.username);
+        // Storage reports a missing key as an error.
+        let liked = storage.get::<bool>(&liker).await.unwrap_or(false);
+        let mut count = storage.get::<i64>("count").await.unwrap_or(change.base);
+        let mut milestone = None;
+        if increment && !liked {
+            count += 1;
+            storage.put(&liker, true).await?;
+            let listed = milestones(self.env.var("LIKE_MILESTONES").ok().map(|v| v.to_string()));
+            if let Some(reached) = crossed(&listed, count - 1, count) {
+                let key = format!("milestone/{}", reached);
+                if storage.get::<bool>(&key).await.is_err() {
+                    storage.put(&key, true).await?;
+                    milestone = Some(reached);
+                }
+            }
+        } else if !increment && liked {
+            count = (count - 1).max(0);
+            storage.delete(&liker).await?;
+        }
+        storage.put("count", count).await?;
+        storage.put("post_id", &change.post_id).await?;
+        if let Some(tenant) = &change.tenant {
+            storage.put("tenant", tenant).await?;
+        }
+
+        let now = Utc::now().timestamp_millis();
+        let flushed_at = storage.get::<i64>("flushed_at").await.unwrap_or(0);
+        let flush = now - flushed_at >= FLUSH_INTERVAL_MS;
+        // The worker copies the count and reports back through `/flushed` once its write went
+        // through. Until then nothing counts as flushed, and the alarm copies it should the
+        // worker's write fail.
+        let alarm_at = if flush {
+            now + FLUSH_INTERVAL_MS
+        } else {
+            flushed_at + FLUSH_INTERVAL_MS
+        };
+        self.set_alarm(alarm_at).await?;
+        Response::from_json(&Counted {
+            likes: count,
+            liked: increment,
+            flush,
+            milestone,
+        })
+    }
+}
+
+/// Sends `body` to the counter of post `id` as `POST /<op>`.
+async fn send<T: Serialize>(
+    ctx: &RouteContext<Session>,
+    id: &str,
+    op: &str,
+    body: &T,
+) -> Result<Response> {
+    let stub = ctx.durable_object(LIKES_DO)?.id_from_name(id)?.get_stub()?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(wasm_bindgen::JsValue::from_str(
+            &serde_json::to_string(body)?,
+        )));
+    let req = Request::new_with_init(&format!("https://likes/{}", op), &init)?;
+    stub.fetch_with_request(req).await
+}
+
+async fn count(
+    ctx: &RouteContext<Session>,
+    id: &str,
+    op: &str,
+    change: &Change,
+) -> Result<Counted> {
+    send(ctx, id, op, change).await?.json().await
+}
+
+/// Notes that the post `id` was removed for good, so [`compact`] clears its counter.
+pub async fn purged(kv: &kv::KvStore, id: &str) -> Result<()> {
+    kv.put(&format!("purged/{}", id), "")?.execute().await?;
+    Ok(())
+}
+
+/// Clears the counters of up to [`COMPACT_BATCH`] posts removed for good, answering how many.
+/// The rest are left for the next run.
+pub async fn compact(env: &Env) -> Result<usize> {
+    let kv = env.kv(LIKES_KV)?;
+    let keys = kv
+        .list()
+        .prefix("purged/".to_string())
+        .limit(COMPACT_BATCH as u64)
+        .execute()
+        .await?
+        .keys;
+    let namespace = env.durable_object(LIKES_DO)?;
+    let mut cleared = 0;
+    for key in keys {
+        let id = &key.name["purged/".len()..];
+        let stub = namespace.id_from_name(id)?.get_stub()?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        let req = Request::new_with_init("https://likes/clear", &init)?;
+        stub.fetch_with_request(req).await?;
+        kv.delete(&key.name).await?;
+        cleared += 1;
+    }
+    Ok(cleared)
+}
+
+async fn change(_req: Request, ctx: RouteContext<Session>, op: &str) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let store = storage::posts(&ctx)?;
+    let post = posts::load(&*store, &id).await?;
+    if posts::is_archived(&post) || posts::is_moderated(&post) {
+        return Err(ApiError::Forbidden("This post can't be liked".to_string()));
+    }
+    let base = post.get("likes").and_then(Value::as_i64).unwrap_or(0);
+    let change = Change {
+        post_id: id.clone(),
+        username,
+        base,
+        tenant: tenants::id(ctx.data().bindings()),
+    };
+    let counted = count(&ctx, &id, op, &change).await?;
+    if counted.liked {
+        if let Some(author) = post.get("username").and_then(Value::as_str) {
+            let event = notifications::Event {
+                kind: "like",
+                from: &change.username,
+                post_id: &id,
+                comment_id: None,
+                excerpt: post.get("title").and_then(Value::as_str).unwrap_or(""),
+                milestone: None,
+            };
+            notifications::notify(&ctx, author, event).await;
+            if let Some(likes) = counted.milestone {
+                let event = notifications::Event {
+                    kind: "like_milestone",
+                    from: "",
+                    post_id: &id,
+                    comment_id: None,
+                    excerpt: post.get("title").and_then(Value::as_str).unwrap_or(""),
+                    milestone: Some(likes),
+                };
+                notifications::notify(&ctx, author, event).await;
+            }
+        }
+    }
+    let Change {
+        post_id, username, ..
+    } = change;
+    let likes = counted.likes;
+    let mutation = if counted.liked {
+        journal::Mutation::PostLiked {
+            id: post_id,
+            username,
+            likes,
+        }
+    } else {
+        journal::Mutation::PostUnliked {
+            id: post_id,
+            username,
+            likes,
+        }
+    };
+    journal::record(&ctx, mutation).await;
+    let event = live::Event::Like {
+        post_id: &id,
+        likes: counted.likes,
+    };
+    live::publish(&ctx, event).await;
+    let answer = json!({ "likes": counted.likes, "liked": counted.liked });
+    if !counted.flush {
+        return Ok(Response::from_json(&answer)?);
+    }
+
+    // Listings read `likes` off the stored post, so it gets a copy of the count. The post is
+    // read again so an edit made while the counter answered isn't undone.
+    let mut post = posts::load(&*store, &id).await?;
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.insert("likes".to_string(), json!(counted.likes));
+    }
+    store.put(&id, &post).await?;
+    let flushed = Flushed {
+        likes: counted.likes,
+    };
+    // Unreported, the counter's alarm writes the count again; that is all it costs.
+    if let Err(e) = send(&ctx, &id, "flushed", &flushed).await {
+        console_log!("counter of {} not told of its flush: {}", id, e);
+    }
+    firehose::post_changed(&ctx, firehose::Kind::Update, &id, &post).await;
+    Ok(Response::from_json(&answer)?)
+}
+
+/// `POST /posts/:id/like`
+pub async fn like(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    change(req, ctx, "increment").await
+}
+
+/// `POST /posts/:id/unlike`
+pub async fn unlike(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    change(req, ctx, "decrement").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn milestones_fall_back_to_the_defaults() {
+        assert_eq!(milestones(None), DEFAULT_MILESTONES);
+        assert_eq!(milestones(Some("5, 50".to_string())), [5, 50]);
+        assert_eq!(milestones(Some("5,lots".to_string())), DEFAULT_MILESTONES);
+        assert_eq!(milestones(Some(String::new())), DEFAULT_MILESTONES);
+    }
+
+    #[test]
+    fn a_like_crosses_a_milestone_once() {
+        assert_eq!(crossed(DEFAULT_MILESTONES, 9, 10), Some(10));
+        assert_eq!(crossed(DEFAULT_MILESTONES, 10, 11), None);
+        assert_eq!(crossed(DEFAULT_MILESTONES, 0, 1000), Some(1000));
+    }
+}
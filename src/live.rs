@@ -0,0 +1,358 @@
+use chrono::Utc;
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+
+/// Binding of the [`LiveHub`] namespace. There are two hubs: [`HUB`] behind `GET /ws` and
+/// `GET /events`, and [`FIREHOSE_HUB`] behind the streams of `GET /firehose`, so neither's
+/// clients get the other's events.
+const LIVE_DO: &str = "LIVE";
+const HUB: &str = "posts";
+pub const FIREHOSE_HUB: &str = "firehose";
+
+/// `readyState` of a WebSocket that is open.
+const OPEN: u16 = 1;
+
+/// How often an event stream with nothing to say gets a comment (an empty line, on NDJSON
+/// streams), in milliseconds, so proxies don't close it as idle.
+const HEARTBEAT_MS: i64 = 30_000;
+
+// `worker` 0.0.7 predates WebSockets and streaming bodies, so the runtime's API is bound here
+// directly.
+#[wasm_bindgen]
+extern "C" {
+    type WebSocketPair;
+
+    #[wasm_bindgen(constructor, catch)]
+    fn new() -> std::result::Result<WebSocketPair, JsValue>;
+
+    type WebSocket;
+
+    #[wasm_bindgen(method, catch)]
+    fn accept(this: &WebSocket) -> std::result::Result<(), JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn send(this: &WebSocket, message: &str) -> std::result::Result<(), JsValue>;
+
+    #[wasm_bindgen(method, getter, js_name = readyState)]
+    fn ready_state(this: &WebSocket) -> u16;
+
+    type TransformStream;
+
+    #[wasm_bindgen(constructor, catch)]
+    fn new() -> std::result::Result<TransformStream, JsValue>;
+
+    #[wasm_bindgen(method, getter)]
+    fn readable(this: &TransformStream) -> JsValue;
+
+    #[wasm_bindgen(method, getter)]
+    fn writable(this: &TransformStream) -> WritableStream;
+
+    type WritableStream;
+
+    #[wasm_bindgen(method, catch, js_name = getWriter)]
+    fn get_writer(this: &WritableStream) -> std::result::Result<StreamWriter, JsValue>;
+
+    type StreamWriter;
+
+    /// `None` once the stream has errored, as it does when the client goes away.
+    #[wasm_bindgen(method, getter, js_name = desiredSize)]
+    fn desired_size(this: &StreamWriter) -> Option<f64>;
+
+    #[wasm_bindgen(method)]
+    fn write(this: &StreamWriter, chunk: &Uint8Array) -> Promise;
+
+    #[wasm_bindgen(js_name = Response)]
+    type StreamResponse;
+
+    #[wasm_bindgen(constructor, catch, js_class = "Response")]
+    fn new(body: &JsValue, init: &Object) -> std::result::Result<StreamResponse, JsValue>;
+}
+
+/// What connected clients are sent, as JSON. Posts are announced by id rather than in full, so
+/// a client fetches them through `GET /posts/:id` like any other reader and gets what
+/// withholding and moderation let it see.
+///
+/// ```json
+/// {"type": "post", "id": "...", "username": "...", "community": null}
+/// {"type": "like", "post_id": "...", "likes": 12}
+/// ```
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Post {
+        id: &'a str,
+        username: &'a str,
+        community: Option<&'a str>,
+    },
+    Like {
+        post_id: &'a str,
+        likes: i64,
+    },
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// Calls `target.method(...args)`.
+fn call(target: &JsValue, method: &str, args: &Array) -> Result<JsValue> {
+    let function: Function = Reflect::get(target, &JsValue::from(method))
+        .map_err(js_error)?
+        .unchecked_into();
+    Reflect::apply(&function, target, args).map_err(js_error)
+}
+
+/// The pub/sub hub behind `GET /ws`, `GET /events` and the streams of `GET /firehose`: it
+/// holds every connected client's
+/// socket or event stream and relays each event published to it to all of them. Clients live
+/// only in memory, so a hub that the runtime evicts drops them, and they reconnect.
+#[durable_object]
+pub struct LiveHub {
+    sockets: Vec<WebSocket>,
+    streams: Vec<StreamWriter>,
+    /// Streams of one JSON event per line, rather than Server-Sent Events.
+    ndjson_streams: Vec<StreamWriter>,
+    /// The runtime's `state`, for the alarm API `worker` 0.0.7 doesn't wrap.
+    raw_state: JsValue,
+    /// Handles the failed write to a stream whose client just went away.
+    ignore: Closure<dyn FnMut(JsValue)>,
+}
+
+impl LiveHub {
+    /// Accepts the server end of a new socket pair and answers the upgrade with the client end.
+    fn connect(&mut self) -> Result<Response> {
+        let pair = WebSocketPair::new().map_err(js_error)?;
+        let client = Reflect::get(&pair, &JsValue::from(0)).map_err(js_error)?;
+        let server: WebSocket = Reflect::get(&pair, &JsValue::from(1))
+            .map_err(js_error)?
+            .unchecked_into();
+        server.accept().map_err(js_error)?;
+        self.sockets.push(server);
+
+        let init = Object::new();
+        Reflect::set(&init, &JsValue::from("status"), &JsValue::from(101)).map_err(js_error)?;
+        Reflect::set(&init, &JsValue::from("webSocket"), &client).map_err(js_error)?;
+        let upgraded =
+            worker_sys::Response::new_with_opt_str_and_init(None, &init.unchecked_into())
+                .map_err(js_error)?;
+        passed_through(upgraded)
+    }
+
+    /// Opens an event stream, of Server-Sent Events or else of NDJSON, and answers with its
+    /// readable end.
+    async fn stream(&mut self, ndjson: bool) -> Result<Response> {
+        let stream = TransformStream::new().map_err(js_error)?;
+        let writer = stream.writable().get_writer().map_err(js_error)?;
+        if ndjson {
+            self.ndjson_streams.push(writer);
+        } else {
+            // Sent right away, so the client sees the stream open before the first event.
+            self.write(&writer, ": connected\n\n");
+            self.streams.push(writer);
+        }
+        self.set_alarm(Utc::now().timestamp_millis() + HEARTBEAT_MS)
+            .await?;
+
+        let mut headers = Headers::new();
+        let content_type = if ndjson {
+            "application/x-ndjson"
+        } else {
+            "text/event-stream"
+        };
+        headers.set("Content-Type", content_type)?;
+        headers.set("Cache-Control", "no-store")?;
+        // The worker's own headers don't make it onto a streamed response; see `passed_through`.
+        crate::set_cors_headers(&mut headers)?;
+        let init = Object::new();
+        Reflect::set(&init, &JsValue::from("headers"), &headers.0).map_err(js_error)?;
+        let res = StreamResponse::new(&stream.readable(), &init).map_err(js_error)?;
+        passed_through(res.unchecked_into())
+    }
+
+    /// Queues `text` on `writer` without waiting for the client to read it.
+    fn write(&self, writer: &StreamWriter, text: &str) {
+        let _ = writer
+            .write(&Uint8Array::from(text.as_bytes()))
+            .catch(&self.ignore);
+    }
+
+    /// Sends `message` to every open socket and stream, forgetting those that have closed.
+    fn broadcast(&mut self, message: &str) -> usize {
+        self.sockets
+            .retain(|socket| socket.ready_state() == OPEN && socket.send(message).is_ok());
+        self.streams
+            .retain(|writer| writer.desired_size().is_some());
+        self.ndjson_streams
+            .retain(|writer| writer.desired_size().is_some());
+        let event = format!("data: {}\n\n", message);
+        for writer in &self.streams {
+            self.write(writer, &event);
+        }
+        let line = format!("{}\n", message);
+        for writer in &self.ndjson_streams {
+            self.write(writer, &line);
+        }
+        self.sockets.len() + self.streams.len() + self.ndjson_streams.len()
+    }
+
+    /// Has the runtime call [`LiveHub::alarm`] at `at`, milliseconds since the epoch.
+    async fn set_alarm(&self, at: i64) -> Result<()> {
+        let storage = Reflect::get(&self.raw_state, &JsValue::from("storage")).map_err(js_error)?;
+        let set_alarm: Function = Reflect::get(&storage, &JsValue::from("setAlarm"))
+            .map_err(js_error)?
+            .unchecked_into();
+        let promise: Promise = set_alarm
+            .call1(&storage, &JsValue::from(at as f64))
+            .map_err(js_error)?
+            .unchecked_into();
+        JsFuture::from(promise).await.map_err(js_error)?;
+        Ok(())
+    }
+
+    /// Sends every open stream a heartbeat, and sets the next one while any are left.
+    async fn heartbeat(&mut self) -> Result<()> {
+        self.streams
+            .retain(|writer| writer.desired_size().is_some());
+        self.ndjson_streams
+            .retain(|writer| writer.desired_size().is_some());
+        for writer in &self.streams {
+            self.write(writer, ": heartbeat\n\n");
+        }
+        for writer in &self.ndjson_streams {
+            self.write(writer, "\n");
+        }
+        if self.streams.is_empty() && self.ndjson_streams.is_empty() {
+            return Ok(());
+        }
+        self.set_alarm(Utc::now().timestamp_millis() + HEARTBEAT_MS)
+            .await
+    }
+}
+
+#[wasm_bindgen]
+impl LiveHub {
+    /// Called by the runtime when the alarm [`LiveHub::set_alarm`] set goes off.
+    #[wasm_bindgen(js_name = alarm)]
+    pub fn alarm(&mut self) -> Promise {
+        // SAFETY: as for `LikeCounter::alarm`.
+        let this: &'static mut Self = unsafe { &mut *(self as *mut _) };
+        future_to_promise(async move {
+            this.heartbeat()
+                .await
+                .map(|_| JsValue::UNDEFINED)
+                .map_err(JsValue::from)
+        })
+    }
+}
+
+/// Wraps a `101 Switching Protocols` or streamed response so it reaches the runtime as it is.
+/// `worker` rebuilds a bodiless response on the way out, which would drop its socket; a stream
+/// body is passed through untouched, so headers set on the wrapper afterwards are ignored.
+fn passed_through(upgraded: worker_sys::Response) -> Result<Response> {
+    let status = upgraded.status();
+    Ok(Response::from_body(ResponseBody::Stream(upgraded))?.with_status(status))
+}
+
+#[durable_object]
+impl DurableObject for LiveHub {
+    fn new(state: State, _env: Env) -> Self {
+        let state = state._inner();
+        Self {
+            sockets: vec![],
+            streams: vec![],
+            ndjson_streams: vec![],
+            raw_state: JsValue::from(&state),
+            ignore: Closure::wrap(Box::new(|_| {}) as Box<dyn FnMut(JsValue)>),
+        }
+    }
+
+    /// `GET /ws` or `GET /firehose` with `Upgrade: websocket` connects a client, `GET /events`
+    /// opens an event stream and `GET /firehose` an NDJSON one; `POST /publish` with an
+    /// [`Event`] (or a firehose event) relays it to every client.
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
+        match req.path().as_str() {
+            "/ws" => self.connect(),
+            "/firehose" if upgrade.eq_ignore_ascii_case("websocket") => self.connect(),
+            "/firehose" => self.stream(true).await,
+            "/events" => self.stream(false).await,
+            "/publish" => {
+                let message = req.text().await?;
+                Response::from_json(&serde_json::json!({ "sent": self.broadcast(&message) }))
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+}
+
+/// The stub of the hub named `name`, through the runtime's own API.
+fn hub(bindings: &JsValue, name: &str) -> Result<JsValue> {
+    let namespace = Reflect::get(bindings, &JsValue::from(LIVE_DO)).map_err(js_error)?;
+    let id = call(&namespace, "idFromName", &Array::of1(&JsValue::from(name)))?;
+    call(&namespace, "get", &Array::of1(&id))
+}
+
+/// Hands `req` to the hub named `name` and passes its answer on as the runtime's own response,
+/// since `worker` 0.0.7's `Stub` would rebuild an upgrade without its socket.
+pub async fn forward(req: &Request, bindings: &JsValue, name: &str) -> Result<Response> {
+    let stub = hub(bindings, name)?;
+    let promise: Promise = call(&stub, "fetch", &Array::of1(req.inner()))?.unchecked_into();
+    let upgraded = JsFuture::from(promise).await.map_err(js_error)?;
+    passed_through(upgraded.unchecked_into())
+}
+
+/// `GET /ws`: upgrades to a WebSocket on which every new post and like is announced as an
+/// [`Event`], so a client can keep its feeds current without polling `GET /posts`.
+pub async fn connect(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err(ApiError::BadRequest(
+            "Expected `Upgrade: websocket`".to_string(),
+        ));
+    }
+    Ok(forward(&req, ctx.data().bindings(), HUB).await?)
+}
+
+/// Sends `message` to every client of the hub named `name`.
+pub async fn relay(bindings: &JsValue, name: &str, message: &str) -> Result<()> {
+    let stub = hub(bindings, name)?;
+    let init = Object::new();
+    Reflect::set(&init, &JsValue::from("method"), &JsValue::from("POST")).map_err(js_error)?;
+    Reflect::set(&init, &JsValue::from("body"), &JsValue::from(message)).map_err(js_error)?;
+    let args = Array::of2(&JsValue::from("https://live/publish"), &init);
+    let promise: Promise = call(&stub, "fetch", &args)?.unchecked_into();
+    JsFuture::from(promise).await.map_err(js_error)?;
+    Ok(())
+}
+
+/// `GET /events`: the events of `GET /ws` as Server-Sent Events, one `data:` line of JSON
+/// each, for clients that can't use WebSockets.
+pub async fn events(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let stub = ctx.durable_object(LIVE_DO)?.id_from_name(HUB)?.get_stub()?;
+    Ok(stub.fetch_with_request(req).await?)
+}
+
+async fn try_publish(ctx: &RouteContext<Session>, event: &Event<'_>) -> Result<()> {
+    let stub = ctx.durable_object(LIVE_DO)?.id_from_name(HUB)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from_str(&serde_json::to_string(event)?)));
+    let req = Request::new_with_init("https://live/publish", &init)?;
+    stub.fetch_with_request(req).await?;
+    Ok(())
+}
+
+/// Announces `event` to every client connected to `GET /ws` or `GET /events`. Failures are
+/// logged, never returned.
+pub async fn publish(ctx: &RouteContext<Session>, event: Event<'_>) {
+    if let Err(e) = try_publish(ctx, &event).await {
+        console_log!("live event {:?} failed: {}", event, e);
+    }
+}
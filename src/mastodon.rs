@@ -0,0 +1,174 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::withholding::Withheld;
+use crate::{communities, posts, render, session, storage, users, utils, validation};
+
+/// Longest title cut from the start of a status that has no `spoiler_text`.
+const TITLE_CHARS: usize = 80;
+
+#[derive(Deserialize, Debug)]
+struct NewStatus {
+    status: String,
+    #[serde(default)]
+    spoiler_text: String,
+    language: Option<String>,
+}
+
+fn account(username: &str, created_at: &str) -> Value {
+    json!({
+        "id": username,
+        "username": username,
+        "acct": username,
+        "display_name": username,
+        "locked": false,
+        "bot": false,
+        "created_at": created_at,
+        "note": "",
+        "url": "",
+        "avatar": "",
+        "avatar_static": "",
+        "header": "",
+        "header_static": "",
+        "followers_count": 0,
+        "following_count": 0,
+        "statuses_count": 0,
+        "emojis": [],
+        "fields": [],
+    })
+}
+
+fn status(post: &Value) -> Value {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or_default();
+    let time = field("time");
+    // Posts stored before rendering existed have no `content_html`.
+    let paragraphs: String = match post.get("content_html").and_then(Value::as_str) {
+        Some(html) => html.to_string(),
+        None => field("content")
+            .split("\n\n")
+            .map(|paragraph| {
+                format!(
+                    "<p>{}</p>",
+                    render::escape_html(paragraph).replace('\n', "<br>")
+                )
+            })
+            .collect(),
+    };
+    json!({
+        "id": field("id"),
+        "uri": field("id"),
+        "url": Value::Null,
+        "created_at": time,
+        "account": account(field("username"), time),
+        "content": paragraphs,
+        "spoiler_text": "",
+        "sensitive": false,
+        "visibility": "public",
+        "language": post.get("lang").and_then(Value::as_str),
+        "favourites_count": post.get("likes").and_then(Value::as_i64).unwrap_or(0),
+        "reblogs_count": 0,
+        "replies_count": 0,
+        "in_reply_to_id": Value::Null,
+        "in_reply_to_account_id": Value::Null,
+        "reblog": Value::Null,
+        "media_attachments": [],
+        "mentions": [],
+        "tags": [],
+        "emojis": [],
+    })
+}
+
+/// `GET /api/v1/accounts/verify_credentials`
+pub async fn verify_credentials(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let profile = users::load_replicated(&ctx, &username)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let mut account = account(&username, &profile.created_at);
+    if let Some(account_obj) = account.as_object_mut() {
+        if let Some(display_name) = profile.display_name {
+            account_obj.insert("display_name".to_string(), json!(display_name));
+        }
+        account_obj.insert("note".to_string(), json!(profile.bio.unwrap_or_default()));
+        let avatar = profile.avatar_url.unwrap_or_default();
+        account_obj.insert("avatar".to_string(), json!(avatar));
+        account_obj.insert("avatar_static".to_string(), json!(avatar));
+    }
+    Ok(Response::from_json(&account)?)
+}
+
+/// `GET /api/v1/timelines/home`: our `/feed`, newest first.
+pub async fn home_timeline(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let mut posts = communities::home(&ctx, &username).await?;
+    Withheld::for_request(&req, &ctx)
+        .await?
+        .apply_to_posts(&mut posts);
+    let mut statuses = vec![];
+    for post in posts {
+        statuses.push(status(&serde_json::to_value(&post)?));
+    }
+    statuses.reverse();
+    Ok(Response::from_json(&statuses)?)
+}
+
+/// `GET /api/v1/statuses/:id`
+pub async fn show_status(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let id = error::param(&ctx, "id")?;
+    let mut post = posts::load(&*storage::posts(&ctx)?, &id).await?;
+    if posts::is_archived(&post) || posts::is_moderated(&post) {
+        return Err(ApiError::NotFound);
+    }
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.entry("id").or_insert_with(|| json!(id));
+    }
+    Withheld::for_request(&req, &ctx).await?.apply(&mut post);
+    Ok(Response::from_json(&status(&post))?)
+}
+
+/// `POST /api/v1/statuses`
+///
+/// Statuses have no title, so `spoiler_text` is used as one, or else the start of the status.
+pub async fn create_status(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let body = match req.json::<NewStatus>().await {
+        Ok(body) if !body.status.trim().is_empty() => body,
+        _ => {
+            return Err(ApiError::Unprocessable(
+                "Validation failed: Text can't be blank".to_string(),
+            ))
+        }
+    };
+    let title = if body.spoiler_text.trim().is_empty() {
+        body.status
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(TITLE_CHARS)
+            .collect()
+    } else {
+        body.spoiler_text
+    };
+
+    let now = Utc::now().to_rfc3339();
+    let id = utils::ulid()?;
+    let mut post = json!({
+        "id": id,
+        "username": username,
+        "title": title,
+        "content": body.status,
+        "time": now,
+    });
+    if let (Some(lang), Some(post_obj)) = (body.language, post.as_object_mut()) {
+        post_obj.insert("lang".to_string(), json!(lang));
+    }
+    validation::post(&mut post)?;
+    posts::normalize_license(&mut post)?;
+    posts::insert(&ctx, &id, &mut post).await?;
+    Ok(Response::from_json(&status(&post))?)
+}
@@ -0,0 +1,277 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::render::escape_html;
+
+/// Deepest nesting of groups and scripts converted; anything below is shown as plain text, so a
+/// post full of `{{{{` can't exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Commands that stand for a single identifier or operator.
+const SYMBOLS: &[(&str, &str, bool)] = &[
+    // (command, character, is an operator)
+    ("alpha", "α", false),
+    ("beta", "β", false),
+    ("gamma", "γ", false),
+    ("delta", "δ", false),
+    ("epsilon", "ε", false),
+    ("varepsilon", "ε", false),
+    ("zeta", "ζ", false),
+    ("eta", "η", false),
+    ("theta", "θ", false),
+    ("iota", "ι", false),
+    ("kappa", "κ", false),
+    ("lambda", "λ", false),
+    ("mu", "μ", false),
+    ("nu", "ν", false),
+    ("xi", "ξ", false),
+    ("pi", "π", false),
+    ("rho", "ρ", false),
+    ("sigma", "σ", false),
+    ("tau", "τ", false),
+    ("upsilon", "υ", false),
+    ("phi", "φ", false),
+    ("varphi", "φ", false),
+    ("chi", "χ", false),
+    ("psi", "ψ", false),
+    ("omega", "ω", false),
+    ("Gamma", "Γ", false),
+    ("Delta", "Δ", false),
+    ("Theta", "Θ", false),
+    ("Lambda", "Λ", false),
+    ("Xi", "Ξ", false),
+    ("Pi", "Π", false),
+    ("Sigma", "Σ", false),
+    ("Phi", "Φ", false),
+    ("Psi", "Ψ", false),
+    ("Omega", "Ω", false),
+    ("infty", "∞", false),
+    ("partial", "∂", false),
+    ("nabla", "∇", false),
+    ("hbar", "ℏ", false),
+    ("ell", "ℓ", false),
+    ("emptyset", "∅", false),
+    ("sum", "∑", true),
+    ("prod", "∏", true),
+    ("int", "∫", true),
+    ("oint", "∮", true),
+    ("cdot", "⋅", true),
+    ("times", "×", true),
+    ("div", "÷", true),
+    ("pm", "±", true),
+    ("mp", "∓", true),
+    ("leq", "≤", true),
+    ("le", "≤", true),
+    ("geq", "≥", true),
+    ("ge", "≥", true),
+    ("neq", "≠", true),
+    ("ne", "≠", true),
+    ("approx", "≈", true),
+    ("equiv", "≡", true),
+    ("sim", "∼", true),
+    ("propto", "∝", true),
+    ("in", "∈", true),
+    ("notin", "∉", true),
+    ("subset", "⊂", true),
+    ("subseteq", "⊆", true),
+    ("cup", "∪", true),
+    ("cap", "∩", true),
+    ("forall", "∀", true),
+    ("exists", "∃", true),
+    ("neg", "¬", true),
+    ("land", "∧", true),
+    ("lor", "∨", true),
+    ("to", "→", true),
+    ("rightarrow", "→", true),
+    ("leftarrow", "←", true),
+    ("Rightarrow", "⇒", true),
+    ("Leftrightarrow", "⇔", true),
+    ("mapsto", "↦", true),
+    ("ldots", "…", true),
+    ("cdots", "⋯", true),
+    ("langle", "⟨", true),
+    ("rangle", "⟩", true),
+];
+
+/// Function names set upright rather than as a product of italic letters.
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "log", "ln", "exp", "lim", "max", "min", "det", "gcd",
+];
+
+struct Converter<'a> {
+    chars: Peekable<Chars<'a>>,
+    depth: usize,
+}
+
+impl<'a> Converter<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    /// Nodes up to the closing `}` of a group (consumed), or to the end of the input.
+    fn row(&mut self) -> String {
+        let mut out = String::new();
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                None => break,
+                Some('}') => {
+                    self.chars.next();
+                    break;
+                }
+                Some(_) => out.push_str(&self.scripted()),
+            }
+        }
+        out
+    }
+
+    /// An atom with any `_` and `^` scripts attached.
+    fn scripted(&mut self) -> String {
+        let base = self.atom();
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some('_') if sub.is_none() => {
+                    self.chars.next();
+                    sub = Some(self.atom());
+                }
+                Some('^') if sup.is_none() => {
+                    self.chars.next();
+                    sup = Some(self.atom());
+                }
+                _ => break,
+            }
+        }
+        match (sub, sup) {
+            (None, None) => base,
+            (Some(sub), None) => format!("<msub>{}{}</msub>", base, sub),
+            (None, Some(sup)) => format!("<msup>{}{}</msup>", base, sup),
+            (Some(sub), Some(sup)) => format!("<msubsup>{}{}{}</msubsup>", base, sub, sup),
+        }
+    }
+
+    /// The raw text up to the closing `}`, for `\text{...}`.
+    fn raw_group(&mut self) -> String {
+        self.skip_whitespace();
+        if self.chars.peek() != Some(&'{') {
+            return String::new();
+        }
+        self.chars.next();
+        let mut text = String::new();
+        for c in self.chars.by_ref() {
+            if c == '}' {
+                break;
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    fn atom(&mut self) -> String {
+        self.skip_whitespace();
+        if self.depth >= MAX_DEPTH {
+            let rest: String = self.chars.by_ref().collect();
+            return format!("<mtext>{}</mtext>", escape_html(&rest));
+        }
+        self.depth += 1;
+        let node = match self.chars.next() {
+            None => "<mrow></mrow>".to_string(),
+            Some('{') => format!("<mrow>{}</mrow>", self.row()),
+            Some('\\') => self.command(),
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(&next) = self.chars.peek() {
+                    if !(next.is_ascii_digit() || next == '.') {
+                        break;
+                    }
+                    number.push(next);
+                    self.chars.next();
+                }
+                format!("<mn>{}</mn>", number)
+            }
+            Some(c) if c.is_alphabetic() => format!("<mi>{}</mi>", c),
+            Some(c) => format!("<mo>{}</mo>", escape_html(&c.to_string())),
+        };
+        self.depth -= 1;
+        node
+    }
+
+    fn command(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_alphabetic() {
+                break;
+            }
+            name.push(c);
+            self.chars.next();
+        }
+        if name.is_empty() {
+            // An escaped character such as `\{` or `\,`.
+            return match self.chars.next() {
+                Some(c) if c == ',' || c == ';' || c == ' ' => "<mspace width=\"0.2em\"/>".into(),
+                Some(c) => format!("<mo>{}</mo>", escape_html(&c.to_string())),
+                None => String::new(),
+            };
+        }
+        match name.as_str() {
+            "frac" => {
+                let numerator = self.atom();
+                let denominator = self.atom();
+                format!("<mfrac>{}{}</mfrac>", numerator, denominator)
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                if self.chars.peek() == Some(&'[') {
+                    self.chars.next();
+                    let index: String = self.chars.by_ref().take_while(|&c| c != ']').collect();
+                    let radicand = self.atom();
+                    let index = Converter {
+                        chars: index.chars().peekable(),
+                        depth: self.depth,
+                    }
+                    .row();
+                    format!("<mroot>{}<mrow>{}</mrow></mroot>", radicand, index)
+                } else {
+                    format!("<msqrt>{}</msqrt>", self.atom())
+                }
+            }
+            "text" | "mathrm" | "operatorname" => {
+                format!("<mtext>{}</mtext>", escape_html(&self.raw_group()))
+            }
+            // Sizing commands only affect how the delimiter that follows is drawn.
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" => self.atom(),
+            "quad" | "qquad" => "<mspace width=\"1em\"/>".to_string(),
+            name if FUNCTIONS.contains(&name) => format!("<mi>{}</mi>", name),
+            name => match SYMBOLS.iter().find(|(command, _, _)| *command == name) {
+                Some((_, symbol, true)) => format!("<mo>{}</mo>", symbol),
+                Some((_, symbol, false)) => format!("<mi>{}</mi>", symbol),
+                None => format!("<mtext>\\{}</mtext>", escape_html(name)),
+            },
+        }
+    }
+}
+
+/// Converts a TeX formula to MathML. Covers the everyday subset (scripts, fractions, roots,
+/// Greek letters, common operators); anything else is shown as its source. The TeX is kept as
+/// an annotation for copying and for readers without MathML support.
+pub fn to_mathml(tex: &str, display: bool) -> String {
+    let mut converter = Converter {
+        chars: tex.chars().peekable(),
+        depth: 0,
+    };
+    // A stray `}` ends a row early; carry on after it rather than drop the rest.
+    let mut body = String::new();
+    while converter.chars.peek().is_some() {
+        body.push_str(&converter.row());
+    }
+    format!(
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"{}\"><semantics><mrow>{}\
+         </mrow><annotation encoding=\"application/x-tex\">{}</annotation></semantics></math>",
+        if display { "block" } else { "inline" },
+        body,
+        escape_html(tex)
+    )
+}
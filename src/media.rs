@@ -0,0 +1,218 @@
+use chrono::Utc;
+use js_sys::{Object, Promise, Reflect, Uint8Array};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{cache, session};
+
+/// Binding of the R2 bucket media is kept in. Objects are keyed `media/<media id>`, with the
+/// uploader and upload time as custom metadata.
+const MEDIA_BUCKET: &str = "MEDIA";
+
+pub const MAX_MEDIA_BYTES: usize = 5 * 1024 * 1024;
+
+/// A media id is the hex SHA-256 of its bytes, so the same image uploaded twice is stored once
+/// and what an id serves never changes.
+pub const MEDIA_ID_LEN: usize = 64;
+
+/// Form field a multipart upload carries the file in.
+const FORM_FIELD: &str = "file";
+
+// `worker` 0.0.7 predates R2, so the bucket's JavaScript API is bound here directly.
+#[wasm_bindgen]
+extern "C" {
+    type R2Bucket;
+
+    #[wasm_bindgen(method, catch)]
+    fn put(
+        this: &R2Bucket,
+        key: &str,
+        value: &Uint8Array,
+        options: &JsValue,
+    ) -> std::result::Result<Promise, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn get(this: &R2Bucket, key: &str) -> std::result::Result<Promise, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn head(this: &R2Bucket, key: &str) -> std::result::Result<Promise, JsValue>;
+
+    type R2ObjectBody;
+
+    #[wasm_bindgen(method, catch, js_name = arrayBuffer)]
+    fn array_buffer(this: &R2ObjectBody) -> std::result::Result<Promise, JsValue>;
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+fn bucket(env: &Env) -> Result<R2Bucket> {
+    let binding = Reflect::get(env, &JsValue::from(MEDIA_BUCKET)).map_err(js_error)?;
+    if binding.is_undefined() {
+        return Err(format!("Binding `{}` is undefined.", MEDIA_BUCKET).into());
+    }
+    Ok(binding.unchecked_into())
+}
+
+fn object(entries: &[(&str, JsValue)]) -> Result<JsValue> {
+    let obj = Object::new();
+    for (key, value) in entries {
+        Reflect::set(&obj, &JsValue::from(*key), value).map_err(js_error)?;
+    }
+    Ok(obj.into())
+}
+
+/// The image type `bytes` actually are, judged by their first bytes rather than by what the
+/// client claims, so nothing but these images is ever served back.
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Whether `id` is shaped like a media id. Posts referencing media are checked with this.
+pub fn is_media_id(id: &str) -> bool {
+    id.len() == MEDIA_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Whether media `id` has been uploaded, for things that point at it from outside a post.
+pub async fn exists(env: &Env, id: &str) -> Result<bool> {
+    if !is_media_id(id) {
+        return Ok(false);
+    }
+    let head = bucket(env)?
+        .head(&format!("media/{}", id))
+        .map_err(js_error)?;
+    let found = JsFuture::from(head).await.map_err(js_error)?;
+    Ok(!found.is_null() && !found.is_undefined())
+}
+
+fn too_large() -> ApiError {
+    ApiError::BadRequest(format!("media can be at most {} bytes", MAX_MEDIA_BYTES))
+}
+
+/// The uploaded bytes: the `file` field of a multipart form, or else the raw body.
+async fn read_upload(req: &mut Request) -> ApiResult<Vec<u8>> {
+    let declared = req
+        .headers()
+        .get("Content-Length")?
+        .and_then(|len| len.parse::<usize>().ok());
+    // Multipart framing adds a little on top of the file itself.
+    if declared.is_some_and(|len| len > MAX_MEDIA_BYTES + 16 * 1024) {
+        return Err(too_large());
+    }
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    if content_type.starts_with("multipart/form-data") {
+        let form = req.form_data().await?;
+        return match form.get(FORM_FIELD) {
+            Some(FormEntry::File(file)) => Ok(file.bytes().await?),
+            _ => Err(ApiError::BadRequest(format!(
+                "a multipart upload needs the image in `{}`",
+                FORM_FIELD
+            ))),
+        };
+    }
+    Ok(req.bytes().await?)
+}
+
+/// `POST /media`, signed in: stores a PNG, JPEG, GIF or WebP image of up to
+/// [`MAX_MEDIA_BYTES`] and answers with its `media_id` and `url`. Posts reference it by listing
+/// the id in `media`.
+pub async fn upload(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let bytes = read_upload(&mut req).await?;
+    if bytes.len() > MAX_MEDIA_BYTES {
+        return Err(too_large());
+    }
+    if bytes.is_empty() {
+        return Err(ApiError::BadRequest("the upload is empty".to_string()));
+    }
+    let content_type = sniff(&bytes).ok_or_else(|| {
+        ApiError::Unprocessable("only PNG, JPEG, GIF and WebP images can be uploaded".to_string())
+    })?;
+
+    let media_id: String = Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let options = object(&[
+        (
+            "httpMetadata",
+            object(&[("contentType", JsValue::from(content_type))])?,
+        ),
+        (
+            "customMetadata",
+            object(&[
+                ("uploaded_by", JsValue::from(username.as_str())),
+                ("uploaded_at", JsValue::from(Utc::now().to_rfc3339())),
+            ])?,
+        ),
+    ])?;
+    let bucket = bucket(&ctx.get_env())?;
+    let put = bucket
+        .put(
+            &format!("media/{}", media_id),
+            &Uint8Array::from(bytes.as_slice()),
+            &options,
+        )
+        .map_err(js_error)?;
+    JsFuture::from(put).await.map_err(js_error)?;
+
+    let mut url = req.url()?;
+    url.set_path(&format!("/media/{}", media_id));
+    url.set_query(None);
+    Ok(Response::from_json(&json!({
+        "media_id": media_id,
+        "url": url.to_string(),
+        "content_type": content_type,
+        "size": bytes.len(),
+    }))?
+    .with_status(201))
+}
+
+/// `GET /media/:id`. The bytes behind an id never change, so the response can be cached for
+/// good (see `cache`) and revalidated by id.
+pub async fn show(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let media_id = error::param(&ctx, "id")?;
+    if !is_media_id(&media_id) {
+        return Err(ApiError::NotFound);
+    }
+    let etag = format!("\"{}\"", media_id);
+    if cache::fresh(&req, &etag)? {
+        return Ok(cache::not_modified(&etag)?);
+    }
+    let bucket = bucket(&ctx.get_env())?;
+    let get = bucket
+        .get(&format!("media/{}", media_id))
+        .map_err(js_error)?;
+    let found = JsFuture::from(get).await.map_err(js_error)?;
+    if found.is_null() || found.is_undefined() {
+        return Err(ApiError::NotFound);
+    }
+    let body: R2ObjectBody = found.unchecked_into();
+    let buffer = JsFuture::from(body.array_buffer().map_err(js_error)?)
+        .await
+        .map_err(js_error)?;
+    let bytes = Uint8Array::new(&buffer).to_vec();
+    let content_type = sniff(&bytes).unwrap_or("application/octet-stream");
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", content_type)?;
+    headers.set("ETag", &etag)?;
+    headers.set("X-Content-Type-Options", "nosniff")?;
+    Ok(Response::from_bytes(bytes)?.with_headers(headers))
+}
@@ -0,0 +1,307 @@
+use chrono::{DateTime, Utc};
+use js_sys::{Array, Function, Object, Promise, Reflect};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::models::{self, Post};
+use crate::session::{self, Session};
+use crate::trace::Trace;
+use crate::withholding::{self, Withheld, Withholding};
+use crate::{communities, isolate, moderation, posts, storage, tenants};
+
+/// Binding of the R2 bucket backups are kept in, apart from media so either can go without the
+/// other. Objects are kept under `instance/` for the deployment's own instance and `t/<id>/`
+/// for a tenant's:
+///
+/// - `posts/latest.json`: the newest [`Snapshot`]
+/// - `posts/<yyyy-mm-dd>.json`: the last snapshot of that UTC day
+/// - `maintenance.json`: the [`Flag`], while an admin has one set
+const BACKUPS_BUCKET: &str = "BACKUPS";
+
+/// How long an isolate keeps the flag, in milliseconds. One set elsewhere takes at most that
+/// long to apply here.
+const FLAG_TTL_MS: i64 = 30 * 1000;
+
+const FLAG_CACHE_KEY: &str = "mirror/flag";
+
+/// Route the flag is managed through, which stays writable whatever the flag says.
+const MAINTENANCE_PATH: &str = "/admin/maintenance";
+
+/// Feeds a snapshot can stand in for.
+const MIRRORED: &[&str] = &["/posts", "/feed/global"];
+
+/// What the worker does while a flag is set.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Writes are refused with 503; reads are served as usual.
+    ReadOnly,
+    /// Writes are refused, and the feeds are served from the latest [`Snapshot`] rather than
+    /// from KV, e.g. while a namespace is unavailable or being restored.
+    Mirror,
+}
+
+/// The maintenance flag, set through `PUT /admin/maintenance`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flag {
+    pub mode: Mode,
+    pub reason: String,
+    pub set_by: String,
+    pub set_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewFlag {
+    mode: Mode,
+    reason: String,
+}
+
+/// The public posts as they were at `taken_at`, with what was withheld where, so a snapshot can
+/// be served without reading KV at all.
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    taken_at: String,
+    posts: Vec<Post>,
+    withholdings: HashMap<String, Withholding>,
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// Where the objects of the instance `bindings` belong to are kept in the bucket.
+fn scope(bindings: &JsValue) -> String {
+    match tenants::id(bindings) {
+        Some(id) => format!("t/{}/", id),
+        None => "instance/".to_string(),
+    }
+}
+
+fn bucket(bindings: &JsValue) -> Result<JsValue> {
+    let binding = Reflect::get(bindings, &JsValue::from(BACKUPS_BUCKET)).map_err(js_error)?;
+    if binding.is_undefined() {
+        return Err(format!("Binding `{}` is undefined.", BACKUPS_BUCKET).into());
+    }
+    Ok(binding)
+}
+
+/// Calls `target.method(args)` and waits for the promise it answers.
+async fn call(target: &JsValue, method: &str, args: &Array) -> Result<JsValue> {
+    let function: Function = Reflect::get(target, &JsValue::from(method))
+        .map_err(js_error)?
+        .unchecked_into();
+    let promise: Promise = function
+        .apply(target, args)
+        .map_err(js_error)?
+        .unchecked_into();
+    JsFuture::from(promise).await.map_err(js_error)
+}
+
+async fn get_json<T: DeserializeOwned>(bindings: &JsValue, key: &str) -> Result<Option<T>> {
+    let key = JsValue::from(format!("{}{}", scope(bindings), key));
+    let found = call(&bucket(bindings)?, "get", &Array::of1(&key)).await?;
+    if found.is_null() || found.is_undefined() {
+        return Ok(None);
+    }
+    let text = call(&found, "text", &Array::new()).await?;
+    Ok(Some(serde_json::from_str(
+        &text.as_string().unwrap_or_default(),
+    )?))
+}
+
+async fn put_json<T: Serialize>(bindings: &JsValue, key: &str, value: &T) -> Result<()> {
+    let key = JsValue::from(format!("{}{}", scope(bindings), key));
+    let text = JsValue::from(serde_json::to_string(value)?);
+    let content_type = Object::new();
+    Reflect::set(
+        &content_type,
+        &JsValue::from("contentType"),
+        &JsValue::from("application/json"),
+    )
+    .map_err(js_error)?;
+    let options = Object::new();
+    Reflect::set(&options, &JsValue::from("httpMetadata"), &content_type).map_err(js_error)?;
+    call(
+        &bucket(bindings)?,
+        "put",
+        &Array::of3(&key, &text, &options),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Writes a [`Snapshot`] of `env`'s public posts, leaving out quarantined communities as the
+/// feeds do, and answers how many posts it holds.
+pub async fn snapshot(env: &Env, trace: &Trace) -> Result<usize> {
+    let store = storage::posts_in(env)?;
+    let quarantined = communities::quarantined_in(&env.kv(communities::COMMUNITIES_KV)?).await?;
+    let posts: Vec<Post> = posts::list_public(&*store, trace)
+        .await?
+        .into_iter()
+        .filter(|post| {
+            !post
+                .extra
+                .get("community")
+                .and_then(|community| community.as_str())
+                .is_some_and(|community| quarantined.contains(community))
+        })
+        .collect();
+    let withholdings = withholding::all_in(&env.kv(moderation::MODERATION_KV)?).await?;
+    let now = Utc::now();
+    let snapshot = Snapshot {
+        taken_at: now.to_rfc3339(),
+        posts,
+        withholdings,
+    };
+    put_json(env, "posts/latest.json", &snapshot).await?;
+    let dated = format!("posts/{}.json", now.format("%Y-%m-%d"));
+    put_json(env, &dated, &snapshot).await?;
+    Ok(snapshot.posts.len())
+}
+
+fn flag_cache_key(bindings: &JsValue) -> String {
+    tenants::isolate_key(bindings, FLAG_CACHE_KEY)
+}
+
+/// The maintenance flag, if one is set. Read on every request, so kept in the isolate for
+/// [`FLAG_TTL_MS`].
+pub async fn flag(bindings: &JsValue) -> Result<Option<Flag>> {
+    let cache_key = flag_cache_key(bindings);
+    if let Some(flag) = isolate::get::<Option<Flag>>(&cache_key) {
+        return Ok(flag);
+    }
+    let flag = get_json::<Flag>(bindings, "maintenance.json").await?;
+    isolate::put(&cache_key, &flag, FLAG_TTL_MS);
+    Ok(flag)
+}
+
+/// `snapshot`'s posts as `GET /posts` answers them, marked as a copy: `X-Mirror-Snapshot` says
+/// when it was taken and `Age` how long ago, and `Warning` flags it stale.
+fn answer(req: &Request, snapshot: &Snapshot) -> ApiResult<Response> {
+    let mut posts = snapshot
+        .posts
+        .iter()
+        .map(|post| json!(post))
+        .collect::<Vec<_>>();
+    let withheld = Withheld::from_withholdings(req, &snapshot.withholdings);
+    for post in &mut posts {
+        withheld.apply(post);
+    }
+    let enveloped = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "v" && value == "2");
+    let mut res = if enveloped {
+        Response::from_json(&models::PostList::new(&posts))?
+    } else {
+        Response::from_json(&posts)?
+    };
+    let age = DateTime::parse_from_rfc3339(&snapshot.taken_at)
+        .map(|taken_at| {
+            (Utc::now() - taken_at.with_timezone(&Utc))
+                .num_seconds()
+                .max(0)
+        })
+        .unwrap_or_default();
+    let headers = res.headers_mut();
+    headers.set("X-Mirror-Snapshot", &snapshot.taken_at)?;
+    headers.set("Age", &age.to_string())?;
+    headers.set("Warning", "110 - \"Response is Stale\"")?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(res)
+}
+
+/// The check every request goes through while a maintenance flag is set, run by `main` before
+/// routing: writes are refused, and in [`Mode::Mirror`] the feeds are answered from the latest
+/// snapshot. `None` lets the request through. A bucket that can't be read sets no flag, so R2
+/// being down doesn't take the API with it.
+pub async fn intercept(req: &Request, path: &str, env: &Env) -> ApiResult<Option<Response>> {
+    let flag = match flag(env).await {
+        Ok(Some(flag)) => flag,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            console_log!("reading the maintenance flag failed: {}", e);
+            return Ok(None);
+        }
+    };
+    if path == MAINTENANCE_PATH {
+        return Ok(None);
+    }
+    if !matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+        return Err(ApiError::Unavailable(format!(
+            "The instance is read-only for now: {}",
+            flag.reason
+        )));
+    }
+    if flag.mode != Mode::Mirror || !MIRRORED.contains(&path) {
+        return Ok(None);
+    }
+    match get_json::<Snapshot>(env, "posts/latest.json").await? {
+        Some(snapshot) => Ok(Some(answer(req, &snapshot)?)),
+        None => Err(ApiError::Unavailable(format!(
+            "The instance is in maintenance: {}",
+            flag.reason
+        ))),
+    }
+}
+
+/// For a feed whose posts couldn't be read from KV: the latest snapshot in their place, or
+/// `error` when there is none.
+pub async fn fallback(req: &Request, bindings: &JsValue, error: ApiError) -> ApiResult<Response> {
+    console_log!(
+        "serving the posts snapshot, reading posts failed: {}",
+        error
+    );
+    match get_json::<Snapshot>(bindings, "posts/latest.json").await {
+        Ok(Some(snapshot)) => answer(req, &snapshot),
+        Ok(None) => Err(error),
+        Err(e) => {
+            console_log!("reading the posts snapshot failed: {}", e);
+            Err(error)
+        }
+    }
+}
+
+/// `GET /admin/maintenance`: the flag, if one is set, and when the latest snapshot was taken.
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let bindings = ctx.data().bindings();
+    let flag = get_json::<Flag>(bindings, "maintenance.json").await?;
+    let snapshot = get_json::<Snapshot>(bindings, "posts/latest.json").await?;
+    Ok(Response::from_json(&json!({
+        "flag": flag,
+        "snapshot_taken_at": snapshot.map(|snapshot| snapshot.taken_at),
+    }))?)
+}
+
+/// `PUT /admin/maintenance` with `{"mode": "read_only" | "mirror", "reason": "..."}` sets the
+/// maintenance flag; `DELETE` clears it. Other isolates pick it up within [`FLAG_TTL_MS`].
+pub async fn set(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let admin = session::authed(&ctx)?.username;
+    let bindings = ctx.data().bindings();
+    let bucket = bucket(bindings)?;
+    let flag = if req.method() == Method::Delete {
+        let key = JsValue::from(format!("{}maintenance.json", scope(bindings)));
+        call(&bucket, "delete", &Array::of1(&key)).await?;
+        None
+    } else {
+        let body = models::from_body::<NewFlag>(&mut req).await?;
+        let flag = Flag {
+            mode: body.mode,
+            reason: body.reason,
+            set_by: admin.clone(),
+            set_at: Utc::now().to_rfc3339(),
+        };
+        put_json(bindings, "maintenance.json", &flag).await?;
+        Some(flag)
+    };
+    isolate::put(&flag_cache_key(bindings), &flag, FLAG_TTL_MS);
+    console_log!("maintenance: {} set the flag to {:?}", admin, flag);
+    Ok(Response::from_json(&json!({ "flag": flag }))?)
+}
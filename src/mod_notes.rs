@@ -0,0 +1,124 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{moderation, session};
+
+/// Longest note accepted.
+const MAX_NOTE_CHARS: usize = 2000;
+
+/// A moderator's private note on a user, scoped to one community. Only that community's
+/// moderators (and admins) ever see it; the user doesn't.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Note {
+    id: String,
+    username: String,
+    author: String,
+    text: String,
+    created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewNote {
+    text: String,
+}
+
+fn prefix(community: &str, username: &str) -> String {
+    format!("note/{}/{}/", community, username)
+}
+
+/// Notes on `username` in `community`, oldest first.
+pub async fn about(
+    ctx: &RouteContext<Session>,
+    community: &str,
+    username: &str,
+) -> Result<Vec<Note>> {
+    let kv = ctx.kv(moderation::MODERATION_KV)?;
+    let mut notes = vec![];
+    for key in kv
+        .list()
+        .prefix(prefix(community, username))
+        .execute()
+        .await?
+        .keys
+    {
+        if let Some(v) = kv.get(&key.name).await? {
+            notes.push(v.as_json::<Note>()?);
+        }
+    }
+    Ok(notes)
+}
+
+fn params(ctx: &RouteContext<Session>) -> Option<(String, String)> {
+    Some((ctx.param("name")?.clone(), ctx.param("username")?.clone()))
+}
+
+/// `GET /c/:name/users/:username/notes`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (community, username) = match params(&ctx) {
+        Some(params) => params,
+        None => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(
+        &about(&ctx, &community, &username).await?,
+    )?)
+}
+
+/// `POST /c/:name/users/:username/notes`
+pub async fn create(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (community, username) = match params(&ctx) {
+        Some(params) => params,
+        None => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let moderator = session::authed(&ctx)?.username;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let body = match req.json::<NewNote>().await {
+        Ok(body) if !body.text.trim().is_empty() => body,
+        _ => return Err(ApiError::BadRequest("`text` is required".to_string())),
+    };
+    if body.text.chars().count() > MAX_NOTE_CHARS {
+        return Err(ApiError::BadRequest(format!(
+            "a note can be at most {} characters",
+            MAX_NOTE_CHARS
+        )));
+    }
+    let now = Utc::now();
+    let note = Note {
+        id: format!("{:013}", now.timestamp_millis()),
+        username,
+        author: moderator,
+        text: body.text,
+        created_at: now.to_rfc3339(),
+    };
+    ctx.kv(moderation::MODERATION_KV)?
+        .put(
+            &format!("{}{}", prefix(&community, &note.username), note.id),
+            &note,
+        )?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&note)?)
+}
+
+/// `DELETE /c/:name/users/:username/notes/:id`
+pub async fn delete(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let (community, username) = match params(&ctx) {
+        Some(params) => params,
+        None => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let id = error::param(&ctx, "id")?;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    ctx.kv(moderation::MODERATION_KV)?
+        .delete(&format!("{}{}", prefix(&community, &username), id))
+        .await?;
+    Ok(Response::empty()?)
+}
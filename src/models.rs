@@ -0,0 +1,157 @@
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::fmt;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+
+fn non_empty<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value.trim().is_empty() {
+        return Err(D::Error::custom("expected a non-empty string"));
+    }
+    Ok(value)
+}
+
+/// Fields of a stored post that only the worker writes. A new post submitted with any of them has
+/// them dropped, so clients can't pass off likes, crossposts, moderation or the like as their own.
+pub const SERVER_FIELDS: &[&str] = &[
+    "id",
+    "likes",
+    "total_likes",
+    "comment_count",
+    "crossposts",
+    "crosspost_of",
+    "aggregate_counts",
+    "moderation",
+    "deleted_at",
+    "deleted_by",
+    "archived",
+    "pending_co_authors",
+    "withheld",
+    "edited_at",
+    "thread_id",
+    "position",
+    "content_html",
+    "tags",
+    "code_languages",
+    "has_math",
+    "has_spoilers",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub title: String,
+    #[serde(deserialize_with = "non_empty")]
+    pub username: String,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    /// Honeypots: clients leave these out of their forms (or hide them), so only bots fill them
+    /// in. They are never stored; see `bots`.
+    #[serde(default, skip_serializing)]
+    pub website: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub email: Option<String>,
+    /// Everything else stored on the post (likes, archive state, co-authors, crossposts).
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+impl Post {
+    /// Drops the [`SERVER_FIELDS`] a client sent along with a new post.
+    pub fn strip_server_fields(&mut self) {
+        for field in SERVER_FIELDS {
+            self.extra.remove(*field);
+        }
+    }
+
+    /// Whether any honeypot field was filled in.
+    pub fn touched_honeypot(&self) -> bool {
+        [&self.website, &self.email]
+            .iter()
+            .any(|field| field.as_deref().is_some_and(|value| !value.is_empty()))
+    }
+}
+
+impl fmt::Display for Post {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{ \"title\": {}, \"username\": {}, \"content\": {} }}",
+            self.title, self.username, self.content
+        )
+    }
+}
+
+/// Body of `GET /posts?v=2`: the posts, each a [`Post`] object with its `id`, `comment_count`
+/// and whatever else is stored on it, wrapped with the version of that shape. Clients can check
+/// `version` instead of guessing; a change to the shape gets a new one. The unversioned answer
+/// is the bare array, as clients written before the envelope expect.
+#[derive(Serialize, Debug)]
+pub struct PostList<'a> {
+    pub version: u32,
+    pub posts: &'a [Value],
+}
+
+impl<'a> PostList<'a> {
+    pub const VERSION: u32 = 2;
+
+    pub fn new(posts: &'a [Value]) -> PostList<'a> {
+        PostList {
+            version: PostList::VERSION,
+            posts,
+        }
+    }
+}
+
+/// Body of `POST /users`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct User {
+    #[serde(deserialize_with = "non_empty")]
+    pub username: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+/// Reads the request body as a `T`. A body that doesn't fit is a [`ApiError::BadRequest`]
+/// naming the offending field where serde can.
+pub async fn from_body<T: DeserializeOwned>(req: &mut Request) -> ApiResult<T> {
+    let body = req.text().await?;
+    serde_json::from_str(&body).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_server_fields() {
+        let mut post: Post = serde_json::from_value(serde_json::json!({
+            "title": "t",
+            "username": "alice",
+            "content": "c",
+            "community": "rust",
+            "likes": 1000000,
+            "crossposts": [{ "id": "someone-elses-post" }],
+            "moderation": { "status": "approved" },
+        }))
+        .unwrap();
+        post.strip_server_fields();
+        assert!(post.extra.get("likes").is_none());
+        assert!(post.extra.get("crossposts").is_none());
+        assert!(post.extra.get("moderation").is_none());
+        assert_eq!(post.extra.get("community"), Some(&Value::from("rust")));
+    }
+
+    #[test]
+    fn rejects_posts_without_a_username() {
+        let post = serde_json::from_value::<Post>(serde_json::json!({
+            "title": "t",
+            "username": " ",
+            "content": "c",
+        }));
+        assert!(post.is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{admin, communities, firehose, models, reports, session, storage, webhooks};
+
+/// Keys in the `moderation` namespace:
+///
+/// - `case/<author>/<post id>`: the latest moderation decision on one of the author's posts
+/// - `flag/<community>/<post id>`: a post an `automod` rule flagged for the moderators to look at
+/// - `note/<community>/<username>/<millis>`: a moderator's note on a user, see `mod_notes`
+/// - `withhold/<post id>`: the countries a post is withheld in, see `withholding`
+pub const MODERATION_KV: &str = "moderation";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Remove,
+    Hold,
+    Restore,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCode {
+    Spam,
+    Harassment,
+    OffTopic,
+    Illegal,
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct Decision {
+    action: Action,
+    reason_code: Option<ReasonCode>,
+    #[serde(default)]
+    message: String,
+}
+
+/// What the author gets to see. Who made the call is kept out of it.
+#[derive(Serialize, Deserialize, Debug)]
+struct Case {
+    post_id: String,
+    title: String,
+    community: Option<String>,
+    action: Action,
+    reason_code: ReasonCode,
+    message: String,
+    decided_at: String,
+    restored_at: Option<String>,
+}
+
+impl Case {
+    fn new(
+        id: &str,
+        post: &Value,
+        action: Action,
+        reason_code: ReasonCode,
+        message: String,
+    ) -> Case {
+        let field = |name: &str| post.get(name).and_then(Value::as_str).map(String::from);
+        Case {
+            post_id: id.to_string(),
+            title: field("title").unwrap_or_default(),
+            community: field("community"),
+            action,
+            reason_code,
+            message,
+            decided_at: Utc::now().to_rfc3339(),
+            restored_at: None,
+        }
+    }
+}
+
+fn case_key(post: &Value, id: &str) -> String {
+    let author = post
+        .get("username")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    format!("case/{}/{}", author, id)
+}
+
+/// Takes `post` out of listings with a `remove` or `hold`.
+pub fn mark(post: &mut Value, action: Action, reason_code: ReasonCode) {
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.insert(
+            "moderation".to_string(),
+            json!({ "action": action, "reason_code": reason_code }),
+        );
+    }
+}
+
+/// Records a decision that was made without a moderator, such as an automod rule, so the author
+/// sees it in `GET /me/moderation` like any other.
+pub async fn open_case(
+    ctx: &RouteContext<Session>,
+    id: &str,
+    post: &Value,
+    action: Action,
+    reason_code: ReasonCode,
+    message: String,
+) -> Result<()> {
+    let case = Case::new(id, post, action, reason_code, message);
+    ctx.kv(MODERATION_KV)?
+        .put(&case_key(post, id), &case)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Admins can moderate anything and use the `/admin/` routes. They are listed,
+/// comma-separated, in the `ADMINS` var.
+pub fn is_admin(ctx: &RouteContext<Session>, username: &str) -> Result<bool> {
+    Ok(admin::listed(&ctx.var("ADMINS")?.to_string(), username))
+}
+
+/// Whether the signed-in user moderates `community`, or is an admin.
+pub async fn may_moderate(ctx: &RouteContext<Session>, community: &str) -> Result<bool> {
+    Ok(match session::current_user(ctx) {
+        Some(username) => {
+            is_admin(ctx, &username)?
+                || communities::is_moderator(ctx, community, &username).await?
+        }
+        None => false,
+    })
+}
+
+/// `POST /posts/:id/moderation`, for admins and moderators of the post's community.
+///
+/// `remove` and `hold` need a `reason_code`; both take the post out of listings and record a
+/// case its author can read from `GET /me/moderation`. `restore` undoes either. Any decision
+/// clears the post's reports, see `reports`.
+pub async fn decide(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let moderator = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let decision = models::from_body::<Decision>(&mut req).await?;
+    let store = storage::posts(&ctx)?;
+    let mut post: Value = match store.get(&id).await? {
+        Some(stored) => match serde_json::from_str(&stored) {
+            Ok(post) => post,
+            Err(_) => return Err(ApiError::Internal("Stored post is malformed".to_string())),
+        },
+        None => return Err(ApiError::NotFound),
+    };
+    let community = post
+        .get("community")
+        .and_then(Value::as_str)
+        .map(String::from);
+    let allowed = is_admin(&ctx, &moderator)?
+        || match &community {
+            Some(community) => communities::is_moderator(&ctx, community, &moderator).await?,
+            None => false,
+        };
+    if !allowed {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+
+    let cases = ctx.kv(MODERATION_KV)?;
+    let case_key = case_key(&post, &id);
+    let case = if decision.action == Action::Restore {
+        let mut case = match cases.get(&case_key).await? {
+            Some(v) => v.as_json::<Case>()?,
+            None => {
+                return Err(ApiError::Conflict(
+                    "Post has not been moderated".to_string(),
+                ))
+            }
+        };
+        case.restored_at = Some(Utc::now().to_rfc3339());
+        if let Some(post_obj) = post.as_object_mut() {
+            post_obj.remove("moderation");
+        }
+        case
+    } else {
+        let reason_code = match decision.reason_code {
+            Some(reason_code) => reason_code,
+            None => {
+                return Err(ApiError::BadRequest(
+                    "`reason_code` is required".to_string(),
+                ))
+            }
+        };
+        mark(&mut post, decision.action, reason_code);
+        Case::new(&id, &post, decision.action, reason_code, decision.message)
+    };
+    store.put(&id, &post).await?;
+    firehose::post_changed(&ctx, firehose::Kind::Create, &id, &post).await;
+    cases.put(&case_key, &case)?.execute().await?;
+    if let Err(e) = reports::reviewed(&ctx, &id).await {
+        console_log!("clearing reports of post {} failed: {}", id, e);
+    }
+    if let (Action::Hold, Some(community)) = (decision.action, &case.community) {
+        webhooks::notify(&ctx, community, webhooks::Event::ModQueue, &post).await;
+    }
+    console_log!(
+        "moderation: {} {:?} post {} ({:?})",
+        moderator,
+        decision.action,
+        id,
+        case.reason_code
+    );
+    Ok(Response::from_json(&case)?)
+}
+
+/// `GET /me/moderation`: every moderation case on the signed-in user's posts.
+pub async fn mine(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(MODERATION_KV)?;
+    let prefix = format!("case/{}/", username);
+    let mut cases = vec![];
+    for key in kv.list().prefix(prefix).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            cases.push(v.as_json::<Case>()?);
+        }
+    }
+    Ok(Response::from_json(&cases)?)
+}
@@ -0,0 +1,388 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{follows, session, users, utils, validation};
+
+/// Keys in the `notifications` namespace:
+///
+/// - `notification/<username>/<id>`: a [`Notification`] for `username`, where `id` is
+///   `<millis, zero-padded>-<hash of what caused it>` so keys sort oldest first
+/// - `group/<username>/<kind>/<hash of target>`: the id of the unread notification of `kind`
+///   about that post or comment that others of the same kind are collapsed into, kept
+///   [`COLLAPSE_WINDOW`] seconds
+/// - `group/<username>/<kind>/<hash of target>/<from>`: `from` is counted in that notification
+///   already, kept as long
+const NOTIFICATIONS_KV: &str = "notifications";
+
+/// Notifications of the same kind about the same post or comment within this many seconds of
+/// the first one are collapsed into it ("alice and 12 others liked your post").
+const COLLAPSE_WINDOW: u64 = 60 * 60;
+
+/// Mentions past this many in one post or comment don't notify anyone.
+const MAX_MENTIONS: usize = 10;
+
+const EXCERPT_CHARS: usize = 140;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Notification {
+    id: String,
+    kind: String,
+    /// Who caused it, e.g. the author of the mentioning post.
+    from: String,
+    post_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment_id: Option<String>,
+    excerpt: String,
+    created_at: String,
+    #[serde(default)]
+    read: bool,
+    /// How many others did the same as `from` since, collapsed into this notification.
+    #[serde(default)]
+    others: u64,
+    /// For `like_milestone`, the number of likes the post reached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    milestone: Option<i64>,
+    /// The notification in words, e.g. "alice and 12 others liked your post".
+    #[serde(default)]
+    summary: String,
+}
+
+/// Something that happened to a user's post or comment, for [`notify`].
+pub struct Event<'a> {
+    /// `mention`, `like`, `like_milestone` or `co_author_invite`.
+    pub kind: &'a str,
+    /// Who caused it; empty for milestones, which nobody in particular did.
+    pub from: &'a str,
+    pub post_id: &'a str,
+    pub comment_id: Option<&'a str>,
+    pub excerpt: &'a str,
+    pub milestone: Option<i64>,
+}
+
+/// What `notification` says, counting those collapsed into it.
+fn summary(notification: &Notification) -> String {
+    if let Some(likes) = notification.milestone {
+        return format!("Your post reached {} likes", likes);
+    }
+    let who = match notification.others {
+        0 => notification.from.clone(),
+        1 => format!("{} and 1 other", notification.from),
+        n => format!("{} and {} others", notification.from, n),
+    };
+    let what = match notification.kind.as_str() {
+        "mention" => "mentioned you",
+        "like" => "liked your post",
+        "co_author_invite" => "invited you to co-author a post",
+        kind => kind,
+    };
+    format!("{} {}", who, what)
+}
+
+/// The usernames `@mentioned` in `text`, without duplicates, in order of appearance. A mention
+/// has to follow the start of the text or a character that isn't part of a word, so
+/// `someone@example.com` mentions nobody; a trailing `.` ends the sentence, not the username.
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    let mut previous = ' ';
+    for (at, c) in text.char_indices() {
+        let starts = c == '@' && !previous.is_alphanumeric() && !"@_.-".contains(previous);
+        previous = c;
+        if !starts {
+            continue;
+        }
+        let rest = &text[at + 1..];
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+        let name = rest[..rest.find(|c| !allowed(c)).unwrap_or(rest.len())].trim_end_matches('.');
+        if validation::username(name).is_ok()
+            && !found.iter().any(|seen| seen == name)
+            && found.len() < MAX_MENTIONS
+        {
+            found.push(name.to_string());
+        }
+    }
+    found
+}
+
+async fn deliver(kv: &kv::KvStore, username: &str, event: &Event<'_>) -> Result<()> {
+    // Each milestone is a target of its own, so reaching 100 likes isn't collapsed into 10.
+    let target = match event.milestone {
+        Some(likes) => format!("{}#{}", event.post_id, likes),
+        None => event.comment_id.unwrap_or(event.post_id).to_string(),
+    };
+    let group = format!(
+        "group/{}/{}/{}",
+        username,
+        event.kind,
+        &utils::sha256_hex(&target)[..16]
+    );
+    let counted = format!("{}/{}", group, event.from);
+    if kv.get(&counted).await?.is_some() {
+        return Ok(());
+    }
+
+    let collapsed = match kv.get(&group).await? {
+        Some(id) => kv
+            .get(&format!("notification/{}/{}", username, id.as_string()))
+            .await?
+            .map(|v| v.as_json::<Notification>())
+            .transpose()?
+            .filter(|notification| !notification.read),
+        None => None,
+    };
+    let notification = match collapsed {
+        Some(mut notification) => {
+            notification.others += 1;
+            notification.from = event.from.to_string();
+            notification.excerpt = event.excerpt.chars().take(EXCERPT_CHARS).collect();
+            notification
+        }
+        None => {
+            let now = Utc::now();
+            let id = format!(
+                "{:013}-{}",
+                now.timestamp_millis(),
+                &utils::sha256_hex(&target)[..16]
+            );
+            kv.put(&group, id.as_str())?
+                .expiration_ttl(COLLAPSE_WINDOW)
+                .execute()
+                .await?;
+            Notification {
+                id,
+                kind: event.kind.to_string(),
+                from: event.from.to_string(),
+                post_id: event.post_id.to_string(),
+                comment_id: event.comment_id.map(String::from),
+                excerpt: event.excerpt.chars().take(EXCERPT_CHARS).collect(),
+                created_at: now.to_rfc3339(),
+                read: false,
+                others: 0,
+                milestone: event.milestone,
+                summary: String::new(),
+            }
+        }
+    };
+    let notification = Notification {
+        summary: summary(&notification),
+        ..notification
+    };
+    kv.put(
+        &format!("notification/{}/{}", username, notification.id),
+        &notification,
+    )?
+    .execute()
+    .await?;
+    kv.put(&counted, "")?
+        .expiration_ttl(COLLAPSE_WINDOW)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Notifies `username` of `event`, unless they caused it themselves or block or mute whoever
+/// did. An event of the same kind about the same post or comment as an unread notification of
+/// the last [`COLLAPSE_WINDOW`] seconds is collapsed into it rather than stored on its own.
+/// Failures are logged, never returned.
+pub async fn notify(ctx: &RouteContext<Session>, username: &str, event: Event<'_>) {
+    let delivered = async {
+        if username == event.from
+            || follows::filters(&ctx.kv(follows::FOLLOWS_KV)?, username, event.from).await?
+        {
+            return Ok(());
+        }
+        deliver(&ctx.kv(NOTIFICATIONS_KV)?, username, &event).await
+    };
+    if let Err(e) = delivered.await {
+        console_log!("{} notification for {} failed: {}", event.kind, username, e);
+    }
+}
+
+async fn notify_mentions(
+    ctx: &RouteContext<Session>,
+    post_id: &str,
+    comment_id: Option<&str>,
+    written: &Value,
+) -> Result<()> {
+    let field = |name: &str| written.get(name).and_then(Value::as_str).unwrap_or("");
+    let author = field("username");
+    let users = ctx.kv(users::USERS_KV)?;
+    let follows = ctx.kv(follows::FOLLOWS_KV)?;
+    let kv = ctx.kv(NOTIFICATIONS_KV)?;
+    for username in mentions(&format!("{}\n{}", field("title"), field("content"))) {
+        if username == author
+            || !users::exists(&users, &username).await?
+            || follows::filters(&follows, &username, author).await?
+        {
+            continue;
+        }
+        let event = Event {
+            kind: "mention",
+            from: author,
+            post_id,
+            comment_id,
+            excerpt: field("content"),
+            milestone: None,
+        };
+        deliver(&kv, &username, &event).await?;
+    }
+    Ok(())
+}
+
+/// Notifies every existing user `@mentioned` in a new post or comment, except its author and
+/// those who block or mute the author.
+/// `written` is the post or comment as stored; `comment_id` is set for comments. Failures are
+/// logged, never returned.
+pub async fn mentioned(
+    ctx: &RouteContext<Session>,
+    post_id: &str,
+    comment_id: Option<&str>,
+    written: &Value,
+) {
+    if let Err(e) = notify_mentions(ctx, post_id, comment_id, written).await {
+        console_log!(
+            "mention notifications for {} failed: {}",
+            comment_id.unwrap_or(post_id),
+            e
+        );
+    }
+}
+
+/// Tells everyone invited to co-author a new post (its `pending_co_authors`) that they were,
+/// so they can accept or decline through `POST /posts/:id/co_authors/:action`. Failures are
+/// logged, never returned.
+pub async fn invited(ctx: &RouteContext<Session>, post_id: &str, post: &Value) {
+    let author = post.get("username").and_then(Value::as_str).unwrap_or("");
+    let invitees = post
+        .get("pending_co_authors")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str);
+    for invitee in invitees {
+        match ctx.kv(users::USERS_KV) {
+            Ok(users) if users::exists(&users, invitee).await.unwrap_or(false) => {}
+            _ => continue,
+        }
+        let event = Event {
+            kind: "co_author_invite",
+            from: author,
+            post_id,
+            comment_id: None,
+            excerpt: post.get("title").and_then(Value::as_str).unwrap_or(""),
+            milestone: None,
+        };
+        notify(ctx, invitee, event).await;
+    }
+}
+
+async fn load_all(kv: &kv::KvStore, username: &str) -> Result<Vec<Notification>> {
+    let keys = kv
+        .list()
+        .prefix(format!("notification/{}/", username))
+        .execute()
+        .await?
+        .keys;
+    let mut notifications = vec![];
+    for key in keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            notifications.push(v.as_json::<Notification>()?);
+        }
+    }
+    Ok(notifications)
+}
+
+/// `GET /notifications[?unread=true]`, newest first.
+pub async fn list(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let unread_only = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "unread" && value == "true");
+    let mut notifications = load_all(&ctx.kv(NOTIFICATIONS_KV)?, &username).await?;
+    notifications.retain(|notification| !unread_only || !notification.read);
+    notifications.reverse();
+    Ok(Response::from_json(&notifications)?)
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct MarkRead {
+    /// The notifications to mark; all of them when left out.
+    #[serde(default)]
+    ids: Option<Vec<String>>,
+}
+
+/// `POST /notifications/read`, with `{"ids": [...]}` or an empty body for all of them.
+pub async fn mark_read(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let text = req.text().await?;
+    let body = if text.trim().is_empty() {
+        MarkRead::default()
+    } else {
+        serde_json::from_str::<MarkRead>(&text).map_err(|e| ApiError::BadRequest(e.to_string()))?
+    };
+    let kv = ctx.kv(NOTIFICATIONS_KV)?;
+    let mut marked = 0;
+    for mut notification in load_all(&kv, &username).await? {
+        let wanted = match &body.ids {
+            Some(ids) => ids.contains(&notification.id),
+            None => true,
+        };
+        if notification.read || !wanted {
+            continue;
+        }
+        notification.read = true;
+        kv.put(
+            &format!("notification/{}/{}", username, notification.id),
+            &notification,
+        )?
+        .execute()
+        .await?;
+        marked += 1;
+    }
+    Ok(Response::from_json(&json!({ "marked": marked }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(kind: &str, from: &str, others: u64, milestone: Option<i64>) -> Notification {
+        Notification {
+            id: "0000000000000-0".to_string(),
+            kind: kind.to_string(),
+            from: from.to_string(),
+            post_id: "p".to_string(),
+            comment_id: None,
+            excerpt: String::new(),
+            created_at: String::new(),
+            read: false,
+            others,
+            milestone,
+            summary: String::new(),
+        }
+    }
+
+    #[test]
+    fn summaries_count_the_others() {
+        let like = |others| summary(&notification("like", "alice", others, None));
+        assert_eq!(like(0), "alice liked your post");
+        assert_eq!(like(1), "alice and 1 other liked your post");
+        assert_eq!(like(12), "alice and 12 others liked your post");
+        let mention = notification("mention", "bob", 0, None);
+        assert_eq!(summary(&mention), "bob mentioned you");
+        let milestone = notification("like_milestone", "", 0, Some(100));
+        assert_eq!(summary(&milestone), "Your post reached 100 likes");
+    }
+
+    #[test]
+    fn mentions_skip_emails_and_duplicates() {
+        assert_eq!(
+            mentions("hi @alice, @bob. mail someone@example.com or @alice"),
+            ["alice", "bob"]
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use serde_json::{json, Map, Value};
+use worker::*;
+
+use crate::error::ApiResult;
+use crate::session::Session;
+use crate::{apikeys, session};
+
+/// A route as the spec describes it. `body` and `response` name schemas in [`schemas`]; routes
+/// without one take no body, or answer something not worth a schema of its own.
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    body: Option<&'static str>,
+    response: Option<&'static str>,
+}
+
+const fn op(
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    body: Option<&'static str>,
+    response: Option<&'static str>,
+) -> Operation {
+    Operation {
+        method,
+        path,
+        summary,
+        body,
+        response,
+    }
+}
+
+/// Every route of the API, as registered in `main`; keep the two in sync. The decoys (see
+/// `bots`), CORS preflights and the worker template's `/` and `/form/:field` are left out.
+#[rustfmt::skip]
+const OPERATIONS: &[Operation] = &[
+    op("get", "/worker-version", "Version of the worker runtime", None, None),
+    op("get", "/posts", "Public posts, newest first; `?v=2` wraps them in a versioned envelope", None, Some("PostList")),
+    op("post", "/posts", "Creates a post, registering a new username on its first post", Some("NewPost"), Some("Post")),
+    op("post", "/posts/bulk_delete", "Deletes many of your posts, or continues doing so", Some("BulkDelete"), Some("BulkDeleteProgress")),
+    op("get", "/posts/bulk_delete/:job", "How far a bulk delete got", None, Some("BulkDeleteProgress")),
+    op("get", "/posts/:id", "A post", None, Some("Post")),
+    op("put", "/posts/:id", "Edits a post's title or content", Some("PostEdit"), Some("Post")),
+    op("delete", "/posts/:id", "Deletes a post; it can be restored for a while", None, None),
+    op("put", "/posts/:id/archive", "Archives or unarchives one of your posts", Some("ArchiveToggle"), Some("Post")),
+    op("post", "/posts/:id/restore", "Restores a deleted post", None, Some("Post")),
+    op("post", "/posts/:id/like", "Likes a post", None, None),
+    op("post", "/posts/:id/unlike", "Takes a like back", None, None),
+    op("get", "/posts/:id/comments", "Comments on a post", None, Some("CommentList")),
+    op("post", "/posts/:id/comments", "Comments on a post", Some("NewComment"), Some("Comment")),
+    op("delete", "/comments/:id", "Deletes a comment", None, None),
+    op("post", "/comments/:id/like", "Likes a comment", None, None),
+    op("post", "/comments/:id/unlike", "Takes a like on a comment back", None, None),
+    op("post", "/posts/:id/co_authors/:action", "Accepts or declines your invitation to co-author", None, Some("Post")),
+    op("post", "/posts/:id/crosspost", "Crossposts one of your posts into another community", None, Some("Post")),
+    op("post", "/posts/:id/moderation", "Removes, holds or restores a post", None, Some("Post")),
+    op("post", "/posts/:id/report", "Reports a post to the admins", None, None),
+    op("get", "/posts/:id/share_link", "A token to share a post with, crediting signups through it", None, None),
+    op("get", "/me/moderation", "Moderation applied to your posts", None, None),
+    op("get", "/me/referrals", "Who signed up through your referral link", None, None),
+    op("get", "/me/filters", "Who you block and mute", None, None),
+    op("get", "/me/archived", "Your archived posts", None, Some("PostArray")),
+    op("get", "/me/profile_views", "Who looked at your profile lately, if you opted in", None, None),
+    op("post", "/threads", "Creates a thread of posts", None, None),
+    op("get", "/threads/:id", "A thread, in order", None, None),
+    op("post", "/media", "Uploads an image, as a multipart form or the raw body", None, Some("Media")),
+    op("get", "/media/:id", "An uploaded image", None, None),
+    op("get", "/notifications", "Your notifications", None, None),
+    op("post", "/notifications/read", "Marks notifications read, all of them without `ids`", None, None),
+    op("get", "/search", "Searches public posts", None, Some("PostArray")),
+    op("get", "/tags/trending", "Trending tags", None, None),
+    op("get", "/tags/:tag", "Posts with a tag", None, Some("PostArray")),
+    op("post", "/searches", "Saves a search to be alerted about", None, None),
+    op("get", "/searches", "Your saved searches", None, None),
+    op("get", "/searches/alerts", "New posts matching your saved searches", None, None),
+    op("delete", "/searches/:id", "Deletes a saved search", None, None),
+    op("get", "/c/:name", "A community", None, None),
+    op("post", "/c/:name", "Founds a community", None, None),
+    op("post", "/c/:name/join", "Joins a community", None, None),
+    op("delete", "/c/:name/join", "Leaves a community", None, None),
+    op("put", "/c/:name/tags", "Sets a community's category tags", None, None),
+    op("get", "/c/:name/webhooks", "A community's outgoing webhooks", None, None),
+    op("put", "/c/:name/webhooks", "Replaces a community's outgoing webhooks", None, None),
+    op("get", "/c/:name/automod", "A community's automod rules", None, None),
+    op("put", "/c/:name/automod", "Replaces a community's automod rules", None, None),
+    op("get", "/c/:name/automod/flags", "Posts automod flagged", None, None),
+    op("delete", "/c/:name/automod/flags/:id", "Dismisses a flag", None, None),
+    op("get", "/c/:name/users/:username/notes", "Moderators' notes on a member", None, None),
+    op("post", "/c/:name/users/:username/notes", "Adds a note on a member", None, None),
+    op("delete", "/c/:name/users/:username/notes/:id", "Deletes a note", None, None),
+    op("get", "/c/:name/posts", "A community's posts, newest first", None, Some("PostArray")),
+    op("put", "/c/:name/quarantine", "Quarantines a community", None, None),
+    op("delete", "/c/:name/quarantine", "Lifts a quarantine", None, None),
+    op("get", "/c/:name/templates", "A community's post templates", None, None),
+    op("put", "/c/:name/templates", "Replaces a community's post templates", None, None),
+    op("get", "/c/:name/domain", "The domain claimed for a community", None, Some("Domain")),
+    op("put", "/c/:name/domain", "Claims a domain for a community", Some("DomainClaim"), Some("Domain")),
+    op("delete", "/c/:name/domain", "Gives up a community's domain", None, None),
+    op("post", "/c/:name/domain/verify", "Checks the domain's TXT record", None, Some("Domain")),
+    op("get", "/communities/discover", "Growing communities", None, None),
+    op("get", "/about/stats", "Numbers about the instance", None, None),
+    op("get", "/branding", "The instance's name, logo and colors", None, Some("Branding")),
+    op("get", "/.well-known/nodeinfo", "Where the nodeinfo document is", None, None),
+    op("get", "/nodeinfo/2.0", "The nodeinfo document", None, None),
+    op("get", "/feed", "Posts of the communities you joined", None, Some("PostArray")),
+    op("get", "/feed/global", "The newest posts across the instance", None, Some("PostArray")),
+    op("get", "/feed/for_you", "Posts picked for you", None, Some("PostArray")),
+    op("get", "/explore", "Recent posts with media, most engaged with first", None, Some("PostArray")),
+    op("get", "/ws", "Live updates over a WebSocket", None, None),
+    op("get", "/events", "Live updates as server-sent events", None, None),
+    op("get", "/api/v1/accounts/verify_credentials", "Mastodon API: who you are", None, None),
+    op("get", "/api/v1/timelines/home", "Mastodon API: your home timeline", None, None),
+    op("post", "/api/v1/statuses", "Mastodon API: posts a status", None, None),
+    op("get", "/api/v1/statuses/:id", "Mastodon API: a status", None, None),
+    op("get", "/firehose", "Changes to posts as NDJSON, streamed with `stream=true` or over a WebSocket, for `firehose` API keys", None, None),
+    op("get", "/triggers/me", "Automation: tests an API key", None, None),
+    op("get", "/triggers/new_posts", "Automation: new posts, for polling", None, Some("PostArray")),
+    op("post", "/actions/create_post", "Automation: creates a post", None, Some("Post")),
+    op("post", "/bot/digest", "Posts a roundup of the day's most-liked posts", None, None),
+    op("post", "/bot/rss", "Polls the RSS feeds", None, None),
+    op("post", "/bot/retention", "Runs the retention sweep", None, None),
+    op("post", "/bot/purge", "Purges posts deleted long enough ago", None, None),
+    op("post", "/admin/api_keys", "Issues an API key", None, None),
+    op("delete", "/admin/api_keys/:id", "Revokes an API key", None, None),
+    op("post", "/admin/service_accounts", "Creates a service account", None, None),
+    op("get", "/admin/service_accounts", "Service accounts", None, None),
+    op("post", "/admin/rss_feeds", "Adds an RSS feed to post from", None, None),
+    op("get", "/admin/rss_feeds", "RSS feeds posted from", None, None),
+    op("delete", "/admin/rss_feeds/:id", "Stops posting from an RSS feed", None, None),
+    op("get", "/admin/signup_limits", "Signup limits", None, None),
+    op("put", "/admin/signup_limits", "Changes the signup limits", None, None),
+    op("get", "/admin/branding", "The instance's branding", None, Some("Branding")),
+    op("put", "/admin/branding", "Replaces the instance's branding", Some("Branding"), Some("Branding")),
+    op("get", "/admin/maintenance", "The maintenance flag and the latest posts snapshot", None, None),
+    op("put", "/admin/maintenance", "Makes the instance read-only, or serves the feeds from a snapshot", None, None),
+    op("delete", "/admin/maintenance", "Clears the maintenance flag", None, None),
+    op("post", "/admin/storage/migrate", "Copies the next posts from KV into D1", None, None),
+    op("delete", "/admin/posts/:id", "Deletes anyone's post", None, None),
+    op("post", "/admin/users/:username/ban", "Bans a user", None, None),
+    op("delete", "/admin/users/:username/ban", "Lifts a ban", None, None),
+    op("get", "/admin/stats", "Numbers for admins", None, None),
+    op("get", "/admin/reports", "Reported posts", None, None),
+    op("post", "/admin/journal/replay", "Replays the journal into a target", None, None),
+    op("get", "/admin/tenants", "Tenants", None, None),
+    op("put", "/admin/tenants/:id", "Provisions or changes a tenant", None, None),
+    op("delete", "/admin/tenants/:id", "Stops serving a tenant", None, None),
+    op("get", "/admin/queue_stats", "Queue redeliveries skipped", None, None),
+    op("get", "/admin/dead_letters", "Queue messages that kept failing", None, None),
+    op("post", "/admin/dead_letters/:id/replay", "Queues a dead letter again", None, None),
+    op("delete", "/admin/dead_letters/:id", "Drops a dead letter", None, None),
+    op("get", "/admin/withholdings", "Posts withheld by country", None, None),
+    op("put", "/admin/withholdings/:id", "Withholds a post in some countries", None, None),
+    op("delete", "/admin/withholdings/:id", "Stops withholding a post", None, None),
+    op("post", "/admin/surveys", "Creates a survey", None, None),
+    op("get", "/surveys", "Open surveys", None, None),
+    op("get", "/surveys/:id", "A survey", None, None),
+    op("post", "/surveys/:id/responses", "Answers a survey", None, None),
+    op("get", "/surveys/:id/results", "A survey's results", None, None),
+    op("get", "/settings/languages", "Languages you read", None, None),
+    op("put", "/settings/languages", "Sets the languages you read", None, None),
+    op("get", "/settings/retention", "Whether your posts are kept from the retention sweep", None, None),
+    op("put", "/settings/retention", "Keeps your posts from the retention sweep, or not", None, None),
+    op("get", "/settings/profile_views", "Whether profile views are recorded and shown to you", None, None),
+    op("put", "/settings/profile_views", "Opts in or out of profile views", None, None),
+    op("put", "/drafts/:id/autosave", "Saves one of your drafts", None, None),
+    op("get", "/drafts/:id/revisions", "One of your drafts' revisions", None, None),
+    op("get", "/users", "Usernames", None, None),
+    op("post", "/users", "Registers a user", Some("NewUser"), None),
+    op("get", "/users/:username", "A profile", None, Some("Profile")),
+    op("patch", "/users/:username", "Edits your profile", None, Some("Profile")),
+    op("post", "/users/:username/follow", "Follows a user", None, None),
+    op("delete", "/users/:username/follow", "Unfollows a user", None, None),
+    op("post", "/users/:username/block", "Blocks a user", None, None),
+    op("delete", "/users/:username/block", "Unblocks a user", None, None),
+    op("post", "/users/:username/mute", "Mutes a user", None, None),
+    op("delete", "/users/:username/mute", "Unmutes a user", None, None),
+    op("get", "/users/:username/followers", "Who follows a user, flagging those followed back as `mutual`", None, None),
+    op("get", "/users/:username/following", "Whom a user follows, flagging those who follow back as `mutual`", None, None),
+    op("get", "/users/:username/mutuals", "Those a user follows who follow them back", None, None),
+    op("get", "/users/:username/activity", "A user's posts and comments per day over the last year", None, None),
+    op("get", "/users/:username/atproto-export", "A user's posts as AT Protocol records", None, None),
+    op("get", "/users/:username/domain", "The domain claimed for your profile", None, Some("Domain")),
+    op("put", "/users/:username/domain", "Claims a domain for your profile", Some("DomainClaim"), Some("Domain")),
+    op("delete", "/users/:username/domain", "Gives up your profile's domain", None, None),
+    op("post", "/users/:username/domain/verify", "Checks the domain's TXT record", None, Some("Domain")),
+    op("get", "/openapi.json", "This document", None, None),
+    op("get", "/docs", "This document, browsable", None, None),
+];
+
+/// The payload shapes routes refer to by name.
+fn schemas() -> Value {
+    let string = json!({ "type": "string" });
+    let time = json!({ "type": "string", "format": "date-time" });
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["error", "code"],
+            "properties": {
+                "error": string,
+                "code": { "type": "integer" },
+                "trace_id": string,
+                "fields": {
+                    "type": "object",
+                    "description": "What is wrong per field, for a 400 about the body",
+                    "additionalProperties": string,
+                },
+            },
+        },
+        "NewPost": {
+            "type": "object",
+            "required": ["title", "username", "content"],
+            "properties": {
+                "title": string,
+                "username": string,
+                "content": { "type": "string", "description": "Markdown" },
+                "community": string,
+                "media": { "type": "array", "items": string, "description": "Media ids" },
+                "license": string,
+                "lang": string,
+            },
+        },
+        "Post": {
+            "type": "object",
+            "required": ["id", "title", "username", "content"],
+            "additionalProperties": true,
+            "properties": {
+                "id": string,
+                "title": string,
+                "username": string,
+                "content": string,
+                "html": { "type": "string", "description": "`content` rendered" },
+                "time": time,
+                "likes": { "type": "integer" },
+                "community": string,
+                "media": { "type": "array", "items": string },
+                "license": string,
+                "lang": string,
+                "archived": { "type": "boolean" },
+                "comment_count": { "type": "integer" },
+            },
+        },
+        "PostArray": { "type": "array", "items": { "$ref": "#/components/schemas/Post" } },
+        "PostList": {
+            "type": "object",
+            "required": ["version", "posts"],
+            "properties": {
+                "version": { "type": "integer", "enum": [2] },
+                "posts": { "$ref": "#/components/schemas/PostArray" },
+            },
+        },
+        "PostEdit": {
+            "type": "object",
+            "properties": { "title": string, "content": string },
+        },
+        "ArchiveToggle": {
+            "type": "object",
+            "required": ["archived"],
+            "properties": { "archived": { "type": "boolean" } },
+        },
+        "NewComment": {
+            "type": "object",
+            "required": ["content"],
+            "properties": { "content": string, "parent_id": string },
+        },
+        "Comment": {
+            "type": "object",
+            "additionalProperties": true,
+            "properties": {
+                "id": string,
+                "username": string,
+                "content": string,
+                "parent_id": string,
+                "likes": { "type": "integer" },
+                "time": time,
+            },
+        },
+        "CommentList": { "type": "array", "items": { "$ref": "#/components/schemas/Comment" } },
+        "BulkDelete": {
+            "type": "object",
+            "properties": {
+                "ids": { "type": "array", "items": string },
+                "continuation": string,
+            },
+        },
+        "BulkDeleteProgress": {
+            "type": "object",
+            "properties": {
+                "id": string,
+                "deleted": { "type": "array", "items": string },
+                "failed": { "type": "array", "items": { "type": "object" } },
+                "remaining": { "type": "integer" },
+                "continuation": { "type": "string", "nullable": true },
+                "queued": { "type": "boolean" },
+            },
+        },
+        "Media": {
+            "type": "object",
+            "properties": {
+                "media_id": string,
+                "url": string,
+                "content_type": string,
+                "size": { "type": "integer" },
+            },
+        },
+        "NewUser": {
+            "type": "object",
+            "required": ["username"],
+            "additionalProperties": true,
+            "properties": { "username": string },
+        },
+        "Profile": {
+            "type": "object",
+            "properties": {
+                "username": string,
+                "display_name": string,
+                "bio": string,
+                "avatar_url": string,
+                "created_at": time,
+                "post_count": { "type": "integer" },
+            },
+        },
+        "Branding": {
+            "type": "object",
+            "properties": {
+                "name": string,
+                "logo": { "type": "string", "description": "Media id" },
+                "logo_url": { "type": "string", "readOnly": true },
+                "colors": {
+                    "type": "object",
+                    "properties": {
+                        "primary": string,
+                        "accent": string,
+                        "background": string,
+                        "text": string,
+                    },
+                },
+                "updated_at": { "type": "string", "format": "date-time", "readOnly": true },
+            },
+        },
+        "DomainClaim": {
+            "type": "object",
+            "required": ["host"],
+            "properties": { "host": string },
+        },
+        "Domain": {
+            "type": "object",
+            "properties": {
+                "host": string,
+                "verified": { "type": "boolean" },
+                "verified_at": time,
+                "txt_record": string,
+                "txt_value": string,
+                "created_at": time,
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// `/posts/:id` -> `/posts/{id}`, with its path parameters.
+fn templated(path: &str) -> (String, Vec<Value>) {
+    let mut parameters = vec![];
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => {
+                parameters.push(json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }));
+                format!("{{{}}}", name)
+            }
+            None => segment.to_string(),
+        })
+        .collect();
+    (segments.join("/"), parameters)
+}
+
+fn operation(route: &Operation) -> Value {
+    let (_, parameters) = templated(route.path);
+    let tag = route
+        .path
+        .split('/')
+        .find(|segment| !segment.is_empty())
+        .unwrap_or("root");
+    let content = |schema: Value| json!({ "application/json": { "schema": schema } });
+    let ok = match route.response {
+        Some(name) => json!({ "description": "OK", "content": content(schema_ref(name)) }),
+        None => json!({ "description": "OK" }),
+    };
+    let mut operation = json!({
+        "summary": route.summary,
+        "tags": [tag],
+        "parameters": parameters,
+        "responses": {
+            "200": ok,
+            "default": { "description": "An error", "content": content(schema_ref("Error")) },
+        },
+    });
+    if let (Some(name), Some(operation_obj)) = (route.body, operation.as_object_mut()) {
+        operation_obj.insert(
+            "requestBody".to_string(),
+            json!({ "required": true, "content": content(schema_ref(name)) }),
+        );
+    }
+    operation
+}
+
+/// The OpenAPI 3.0 document for the API, served from `origin`.
+fn document(origin: &str) -> Value {
+    let mut paths = Map::new();
+    for route in OPERATIONS {
+        let (path, _) = templated(route.path);
+        if let Some(item) = paths
+            .entry(path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+        {
+            item.insert(route.method.to_string(), operation(route));
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Routes under `/admin/` are for the admins listed in `ADMINS`. \
+                Request bodies are JSON unless a route says otherwise.",
+        },
+        "servers": [{ "url": origin }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": session::COOKIE_NAME },
+                "apiKey": { "type": "apiKey", "in": "header", "name": apikeys::HEADER },
+            },
+        },
+        "security": [{}, { "session": [] }, { "apiKey": [] }],
+    })
+}
+
+/// `GET /openapi.json`: the OpenAPI document, for client generators and `GET /docs`.
+pub async fn spec(req: Request, _ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let url = req.url()?;
+    let origin = url.origin().ascii_serialization();
+    Ok(Response::from_json(&document(&origin))?)
+}
+
+/// Swagger UI's release the docs page loads, from its CDN.
+const SWAGGER_UI: &str = "https://unpkg.com/swagger-ui-dist@5";
+
+/// `GET /docs`: Swagger UI over `GET /openapi.json`.
+pub async fn docs(_req: Request, _ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let html = format!(
+        r##"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{name} API</title>
+<link rel="stylesheet" href="{ui}/swagger-ui.css">
+</head>
+<body>
+<div id="docs"></div>
+<script src="{ui}/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: "/openapi.json", dom_id: "#docs" }});</script>
+</body>
+</html>
+"##,
+        name = env!("CARGO_PKG_NAME"),
+        ui = SWAGGER_UI,
+    );
+    Ok(Response::from_html(html)?)
+}
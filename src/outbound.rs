@@ -0,0 +1,217 @@
+use chrono::Utc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use worker::*;
+
+use crate::trace::{Span, Trace};
+
+/// Limits applied to a fetch the worker makes on someone else's behalf.
+pub struct Policy {
+    /// Hosts (and their subdomains) this destination may reach. Empty allows any public host.
+    pub allowed_hosts: Vec<String>,
+    pub max_redirects: u8,
+    pub max_response_bytes: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            allowed_hosts: vec![],
+            max_redirects: 3,
+            max_response_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Policy {
+    /// A policy that only lets requests through to `host`.
+    pub fn allow_only(host: &str) -> Self {
+        Policy {
+            allowed_hosts: vec![host.to_ascii_lowercase()],
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Fetched {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_unspecified()
+        || ip.is_documentation()
+        || a == 0
+        // carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_private_v4(v4);
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// Rejects anything but plain http(s) to a host permitted by `policy`. Private and loopback
+/// destinations are refused unless the operator listed them explicitly. Workers cannot resolve
+/// DNS up front, so hostnames are judged by name and IP literals by range.
+pub fn check_destination(url: &Url, policy: &Policy) -> Result<()> {
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err(format!("blocked outbound fetch with scheme `{}`", url.scheme()).into());
+    }
+    let host = match url.host_str() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase(),
+        None => return Err("blocked outbound fetch without a host".into()),
+    };
+    let listed = policy
+        .allowed_hosts
+        .iter()
+        .any(|allowed| host == *allowed || host.ends_with(&format!(".{}", allowed)));
+    if listed {
+        return Ok(());
+    }
+    if !policy.allowed_hosts.is_empty() {
+        return Err(format!("blocked outbound fetch to `{}`: not on the allowlist", host).into());
+    }
+    let private = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => is_private_v4(ip),
+        Ok(IpAddr::V6(ip)) => is_private_v6(ip),
+        Err(_) => {
+            host == "localhost"
+                || host.ends_with(".localhost")
+                || host.ends_with(".local")
+                || host.ends_with(".internal")
+        }
+    };
+    if private {
+        return Err(format!("blocked outbound fetch to private host `{}`", host).into());
+    }
+    Ok(())
+}
+
+/// Records a finished fetch as a span of `trace`, and its time under `fetch`.
+fn record(trace: &Trace, url: &str, started_at: i64, fetched: &Result<Fetched>) {
+    trace.time("fetch", started_at);
+    trace.record(Span {
+        host: Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_default(),
+        duration_ms: Utc::now().timestamp_millis() - started_at,
+        status: fetched.as_ref().ok().map(|fetched| fetched.status),
+    });
+}
+
+/// `POST`s a JSON body to `url` under `policy`, as part of `trace`. Redirects are not followed:
+/// a webhook that answers with one is treated as having answered.
+pub async fn post_json(
+    url: &str,
+    body: &serde_json::Value,
+    policy: &Policy,
+    trace: &Trace,
+) -> Result<Fetched> {
+    let started_at = Utc::now().timestamp_millis();
+    let fetched = send_json(url, body, policy, trace).await;
+    record(trace, url, started_at, &fetched);
+    fetched
+}
+
+async fn send_json(
+    url: &str,
+    body: &serde_json::Value,
+    policy: &Policy,
+    trace: &Trace,
+) -> Result<Fetched> {
+    let url = Url::parse(url)?;
+    check_destination(&url, policy)?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    trace.propagate(&mut headers)?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(wasm_bindgen::JsValue::from_str(&body.to_string())))
+        .with_redirect(RequestRedirect::Manual);
+    let mut res = Fetch::Request(Request::new_with_init(url.as_str(), &init)?)
+        .send()
+        .await?;
+    let status = res.status_code();
+    let body = res.bytes().await?;
+    if body.len() > policy.max_response_bytes {
+        return Err("outbound response exceeds the size limit".into());
+    }
+    Ok(Fetched { status, body })
+}
+
+/// `GET`s `url` under `policy`, as part of `trace`, following redirects by hand so every hop is
+/// checked again.
+pub async fn get(url: &str, headers: &Headers, policy: &Policy, trace: &Trace) -> Result<Fetched> {
+    let started_at = Utc::now().timestamp_millis();
+    let fetched = follow(url, headers, policy, trace).await;
+    record(trace, url, started_at, &fetched);
+    fetched
+}
+
+async fn follow(url: &str, headers: &Headers, policy: &Policy, trace: &Trace) -> Result<Fetched> {
+    let mut url = Url::parse(url)?;
+    let mut headers = headers.clone();
+    trace.propagate(&mut headers)?;
+    let mut redirects = 0;
+    loop {
+        check_destination(&url, policy)?;
+        let mut init = RequestInit::new();
+        init.with_headers(headers.clone())
+            .with_redirect(RequestRedirect::Manual);
+        let mut res = Fetch::Request(Request::new_with_init(url.as_str(), &init)?)
+            .send()
+            .await?;
+
+        let status = res.status_code();
+        if (300..400).contains(&status) {
+            let location = match res.headers().get("Location")? {
+                Some(location) => location,
+                None => {
+                    return Ok(Fetched {
+                        status,
+                        body: vec![],
+                    })
+                }
+            };
+            redirects += 1;
+            if redirects > policy.max_redirects {
+                return Err(
+                    format!("outbound fetch exceeded {} redirects", policy.max_redirects).into(),
+                );
+            }
+            url = url.join(&location)?;
+            continue;
+        }
+
+        let declared = res
+            .headers()
+            .get("Content-Length")?
+            .and_then(|len| len.parse::<usize>().ok());
+        if declared.is_some_and(|len| len > policy.max_response_bytes) {
+            return Err("outbound response exceeds the size limit".into());
+        }
+        let body = res.bytes().await?;
+        if body.len() > policy.max_response_bytes {
+            return Err("outbound response exceeds the size limit".into());
+        }
+        return Ok(Fetched { status, body });
+    }
+}
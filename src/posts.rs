@@ -0,0 +1,789 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::models::{self, Post};
+use crate::session::Session;
+use crate::storage::{self, PostStore};
+use crate::trace::Trace;
+use crate::withholding::Withheld;
+use crate::{
+    activity, automod, comments, communities, feeds, firehose, live, moderation, notifications,
+    render, search, searches, session, tags, templates, validation, webhooks,
+};
+
+pub const POSTS_KV: &str = "my-app-general_posts_preview";
+
+/// Terms a post is published under. Posts stored without one are all rights reserved.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum License {
+    #[serde(rename = "CC-BY")]
+    CcBy,
+    #[serde(rename = "CC0")]
+    Cc0,
+    #[default]
+    #[serde(rename = "all-rights-reserved")]
+    AllRightsReserved,
+}
+
+impl License {
+    /// Whether others may reuse the post without asking.
+    pub fn is_reusable(self) -> bool {
+        self != License::AllRightsReserved
+    }
+
+    /// Whether a `?license=` filter keeps posts under this license: `reusable` keeps anything
+    /// others may reuse, any other value is matched exactly.
+    pub fn matches(self, wanted: &str) -> bool {
+        match wanted {
+            "reusable" => self.is_reusable(),
+            wanted => serde_json::to_value(self).is_ok_and(|license| license == wanted),
+        }
+    }
+
+    pub fn of(post: &Post) -> License {
+        Self::parse(post.extra.get("license"))
+    }
+
+    /// Like [`License::of`], for a post read as JSON.
+    pub fn of_value(post: &Value) -> License {
+        Self::parse(post.get("license"))
+    }
+
+    fn parse(license: Option<&Value>) -> License {
+        license
+            .and_then(|license| serde_json::from_value(license.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks the `license` a new post was submitted with and stores the default when there is none.
+pub fn normalize_license(post: &mut Value) -> Result<()> {
+    let license = match post.get("license") {
+        Some(license) => serde_json::from_value::<License>(license.clone())
+            .map_err(|_| "`license` must be one of CC-BY, CC0, all-rights-reserved")?,
+        None => License::default(),
+    };
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.insert("license".to_string(), serde_json::to_value(license)?);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct Edit {
+    title: Option<String>,
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Crosspost {
+    communities: Vec<String>,
+    /// Whether likes on the copies should count towards the original.
+    #[serde(default)]
+    aggregate: bool,
+}
+
+/// Archived posts stay in KV but are left out of public listings.
+pub fn is_archived(post: &Value) -> bool {
+    post.get("archived")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Deleted posts stay stored, out of sight, until `POST /bot/purge` removes them for good; until
+/// then their author can restore them.
+pub fn is_deleted(post: &Value) -> bool {
+    post.get("deleted_at").is_some()
+}
+
+/// Days a deleted post can be restored for, `DELETED_POST_DAYS`; 30 when unset.
+fn window(days: Option<String>) -> Duration {
+    let days = days
+        .and_then(|days| days.trim().parse::<i64>().ok())
+        .unwrap_or(30);
+    Duration::days(days)
+}
+
+/// How long a deleted post can be restored, from the `DELETED_POST_DAYS` var.
+pub fn restore_window(ctx: &RouteContext<Session>) -> Duration {
+    window(ctx.var("DELETED_POST_DAYS").ok().map(|var| var.to_string()))
+}
+
+/// [`restore_window`] for code that has the `Env` but no route, such as a scheduled job.
+pub fn restore_window_in(env: &Env) -> Duration {
+    window(env.var("DELETED_POST_DAYS").ok().map(|var| var.to_string()))
+}
+
+/// Whether a deleted post is more than `window` past its deletion, and due to be purged.
+pub fn is_expired(window: Duration, post: &Value) -> bool {
+    post.get("deleted_at")
+        .and_then(Value::as_str)
+        .and_then(|deleted_at| DateTime::parse_from_rfc3339(deleted_at).ok())
+        .is_some_and(|deleted_at| deleted_at + window < Utc::now())
+}
+
+/// Removed and held posts stay in KV (so their authors can see what happened) but are left out
+/// of public listings until a moderator restores them.
+pub fn is_moderated(post: &Value) -> bool {
+    post.get("moderation").is_some()
+}
+
+fn string_list(post: &Value, field: &str) -> Vec<String> {
+    post.get(field)
+        .and_then(Value::as_array)
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Turns the `co_authors` a new post was submitted with into pending invites. Nobody is
+/// listed as a co-author until they accept.
+pub fn invite_co_authors(post: &mut Value) {
+    let author = post.get("username").and_then(Value::as_str).unwrap_or("");
+    let mut invited = string_list(post, "co_authors");
+    invited.retain(|name| !name.is_empty() && name != author);
+    invited.sort();
+    invited.dedup();
+    if let Some(post_obj) = post.as_object_mut() {
+        if post_obj.contains_key("co_authors") {
+            post_obj.insert("co_authors".to_string(), json!([]));
+            post_obj.insert("pending_co_authors".to_string(), json!(invited));
+        }
+    }
+}
+
+/// What every new post goes through before [`insert`], whichever route it came in by: its
+/// `license` is checked, it is held to the community template it names, and its `co_authors`
+/// become pending invites.
+pub async fn prepare(ctx: &RouteContext<Session>, post: &mut Value) -> ApiResult<()> {
+    if let Err(e) = normalize_license(post) {
+        return Err(ApiError::BadRequest(e.to_string()));
+    }
+    if let Some(problem) = templates::check(ctx, post).await? {
+        return Err(ApiError::BadRequest(problem));
+    }
+    invite_co_authors(post);
+    Ok(())
+}
+
+/// Strips outstanding invites before a post is shown to anyone.
+pub fn hide_pending_co_authors(post: &mut Value) {
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.remove("pending_co_authors");
+    }
+}
+
+fn likes(post: &Value) -> i64 {
+    post.get("likes").and_then(Value::as_i64).unwrap_or(0)
+}
+
+/// Adds `total_likes` to every original post that has aggregating crossposts, summing its own
+/// likes with those of its copies.
+pub fn aggregate_crosspost_likes(posts: &mut [(String, Value)]) {
+    let mut extra: HashMap<String, i64> = HashMap::new();
+    for (_, post) in posts.iter() {
+        let aggregate = post
+            .get("aggregate_counts")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if let (true, Some(original)) =
+            (aggregate, post.get("crosspost_of").and_then(Value::as_str))
+        {
+            *extra.entry(original.to_string()).or_default() += likes(post);
+        }
+    }
+    for (id, post) in posts.iter_mut() {
+        if let Some(copies) = extra.get(id.as_str()) {
+            let total = likes(post) + copies;
+            if let Some(post_obj) = post.as_object_mut() {
+                post_obj.insert("total_likes".to_string(), json!(total));
+            }
+        }
+    }
+}
+
+/// Every post that belongs in a public listing, in key order: archived and moderated posts are
+/// skipped, crosspost likes aggregated and pending co-author invites hidden. The reads are
+/// timed into `trace`.
+pub async fn list_public(store: &dyn PostStore, trace: &Trace) -> Result<Vec<Post>> {
+    Ok(public(store.list("", trace).await?))
+}
+
+/// [`list_public`] for just the posts `ids` names, in that order. Ids no longer stored are
+/// skipped.
+pub async fn load_public(store: &dyn PostStore, ids: &[String]) -> Result<Vec<Post>> {
+    let mut found = vec![];
+    for id in ids {
+        if let Some(value) = store.get(id).await? {
+            found.push((id.clone(), value));
+        }
+    }
+    Ok(public(found))
+}
+
+/// The posts of `found`, as `(id, JSON text)`, that belong in a public listing.
+fn public(found: Vec<(String, String)>) -> Vec<Post> {
+    let mut stored: Vec<(String, Value)> = vec![];
+    for (id, value) in found {
+        match serde_json::from_str::<Value>(&value) {
+            Ok(post) if is_archived(&post) || is_moderated(&post) || is_deleted(&post) => continue,
+            Ok(mut post) => {
+                // Posts written before ids were stored are identified by their key.
+                if let Some(post_obj) = post.as_object_mut() {
+                    post_obj
+                        .entry("id")
+                        .or_insert_with(|| Value::String(id.clone()));
+                }
+                stored.push((id, post))
+            }
+            Err(e) => console_log!("skipping malformed post {}: {}", id, e),
+        }
+    }
+    aggregate_crosspost_likes(&mut stored);
+
+    let mut posts = vec![];
+    for (key, mut post) in stored {
+        hide_pending_co_authors(&mut post);
+        match serde_json::from_value::<Post>(post) {
+            Ok(post) => posts.push(post),
+            Err(e) => console_log!("skipping malformed post {}: {}", key, e),
+        }
+    }
+    posts
+}
+
+/// Sets `content_html` from the post's markdown `content`, along with `code_languages` if it has
+/// fenced code, `has_math` if it has formulas and `has_spoilers` if it has spoilers, so feeds can
+/// warn about or collapse such posts. `tags` gets the content's hashtags.
+pub fn render_content(post: &mut Value) {
+    let content = post
+        .get("content")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let rendered = render::markdown(content);
+    let hashtags = tags::extract(content);
+    if let Some(obj) = post.as_object_mut() {
+        obj.insert("content_html".to_string(), json!(rendered.html));
+        if hashtags.is_empty() {
+            obj.remove("tags");
+        } else {
+            obj.insert("tags".to_string(), json!(hashtags));
+        }
+        if rendered.code_languages.is_empty() {
+            obj.remove("code_languages");
+        } else {
+            obj.insert("code_languages".to_string(), json!(rendered.code_languages));
+        }
+        for &(flag, set) in &[
+            ("has_math", rendered.has_math),
+            ("has_spoilers", rendered.has_spoilers),
+        ] {
+            if set {
+                obj.insert(flag.to_string(), json!(true));
+            } else {
+                obj.remove(flag);
+            }
+        }
+    }
+}
+
+/// Renders a brand new post, runs it past automod and stores it under `id`, then tells the
+/// users it mentions or invites to co-author it, the firehose, saved searches, live clients and the community's webhooks
+/// about it.
+pub async fn insert(ctx: &RouteContext<Session>, id: &str, post: &mut Value) -> Result<()> {
+    render_content(post);
+    let rule = automod::screen(ctx, post).await;
+    let post = &*post;
+    storage::posts(ctx)?.put(id, post).await?;
+    if let Some(rule) = &rule {
+        automod::report(ctx, id, post, rule).await;
+    }
+    // Removed or held by automod: nobody is told about a post they can't see.
+    if is_moderated(post) {
+        return Ok(());
+    }
+    if let Some(username) = post.get("username").and_then(Value::as_str) {
+        activity::record(ctx, username, activity::Kind::Post).await;
+    }
+    notifications::mentioned(ctx, id, None, post).await;
+    notifications::invited(ctx, id, post).await;
+    firehose::post_changed(ctx, firehose::Kind::Create, id, post).await;
+    feeds::fan_out(ctx, id, post).await;
+    // A failure here shouldn't fail a post that has already been stored. Posts in quarantined
+    // communities are kept out of search, tags and saved-search alerts.
+    let community = post.get("community").and_then(Value::as_str);
+    let quarantined = match community {
+        Some(community) => communities::is_quarantined(ctx, community)
+            .await
+            .unwrap_or(false),
+        None => false,
+    };
+    if !quarantined {
+        if let Err(e) = search::index(ctx, id, post).await {
+            console_log!("indexing {} for search failed: {}", id, e);
+        }
+        if let Err(e) = tags::index(ctx, id, post).await {
+            console_log!("indexing {} for its tags failed: {}", id, e);
+        }
+        if let Err(e) = feeds::index(ctx, id, post).await {
+            console_log!("indexing {} for the global feed failed: {}", id, e);
+        }
+        if let Err(e) = searches::alert_matches(ctx, id, post).await {
+            console_log!("saved-search alerts for {} failed: {}", id, e);
+        }
+        let event = live::Event::Post {
+            id,
+            username: post
+                .get("username")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+            community,
+        };
+        live::publish(ctx, event).await;
+    }
+    if let Some(community) = community {
+        webhooks::notify(ctx, community, webhooks::Event::NewPost, post).await;
+    }
+    Ok(())
+}
+
+/// Loads a stored post, deleted or not.
+async fn load_stored(store: &dyn PostStore, id: &str) -> ApiResult<Value> {
+    match store.get(id).await? {
+        Some(stored) => serde_json::from_str(&stored)
+            .map_err(|_| ApiError::Internal("Stored post is malformed".to_string())),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// Loads a stored post. A deleted post is not found.
+pub async fn load(store: &dyn PostStore, id: &str) -> ApiResult<Value> {
+    let post = load_stored(store, id).await?;
+    if is_deleted(&post) {
+        return Err(ApiError::NotFound);
+    }
+    Ok(post)
+}
+
+/// Marks `post` deleted by `username`, for [`restore`] to undo within the restore window.
+pub async fn soft_delete(
+    ctx: &RouteContext<Session>,
+    store: &dyn PostStore,
+    id: &str,
+    post: Value,
+    username: &str,
+) -> Result<()> {
+    let firehose = ctx.kv(firehose::FIREHOSE_KV)?;
+    soft_delete_in(&firehose, ctx.data().bindings(), store, id, post, username).await
+}
+
+/// [`soft_delete`], publishing the delete to the firehose in `firehose` and the hub in
+/// `bindings`.
+pub async fn soft_delete_in(
+    firehose: &kv::KvStore,
+    bindings: &JsValue,
+    store: &dyn PostStore,
+    id: &str,
+    mut post: Value,
+    username: &str,
+) -> Result<()> {
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.insert("deleted_at".to_string(), json!(Utc::now().to_rfc3339()));
+        post_obj.insert("deleted_by".to_string(), json!(username));
+    }
+    store.put(id, &post).await?;
+    firehose::publish_in(firehose, bindings, firehose::Kind::Delete, id, None).await;
+    Ok(())
+}
+
+/// Loads the posts `ids` for a listing, in the same order, leaving out what listings don't show:
+/// posts that are gone, archived or moderated, withheld from the reader, or in a quarantined
+/// community.
+pub async fn load_listed(
+    ctx: &RouteContext<Session>,
+    withheld: &Withheld,
+    ids: impl IntoIterator<Item = String>,
+) -> ApiResult<Vec<Value>> {
+    let store = storage::posts(ctx)?;
+    let mut quarantined: HashMap<String, bool> = HashMap::new();
+    let mut listed = vec![];
+    for id in ids {
+        let mut post = match load(&*store, &id).await {
+            Ok(post) => post,
+            Err(ApiError::NotFound) => continue,
+            Err(e) => return Err(e),
+        };
+        if is_archived(&post) || is_moderated(&post) {
+            continue;
+        }
+        if let Some(community) = post.get("community").and_then(Value::as_str) {
+            if !quarantined.contains_key(community) {
+                let is_quarantined = communities::is_quarantined(ctx, community).await?;
+                quarantined.insert(community.to_string(), is_quarantined);
+            }
+            if quarantined[community] {
+                continue;
+            }
+        }
+        if let Some(post_obj) = post.as_object_mut() {
+            post_obj.entry("id").or_insert_with(|| json!(id));
+        }
+        withheld.apply(&mut post);
+        if post.get("withheld").is_some() {
+            continue;
+        }
+        hide_pending_co_authors(&mut post);
+        listed.push(post);
+    }
+    Ok(listed)
+}
+
+/// `GET /posts/:id`, in the shape `GET /posts` lists it. Archived and moderated posts are
+/// not found, as they aren't in the listing either; an archived post still is for its author.
+pub async fn show(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let id = error::param(&ctx, "id")?;
+    let mut post = load(&*storage::posts(&ctx)?, &id).await?;
+    let own = session::current_user(&ctx).is_some_and(|username| {
+        post.get("username").and_then(Value::as_str) == Some(username.as_str())
+    });
+    if (is_archived(&post) && !own) || is_moderated(&post) {
+        return Err(ApiError::NotFound);
+    }
+    hide_pending_co_authors(&mut post);
+    let comment_count = comments::counts(&ctx, std::slice::from_ref(&id)).await?[0];
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.entry("id").or_insert_with(|| json!(id));
+        post_obj.insert("comment_count".to_string(), json!(comment_count));
+    }
+    Withheld::for_request(&req, &ctx).await?.apply(&mut post);
+    Ok(Response::from_json(&post)?)
+}
+
+/// `GET /me/archived`: the signed-in user's archived posts, newest first. Nobody else is shown
+/// them anywhere.
+pub async fn archived(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let mut found = vec![];
+    for (id, stored) in storage::posts(&ctx)?.list("", ctx.data().trace()).await? {
+        let mut post = match serde_json::from_str::<Value>(&stored) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        if post.get("username").and_then(Value::as_str) != Some(username.as_str())
+            || !is_archived(&post)
+            || is_moderated(&post)
+            || is_deleted(&post)
+        {
+            continue;
+        }
+        if let Some(post_obj) = post.as_object_mut() {
+            post_obj.entry("id").or_insert_with(|| json!(id));
+        }
+        hide_pending_co_authors(&mut post);
+        found.push(post);
+    }
+    found.sort_by(|a, b| {
+        let time = |post: &Value| post.get("time").and_then(Value::as_str).map(str::to_string);
+        time(b).cmp(&time(a))
+    });
+    Ok(Response::from_json(&found)?)
+}
+
+/// Replaces the fields of `post` given in `edit` and renders it again.
+fn apply_edit(post: &mut Value, edit: &Edit, edited_at: &str) {
+    if let Some(post_obj) = post.as_object_mut() {
+        if let Some(title) = &edit.title {
+            post_obj.insert("title".to_string(), json!(title));
+        }
+        if let Some(content) = &edit.content {
+            post_obj.insert("content".to_string(), json!(content));
+        }
+        post_obj.insert("edited_at".to_string(), json!(edited_at));
+    }
+    render_content(post);
+}
+
+/// `PUT /posts/:id`, for the author and accepted co-authors: replaces the `title` and/or
+/// `content`. Crosspost copies get the same edit. The edited text goes through automod again.
+pub async fn edit(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let mut edit = match req.json::<Edit>().await {
+        Ok(edit) if edit.title.is_some() || edit.content.is_some() => edit,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "`title` or `content` is required".to_string(),
+            ))
+        }
+    };
+    let mut problems = validation::Problems::default();
+    edit.title = edit
+        .title
+        .and_then(|title| problems.check("title", validation::title(&title)));
+    edit.content = edit
+        .content
+        .and_then(|content| problems.check("content", validation::content(&content)));
+    problems.finish()?;
+    let store = storage::posts(&ctx)?;
+    let mut post = load(&*store, &id).await?;
+    let is_author = post.get("username").and_then(Value::as_str) == Some(username.as_str())
+        || string_list(&post, "co_authors").contains(&username);
+    if !is_author {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let author = post.get("username").cloned();
+    let mut edited = vec![(id.clone(), post.take())];
+    for copy in post_copies(&edited[0].1) {
+        match load(&*store, &copy).await {
+            // Only edit what really is a copy of this post, whatever its `crossposts` claims.
+            Ok(copy_post)
+                if copy_post.get("crosspost_of").and_then(Value::as_str) == Some(id.as_str())
+                    && copy_post.get("username") == author.as_ref() =>
+            {
+                edited.push((copy, copy_post))
+            }
+            Ok(_) => console_log!("{} is not a crosspost of {}, not editing it", copy, id),
+            Err(_) => console_log!("crosspost {} is gone, not editing it", copy),
+        }
+    }
+    for (id, post) in &mut edited {
+        apply_edit(post, &edit, &now);
+        let rule = automod::screen(&ctx, post).await;
+        store.put(id, post).await?;
+        if let Some(rule) = &rule {
+            automod::report(&ctx, id, post, rule).await;
+        }
+        firehose::post_changed(&ctx, firehose::Kind::Update, id, post).await;
+        // Terms the edit removed stay indexed; search drops the post when it reads it back.
+        if !is_moderated(post) {
+            if let Err(e) = search::index(&ctx, id, post).await {
+                console_log!("indexing {} for search failed: {}", id, e);
+            }
+        }
+    }
+
+    let (_, mut post) = edited.swap_remove(0);
+    hide_pending_co_authors(&mut post);
+    Ok(Response::from_json(&post)?)
+}
+
+/// Ids of the crosspost copies of an original post.
+fn post_copies(post: &Value) -> Vec<String> {
+    post.get("crossposts")
+        .and_then(Value::as_array)
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| entry.get("id")?.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `DELETE /posts/:id`, for the post's author only. The post is only marked deleted; see
+/// [`restore`].
+pub async fn delete(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let store = storage::posts(&ctx)?;
+    let post = load(&*store, &id).await?;
+    if post.get("username").and_then(Value::as_str) != Some(username.as_str()) {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    soft_delete(&ctx, &*store, &id, post, &username).await?;
+    Ok(Response::empty()?)
+}
+
+/// `POST /posts/:id/restore`, for the post's author or an admin: brings back a deleted post
+/// within [`restore_window`] of its deletion.
+pub async fn restore(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let store = storage::posts(&ctx)?;
+    let mut post = load_stored(&*store, &id).await?;
+    let is_author = post.get("username").and_then(Value::as_str) == Some(username.as_str());
+    if !is_author && !moderation::is_admin(&ctx, &username)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    if !is_deleted(&post) {
+        return Err(ApiError::Conflict("Post has not been deleted".to_string()));
+    }
+    if is_expired(restore_window(&ctx), &post) {
+        return Err(ApiError::Conflict(
+            "The post can no longer be restored".to_string(),
+        ));
+    }
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.remove("deleted_at");
+        post_obj.remove("deleted_by");
+    }
+    store.put(&id, &post).await?;
+    firehose::post_changed(&ctx, firehose::Kind::Create, &id, &post).await;
+    hide_pending_co_authors(&mut post);
+    Ok(Response::from_json(&post)?)
+}
+
+/// `POST /posts/:id/crosspost`, for the post's author only, into communities that have been
+/// founded and aren't quarantined.
+///
+/// Each copy is stored under `<id>@<community>` and points back at the original through
+/// `crosspost_of`; the original keeps a list of its copies in `crossposts`. Copies are indexed
+/// for search like any new post.
+pub async fn crosspost(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let body = models::from_body::<Crosspost>(&mut req).await?;
+    let store = storage::posts(&ctx)?;
+    let mut original = load(&*store, &id).await?;
+    if original.get("username").and_then(Value::as_str) != Some(username.as_str()) {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    if original.get("crosspost_of").is_some() {
+        return Err(ApiError::BadRequest(
+            "Crosspost the original post instead of a copy".to_string(),
+        ));
+    }
+
+    let mut communities = body.communities;
+    communities.retain(|community| !community.is_empty());
+    communities.sort();
+    communities.dedup();
+    for community in &communities {
+        if !communities::exists(&ctx, community).await? {
+            return Err(ApiError::BadRequest(format!(
+                "There is no community `{}`",
+                community
+            )));
+        }
+        if communities::is_quarantined(&ctx, community).await? {
+            return Err(ApiError::Forbidden(format!(
+                "`{}` is quarantined and can't be crossposted into",
+                community
+            )));
+        }
+    }
+
+    let mut crossposts = original
+        .get("crossposts")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let mut created = vec![];
+    for community in communities {
+        let copy_id = format!("{}@{}", id, community);
+        let mut copy = original.clone();
+        if let Some(copy_obj) = copy.as_object_mut() {
+            copy_obj.remove("crossposts");
+            copy_obj.insert("id".to_string(), json!(copy_id));
+            copy_obj.insert("community".to_string(), json!(community));
+            copy_obj.insert("crosspost_of".to_string(), json!(id));
+            copy_obj.insert("aggregate_counts".to_string(), json!(body.aggregate));
+            copy_obj.insert("likes".to_string(), json!(0));
+        }
+        // The copy lands in a community of its own, with that community's rules.
+        let rule = automod::screen(&ctx, &mut copy).await;
+        store.put(&copy_id, &copy).await?;
+        if let Some(rule) = &rule {
+            automod::report(&ctx, &copy_id, &copy, rule).await;
+        }
+        firehose::post_changed(&ctx, firehose::Kind::Create, &copy_id, &copy).await;
+        if !is_moderated(&copy) {
+            if let Err(e) = search::index(&ctx, &copy_id, &copy).await {
+                console_log!("indexing {} for search failed: {}", copy_id, e);
+            }
+        }
+
+        let entry = json!({ "id": copy_id, "community": community, "aggregate": body.aggregate });
+        crossposts.retain(|existing| existing.get("id") != entry.get("id"));
+        crossposts.push(entry.clone());
+        created.push(entry);
+    }
+    if let Some(original_obj) = original.as_object_mut() {
+        original_obj.insert("crossposts".to_string(), Value::Array(crossposts));
+    }
+    store.put(&id, &original).await?;
+    firehose::post_changed(&ctx, firehose::Kind::Update, &id, &original).await;
+
+    Ok(Response::from_json(&json!({ "crossposts": created }))?)
+}
+
+/// `POST /posts/:id/co_authors/accept` and `POST /posts/:id/co_authors/decline`, for the
+/// signed-in user's own invitation.
+pub async fn respond_to_invite(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let (id, accept) = match (ctx.param("id"), ctx.param("action").map(String::as_str)) {
+        (Some(id), Some("accept")) => (id.clone(), true),
+        (Some(id), Some("decline")) => (id.clone(), false),
+        _ => return Err(ApiError::NotFound),
+    };
+    let store = storage::posts(&ctx)?;
+    let mut post = load(&*store, &id).await?;
+
+    let mut pending = string_list(&post, "pending_co_authors");
+    if !pending.contains(&username) {
+        return Err(ApiError::NotFound);
+    }
+    pending.retain(|name| name != &username);
+    let mut co_authors = string_list(&post, "co_authors");
+    if accept {
+        co_authors.push(username);
+    }
+    if let Some(post_obj) = post.as_object_mut() {
+        post_obj.insert("co_authors".to_string(), json!(co_authors));
+        post_obj.insert("pending_co_authors".to_string(), json!(pending));
+    }
+    store.put(&id, &post).await?;
+    firehose::post_changed(&ctx, firehose::Kind::Update, &id, &post).await;
+
+    hide_pending_co_authors(&mut post);
+    Ok(Response::from_json(&post)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn license_filters_match_reusable_or_exactly() {
+        assert!(License::CcBy.matches("reusable"));
+        assert!(License::Cc0.matches("reusable"));
+        assert!(!License::AllRightsReserved.matches("reusable"));
+        assert!(License::CcBy.matches("CC-BY"));
+        assert!(!License::CcBy.matches("CC0"));
+        assert!(License::AllRightsReserved.matches("all-rights-reserved"));
+        assert!(!License::Cc0.matches("cc0"));
+    }
+
+    #[test]
+    fn posts_without_a_known_license_are_all_rights_reserved() {
+        assert_eq!(License::of_value(&json!({})), License::AllRightsReserved);
+        assert_eq!(
+            License::of_value(&json!({ "license": "MIT" })),
+            License::AllRightsReserved
+        );
+        assert_eq!(
+            License::of_value(&json!({ "license": "CC0" })),
+            License::Cc0
+        );
+    }
+
+    #[test]
+    fn new_posts_get_the_default_license_or_a_400() {
+        let mut post = json!({});
+        assert!(normalize_license(&mut post).is_ok());
+        assert_eq!(post["license"], "all-rights-reserved");
+        assert!(normalize_license(&mut json!({ "license": "MIT" })).is_err());
+    }
+}
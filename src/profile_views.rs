@@ -0,0 +1,85 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{session, settings};
+
+/// Keys in the `profile_views` namespace:
+///
+/// - `<username>/<viewer>`: a [`View`], `viewer`'s latest look at `username`'s profile, kept
+///   [`VIEW_TTL`] seconds
+///
+/// Views are only recorded between users who both opted in (see `settings::ProfileViews`).
+const PROFILE_VIEWS_KV: &str = "profile_views";
+
+/// Views are forgotten after 30 days.
+const VIEW_TTL: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct View {
+    viewer: String,
+    time: String,
+}
+
+/// Notes that `viewer` looked at `username`'s profile, if both of them opted in. Failures are
+/// logged, never returned.
+pub async fn record(ctx: &RouteContext<Session>, username: &str, viewer: &str) {
+    let recorded = async {
+        if username == viewer
+            || !settings::profile_views(ctx, username).await?.enabled
+            || !settings::profile_views(ctx, viewer).await?.enabled
+        {
+            return Ok(());
+        }
+        let view = View {
+            viewer: viewer.to_string(),
+            time: Utc::now().to_rfc3339(),
+        };
+        ctx.kv(PROFILE_VIEWS_KV)?
+            .put(&format!("{}/{}", username, viewer), &view)?
+            .expiration_ttl(VIEW_TTL)
+            .execute()
+            .await?;
+        Ok::<_, Error>(())
+    };
+    if let Err(e) = recorded.await {
+        console_log!("view of {} by {} not recorded: {}", username, viewer, e);
+    }
+}
+
+/// `GET /me/profile_views`, newest first: who looked at your profile in the last 30 days. Only
+/// for users who opted in, and only viewers who still are.
+pub async fn mine(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    if !settings::profile_views(&ctx, &username).await?.enabled {
+        return Err(ApiError::Forbidden(
+            "Turn on profile views in `/settings/profile_views` first".to_string(),
+        ));
+    }
+    let kv = ctx.kv(PROFILE_VIEWS_KV)?;
+    let mut views = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(format!("{}/", username));
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(v) = kv.get(&key.name).await? {
+                let view = v.as_json::<View>()?;
+                if settings::profile_views(&ctx, &view.viewer).await?.enabled {
+                    views.push(view);
+                }
+            }
+        }
+        if page.list_complete || page.cursor.is_none() {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    views.sort_by(|a, b| b.time.cmp(&a.time));
+    Ok(Response::from_json(&views)?)
+}
@@ -0,0 +1,272 @@
+use chrono::Utc;
+use js_sys::{Array, Function, Promise, Reflect};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{bulk, feeds, utils};
+
+/// Keys in the `queues` namespace:
+///
+/// - `dead/<id>`: a [`DeadLetter`], where `id` is `<millis, zero-padded>-<hash of the body>`
+/// - `done/<idempotency key>`: a message with that key was handled, kept [`DONE_TTL`] seconds
+/// - `deduped/<yyyy-mm-dd>/<queue>`: deliveries of handled messages skipped that UTC day, kept
+///   [`DEDUPED_TTL`] seconds
+pub const QUEUES_KV: &str = "queues";
+
+/// How long a handled message is remembered: past the four days a queue keeps a message, so any
+/// redelivery of it is caught.
+const DONE_TTL: u64 = 60 * 60 * 24 * 5;
+
+/// How long daily dedupe counts are kept.
+const DEDUPED_TTL: u64 = 60 * 60 * 24 * 30;
+
+/// Deliveries a message gets before it is dead-lettered. The consumers in `wrangler.toml` allow
+/// more retries than this, so the queue never drops a message before it is stored here.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// A message a consumer gave up on, kept for an admin to inspect and replay.
+#[derive(Serialize, Deserialize, Debug)]
+struct DeadLetter {
+    id: String,
+    /// Binding of the queue it came from, which a replay sends it back to.
+    queue: String,
+    body: String,
+    error: String,
+    attempts: u32,
+    failed_at: String,
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// Calls `target.method()`.
+fn call(target: &JsValue, method: &str) -> Result<JsValue> {
+    let function: Function = Reflect::get(target, &JsValue::from(method))
+        .map_err(js_error)?
+        .unchecked_into();
+    function.call0(target).map_err(js_error)
+}
+
+/// One message of a batch a consumer was handed. `worker` 0.0.7 predates Queues, so the
+/// runtime's message is read directly.
+pub struct Message(JsValue);
+
+impl Message {
+    /// The body, if it was sent as text, as every queue here sends it.
+    pub fn body(&self) -> Option<String> {
+        Reflect::get(&self.0, &JsValue::from("body"))
+            .ok()
+            .and_then(|body| body.as_string())
+    }
+
+    /// How many times the message was delivered, this time included.
+    fn attempts(&self) -> u32 {
+        Reflect::get(&self.0, &JsValue::from("attempts"))
+            .ok()
+            .and_then(|attempts| attempts.as_f64())
+            .map_or(1, |attempts| attempts as u32)
+    }
+
+    /// Marks the message handled, whatever happens to the rest of the batch.
+    pub fn ack(&self) -> Result<()> {
+        call(&self.0, "ack").map(drop)
+    }
+
+    fn retry(&self) -> Result<()> {
+        call(&self.0, "retry").map(drop)
+    }
+}
+
+/// The messages of `batch`, as handed to a consumer.
+fn messages(batch: &JsValue) -> Result<Vec<Message>> {
+    let messages = Reflect::get(batch, &JsValue::from("messages")).map_err(js_error)?;
+    Ok(Array::from(&messages).iter().map(Message).collect())
+}
+
+/// The worker's queue handler, handing each batch to the consumer of the queue it came from.
+/// `#[event]` in `worker` 0.0.7 only knows `fetch` and `scheduled`, so this is exported to the
+/// runtime directly, and a worker has just the one for all its queues.
+#[wasm_bindgen]
+pub async fn queue(batch: JsValue, env: Env) -> std::result::Result<(), JsValue> {
+    let name = Reflect::get(&batch, &JsValue::from("queue"))
+        .ok()
+        .and_then(|name| name.as_string())
+        .unwrap_or_default();
+    let messages = messages(&batch).map_err(|e| JsValue::from(e.to_string()))?;
+    let consumed = match name.as_str() {
+        feeds::FEED_QUEUE_NAME => feeds::consume(messages, &env).await,
+        bulk::BULK_QUEUE_NAME => bulk::consume(messages, &env).await,
+        _ => Err(Error::RustError(format!(
+            "no consumer for queue `{}`",
+            name
+        ))),
+    };
+    consumed.map_err(|e| JsValue::from(e.to_string()))
+}
+
+/// Sends `body` to the queue bound as `queue`.
+pub async fn send(queue: &JsValue, body: &str) -> Result<()> {
+    let send: Function = Reflect::get(queue, &JsValue::from("send"))
+        .map_err(js_error)?
+        .unchecked_into();
+    let promise: Promise = send
+        .call1(queue, &JsValue::from(body))
+        .map_err(js_error)?
+        .unchecked_into();
+    JsFuture::from(promise).await.map_err(js_error)?;
+    Ok(())
+}
+
+/// Handles a message from the queue bound as `queue` that its consumer failed on: it is retried
+/// until it has had [`MAX_ATTEMPTS`] deliveries, then stored as a [`DeadLetter`]. A `poison`
+/// message, one that can never succeed such as a body that doesn't parse, is stored at once.
+pub async fn failed(
+    kv: &kv::KvStore,
+    queue: &str,
+    message: &Message,
+    error: String,
+    poison: bool,
+) -> Result<()> {
+    let attempts = message.attempts();
+    if !poison && attempts < MAX_ATTEMPTS {
+        console_log!("{} message failed (attempt {}): {}", queue, attempts, error);
+        return message.retry();
+    }
+    let body = message.body().unwrap_or_default();
+    let now = Utc::now();
+    let id = format!(
+        "{:013}-{}",
+        now.timestamp_millis(),
+        &utils::sha256_hex(&body)[..16]
+    );
+    let letter = DeadLetter {
+        id: id.clone(),
+        queue: queue.to_string(),
+        body,
+        error,
+        attempts,
+        failed_at: now.to_rfc3339(),
+    };
+    kv.put(&format!("dead/{}", id), &letter)?.execute().await?;
+    console_log!(
+        "{} message dead-lettered as {}: {}",
+        queue,
+        id,
+        letter.error
+    );
+    message.ack()
+}
+
+fn done_key(key: &str) -> String {
+    format!("done/{}", key)
+}
+
+/// Whether a message with the idempotency key `key` was already handled, in which case a
+/// consumer acks it without handling it again. Queues deliver at least once, so a message can
+/// come back after it was handled, e.g. when acking it failed. Each skip is logged as a
+/// `queue_dedupe` event and counted for `GET /admin/queue_stats`.
+pub async fn handled(kv: &kv::KvStore, queue: &str, key: &str) -> Result<bool> {
+    if kv.get(&done_key(key)).await?.is_none() {
+        return Ok(false);
+    }
+    let day = Utc::now().format("%Y-%m-%d");
+    let counter = format!("deduped/{}/{}", day, queue);
+    let count = match kv.get(&counter).await? {
+        Some(v) => v.as_string().parse::<u64>().unwrap_or(0),
+        None => 0,
+    } + 1;
+    // Read-modify-write, so a burst of redeliveries may be undercounted by a few.
+    if let Err(e) = kv
+        .put(&counter, count.to_string())?
+        .expiration_ttl(DEDUPED_TTL)
+        .execute()
+        .await
+    {
+        console_log!("dedupe count failed: {}", e);
+    }
+    let event = json!({ "event": "queue_dedupe", "queue": queue, "key": key, "today": count });
+    console_log!("{}", event);
+    Ok(true)
+}
+
+/// Remembers that the message with the idempotency key `key` was handled, see [`handled`].
+/// Call it before acking, so a redelivery can't slip in between.
+pub async fn mark_handled(kv: &kv::KvStore, key: &str) -> Result<()> {
+    kv.put(&done_key(key), "")?
+        .expiration_ttl(DONE_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// `GET /admin/queue_stats`: deliveries of already handled messages each consumer skipped, per
+/// UTC day, e.g. `{"deduped": {"2024-05-01": {"FEED_QUEUE": 3}}}`.
+pub async fn stats(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(QUEUES_KV)?;
+    let mut deduped: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for key in kv
+        .list()
+        .prefix("deduped/".to_string())
+        .execute()
+        .await?
+        .keys
+    {
+        let (day, queue) = match key.name["deduped/".len()..].split_once('/') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if let Some(v) = kv.get(&key.name).await? {
+            let count = v.as_string().parse::<u64>().unwrap_or(0);
+            deduped
+                .entry(day.to_string())
+                .or_default()
+                .insert(queue.to_string(), count);
+        }
+    }
+    Ok(Response::from_json(&json!({ "deduped": deduped }))?)
+}
+
+/// `GET /admin/dead_letters`: every message a consumer gave up on, oldest first.
+pub async fn dead_letters(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(QUEUES_KV)?;
+    let mut letters = vec![];
+    for key in kv.list().prefix("dead/".to_string()).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            letters.push(v.as_json::<DeadLetter>()?);
+        }
+    }
+    Ok(Response::from_json(&letters)?)
+}
+
+/// `POST /admin/dead_letters/:id/replay` sends the message back to its queue for another round
+/// of [`MAX_ATTEMPTS`] deliveries; `DELETE /admin/dead_letters/:id` drops it.
+pub async fn dead_letter(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(QUEUES_KV)?;
+    let key = format!("dead/{}", id);
+    let letter = match kv.get(&key).await? {
+        Some(v) => v.as_json::<DeadLetter>()?,
+        None => return Err(ApiError::NotFound),
+    };
+    if req.method() == Method::Post {
+        let queue = Reflect::get(ctx.data().bindings(), &JsValue::from(letter.queue.as_str()))
+            .map_err(js_error)?;
+        if queue.is_undefined() {
+            return Err(ApiError::Conflict(format!(
+                "Queue `{}` is no longer bound",
+                letter.queue
+            )));
+        }
+        send(&queue, &letter.body).await?;
+    }
+    kv.delete(&key).await?;
+    Ok(Response::empty()?)
+}
@@ -0,0 +1,87 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{session, storage};
+
+/// Keys in the `referrals` namespace:
+///
+/// - `referral/<sharer>/<new username>`: [`Referral`], one per account signed up through a
+///   share link
+const REFERRALS_KV: &str = "referrals";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Referral {
+    username: String,
+    post_id: String,
+    signed_up_at: String,
+}
+
+/// Share tokens are sealed with their own key so they can never pass for a session cookie.
+fn share_secret(ctx: &RouteContext<Session>) -> Result<String> {
+    Ok(format!("share:{}", ctx.secret("SESSION_SECRET")?.to_string()))
+}
+
+/// `GET /posts/:id/share_link`
+///
+/// Returns a token naming the signed-in user and the post. Clients put it on the link they
+/// share and pass it back as `?ref=` on the request that signs someone up from that link
+/// (`POST /users`, or a first `POST /posts`).
+pub async fn share_link(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    if storage::posts(&ctx)?.get(&id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    let token = session::seal(&format!("{}|{}", username, id), &share_secret(&ctx)?);
+    Ok(Response::from_json(
+        &json!({ "post_id": id, "ref": token }),
+    )?)
+}
+
+/// Credits whoever shared the link a new account signed up from, going by the request's `?ref=`.
+/// Missing or forged tokens, and people referring themselves, are ignored.
+pub async fn attribute(req: &Request, ctx: &RouteContext<Session>, new_user: &str) -> Result<()> {
+    let ref_token = match req.url()?.query_pairs().find(|(key, _)| key == "ref") {
+        Some((_, token)) => token.into_owned(),
+        None => return Ok(()),
+    };
+    let payload = match session::unseal(&ref_token, &share_secret(ctx)?) {
+        Some(payload) => payload,
+        None => return Ok(()),
+    };
+    let (sharer, post_id) = match payload.split_once('|') {
+        Some((sharer, _)) if sharer == new_user => return Ok(()),
+        Some(parts) => parts,
+        None => return Ok(()),
+    };
+    let referral = Referral {
+        username: new_user.to_string(),
+        post_id: post_id.to_string(),
+        signed_up_at: Utc::now().to_rfc3339(),
+    };
+    ctx.kv(REFERRALS_KV)?
+        .put(&format!("referral/{}/{}", sharer, new_user), &referral)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// `GET /me/referrals`
+pub async fn mine(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(REFERRALS_KV)?;
+    let prefix = format!("referral/{}/", username);
+    let mut referrals = vec![];
+    for key in kv.list().prefix(prefix).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            referrals.push(v.as_json::<Referral>()?);
+        }
+    }
+    Ok(Response::from_json(
+        &json!({ "count": referrals.len(), "referrals": referrals }),
+    )?)
+}
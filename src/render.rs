@@ -0,0 +1,466 @@
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd, TextMergeStream};
+
+use crate::math;
+
+/// A post's content turned into HTML at write time, so clients don't each need a markdown
+/// renderer.
+pub struct Rendered {
+    pub html: String,
+    /// Languages of the fenced code blocks, in order of first appearance.
+    pub code_languages: Vec<String>,
+    /// Whether there was any `$...$` or `$$...$$` math, rendered to MathML.
+    pub has_math: bool,
+    /// Whether there was any `||spoiler||` text.
+    pub has_spoilers: bool,
+}
+
+/// Opening tag of a spoiler. It starts out collapsed: clients blur the text while
+/// `aria-expanded` is false, and on activation flip it and drop the label, which otherwise keeps
+/// screen readers from reading the hidden text out.
+const SPOILER_OPEN: &str =
+    "<span class=\"spoiler\" role=\"button\" tabindex=\"0\" aria-expanded=\"false\" \
+     aria-label=\"Spoiler, activate to reveal\">";
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// How to pick tokens out of one language. Deliberately rough: enough to colour keywords,
+/// strings, numbers and comments, not a parser.
+struct Syntax {
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+const RUST: Syntax = Syntax {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+        "type", "unsafe", "use", "where", "while",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"'],
+};
+
+const PYTHON: Syntax = Syntax {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+        "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True",
+        "try", "while", "with", "yield",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const JAVASCRIPT: Syntax = Syntax {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "export",
+        "extends",
+        "false",
+        "finally",
+        "for",
+        "function",
+        "if",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "let",
+        "new",
+        "null",
+        "return",
+        "super",
+        "switch",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "type",
+        "typeof",
+        "undefined",
+        "var",
+        "void",
+        "while",
+        "yield",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+};
+
+const GO: Syntax = Syntax {
+    keywords: &[
+        "break",
+        "case",
+        "chan",
+        "const",
+        "continue",
+        "default",
+        "defer",
+        "else",
+        "false",
+        "for",
+        "func",
+        "go",
+        "goto",
+        "if",
+        "import",
+        "interface",
+        "map",
+        "nil",
+        "package",
+        "range",
+        "return",
+        "select",
+        "struct",
+        "switch",
+        "true",
+        "type",
+        "var",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '`'],
+};
+
+const SHELL: Syntax = Syntax {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "while",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const JSON: Syntax = Syntax {
+    keywords: &["true", "false", "null"],
+    line_comments: &[],
+    block_comment: None,
+    quotes: &['"'],
+};
+
+fn syntax(language: &str) -> Option<&'static Syntax> {
+    match language {
+        "rust" | "rs" => Some(&RUST),
+        "python" | "py" => Some(&PYTHON),
+        "javascript" | "js" | "typescript" | "ts" | "jsx" | "tsx" => Some(&JAVASCRIPT),
+        "go" | "golang" => Some(&GO),
+        "bash" | "sh" | "shell" | "zsh" => Some(&SHELL),
+        "json" => Some(&JSON),
+        _ => None,
+    }
+}
+
+fn span(out: &mut String, class: &str, text: &str) {
+    out.push_str(&format!(
+        "<span class=\"tok-{}\">{}</span>",
+        class,
+        escape_html(text)
+    ));
+}
+
+/// Wraps keywords, strings, numbers and comments of `code` in `<span class="tok-...">`.
+/// Languages we don't know come back escaped but otherwise untouched.
+pub fn highlight(code: &str, language: &str) -> String {
+    let syntax = match syntax(language) {
+        Some(syntax) => syntax,
+        None => return escape_html(code),
+    };
+    let mut out = String::new();
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let len = if let Some(prefix) = syntax.line_comments.iter().find(|p| rest.starts_with(**p))
+        {
+            let end = rest.find('\n').unwrap_or(rest.len()).max(prefix.len());
+            span(&mut out, "comment", &rest[..end]);
+            end
+        } else if let Some((open, close)) = syntax
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let end = rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |at| open.len() + at + close.len());
+            span(&mut out, "comment", &rest[..end]);
+            end
+        } else if syntax.quotes.contains(&c) {
+            let mut escaped = false;
+            let end = rest[1..]
+                .char_indices()
+                .find(|&(_, next)| {
+                    let closes = next == c && !escaped;
+                    escaped = next == '\\' && !escaped;
+                    closes
+                })
+                .map_or(rest.len(), |(at, _)| 1 + at + c.len_utf8());
+            span(&mut out, "string", &rest[..end]);
+            end
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|next: char| !(next.is_ascii_alphanumeric() || next == '.' || next == '_'))
+                .unwrap_or(rest.len());
+            span(&mut out, "number", &rest[..end]);
+            end
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|next: char| !(next.is_alphanumeric() || next == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if syntax.keywords.contains(&word) {
+                span(&mut out, "keyword", word);
+            } else {
+                out.push_str(word);
+            }
+            end
+        } else {
+            out.push_str(&escape_html(&rest[..c.len_utf8()]));
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    out
+}
+
+/// Turns `||text||` into spoilers. Both markers must sit in the same element (a spoiler can wrap
+/// emphasis but not close inside it, or span two paragraphs), so the HTML stays well nested;
+/// markers that don't pair up are left as they are. Returns whether there was any spoiler.
+fn spoilers(events: Vec<Event>) -> (Vec<Event>, bool) {
+    enum Piece<'a> {
+        Event(Event<'a>),
+        Marker,
+    }
+    let mut pieces = vec![];
+    for event in events {
+        match event {
+            Event::Text(text) if text.contains("||") => {
+                for (i, part) in text.split("||").enumerate() {
+                    if i > 0 {
+                        pieces.push(Piece::Marker);
+                    }
+                    if !part.is_empty() {
+                        pieces.push(Piece::Event(Event::Text(part.to_string().into())));
+                    }
+                }
+            }
+            event => pieces.push(Piece::Event(event)),
+        }
+    }
+
+    let mut pairs = vec![];
+    let mut open: Option<(usize, usize)> = None;
+    let mut depth = 0;
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Event(Event::Start(_)) => depth += 1,
+            Piece::Event(Event::End(_)) => {
+                depth -= 1;
+                if open.is_some_and(|(_, open_depth)| depth < open_depth) {
+                    open = None;
+                }
+            }
+            Piece::Marker => match open {
+                Some((start, open_depth)) if open_depth == depth => {
+                    pairs.push((start, i));
+                    open = None;
+                }
+                None => open = Some((i, depth)),
+                Some(_) => {}
+            },
+            Piece::Event(_) => {}
+        }
+    }
+
+    let mut out = vec![];
+    for (i, piece) in pieces.into_iter().enumerate() {
+        out.push(match piece {
+            Piece::Event(event) => event,
+            Piece::Marker if pairs.iter().any(|(start, _)| *start == i) => {
+                Event::InlineHtml(SPOILER_OPEN.into())
+            }
+            Piece::Marker if pairs.iter().any(|(_, end)| *end == i) => {
+                Event::InlineHtml("</span>".into())
+            }
+            Piece::Marker => Event::Text("||".into()),
+        });
+    }
+    (out, !pairs.is_empty())
+}
+
+/// URL schemes links and images may use. Anything else, `javascript:` above all, is replaced.
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Whether a link or image destination is relative or uses one of [`SAFE_SCHEMES`]. Browsers
+/// ignore tabs, newlines and other control characters in a scheme, so those are too.
+fn is_safe_url(url: &str) -> bool {
+    let scheme_end = match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => i,
+        _ => return true,
+    };
+    let scheme = url[..scheme_end]
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    SAFE_SCHEMES.contains(&scheme.as_str())
+}
+
+/// `url` if it is safe to link to, otherwise `#`.
+fn safe_url(url: CowStr) -> CowStr {
+    if is_safe_url(&url) {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+/// Renders markdown `content` to HTML. Raw HTML in the source is escaped, never passed through,
+/// and link and image destinations are kept to [`SAFE_SCHEMES`] and relative URLs.
+pub fn markdown(content: &str) -> Rendered {
+    let mut code_languages: Vec<String> = vec![];
+    let mut code_block: Option<(String, String)> = None;
+    let mut has_math = false;
+    let mut events = vec![];
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES | Options::ENABLE_MATH;
+    for event in TextMergeStream::new(Parser::new_ext(content, options)) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_ascii_lowercase(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                if !language.is_empty() && !code_languages.contains(&language) {
+                    code_languages.push(language.clone());
+                }
+                code_block = Some((language, String::new()));
+            }
+            Event::Text(text) if code_block.is_some() => {
+                if let Some((_, code)) = code_block.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                let (language, code) = code_block.take().unwrap_or_default();
+                let class = if language.is_empty() {
+                    String::new()
+                } else {
+                    format!(" class=\"language-{}\"", escape_html(&language))
+                };
+                events.push(Event::Html(CowStr::from(format!(
+                    "<pre><code{}>{}</code></pre>\n",
+                    class,
+                    highlight(&code, &language)
+                ))));
+            }
+            Event::InlineMath(tex) => {
+                has_math = true;
+                events.push(Event::InlineHtml(math::to_mathml(&tex, false).into()));
+            }
+            Event::DisplayMath(tex) => {
+                has_math = true;
+                events.push(Event::InlineHtml(math::to_mathml(&tex, true).into()));
+            }
+            Event::Html(html) | Event::InlineHtml(html) => events.push(Event::Text(html)),
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => events.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            })),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => events.push(Event::Start(Tag::Image {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            })),
+            event => events.push(event),
+        }
+    }
+    let (events, has_spoilers) = spoilers(events);
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    Rendered {
+        html,
+        code_languages,
+        has_math,
+        has_spoilers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_safe_links() {
+        let html =
+            markdown("[a](https://example.com) [b](/posts/1) [c](mailto:a@example.com)").html;
+        assert!(html.contains("href=\"https://example.com\""));
+        assert!(html.contains("href=\"/posts/1\""));
+        assert!(html.contains("href=\"mailto:a@example.com\""));
+    }
+
+    #[test]
+    fn replaces_unsafe_links_and_images() {
+        for content in [
+            "[x](javascript:alert(1))",
+            "[x](JavaScript:alert(1))",
+            "[x](javascript&#58;alert(1))",
+            "[x](<java\tscript:alert(1)>)",
+            "[x][r]\n\n[r]: vbscript:msgbox(1)",
+            "<javascript:alert(1)>",
+            "![x](data:text/html,hi)",
+        ] {
+            let html = markdown(content).html;
+            assert!(
+                html.contains("href=\"#\"") || html.contains("src=\"#\""),
+                "{}: {}",
+                content,
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_control_characters_in_schemes() {
+        assert!(!is_safe_url("java\tscript:alert(1)"));
+        assert!(!is_safe_url(" javascript:alert(1)"));
+        assert!(is_safe_url("posts/1?at=10:00"));
+        assert!(is_safe_url("#comments"));
+    }
+}
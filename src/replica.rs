@@ -0,0 +1,57 @@
+use js_sys::{Function, Object, Promise, Reflect};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::session::Session;
+
+/// How long a location keeps a user profile it read, in seconds.
+pub const PROFILE_TTL: u64 = 60;
+
+/// How long a location keeps a community's metadata it read, in seconds. It only changes when
+/// the founder edits the community's tags.
+pub const COMMUNITY_META_TTL: u64 = 300;
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// Reads `key` from the KV namespace `binding` with a `cacheTtl` of `ttl` seconds, so the data
+/// center serving the request answers from its own copy instead of asking the KV origin, which
+/// may be on another continent.
+///
+/// The copy may miss writes for up to `ttl` seconds, on top of the up to 60 seconds KV takes to
+/// spread a write anyway. Use it for records that are read far more often than written, never
+/// for a read-modify-write. Every such read is noted on the request's trace, which reports the
+/// largest `ttl` in the response's `X-Max-Staleness` header.
+pub async fn get(
+    ctx: &RouteContext<Session>,
+    binding: &str,
+    key: &str,
+    ttl: u64,
+) -> Result<Option<String>> {
+    // `worker` 0.0.7's `KvStore::get` takes no options, so the namespace is called directly.
+    let namespace =
+        Reflect::get(ctx.data().bindings(), &JsValue::from(binding)).map_err(js_error)?;
+    if namespace.is_undefined() {
+        return Err(format!("Binding `{}` is undefined.", binding).into());
+    }
+    let get: Function = Reflect::get(&namespace, &JsValue::from("get"))
+        .map_err(js_error)?
+        .unchecked_into();
+    let options = Object::new();
+    Reflect::set(&options, &JsValue::from("type"), &JsValue::from("text")).map_err(js_error)?;
+    Reflect::set(
+        &options,
+        &JsValue::from("cacheTtl"),
+        &JsValue::from(ttl as f64),
+    )
+    .map_err(js_error)?;
+    let promise: Promise = get
+        .call2(&namespace, &JsValue::from(key), &options)
+        .map_err(js_error)?
+        .unchecked_into();
+    let value = JsFuture::from(promise).await.map_err(js_error)?;
+    ctx.data().trace().may_be_stale(ttl);
+    Ok(value.as_string())
+}
@@ -0,0 +1,169 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::moderation::{self, Action, ReasonCode};
+use crate::session::Session;
+use crate::{firehose, posts, session, storage};
+
+/// Keys in the `reports` namespace:
+///
+/// - `report/<post id>/<reporter>`: one user's report of a post; reporting again replaces it, so
+///   each reporter counts once
+/// - `hidden/<post id>`: a post hidden after [`HIDE_AFTER_REPORTERS`] reports, waiting for an
+///   admin in `GET /admin/reports`
+pub const REPORTS_KV: &str = "reports";
+
+/// Reporters it takes to hide a post until an admin has looked at it.
+const HIDE_AFTER_REPORTERS: usize = 5;
+
+/// Longest reason accepted.
+const MAX_REASON_CHARS: usize = 500;
+
+#[derive(Deserialize, Debug)]
+struct NewReport {
+    reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Report {
+    reporter: String,
+    reason: String,
+    reported_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Hidden {
+    post_id: String,
+    title: String,
+    author: String,
+    reporters: usize,
+    hidden_at: String,
+}
+
+fn prefix(id: &str) -> String {
+    format!("report/{}/", id)
+}
+
+async fn reports_of(kv: &kv::KvStore, id: &str) -> Result<Vec<Report>> {
+    let mut reports = vec![];
+    for key in kv.list().prefix(prefix(id)).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            reports.push(v.as_json::<Report>()?);
+        }
+    }
+    Ok(reports)
+}
+
+/// `POST /posts/:id/report`, signed in, with `{"reason": "..."}`. Once
+/// [`HIDE_AFTER_REPORTERS`] different users have reported a post, it is held like a moderator
+/// would hold it and queued for the admins. Reporters aren't told either way.
+pub async fn report(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let reporter = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let body = match req.json::<NewReport>().await {
+        Ok(body) if !body.reason.trim().is_empty() => body,
+        _ => return Err(ApiError::BadRequest("`reason` is required".to_string())),
+    };
+    if body.reason.chars().count() > MAX_REASON_CHARS {
+        return Err(ApiError::BadRequest(format!(
+            "a reason can be at most {} characters",
+            MAX_REASON_CHARS
+        )));
+    }
+    let store = storage::posts(&ctx)?;
+    let mut post = posts::load(&*store, &id).await?;
+    let author = post
+        .get("username")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    if author == reporter {
+        return Err(ApiError::BadRequest(
+            "You can't report your own post".to_string(),
+        ));
+    }
+
+    let kv = ctx.kv(REPORTS_KV)?;
+    let report = Report {
+        reporter: reporter.clone(),
+        reason: body.reason.trim().to_string(),
+        reported_at: Utc::now().to_rfc3339(),
+    };
+    kv.put(&format!("{}{}", prefix(&id), reporter), &report)?
+        .execute()
+        .await?;
+
+    let reporters = kv.list().prefix(prefix(&id)).execute().await?.keys.len();
+    if reporters < HIDE_AFTER_REPORTERS || posts::is_moderated(&post) {
+        return Ok(Response::empty()?);
+    }
+    moderation::mark(&mut post, Action::Hold, ReasonCode::Other);
+    store.put(&id, &post).await?;
+    firehose::post_changed(&ctx, firehose::Kind::Create, &id, &post).await;
+    let hidden = Hidden {
+        post_id: id.clone(),
+        title: post
+            .get("title")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        author,
+        reporters,
+        hidden_at: Utc::now().to_rfc3339(),
+    };
+    kv.put(&format!("hidden/{}", id), &hidden)?
+        .execute()
+        .await?;
+    // The post is held either way; without the case its author just can't see why.
+    let message = "Hidden after several reports, pending review".to_string();
+    if let Err(e) =
+        moderation::open_case(&ctx, &id, &post, Action::Hold, ReasonCode::Other, message).await
+    {
+        console_log!("moderation case for reported post {} failed: {}", id, e);
+    }
+    console_log!("reports: post {} hidden after {} reports", id, reporters);
+    Ok(Response::empty()?)
+}
+
+/// Clears a post's reports once a moderator has decided on it, taking it out of the admins'
+/// queue. Reports filed after that count afresh.
+pub async fn reviewed(ctx: &RouteContext<Session>, id: &str) -> Result<()> {
+    let kv = ctx.kv(REPORTS_KV)?;
+    for key in kv.list().prefix(prefix(id)).execute().await?.keys {
+        kv.delete(&key.name).await?;
+    }
+    kv.delete(&format!("hidden/{}", id)).await?;
+    Ok(())
+}
+
+/// `GET /admin/reports`: the posts reports have hidden, with every report on them. Restore or
+/// remove one with `POST /posts/:id/moderation`, which also clears its reports.
+pub async fn queue(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(REPORTS_KV)?;
+    let mut queue = vec![];
+    for key in kv
+        .list()
+        .prefix("hidden/".to_string())
+        .execute()
+        .await?
+        .keys
+    {
+        let hidden = match kv.get(&key.name).await? {
+            Some(v) => v.as_json::<Hidden>()?,
+            None => continue,
+        };
+        let reports = reports_of(&kv, &hidden.post_id).await?;
+        queue.push(json!({
+            "post_id": hidden.post_id,
+            "title": hidden.title,
+            "author": hidden.author,
+            "hidden_at": hidden.hidden_at,
+            "reporters_when_hidden": hidden.reporters,
+            "reports": reports,
+        }));
+    }
+    Ok(Response::from_json(&queue)?)
+}
@@ -0,0 +1,165 @@
+use chrono::{DateTime, Duration, Months, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::storage::{self, PostStore};
+use crate::trace::Trace;
+use crate::{apikeys, firehose, likes, posts, settings};
+
+/// What the sweep does with a post past the retention period, from the `RETENTION_ACTION` var.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Archive,
+    Delete,
+}
+
+/// The instance's retention period in months, `None` when retention is off. Set with the
+/// `RETENTION_MONTHS` var; unset or `0` keeps posts forever.
+fn months(ctx: &RouteContext<Session>) -> Option<u32> {
+    ctx.var("RETENTION_MONTHS")
+        .ok()?
+        .to_string()
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|months| *months > 0)
+}
+
+fn action(ctx: &RouteContext<Session>) -> Result<Action> {
+    let action = ctx
+        .var("RETENTION_ACTION")
+        .map(|var| var.to_string())
+        .unwrap_or_default();
+    match action.trim() {
+        "" | "archive" => Ok(Action::Archive),
+        "delete" => Ok(Action::Delete),
+        other => Err(Error::RustError(format!(
+            "RETENTION_ACTION must be archive or delete, not {}",
+            other
+        ))),
+    }
+}
+
+/// `POST /bot/retention`, for a key with the `retention` scope.
+///
+/// Archives or deletes every post older than the retention period whose author hasn't opted
+/// out. Meant to be called by the deployment's scheduler, like `POST /bot/digest`; posts already
+/// archived are left alone by the archive action, so calling it often is harmless.
+pub async fn sweep(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if apikeys::authorize(&req, &ctx, apikeys::Scope::Retention)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    let months = months(&ctx)
+        .ok_or_else(|| ApiError::Unprocessable("Retention is off on this instance".to_string()))?;
+    let action = action(&ctx)?;
+    let cutoff = Utc::now()
+        .checked_sub_months(Months::new(months))
+        .ok_or_else(|| ApiError::Internal("Retention period is out of range".to_string()))?;
+
+    let store = storage::posts(&ctx)?;
+    let likes_kv = ctx.kv(likes::LIKES_KV)?;
+    let mut opted_out: HashMap<String, bool> = HashMap::new();
+    let mut swept = vec![];
+    for (id, stored) in store.list("", ctx.data().trace()).await? {
+        let mut post = match serde_json::from_str::<Value>(&stored) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        let old = post
+            .get("time")
+            .and_then(Value::as_str)
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .is_some_and(|time| time < cutoff);
+        // Deleted posts are left to `purge`.
+        if !old
+            || posts::is_deleted(&post)
+            || (action == Action::Archive && posts::is_archived(&post))
+        {
+            continue;
+        }
+        let author = match post.get("username").and_then(Value::as_str) {
+            Some(author) => author.to_string(),
+            None => continue,
+        };
+        if !opted_out.contains_key(&author) {
+            let opt_out = settings::retention(&ctx, &author).await?.opt_out;
+            opted_out.insert(author.clone(), opt_out);
+        }
+        if opted_out[&author] {
+            continue;
+        }
+
+        match action {
+            Action::Archive => {
+                if let Some(post_obj) = post.as_object_mut() {
+                    post_obj.insert("archived".to_string(), Value::Bool(true));
+                }
+                store.put(&id, &post).await?;
+                firehose::post_changed(&ctx, firehose::Kind::Update, &id, &post).await;
+            }
+            Action::Delete => {
+                store.delete(&id).await?;
+                likes::purged(&likes_kv, &id).await?;
+                firehose::publish(&ctx, firehose::Kind::Delete, &id, None).await;
+            }
+        }
+        swept.push(id);
+    }
+    console_log!("retention: {:?} {} posts", action, swept.len());
+    Ok(Response::from_json(&json!({
+        "action": action,
+        "cutoff": cutoff.to_rfc3339(),
+        "posts": swept,
+    }))?)
+}
+
+/// Removes for good every deleted post more than `window` past its deletion, answering their
+/// ids. Their like counters are left for `likes::compact`.
+pub async fn purge_expired(
+    store: &dyn PostStore,
+    likes_kv: &kv::KvStore,
+    window: Duration,
+    trace: &Trace,
+) -> Result<Vec<String>> {
+    let mut purged = vec![];
+    for (id, stored) in store.list("", trace).await? {
+        let expired = serde_json::from_str::<Value>(&stored)
+            .is_ok_and(|post| posts::is_deleted(&post) && posts::is_expired(window, &post));
+        if expired {
+            store.delete(&id).await?;
+            likes::purged(likes_kv, &id).await?;
+            purged.push(id);
+        }
+    }
+    Ok(purged)
+}
+
+/// `POST /bot/purge`, for a key with the `retention` scope.
+///
+/// Removes for good every deleted post past its restore window (`DELETED_POST_DAYS`). The
+/// scheduled `purge` job does the same daily; this is for running it on demand.
+pub async fn purge(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if apikeys::authorize(&req, &ctx, apikeys::Scope::Retention)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    let purged = purge_expired(
+        &*storage::posts(&ctx)?,
+        &ctx.kv(likes::LIKES_KV)?,
+        posts::restore_window(&ctx),
+        ctx.data().trace(),
+    )
+    .await?;
+    console_log!("purge: {} deleted posts", purged.len());
+    Ok(Response::from_json(&json!({ "posts": purged }))?)
+}
@@ -0,0 +1,257 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{apikeys, moderation, outbound, posts, session, utils};
+
+/// Keys in the `rss` namespace:
+///
+/// - `feed/<feed id>`: [`Feed`], where `feed id` is derived from the feed URL
+/// - `seen/<feed id>/<guid hash>`: an item that has already been posted
+const RSS_KV: &str = "rss";
+
+/// New items posted per feed and poll, so a feed added with a long backlog trickles in.
+const MAX_NEW_ITEMS: usize = 5;
+
+/// Longest content taken from an item's description.
+const MAX_CONTENT_CHARS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Feed {
+    id: String,
+    url: String,
+    community: String,
+    /// Service account the items are posted as.
+    account: String,
+    created_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewFeed {
+    url: String,
+    community: String,
+    account: String,
+}
+
+#[derive(Debug)]
+struct Item {
+    guid: String,
+    title: String,
+    link: String,
+    description: String,
+}
+
+/// The raw contents of the first `<name>` element in `xml`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    loop {
+        let start = from + xml[from..].find(&open)?;
+        let rest = &xml[start + open.len()..];
+        // `<title>` must not match `<titles>`.
+        if rest.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            let body = &rest[rest.find('>')? + 1..];
+            return Some(&body[..body.find(&format!("</{}>", name))?]);
+        }
+        from = start + open.len();
+    }
+}
+
+/// Unwraps CDATA, drops markup and decodes the entities feeds commonly use.
+fn text(raw: &str) -> String {
+    let raw = raw.trim();
+    let raw = raw
+        .strip_prefix("<![CDATA[")
+        .and_then(|inner| inner.strip_suffix("]]>"))
+        .map(String::from)
+        .unwrap_or_else(|| {
+            raw.replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&#39;", "'")
+                .replace("&amp;", "&")
+        });
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in raw.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out.trim().to_string()
+}
+
+/// Items of an RSS 2.0 document, in document order (usually newest first). Items with neither a
+/// guid, a link nor a title can't be deduplicated and are skipped.
+fn items(xml: &str) -> Vec<Item> {
+    xml.split("<item")
+        .skip(1)
+        .filter(|chunk| chunk.starts_with(|c: char| c == '>' || c.is_whitespace()))
+        .filter_map(|chunk| {
+            let chunk = &chunk[..chunk.find("</item>")?];
+            let field = |name: &str| element(chunk, name).map(text).unwrap_or_default();
+            let (title, link) = (field("title"), field("link"));
+            let guid = vec![field("guid"), link.clone(), title.clone()]
+                .into_iter()
+                .find(|candidate| !candidate.is_empty())?;
+            Some(Item {
+                guid,
+                title,
+                link,
+                description: field("description"),
+            })
+        })
+        .collect()
+}
+
+fn admin(ctx: &RouteContext<Session>) -> Result<bool> {
+    Ok(match session::current_user(ctx) {
+        Some(username) => moderation::is_admin(ctx, &username)?,
+        None => false,
+    })
+}
+
+/// `POST /admin/rss_feeds`
+pub async fn add_feed(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if !admin(&ctx)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let body = match req.json::<NewFeed>().await {
+        Ok(body) if !body.community.is_empty() => body,
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    let url = match Url::parse(&body.url) {
+        Ok(url) => url,
+        Err(_) => return Err(ApiError::BadRequest("`url` is not a URL".to_string())),
+    };
+    if let Err(e) = outbound::check_destination(&url, &outbound::Policy::default()) {
+        return Err(ApiError::BadRequest(e.to_string()));
+    }
+    if !apikeys::is_service_account(&ctx.kv(apikeys::API_KEYS_KV)?, &body.account).await? {
+        return Err(ApiError::BadRequest(
+            "`account` must be a service account".to_string(),
+        ));
+    }
+    let feed = Feed {
+        id: utils::sha256_hex(url.as_str())[..16].to_string(),
+        url: url.to_string(),
+        community: body.community,
+        account: body.account,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    ctx.kv(RSS_KV)?
+        .put(&format!("feed/{}", feed.id), &feed)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&feed)?)
+}
+
+async fn feeds(kv: &kv::KvStore) -> Result<Vec<Feed>> {
+    let mut feeds = vec![];
+    for key in kv.list().prefix("feed/".to_string()).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            feeds.push(v.as_json::<Feed>()?);
+        }
+    }
+    Ok(feeds)
+}
+
+/// `GET /admin/rss_feeds`
+pub async fn list_feeds(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if !admin(&ctx)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(&feeds(&ctx.kv(RSS_KV)?).await?)?)
+}
+
+/// `DELETE /admin/rss_feeds/:id`. Items already posted stay.
+pub async fn remove_feed(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if !admin(&ctx)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(RSS_KV)?;
+    if kv.get(&format!("feed/{}", id)).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    kv.delete(&format!("feed/{}", id)).await?;
+    Ok(Response::empty()?)
+}
+
+/// Posts the unseen items of one feed, oldest first, and returns how many were posted.
+async fn poll(ctx: &RouteContext<Session>, kv: &kv::KvStore, feed: &Feed) -> Result<usize> {
+    let fetched = outbound::get(
+        &feed.url,
+        &Headers::new(),
+        &outbound::Policy::default(),
+        ctx.data().trace(),
+    )
+    .await?;
+    if !(200..300).contains(&fetched.status) {
+        return Err(format!("{} answered {}", feed.url, fetched.status).into());
+    }
+    let xml = String::from_utf8_lossy(&fetched.body);
+
+    let mut new_items = vec![];
+    for item in items(&xml) {
+        let seen_key = format!("seen/{}/{}", feed.id, utils::sha256_hex(&item.guid));
+        if kv.get(&seen_key).await?.is_none() {
+            new_items.push((seen_key, item));
+        }
+        if new_items.len() == MAX_NEW_ITEMS {
+            break;
+        }
+    }
+
+    let posted = new_items.len();
+    for (seen_key, item) in new_items.into_iter().rev() {
+        // Several items go out within the same millisecond, so the id comes from the guid.
+        let id = format!("rss-{}-{}", feed.id, &seen_key[seen_key.len() - 16..]);
+        let mut content: String = item.description.chars().take(MAX_CONTENT_CHARS).collect();
+        if !item.link.is_empty() {
+            content = format!("{}\n\n{}", content, item.link).trim().to_string();
+        }
+        let mut post = json!({
+            "id": id,
+            "username": feed.account,
+            "title": item.title,
+            "content": content,
+            "time": Utc::now().to_rfc3339(),
+            "community": feed.community,
+            "rss_guid": item.guid,
+        });
+        posts::normalize_license(&mut post)?;
+        posts::insert(ctx, &id, &mut post).await?;
+        kv.put(&seen_key, &id)?.execute().await?;
+    }
+    Ok(posted)
+}
+
+/// `POST /bot/rss`, for a key with the `bridge` scope.
+///
+/// Polls every configured feed once. Like the digest, it is meant to be called on a schedule
+/// from outside, since cron handlers get no bindings. One broken feed doesn't stop the others.
+pub async fn poll_all(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if apikeys::authorize(&req, &ctx, apikeys::Scope::Bridge)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    let kv = ctx.kv(RSS_KV)?;
+    let mut results = vec![];
+    for feed in feeds(&kv).await? {
+        results.push(match poll(&ctx, &kv, &feed).await {
+            Ok(posted) => json!({ "feed": feed.id, "posted": posted }),
+            Err(e) => json!({ "feed": feed.id, "error": e.to_string() }),
+        });
+    }
+    Ok(Response::from_json(&results)?)
+}
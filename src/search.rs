@@ -0,0 +1,124 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashSet;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::withholding::Withheld;
+use crate::{posts, searches};
+
+/// Keys in the `search` namespace:
+///
+/// - `word/<term>/<post id>`: the post's title or content had `term` in it
+///
+/// Entries are only ever added. A post that lost a term in an edit, or was deleted, is weeded
+/// out when a query reads it back.
+const SEARCH_KV: &str = "search";
+
+/// Queries are cut down to this many distinct terms.
+const MAX_TERMS: usize = 5;
+
+/// A post is indexed under this many of its terms, title first. Every term is a KV write, and a
+/// worker only gets so many per request.
+pub const MAX_INDEXED_TERMS: usize = 200;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 50;
+
+fn text_terms(post: &Value) -> Vec<String> {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or("");
+    searches::terms(&format!("{} {}", field("title"), field("content")))
+}
+
+/// Adds `post` to the index under the terms of its title and content.
+pub async fn index(ctx: &RouteContext<Session>, id: &str, post: &Value) -> Result<()> {
+    let kv = ctx.kv(SEARCH_KV)?;
+    for term in text_terms(post).into_iter().take(MAX_INDEXED_TERMS) {
+        kv.put(&format!("word/{}/{}", term, id), "")?
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+/// The ids of posts indexed under `term`.
+async fn postings(kv: &kv::KvStore, term: &str) -> Result<HashSet<String>> {
+    let prefix = format!("word/{}/", term);
+    let keys = kv.list().prefix(prefix.clone()).execute().await?.keys;
+    Ok(keys
+        .into_iter()
+        .map(|key| key.name[prefix.len()..].to_string())
+        .collect())
+}
+
+/// Likes decayed by age, so a liked post stays near the top for a day or two and a new post
+/// isn't buried under old favourites.
+fn rank(post: &Value, now: DateTime<Utc>) -> f64 {
+    let likes = post
+        .get("likes")
+        .and_then(Value::as_i64)
+        .unwrap_or(0)
+        .max(0);
+    let age_hours = post
+        .get("time")
+        .and_then(Value::as_str)
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| (now - time.with_timezone(&Utc)).num_minutes().max(0) as f64 / 60.0)
+        .unwrap_or(f64::INFINITY);
+    (likes as f64 + 1.0) / (age_hours + 2.0).powf(1.5)
+}
+
+/// `GET /search?q=<words>[&limit=<n>][&license=<license>]`: posts whose title or content has
+/// every word of `q`, best ranked first, leaving out the posts `posts::load_listed` leaves out.
+/// `license` filters them as on `GET /posts`, see `posts::License::matches`.
+pub async fn search(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let url = req.url()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+    };
+    let mut query_terms = searches::terms(&param("q").unwrap_or_default());
+    query_terms.truncate(MAX_TERMS);
+    if query_terms.is_empty() {
+        return Err(ApiError::BadRequest(
+            "`q` needs at least one word".to_string(),
+        ));
+    }
+    let license = param("license");
+    let limit = param("limit")
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+
+    let kv = ctx.kv(SEARCH_KV)?;
+    let mut candidates: Option<HashSet<String>> = None;
+    for term in &query_terms {
+        let ids = postings(&kv, term).await?;
+        candidates = Some(match candidates {
+            Some(found) => found.intersection(&ids).cloned().collect(),
+            None => ids,
+        });
+    }
+
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    let mut found: Vec<Value> = posts::load_listed(&ctx, &withheld, candidates.unwrap_or_default())
+        .await?
+        .into_iter()
+        .filter(|post| {
+            license
+                .as_deref()
+                .is_none_or(|wanted| posts::License::of_value(post).matches(wanted))
+        })
+        .filter(|post| {
+            let post_terms: HashSet<String> = text_terms(post).into_iter().collect();
+            query_terms.iter().all(|term| post_terms.contains(term))
+        })
+        .collect();
+
+    let now = Utc::now();
+    found.sort_by(|a, b| rank(b, now).total_cmp(&rank(a, now)));
+    found.truncate(limit);
+    Ok(Response::from_json(&found)?)
+}
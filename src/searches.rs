@@ -0,0 +1,191 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{models, search, session};
+
+/// Keys in the `searches` namespace:
+///
+/// - `search/<username>/<id>`: the saved search itself
+/// - `term/<term>/<username>/<id>`: index entry, one per term of every saved search
+/// - `alert/<username>/<post id>`: a post that matched one of the user's searches
+const SEARCHES_KV: &str = "searches";
+
+/// Queries are cut down to this many distinct terms.
+const MAX_TERMS: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedSearch {
+    id: String,
+    username: String,
+    query: String,
+    terms: Vec<String>,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Alert {
+    search_id: String,
+    query: String,
+    post_id: String,
+    title: String,
+    matched_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewSearch {
+    query: String,
+}
+
+/// Lowercased words of two or more characters, without duplicates, in order of appearance.
+pub fn terms(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2)
+        .map(str::to_lowercase)
+        .filter(|word| seen.insert(word.clone()))
+        .collect()
+}
+
+fn search_key(username: &str, id: &str) -> String {
+    format!("search/{}/{}", username, id)
+}
+
+fn term_key(term: &str, username: &str, id: &str) -> String {
+    format!("term/{}/{}/{}", term, username, id)
+}
+
+async fn list_prefix(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let keys = kv.list().prefix(prefix).execute().await?.keys;
+    Ok(keys.into_iter().map(|key| key.name).collect())
+}
+
+/// `POST /searches`
+pub async fn create(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let body = models::from_body::<NewSearch>(&mut req).await?;
+    let mut search_terms = terms(&body.query);
+    search_terms.truncate(MAX_TERMS);
+    if search_terms.is_empty() {
+        return Err(ApiError::BadRequest(
+            "`query` needs at least one word".to_string(),
+        ));
+    }
+
+    let now = Utc::now();
+    let search = SavedSearch {
+        id: now.timestamp_millis().to_string(),
+        username,
+        query: body.query,
+        terms: search_terms,
+        created_at: now.to_rfc3339(),
+    };
+    let kv = ctx.kv(SEARCHES_KV)?;
+    kv.put(&search_key(&search.username, &search.id), &search)?
+        .execute()
+        .await?;
+    for term in &search.terms {
+        kv.put(&term_key(term, &search.username, &search.id), &search.id)?
+            .execute()
+            .await?;
+    }
+    Ok(Response::from_json(&search)?)
+}
+
+/// `GET /searches`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(SEARCHES_KV)?;
+    let mut searches = vec![];
+    for key in list_prefix(&kv, format!("search/{}/", username)).await? {
+        if let Some(v) = kv.get(&key).await? {
+            searches.push(v.as_json::<SavedSearch>()?);
+        }
+    }
+    Ok(Response::from_json(&searches)?)
+}
+
+/// `DELETE /searches/:id`
+pub async fn delete(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let kv = ctx.kv(SEARCHES_KV)?;
+    let search = match kv.get(&search_key(&username, &id)).await? {
+        Some(v) => v.as_json::<SavedSearch>()?,
+        None => return Err(ApiError::NotFound),
+    };
+    for term in &search.terms {
+        kv.delete(&term_key(term, &username, &id)).await?;
+    }
+    kv.delete(&search_key(&username, &id)).await?;
+    Ok(Response::empty()?)
+}
+
+/// `GET /searches/alerts`, oldest match first.
+pub async fn alerts(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let kv = ctx.kv(SEARCHES_KV)?;
+    let mut alerts = vec![];
+    for key in list_prefix(&kv, format!("alert/{}/", username)).await? {
+        if let Some(v) = kv.get(&key).await? {
+            alerts.push(v.as_json::<Alert>()?);
+        }
+    }
+    Ok(Response::from_json(&alerts)?)
+}
+
+/// Checks a freshly written post against the saved-search index and records an alert for
+/// every search whose terms all appear in it. Only searches sharing at least one term with the
+/// post are ever loaded, and only through the post's first [`search::MAX_INDEXED_TERMS`] terms,
+/// title first: each is a KV list on the write path. Searches for words further into a long post
+/// aren't alerted, just as `search` doesn't find them.
+pub async fn alert_matches(ctx: &RouteContext<Session>, post_id: &str, post: &Value) -> Result<()> {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or("");
+    let author = field("username");
+    let ordered_terms = terms(&format!("{} {}", field("title"), field("content")));
+    let post_terms: HashSet<&str> = ordered_terms.iter().map(String::as_str).collect();
+
+    let kv = ctx.kv(SEARCHES_KV)?;
+    let mut candidates = HashSet::new();
+    for term in ordered_terms.iter().take(search::MAX_INDEXED_TERMS) {
+        for key in list_prefix(&kv, format!("term/{}/", term)).await? {
+            // term/<term>/<username>/<id>
+            let mut parts = key.splitn(4, '/').skip(2);
+            if let (Some(username), Some(id)) = (parts.next(), parts.next()) {
+                if username != author {
+                    candidates.insert(search_key(username, id));
+                }
+            }
+        }
+    }
+
+    let now = Utc::now().to_rfc3339();
+    for key in candidates {
+        let search = match kv.get(&key).await? {
+            Some(v) => v.as_json::<SavedSearch>()?,
+            None => continue,
+        };
+        if !search
+            .terms
+            .iter()
+            .all(|term| post_terms.contains(term.as_str()))
+        {
+            continue;
+        }
+        let alert = Alert {
+            search_id: search.id,
+            query: search.query,
+            post_id: post_id.to_string(),
+            title: field("title").to_string(),
+            matched_at: now.clone(),
+        };
+        kv.put(&format!("alert/{}/{}", search.username, post_id), &alert)?
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+/// Bits in one generation of a filter. At [`GENERATION_CAPACITY`] posts, 1 KiB keeps false
+/// positives (posts taken for seen that weren't) around 2%.
+const BITS: usize = 8 * 1024;
+
+/// Bits each post sets.
+const HASHES: u64 = 5;
+
+/// Posts a generation takes before it becomes the previous one and a fresh one starts, so the
+/// oldest posts are forgotten a generation at a time rather than all at once.
+const GENERATION_CAPACITY: u32 = 1000;
+
+/// The posts a user was already served, as a Bloom filter: it can wrongly say a post was seen,
+/// never that a seen post wasn't, and stays the same size however many posts go in. Stored by
+/// `feeds` under `seen/<username>` as [`Stored`].
+#[derive(Debug)]
+pub struct Seen {
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    /// Posts in `current`.
+    count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Stored {
+    /// Base64 of the filters' bits.
+    current: String,
+    previous: String,
+    count: u32,
+}
+
+fn empty() -> Vec<u8> {
+    vec![0; BITS / 8]
+}
+
+fn decode(filter: &str) -> Vec<u8> {
+    STANDARD
+        .decode(filter)
+        .ok()
+        .filter(|bits| bits.len() == BITS / 8)
+        .unwrap_or_else(empty)
+}
+
+/// The bits `id` sets, by double hashing one SHA-256 digest.
+fn positions(id: &str) -> impl Iterator<Item = usize> {
+    let digest = Sha256::digest(id.as_bytes());
+    let half = |at: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let (h1, h2) = (half(0), half(8));
+    (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % BITS as u64) as usize)
+}
+
+fn holds(bits: &[u8], id: &str) -> bool {
+    positions(id).all(|at| bits[at / 8] & (1 << (at % 8)) != 0)
+}
+
+impl Seen {
+    /// Whether `id` was, most likely, served before.
+    pub fn contains(&self, id: &str) -> bool {
+        holds(&self.current, id) || holds(&self.previous, id)
+    }
+
+    pub fn insert(&mut self, id: &str) {
+        if self.contains(id) {
+            return;
+        }
+        if self.count >= GENERATION_CAPACITY {
+            self.previous = std::mem::replace(&mut self.current, empty());
+            self.count = 0;
+        }
+        for at in positions(id) {
+            self.current[at / 8] |= 1 << (at % 8);
+        }
+        self.count += 1;
+    }
+}
+
+fn key(username: &str) -> String {
+    format!("seen/{}", username)
+}
+
+/// `username`'s filter, empty if they were never served anything.
+pub async fn load(kv: &kv::KvStore, username: &str) -> Result<Seen> {
+    let stored = match kv.get(&key(username)).await? {
+        Some(v) => v.as_json::<Stored>().ok(),
+        None => None,
+    };
+    Ok(match stored {
+        Some(stored) => Seen {
+            current: decode(&stored.current),
+            previous: decode(&stored.previous),
+            count: stored.count,
+        },
+        None => Seen {
+            current: empty(),
+            previous: empty(),
+            count: 0,
+        },
+    })
+}
+
+pub async fn save(kv: &kv::KvStore, username: &str, seen: &Seen) -> Result<()> {
+    let stored = Stored {
+        current: STANDARD.encode(&seen.current),
+        previous: STANDARD.encode(&seen.previous),
+        count: seen.count,
+    };
+    kv.put(&key(username), &stored)?.execute().await?;
+    Ok(())
+}
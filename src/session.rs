@@ -0,0 +1,298 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::auth::{self, Verdict};
+use crate::error::{ApiError, ApiResult};
+use crate::trace::Trace;
+use crate::{apikeys, outbound, users};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const COOKIE_NAME: &str = "session";
+
+/// How long a minted session stays valid, in seconds.
+pub const SESSION_TTL: i64 = 60 * 60 * 24 * 30;
+
+fn sign(secret: &str, payload: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Signs `payload` into a `base64(payload).base64(hmac)` token that can be handed to clients.
+pub fn seal(payload: &str, secret: &str) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(payload);
+    let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload).finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// Lowercase hex HMAC-SHA256 of `payload` under `secret`, for ids and tokens that are derived
+/// from a secret without giving it away.
+pub fn keyed_hash(payload: &str, secret: &str) -> String {
+    sign(secret, payload)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The payload of a token made by [`seal`], if its signature checks out.
+pub fn unseal(token: &str, secret: &str) -> Option<String> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    sign(secret, payload).verify_slice(&signature).ok()?;
+    String::from_utf8(URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+/// Builds a `Set-Cookie` value carrying `username` and its expiry, signed with `secret`.
+///
+/// The token is `username|expires_at` passed through [`seal`], so it can be checked without
+/// asking anyone else.
+pub fn mint(username: &str, secret: &str) -> String {
+    let expires_at = Utc::now().timestamp() + SESSION_TTL;
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+        COOKIE_NAME,
+        seal(&format!("{}|{}", username, expires_at), secret),
+        SESSION_TTL
+    )
+}
+
+/// Finds our session cookie in a `Cookie` header and returns the username it was minted for,
+/// provided the signature checks out and it hasn't expired.
+pub fn verify(cookie_header: &str, secret: &str) -> Option<String> {
+    let token = cookie_header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value)?;
+    verify_token(token, secret)
+}
+
+/// Like [`verify`], for the bare token (what the session cookie holds).
+pub fn verify_token(token: &str, secret: &str) -> Option<String> {
+    let payload = unseal(token, secret)?;
+    let (username, expires_at) = payload.rsplit_once('|')?;
+    if expires_at.parse::<i64>().ok()? <= Utc::now().timestamp() {
+        return None;
+    }
+    Some(username.to_string())
+}
+
+/// Asks the auth server who a session cookie belongs to. `None` means the cookie was rejected.
+async fn verify_remote(auth_server: &str, cookie: &str, trace: &Trace) -> Result<Option<String>> {
+    let verify_url = Url::parse(&format!("{}/verify", auth_server.trim_end_matches('/')))?;
+    let policy = outbound::Policy::allow_only(verify_url.host_str().unwrap_or_default());
+    let mut headers = Headers::new();
+    headers.set("Cookie", cookie)?;
+    let res = outbound::get(verify_url.as_str(), &headers, &policy, trace).await?;
+    if !(200..300).contains(&res.status) {
+        return Ok(None);
+    }
+    let username = String::from_utf8(res.body).map_err(|_| Error::BadEncoding)?;
+    Ok(Some(username.trim().to_string()))
+}
+
+/// Service accounts and banned users never sign in, whatever the auth server says and however
+/// long a session minted before the ban still has to run.
+async fn signed_in(env: &Env, username: Option<String>) -> Result<Option<String>> {
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let api_keys = env.kv(apikeys::API_KEYS_KV)?;
+    if apikeys::is_service_account(&api_keys, &username).await?
+        || users::is_banned(&env.kv(users::USERS_KV)?, &username).await?
+    {
+        return Ok(None);
+    }
+    Ok(Some(username))
+}
+
+/// The user a request is acting as, if it carries a valid session. Cookies the worker minted
+/// and the auth server's HS256 JWTs are checked locally; anything else is passed to the auth
+/// server's `/verify`. Whichever way the user is found, [`signed_in`] has the last word.
+///
+/// API clients that can't keep cookies may send either token as `Authorization: Bearer`.
+///
+/// Bindings are only looked up once there is a credential to check, so anonymous requests (most
+/// of them, and most first requests in a colo) don't pay for them.
+async fn identify(req: &Request, env: &Env, trace: &Trace) -> Result<Option<String>> {
+    let authorization = req.headers().get("Authorization")?.unwrap_or_default();
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        let token = token.trim();
+        let secret = env.secret("SESSION_SECRET")?.to_string();
+        if let Some(username) = verify_token(token, &secret) {
+            return signed_in(env, Some(username)).await;
+        }
+        return match auth::verify_jwt(env, token, trace).await? {
+            Verdict::Valid(username) => signed_in(env, Some(username)).await,
+            Verdict::Invalid | Verdict::Unknown => Ok(None),
+        };
+    }
+    let cookie = req.headers().get("Cookie")?.unwrap_or_default();
+    if cookie.trim().is_empty() {
+        return Ok(None);
+    }
+    let secret = env.secret("SESSION_SECRET")?.to_string();
+    if let Some(username) = verify(&cookie, &secret) {
+        return signed_in(env, Some(username)).await;
+    }
+    match auth::verify_cookies(env, &cookie, trace).await? {
+        Verdict::Valid(username) => return signed_in(env, Some(username)).await,
+        Verdict::Invalid => return Ok(None),
+        Verdict::Unknown => {}
+    }
+    let auth_server = env.var("AUTH_SERVER_URL")?.to_string();
+    signed_in(env, verify_remote(&auth_server, &cookie, trace).await?).await
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthedUser {
+    pub username: String,
+}
+
+/// What the session middleware made of a request. The router hands it to every route as its
+/// data, so routes never read cookies or tokens themselves.
+#[derive(Debug, Clone)]
+pub struct Session {
+    user: Option<AuthedUser>,
+    /// Why the session couldn't be checked, e.g. the auth server was unreachable.
+    failure: Option<String>,
+    /// The request's trace, for the fetches routes make.
+    trace: Trace,
+    /// The worker's `Env`, for bindings `worker` 0.0.7 can't look up from a route, such as D1
+    /// (see `storage`) or KV with options (see `replica`).
+    bindings: JsValue,
+}
+
+impl Session {
+    /// Checks whatever session `req` carries. Runs before routing, for every request, so a
+    /// failure is kept for the routes that need a user rather than failing the request.
+    pub async fn of(req: &Request, env: &Env, trace: Trace) -> Session {
+        let started_at = Utc::now().timestamp_millis();
+        let identified = identify(req, env, &trace).await;
+        trace.time("auth", started_at);
+        let bindings = JsValue::from(env);
+        match identified {
+            Ok(username) => Session {
+                user: username
+                    .map(|username| username.trim().to_string())
+                    .filter(|username| !username.is_empty())
+                    .map(|username| AuthedUser { username }),
+                failure: None,
+                trace,
+                bindings: bindings.clone(),
+            },
+            Err(e) => Session {
+                user: None,
+                failure: Some(e.to_string()),
+                trace,
+                bindings,
+            },
+        }
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| user.username.as_str())
+    }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    pub fn bindings(&self) -> &JsValue {
+        &self.bindings
+    }
+}
+
+/// The signed-in user, for routes that need one: 401 without a session, 500 when it couldn't be
+/// checked.
+pub fn authed(ctx: &RouteContext<Session>) -> ApiResult<AuthedUser> {
+    let session = ctx.data();
+    match (&session.user, &session.failure) {
+        (Some(user), _) => Ok(user.clone()),
+        (None, Some(failure)) => Err(ApiError::Internal(failure.clone())),
+        (None, None) => Err(ApiError::Unauthorized),
+    }
+}
+
+/// The signed-in user's name, if any, for routes anyone may use.
+pub fn current_user(ctx: &RouteContext<Session>) -> Option<String> {
+    ctx.data().user.as_ref().map(|user| user.username.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    /// The token part of a `Set-Cookie` value from [`mint`].
+    fn token_of(set_cookie: &str) -> &str {
+        set_cookie
+            .split(';')
+            .next()
+            .and_then(|pair| pair.split_once('='))
+            .map(|(_, token)| token)
+            .unwrap()
+    }
+
+    #[test]
+    fn verifies_minted_sessions() {
+        let set_cookie = mint("alice", SECRET);
+        let token = token_of(&set_cookie);
+        assert_eq!(verify_token(token, SECRET).as_deref(), Some("alice"));
+        let cookie = format!("theme=dark; {}={}; other=1", COOKIE_NAME, token);
+        assert_eq!(verify(&cookie, SECRET).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn rejects_sessions_signed_with_another_secret() {
+        let set_cookie = mint("alice", "someone else's secret");
+        assert_eq!(verify_token(token_of(&set_cookie), SECRET), None);
+    }
+
+    #[test]
+    fn rejects_tampered_sessions() {
+        let set_cookie = mint("alice", SECRET);
+        let (_, signature) = token_of(&set_cookie).split_once('.').unwrap();
+        let expires_at = Utc::now().timestamp() + SESSION_TTL;
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(format!("bob|{}", expires_at)),
+            signature
+        );
+        assert_eq!(verify_token(&forged, SECRET), None);
+        assert_eq!(verify_token("bob", SECRET), None);
+        assert_eq!(verify(&format!("{}=", COOKIE_NAME), SECRET), None);
+    }
+
+    #[test]
+    fn rejects_expired_sessions() {
+        let expired = seal(&format!("alice|{}", Utc::now().timestamp() - 1), SECRET);
+        assert_eq!(verify_token(&expired, SECRET), None);
+    }
+
+    #[test]
+    fn keyed_hash_is_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            keyed_hash("what do ya want for nothing?", "Jefe"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn only_reads_the_session_cookie() {
+        let token = token_of(&mint("alice", SECRET)).to_string();
+        assert_eq!(verify(&format!("not_session={}", token), SECRET), None);
+    }
+}
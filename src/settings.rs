@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{models, session};
+
+/// Keys in the `settings` namespace:
+///
+/// - `languages/<username>`: [`Languages`]
+/// - `retention/<username>`: [`Retention`]
+/// - `profile_views/<username>`: [`ProfileViews`]
+/// - `branding`: the instance's name, logo and colors, see `branding`
+pub const SETTINGS_KV: &str = "settings";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Languages {
+    /// Primary language subtags (`en`, `pt`, ...). Empty means no preference.
+    pub languages: Vec<String>,
+}
+
+impl Languages {
+    /// Whether a post tagged `lang` belongs in this user's feed. Posts without a `lang` always do.
+    pub fn wants(&self, lang: Option<&str>) -> bool {
+        match lang.and_then(primary_subtag) {
+            Some(lang) => self.languages.is_empty() || self.languages.contains(&lang),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Retention {
+    /// Keeps the user's posts out of the instance's retention sweep, see `retention`.
+    pub opt_out: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProfileViews {
+    /// Off unless the user turns it on. While on, the profiles they look at record the view, and
+    /// they see who looked at theirs; both only with users who have it on too, see
+    /// `profile_views`.
+    pub enabled: bool,
+}
+
+/// `pt-BR` -> `pt`. `None` for anything that isn't a two or three letter language code.
+fn primary_subtag(tag: &str) -> Option<String> {
+    let primary = tag.trim().split(['-', '_']).next()?;
+    if (2..=3).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(primary.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+pub async fn languages(ctx: &RouteContext<Session>, username: &str) -> Result<Languages> {
+    let kv = ctx.kv(SETTINGS_KV)?;
+    match kv.get(&format!("languages/{}", username)).await? {
+        Some(v) => Ok(v.as_json::<Languages>()?),
+        None => Ok(Languages::default()),
+    }
+}
+
+/// `GET /settings/languages`
+pub async fn get_languages(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    Ok(Response::from_json(&languages(&ctx, &username).await?)?)
+}
+
+/// `PUT /settings/languages`
+pub async fn put_languages(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let body = models::from_body::<Languages>(&mut req).await?;
+    let mut languages = vec![];
+    for tag in &body.languages {
+        match primary_subtag(tag) {
+            Some(lang) if !languages.contains(&lang) => languages.push(lang),
+            Some(_) => {}
+            None => {
+                return Err(ApiError::BadRequest(format!(
+                    "`{}` is not a language code",
+                    tag
+                )))
+            }
+        }
+    }
+    let languages = Languages { languages };
+    let kv = ctx.kv(SETTINGS_KV)?;
+    kv.put(&format!("languages/{}", username), &languages)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&languages)?)
+}
+
+pub async fn retention(ctx: &RouteContext<Session>, username: &str) -> Result<Retention> {
+    let kv = ctx.kv(SETTINGS_KV)?;
+    match kv.get(&format!("retention/{}", username)).await? {
+        Some(v) => Ok(v.as_json::<Retention>()?),
+        None => Ok(Retention::default()),
+    }
+}
+
+/// `GET /settings/retention`
+pub async fn get_retention(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    Ok(Response::from_json(&retention(&ctx, &username).await?)?)
+}
+
+/// `PUT /settings/retention`
+pub async fn put_retention(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let retention = models::from_body::<Retention>(&mut req).await?;
+    let kv = ctx.kv(SETTINGS_KV)?;
+    kv.put(&format!("retention/{}", username), &retention)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&retention)?)
+}
+
+pub async fn profile_views(ctx: &RouteContext<Session>, username: &str) -> Result<ProfileViews> {
+    let kv = ctx.kv(SETTINGS_KV)?;
+    match kv.get(&format!("profile_views/{}", username)).await? {
+        Some(v) => Ok(v.as_json::<ProfileViews>()?),
+        None => Ok(ProfileViews::default()),
+    }
+}
+
+/// `GET /settings/profile_views`
+pub async fn get_profile_views(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    Ok(Response::from_json(&profile_views(&ctx, &username).await?)?)
+}
+
+/// `PUT /settings/profile_views`
+pub async fn put_profile_views(
+    mut req: Request,
+    ctx: RouteContext<Session>,
+) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let profile_views = models::from_body::<ProfileViews>(&mut req).await?;
+    let kv = ctx.kv(SETTINGS_KV)?;
+    kv.put(&format!("profile_views/{}", username), &profile_views)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&profile_views)?)
+}
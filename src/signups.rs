@@ -0,0 +1,202 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{bots, isolate, models, moderation, outbound, session, tenants, utils};
+
+/// Keys in the `signups` namespace:
+///
+/// - `limits`: [`Limits`], set by admins; the defaults apply until then
+/// - `ip/<ip>/<yyyy-mm-ddThh>`: accounts created from `ip` in that UTC hour
+/// - `asn/<asn>/<yyyy-mm-ddThh>`: accounts created from network `asn` in that UTC hour
+const SIGNUPS_KV: &str = "signups";
+
+/// Hourly counters are kept a little past their hour, then KV drops them.
+const COUNTER_TTL: u64 = 60 * 60 * 2;
+
+const LIMITS_CACHE_KEY: &str = "signups/limits";
+
+/// How long an isolate keeps the limits, in milliseconds.
+const LIMITS_TTL_MS: i64 = 60 * 1000;
+
+/// Header carrying the Turnstile token of a challenged signup.
+pub const TURNSTILE_HEADER: &str = "CF-Turnstile-Response";
+
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// How many accounts may be created per hour from one IP and from one network (ASN). Past a
+/// `challenge_*` count a signup has to pass a Turnstile challenge; past a `max_*` count it is
+/// refused until the hour is over. Requests from likely bots (see `bots`) are challenged from
+/// the first signup.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Limits {
+    pub challenge_per_ip: u32,
+    pub max_per_ip: u32,
+    pub challenge_per_asn: u32,
+    pub max_per_asn: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            challenge_per_ip: 2,
+            max_per_ip: 5,
+            challenge_per_asn: 20,
+            max_per_asn: 100,
+        }
+    }
+}
+
+/// Where a signup comes from.
+struct Origin {
+    ip: String,
+    asn: u32,
+}
+
+impl Origin {
+    fn of(req: &Request) -> Result<Origin> {
+        Ok(Origin {
+            ip: utils::client_ip(req)?,
+            asn: req.cf().asn(),
+        })
+    }
+
+    fn keys(&self) -> (String, String) {
+        let hour = Utc::now().format("%Y-%m-%dT%H");
+        (
+            format!("ip/{}/{}", self.ip, hour),
+            format!("asn/{}/{}", self.asn, hour),
+        )
+    }
+}
+
+fn limits_cache_key(ctx: &RouteContext<Session>) -> String {
+    tenants::isolate_key(ctx.data().bindings(), LIMITS_CACHE_KEY)
+}
+
+/// Read on every signup, so kept in the isolate for [`LIMITS_TTL_MS`].
+async fn limits(ctx: &RouteContext<Session>) -> Result<Limits> {
+    let cache_key = limits_cache_key(ctx);
+    if let Some(limits) = isolate::get::<Limits>(&cache_key) {
+        return Ok(limits);
+    }
+    let kv = ctx.kv(SIGNUPS_KV)?;
+    let limits = match kv.get("limits").await? {
+        Some(v) => v.as_json::<Limits>()?,
+        None => Limits::default(),
+    };
+    isolate::put(&cache_key, &limits, LIMITS_TTL_MS);
+    Ok(limits)
+}
+
+async fn count(kv: &kv::KvStore, key: &str) -> Result<u32> {
+    Ok(match kv.get(key).await? {
+        Some(v) => v.as_string().parse().unwrap_or(0),
+        None => 0,
+    })
+}
+
+/// Whether Turnstile accepts the token the request carries. Without a `TURNSTILE_SECRET` there
+/// is nothing to check tokens against, and challenged signups are refused like capped ones.
+async fn passes_challenge(req: &Request, ctx: &RouteContext<Session>, ip: &str) -> Result<bool> {
+    let token = match req.headers().get(TURNSTILE_HEADER)? {
+        Some(token) if !token.trim().is_empty() => token,
+        _ => return Ok(false),
+    };
+    let secret = match ctx.secret("TURNSTILE_SECRET") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Ok(false),
+    };
+    let body = json!({ "secret": secret, "response": token.trim(), "remoteip": ip });
+    let policy = outbound::Policy::allow_only("challenges.cloudflare.com");
+    let res = outbound::post_json(TURNSTILE_VERIFY_URL, &body, &policy, ctx.data().trace()).await?;
+    Ok(serde_json::from_slice::<Value>(&res.body)
+        .ok()
+        .and_then(|outcome| outcome.get("success")?.as_bool())
+        .unwrap_or(false))
+}
+
+/// Checks a request that is about to create an account against the signup limits. Call
+/// [`record`] once the account exists.
+pub async fn check(req: &Request, ctx: &RouteContext<Session>) -> ApiResult<()> {
+    let kv = ctx.kv(SIGNUPS_KV)?;
+    let limits = limits(ctx).await?;
+    let origin = Origin::of(req)?;
+    let (ip_key, asn_key) = origin.keys();
+    let (by_ip, by_asn) = (count(&kv, &ip_key).await?, count(&kv, &asn_key).await?);
+
+    if by_ip >= limits.max_per_ip || by_asn >= limits.max_per_asn {
+        console_log!("signups: refused {} (AS{})", origin.ip, origin.asn);
+        return Err(ApiError::TooManyRequests(
+            "Too many accounts were created from your network, try again later".to_string(),
+        ));
+    }
+    let suspicious = bots::score(req, ctx).await? >= bots::LIKELY_BOT;
+    if (by_ip >= limits.challenge_per_ip || by_asn >= limits.challenge_per_asn || suspicious)
+        && !passes_challenge(req, ctx, &origin.ip)
+            .await
+            .map_err(|e| ApiError::Upstream(format!("Turnstile: {}", e)))?
+    {
+        return Err(ApiError::TooManyRequests(format!(
+            "Signing up from your network needs a Turnstile token in `{}`",
+            TURNSTILE_HEADER
+        )));
+    }
+    Ok(())
+}
+
+async fn increment(ctx: &RouteContext<Session>, req: &Request) -> Result<()> {
+    let kv = ctx.kv(SIGNUPS_KV)?;
+    let (ip_key, asn_key) = Origin::of(req)?.keys();
+    for key in [ip_key, asn_key] {
+        let count = count(&kv, &key).await? + 1;
+        kv.put(&key, count.to_string())?
+            .expiration_ttl(COUNTER_TTL)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Counts an account just created by `req`. Counts are read-modify-write, so a burst may be
+/// undercounted by a few; the caps are coarse anyway. Failures are logged, never returned.
+pub async fn record(req: &Request, ctx: &RouteContext<Session>) {
+    if let Err(e) = increment(ctx, req).await {
+        console_log!("signup count failed: {}", e);
+    }
+}
+
+fn admin(ctx: &RouteContext<Session>) -> Result<bool> {
+    Ok(match session::current_user(ctx) {
+        Some(username) => moderation::is_admin(ctx, &username)?,
+        None => false,
+    })
+}
+
+/// `GET /admin/signup_limits`
+pub async fn get_limits(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if !admin(&ctx)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(&limits(&ctx).await?)?)
+}
+
+/// `PUT /admin/signup_limits`
+pub async fn put_limits(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if !admin(&ctx)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let limits = models::from_body::<Limits>(&mut req).await?;
+    if limits.challenge_per_ip > limits.max_per_ip || limits.challenge_per_asn > limits.max_per_asn
+    {
+        return Err(ApiError::BadRequest(
+            "Challenges have to start at or below the caps".to_string(),
+        ));
+    }
+    ctx.kv(SIGNUPS_KV)?.put("limits", limits)?.execute().await?;
+    isolate::put(&limits_cache_key(&ctx), &limits, LIMITS_TTL_MS);
+    Ok(Response::from_json(&limits)?)
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use worker::*;
+
+use crate::error::ApiResult;
+use crate::session::Session;
+use crate::{branding, communities, models, posts, storage, users};
+
+/// The window "this week" and "active" refer to on `GET /about/stats`.
+const WEEK_DAYS: i64 = 7;
+
+const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.0";
+
+/// Public numbers about the instance. Only public posts count, and nothing from quarantined
+/// communities.
+#[derive(Serialize, Debug)]
+struct Stats {
+    total_users: usize,
+    total_posts: usize,
+    posts_this_week: usize,
+    /// Communities with a post made this week.
+    active_communities: usize,
+    /// Users who posted in the last 30 and 180 days, which is how nodeinfo counts activity.
+    #[serde(skip)]
+    active_month: usize,
+    #[serde(skip)]
+    active_half_year: usize,
+}
+
+fn posted_since(post: &models::Post, since: DateTime<Utc>) -> bool {
+    post.time
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .is_some_and(|time| time >= since)
+}
+
+fn authors_since(posts: &[models::Post], since: DateTime<Utc>) -> usize {
+    posts
+        .iter()
+        .filter(|post| posted_since(post, since))
+        .map(|post| post.username.as_str())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+async fn gather(ctx: &RouteContext<Session>) -> Result<Stats> {
+    let total_users = ctx.kv(users::USERS_KV)?.list().execute().await?.keys.len();
+    let listed = posts::list_public(&*storage::posts(ctx)?, ctx.data().trace()).await?;
+    let listed = communities::without_quarantined(ctx, listed).await?;
+
+    let now = Utc::now();
+    let week_ago = now - Duration::days(WEEK_DAYS);
+    let this_week: Vec<&models::Post> = listed
+        .iter()
+        .filter(|post| posted_since(post, week_ago))
+        .collect();
+    let active_communities = this_week
+        .iter()
+        .filter_map(|post| post.extra.get("community")?.as_str())
+        .collect::<HashSet<_>>()
+        .len();
+    Ok(Stats {
+        total_users,
+        total_posts: listed.len(),
+        posts_this_week: this_week.len(),
+        active_communities,
+        active_month: authors_since(&listed, now - Duration::days(30)),
+        active_half_year: authors_since(&listed, now - Duration::days(180)),
+    })
+}
+
+/// `GET /about/stats`
+pub async fn about(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    Ok(Response::from_json(&gather(&ctx).await?)?)
+}
+
+/// `GET /.well-known/nodeinfo`: where fediverse crawlers find the nodeinfo document.
+pub async fn nodeinfo_links(req: Request, _ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let mut href = req.url()?;
+    href.set_path("/nodeinfo/2.0");
+    href.set_query(None);
+    Ok(Response::from_json(&json!({
+        "links": [{ "rel": NODEINFO_SCHEMA, "href": href.as_str() }],
+    }))?)
+}
+
+/// `GET /nodeinfo/2.0`, the nodeinfo document built from the same numbers as `/about/stats`.
+pub async fn nodeinfo(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let stats = gather(&ctx).await?;
+    let branding = branding::get(&ctx).await?;
+    Ok(Response::from_json(&json!({
+        "version": "2.0",
+        "software": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        // The instance offers a Mastodon-style client API and exports, but federates over none
+        // of the protocols nodeinfo knows.
+        "protocols": [],
+        "services": { "inbound": [], "outbound": [] },
+        "openRegistrations": true,
+        "usage": {
+            "users": {
+                "total": stats.total_users,
+                "activeMonth": stats.active_month,
+                "activeHalfyear": stats.active_half_year,
+            },
+            "localPosts": stats.total_posts,
+        },
+        "metadata": { "nodeName": branding::name(&branding) },
+    }))?)
+}
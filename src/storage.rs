@@ -0,0 +1,371 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use js_sys::{Array, Promise, Reflect, JSON};
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::trace::Trace;
+use crate::{cache, journal, moderation, posts, session, utils};
+
+/// Binding of the D1 database. Its schema is in `migrations/`, applied with
+/// `wrangler d1 migrations apply`.
+const DATABASE: &str = "DB";
+
+/// Var naming where posts are read and written: `kv` (the default) or `d1`. Switch it to `d1`
+/// once `POST /admin/storage/migrate` has copied every post over.
+const BACKEND_VAR: &str = "POSTS_STORAGE";
+
+/// Var capping how many KV reads a listing has in flight at once, [`DEFAULT_KV_CONCURRENCY`]
+/// when unset. Higher answers sooner; a worker can only have so many subrequests open, though.
+const KV_CONCURRENCY_VAR: &str = "KV_CONCURRENCY";
+
+const DEFAULT_KV_CONCURRENCY: usize = 16;
+
+/// Posts `POST /admin/storage/migrate` copies per call, well within a worker's subrequests.
+const MIGRATE_BATCH: u64 = 100;
+
+// `worker` 0.0.7 predates D1, so its JavaScript API is bound here directly.
+#[wasm_bindgen]
+extern "C" {
+    type D1Database;
+
+    #[wasm_bindgen(method, catch)]
+    fn prepare(this: &D1Database, query: &str)
+        -> std::result::Result<D1PreparedStatement, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn batch(this: &D1Database, statements: &Array) -> std::result::Result<Promise, JsValue>;
+
+    type D1PreparedStatement;
+
+    #[wasm_bindgen(method, catch, variadic)]
+    fn bind(
+        this: &D1PreparedStatement,
+        values: &Array,
+    ) -> std::result::Result<D1PreparedStatement, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn all(this: &D1PreparedStatement) -> std::result::Result<Promise, JsValue>;
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+/// The `DB` binding, if the worker has one.
+fn database(bindings: &JsValue) -> Option<D1Database> {
+    Reflect::get(bindings, &JsValue::from(DATABASE))
+        .ok()
+        .filter(|binding| !binding.is_undefined())
+        .map(JsCast::unchecked_into)
+}
+
+fn statement(db: &D1Database, sql: &str, params: &[JsValue]) -> Result<D1PreparedStatement> {
+    let params: Array = params.iter().collect();
+    db.prepare(sql)
+        .and_then(|statement| statement.bind(&params))
+        .map_err(js_error)
+}
+
+/// The rows `sql` selects, as JSON objects keyed by column.
+async fn select(db: &D1Database, sql: &str, params: &[JsValue]) -> Result<Vec<Value>> {
+    let promise = statement(db, sql, params)?.all().map_err(js_error)?;
+    let result = JsFuture::from(promise).await.map_err(js_error)?;
+    let rows = Reflect::get(&result, &JsValue::from("results")).map_err(js_error)?;
+    let text = JSON::stringify(&rows)
+        .map_err(js_error)?
+        .as_string()
+        .unwrap_or_default();
+    Ok(serde_json::from_str(&text).unwrap_or_default())
+}
+
+/// Runs `statements` in one transaction.
+async fn execute(db: &D1Database, statements: Vec<D1PreparedStatement>) -> Result<()> {
+    let statements: Array = statements.into_iter().collect();
+    let promise = db.batch(&statements).map_err(js_error)?;
+    JsFuture::from(promise).await.map_err(js_error)?;
+    Ok(())
+}
+
+/// Where posts are kept. Posts are stored as their JSON text under their id, and listed in id
+/// order, byte by byte, which is the order KV lists keys in.
+#[async_trait(?Send)]
+pub trait PostStore {
+    /// The post stored under `id`, as JSON text.
+    async fn get(&self, id: &str) -> Result<Option<String>>;
+
+    async fn put(&self, id: &str, post: &Value) -> Result<()>;
+
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    /// Every post whose id starts with `prefix`, as `(id, JSON text)`, timed into `trace`.
+    async fn list(&self, prefix: &str, trace: &Trace) -> Result<Vec<(String, String)>>;
+}
+
+/// How many KV reads a listing may have in flight at once, as [`KV_CONCURRENCY_VAR`] says.
+pub fn kv_concurrency(bindings: &JsValue) -> usize {
+    Reflect::get(bindings, &JsValue::from(KV_CONCURRENCY_VAR))
+        .ok()
+        .and_then(|var| var.as_string())
+        .and_then(|var| var.parse().ok())
+        .filter(|&concurrency| concurrency > 0)
+        .unwrap_or(DEFAULT_KV_CONCURRENCY)
+}
+
+/// Posts in the `my-app-general_posts_preview` KV namespace, keyed by id. Listings read up to
+/// `concurrency` posts at once.
+pub struct KvPosts {
+    kv: kv::KvStore,
+    concurrency: usize,
+}
+
+#[async_trait(?Send)]
+impl PostStore for KvPosts {
+    async fn get(&self, id: &str) -> Result<Option<String>> {
+        Ok(self.kv.get(id).await?.map(|v| v.as_string()))
+    }
+
+    async fn put(&self, id: &str, post: &Value) -> Result<()> {
+        self.kv.put(id, post.to_string())?.execute().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.kv.delete(id).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, trace: &Trace) -> Result<Vec<(String, String)>> {
+        let started_at = Utc::now().timestamp_millis();
+        let keys = self
+            .kv
+            .list()
+            .prefix(prefix.to_string())
+            .execute()
+            .await?
+            .keys;
+        trace.time("kv-list", started_at);
+        let gets = keys.into_iter().map(|key| async move {
+            let started_at = Utc::now().timestamp_millis();
+            let value = self.kv.get(&key.name).await;
+            trace.time("kv-get", started_at);
+            value.map(|value| value.map(|v| (key.name, v.as_string())))
+        });
+        let mut posts = vec![];
+        for value in utils::bounded(gets, self.concurrency).await {
+            if let Some(post) = value? {
+                posts.push(post);
+            }
+        }
+        Ok(posts)
+    }
+}
+
+/// Posts in the `posts` table of D1. The columns other than `body` are copies of the post's
+/// fields for queries to filter and sort by; `body` is the post as stored.
+pub struct D1Posts(D1Database);
+
+/// Upserts `post` into the `posts` table.
+fn upsert(db: &D1Database, id: &str, post: &Value) -> Result<D1PreparedStatement> {
+    let field = |name: &str| match post.get(name).and_then(Value::as_str) {
+        Some(value) => JsValue::from(value),
+        None => JsValue::NULL,
+    };
+    statement(
+        db,
+        "INSERT INTO posts (id, username, title, content, community, time, likes, archived, body)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (id) DO UPDATE SET username = excluded.username, title = excluded.title,
+           content = excluded.content, community = excluded.community, time = excluded.time,
+           likes = excluded.likes, archived = excluded.archived, body = excluded.body",
+        &[
+            JsValue::from(id),
+            field("username"),
+            field("title"),
+            field("content"),
+            field("community"),
+            field("time"),
+            JsValue::from(post.get("likes").and_then(Value::as_f64).unwrap_or(0.0)),
+            JsValue::from(posts::is_archived(post)),
+            JsValue::from(post.to_string()),
+        ],
+    )
+}
+
+#[async_trait(?Send)]
+impl PostStore for D1Posts {
+    async fn get(&self, id: &str) -> Result<Option<String>> {
+        let rows = select(
+            &self.0,
+            "SELECT body FROM posts WHERE id = ?1",
+            &[JsValue::from(id)],
+        )
+        .await?;
+        Ok(rows
+            .first()
+            .and_then(|row| row.get("body"))
+            .and_then(Value::as_str)
+            .map(String::from))
+    }
+
+    async fn put(&self, id: &str, post: &Value) -> Result<()> {
+        execute(&self.0, vec![upsert(&self.0, id, post)?]).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let delete = statement(
+            &self.0,
+            "DELETE FROM posts WHERE id = ?1",
+            &[JsValue::from(id)],
+        )?;
+        execute(&self.0, vec![delete]).await
+    }
+
+    async fn list(&self, prefix: &str, trace: &Trace) -> Result<Vec<(String, String)>> {
+        let started_at = Utc::now().timestamp_millis();
+        let rows = select(
+            &self.0,
+            "SELECT id, body FROM posts WHERE substr(id, 1, length(?1)) = ?1 ORDER BY id",
+            &[JsValue::from(prefix)],
+        )
+        .await?;
+        trace.time("d1-query", started_at);
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let text = |column: &str| row.get(column)?.as_str().map(String::from);
+                Some((text("id")?, text("body")?))
+            })
+            .collect())
+    }
+}
+
+/// A [`PostStore`] that records every write in the journal as well, see `journal`, and drops
+/// the `GET /posts` lists this colo cached.
+struct Journaled {
+    store: Box<dyn PostStore>,
+    journal: kv::KvStore,
+}
+
+#[async_trait(?Send)]
+impl PostStore for Journaled {
+    async fn get(&self, id: &str) -> Result<Option<String>> {
+        self.store.get(id).await
+    }
+
+    async fn put(&self, id: &str, post: &Value) -> Result<()> {
+        self.store.put(id, post).await?;
+        let mutation = journal::Mutation::PostWritten {
+            id: id.to_string(),
+            post: post.clone(),
+        };
+        journal::record_in(&self.journal, mutation).await;
+        cache::invalidate_posts().await;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.store.delete(id).await?;
+        let mutation = journal::Mutation::PostDeleted { id: id.to_string() };
+        journal::record_in(&self.journal, mutation).await;
+        cache::invalidate_posts().await;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, trace: &Trace) -> Result<Vec<(String, String)>> {
+        self.store.list(prefix, trace).await
+    }
+}
+
+/// The store `backend` (the value of [`BACKEND_VAR`]) names, writing around the journal. Only
+/// for replaying the journal; everything else goes through [`posts`].
+pub fn unjournaled(backend: Option<String>, bindings: &JsValue) -> Result<Box<dyn PostStore>> {
+    if backend.as_deref() != Some("d1") {
+        let kv = kv::KvStore::from_this(bindings, posts::POSTS_KV)?;
+        return Ok(Box::new(KvPosts {
+            kv,
+            concurrency: kv_concurrency(bindings),
+        }));
+    }
+    match database(bindings) {
+        Some(db) => Ok(Box::new(D1Posts(db))),
+        None => Err(format!("Binding `{}` is undefined.", DATABASE).into()),
+    }
+}
+
+/// The store `backend` (the value of [`BACKEND_VAR`]) names.
+fn open(backend: Option<String>, bindings: &JsValue) -> Result<Box<dyn PostStore>> {
+    Ok(Box::new(Journaled {
+        store: unjournaled(backend, bindings)?,
+        journal: kv::KvStore::from_this(bindings, journal::JOURNAL_KV)?,
+    }))
+}
+
+/// Where this worker keeps posts, as [`BACKEND_VAR`] says.
+pub fn posts(ctx: &RouteContext<Session>) -> Result<Box<dyn PostStore>> {
+    open(
+        ctx.var(BACKEND_VAR).ok().map(|var| var.to_string()),
+        ctx.data().bindings(),
+    )
+}
+
+/// [`posts`] for code that has the `Env` but no route, such as a Durable Object.
+pub fn posts_in(env: &Env) -> Result<Box<dyn PostStore>> {
+    open(env.var(BACKEND_VAR).ok().map(|var| var.to_string()), env)
+}
+
+/// `POST /admin/storage/migrate[?cursor=...]`, for admins: copies the next posts from KV into
+/// D1 and answers `{"copied": 100, "skipped": [...], "cursor": "..."}`. Call it again with the
+/// `cursor` until it comes back `null`. Copying a post again overwrites it, so a run that failed
+/// halfway can simply be repeated; posts written to KV meanwhile are copied by a later run.
+pub async fn migrate(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    if !moderation::is_admin(&ctx, &username)? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let db = database(ctx.data().bindings())
+        .ok_or_else(|| ApiError::Internal(format!("Binding `{}` is undefined.", DATABASE)))?;
+    let cursor = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "cursor")
+        .map(|(_, cursor)| cursor.to_string());
+
+    let kv = ctx.kv(posts::POSTS_KV)?;
+    let mut list = kv.list().limit(MIGRATE_BATCH);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+    let mut statements = vec![];
+    let mut skipped = vec![];
+    for key in &page.keys {
+        let post = match kv.get(&key.name).await? {
+            Some(v) => serde_json::from_str::<Value>(&v.as_string()),
+            None => continue,
+        };
+        match post {
+            Ok(post) => statements.push(upsert(&db, &key.name, &post)?),
+            Err(_) => skipped.push(key.name.clone()),
+        }
+    }
+    let copied = statements.len();
+    if !statements.is_empty() {
+        execute(&db, statements).await?;
+    }
+    let cursor = if page.list_complete {
+        None
+    } else {
+        page.cursor
+    };
+    Ok(Response::from_json(&json!({
+        "copied": copied,
+        "skipped": skipped,
+        "cursor": cursor,
+    }))?)
+}
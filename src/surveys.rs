@@ -0,0 +1,265 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{moderation, session};
+
+/// Keys in the `surveys` namespace:
+///
+/// - `survey/<id>`: [`Survey`]
+/// - `response/<survey id>/<respondent>`: one response, where `respondent` is a hash of the
+///   username salted with a secret and the survey id, so responses can't be traced back to users
+///   or linked across surveys. The answers are the key's metadata, so results come from a single
+///   list.
+const SURVEYS_KV: &str = "surveys";
+
+const MAX_QUESTIONS: usize = 20;
+const MAX_CHOICES: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Question {
+    prompt: String,
+    choices: Vec<String>,
+    /// Whether more than one choice may be picked.
+    #[serde(default)]
+    multiple: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Survey {
+    id: String,
+    title: String,
+    questions: Vec<Question>,
+    created_by: String,
+    created_at: String,
+    closes_at: Option<String>,
+}
+
+impl Survey {
+    fn is_closed(&self) -> bool {
+        self.closes_at
+            .as_deref()
+            .and_then(|closes_at| DateTime::parse_from_rfc3339(closes_at).ok())
+            .is_some_and(|closes_at| closes_at <= Utc::now())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NewSurvey {
+    title: String,
+    questions: Vec<Question>,
+    closes_at: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewResponse {
+    /// Indices of the picked choices, one list per question. An empty list skips the question.
+    answers: Vec<Vec<usize>>,
+}
+
+async fn load(ctx: &RouteContext<Session>, id: &str) -> Result<Option<Survey>> {
+    match ctx.kv(SURVEYS_KV)?.get(&format!("survey/{}", id)).await? {
+        Some(v) => Ok(Some(v.as_json::<Survey>()?)),
+        None => Ok(None),
+    }
+}
+
+fn respondent(ctx: &RouteContext<Session>, survey_id: &str, username: &str) -> Result<String> {
+    let secret = format!("survey:{}", ctx.secret("SESSION_SECRET")?.to_string());
+    Ok(session::keyed_hash(
+        &format!("{}|{}", survey_id, username),
+        &secret,
+    ))
+}
+
+/// `POST /admin/surveys`
+pub async fn create(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = match session::current_user(&ctx) {
+        Some(username) if moderation::is_admin(&ctx, &username)? => username,
+        _ => return Err(ApiError::Forbidden("Forbidden".to_string())),
+    };
+    let body = match req.json::<NewSurvey>().await {
+        Ok(body) if !body.title.trim().is_empty() => body,
+        _ => return Err(ApiError::BadRequest("Bad Request".to_string())),
+    };
+    if body.questions.is_empty() || body.questions.len() > MAX_QUESTIONS {
+        return Err(ApiError::BadRequest(format!(
+            "a survey needs between 1 and {} questions",
+            MAX_QUESTIONS
+        )));
+    }
+    for (i, question) in body.questions.iter().enumerate() {
+        if question.choices.len() < 2 || question.choices.len() > MAX_CHOICES {
+            return Err(ApiError::BadRequest(format!(
+                "question {} needs between 2 and {} choices",
+                i, MAX_CHOICES
+            )));
+        }
+    }
+    if let Some(closes_at) = &body.closes_at {
+        if DateTime::parse_from_rfc3339(closes_at).is_err() {
+            return Err(ApiError::BadRequest(
+                "`closes_at` must be an RFC 3339 time".to_string(),
+            ));
+        }
+    }
+    let now = Utc::now();
+    let survey = Survey {
+        id: now.timestamp_millis().to_string(),
+        title: body.title,
+        questions: body.questions,
+        created_by: username,
+        created_at: now.to_rfc3339(),
+        closes_at: body.closes_at,
+    };
+    ctx.kv(SURVEYS_KV)?
+        .put(&format!("survey/{}", survey.id), &survey)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&survey)?)
+}
+
+/// `GET /surveys`, newest first.
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(SURVEYS_KV)?;
+    let mut surveys = vec![];
+    for key in kv
+        .list()
+        .prefix("survey/".to_string())
+        .execute()
+        .await?
+        .keys
+    {
+        if let Some(v) = kv.get(&key.name).await? {
+            surveys.push(v.as_json::<Survey>()?);
+        }
+    }
+    surveys.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(Response::from_json(&surveys)?)
+}
+
+/// `GET /surveys/:id`
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let id = error::param(&ctx, "id")?;
+    match load(&ctx, &id).await? {
+        Some(survey) => Ok(Response::from_json(&survey)?),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// `POST /surveys/:id/responses`. Responding again replaces the earlier response.
+pub async fn respond(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let id = error::param(&ctx, "id")?;
+    let survey = match load(&ctx, &id).await? {
+        Some(survey) => survey,
+        None => return Err(ApiError::NotFound),
+    };
+    if survey.is_closed() {
+        return Err(ApiError::Forbidden("This survey is closed".to_string()));
+    }
+    let mut body = match req.json::<NewResponse>().await {
+        Ok(body) if body.answers.len() == survey.questions.len() => body,
+        _ => {
+            return Err(ApiError::BadRequest(format!(
+                "`answers` needs one list per question ({})",
+                survey.questions.len()
+            )))
+        }
+    };
+    for (i, (answer, question)) in body.answers.iter_mut().zip(&survey.questions).enumerate() {
+        answer.sort_unstable();
+        answer.dedup();
+        if answer
+            .iter()
+            .any(|&choice| choice >= question.choices.len())
+        {
+            return Err(ApiError::BadRequest(format!(
+                "question {} has no such choice",
+                i
+            )));
+        }
+        if !question.multiple && answer.len() > 1 {
+            return Err(ApiError::BadRequest(format!(
+                "question {} takes a single choice",
+                i
+            )));
+        }
+    }
+    let key = format!("response/{}/{}", id, respondent(&ctx, &id, &username)?);
+    ctx.kv(SURVEYS_KV)?
+        .put(&key, "")?
+        .metadata(&body.answers)?
+        .execute()
+        .await?;
+    Ok(Response::empty()?)
+}
+
+/// `GET /surveys/:id/results`: how often each choice was picked, and by how many respondents.
+pub async fn results(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let id = error::param(&ctx, "id")?;
+    let survey = match load(&ctx, &id).await? {
+        Some(survey) => survey,
+        None => return Err(ApiError::NotFound),
+    };
+    let mut counts: Vec<Vec<u64>> = survey
+        .questions
+        .iter()
+        .map(|question| vec![0; question.choices.len()])
+        .collect();
+    let kv = ctx.kv(SURVEYS_KV)?;
+    let mut responses = vec![];
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(format!("response/{}/", id));
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        responses.extend(page.keys);
+        if page.list_complete || page.cursor.is_none() {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    for key in &responses {
+        let answers = key
+            .metadata
+            .clone()
+            .and_then(|metadata| serde_json::from_value::<Vec<Vec<usize>>>(metadata).ok())
+            .unwrap_or_default();
+        for (answer, question_counts) in answers.iter().zip(counts.iter_mut()) {
+            for &choice in answer {
+                if let Some(count) = question_counts.get_mut(choice) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+    let questions: Vec<_> = survey
+        .questions
+        .iter()
+        .zip(counts)
+        .map(|(question, counts)| {
+            json!({
+                "prompt": question.prompt,
+                "choices": question
+                    .choices
+                    .iter()
+                    .zip(counts)
+                    .map(|(choice, count)| json!({ "choice": choice, "count": count }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    Ok(Response::from_json(&json!({
+        "id": survey.id,
+        "title": survey.title,
+        "closed": survey.is_closed(),
+        "respondents": responses.len(),
+        "questions": questions,
+    }))?)
+}
@@ -0,0 +1,177 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::error::{self, ApiResult};
+use crate::posts;
+use crate::session::Session;
+use crate::withholding::Withheld;
+
+/// Keys in the `tags` namespace:
+///
+/// - `post/<tag>/<post id>`: the post had `#tag` in its content when it was created
+/// - `use/<yyyy-mm-ddThh>/<tag>/<post id>`: the same, per UTC hour, kept a day for
+///   [`trending`]
+/// - `trending`: a [`Snapshot`] of [`trending`], written by [`recompute`]
+pub const TAGS_KV: &str = "tags";
+
+/// Hourly entries are kept a little past the trending window, then KV drops them.
+const USE_TTL: u64 = 60 * 60 * 25;
+
+const MAX_TAG_CHARS: usize = 64;
+
+/// Tags past this many in one post are ignored.
+const MAX_TAGS_PER_POST: usize = 10;
+
+const TRENDING_HOURS: i64 = 24;
+const TRENDING_LIMIT: usize = 10;
+
+/// A snapshot older than this, in minutes, is computed afresh on read; the scheduled job
+/// recomputes it every ten.
+const SNAPSHOT_MAX_AGE_MINUTES: i64 = 30;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    computed_at: String,
+    tags: Vec<Value>,
+}
+
+/// The `#hashtags` of a post's content, lowercased and without duplicates, in order of
+/// appearance. A tag is letters, digits and `_` with at least one letter, after the start of the
+/// text or a space or punctuation, so `# Heading`, `#1`, `&#39;` and `page#anchor` aren't tags.
+pub fn extract(content: &str) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    let mut previous = ' ';
+    let mut chars = content.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let starts_tag = c == '#' && !previous.is_alphanumeric() && !"#&_/".contains(previous);
+        previous = c;
+        if !starts_tag {
+            continue;
+        }
+        let rest = &content[at + 1..];
+        let len = rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let tag = rest[..len].to_lowercase();
+        if tag.chars().any(char::is_alphabetic)
+            && tag.chars().count() <= MAX_TAG_CHARS
+            && !tags.contains(&tag)
+            && tags.len() < MAX_TAGS_PER_POST
+        {
+            tags.push(tag);
+        }
+        while chars.peek().is_some_and(|&(next, _)| next <= at + len) {
+            previous = chars.next().map(|(_, c)| c).unwrap_or(previous);
+        }
+    }
+    tags
+}
+
+/// The `tags` `posts::render_content` stored on a post.
+fn post_tags(post: &Value) -> Vec<String> {
+    post.get("tags")
+        .and_then(Value::as_array)
+        .map(|tags| {
+            tags.iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Adds a new post to the index of every tag it has.
+pub async fn index(ctx: &RouteContext<Session>, id: &str, post: &Value) -> Result<()> {
+    let kv = ctx.kv(TAGS_KV)?;
+    let hour = Utc::now().format("%Y-%m-%dT%H");
+    for tag in post_tags(post) {
+        kv.put(&format!("post/{}/{}", tag, id), "")?
+            .execute()
+            .await?;
+        kv.put(&format!("use/{}/{}/{}", hour, tag, id), "")?
+            .expiration_ttl(USE_TTL)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn list_prefix(kv: &kv::KvStore, prefix: String) -> Result<Vec<String>> {
+    let keys = kv.list().prefix(prefix.clone()).execute().await?.keys;
+    Ok(keys
+        .into_iter()
+        .map(|key| key.name[prefix.len()..].to_string())
+        .collect())
+}
+
+/// `GET /tags/:tag`, newest first. A post that lost the tag in an edit is left out, as are the
+/// posts `posts::load_listed` leaves out.
+pub async fn list(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let tag = error::param(&ctx, "tag")?
+        .trim_start_matches('#')
+        .to_lowercase();
+    let ids = list_prefix(&ctx.kv(TAGS_KV)?, format!("post/{}/", tag)).await?;
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    let mut found: Vec<Value> = posts::load_listed(&ctx, &withheld, ids)
+        .await?
+        .into_iter()
+        .filter(|post| post_tags(post).contains(&tag))
+        .collect();
+    let time = |post: &Value| post.get("time").and_then(Value::as_str).map(String::from);
+    found.sort_by_key(|post| std::cmp::Reverse(time(post)));
+    Ok(Response::from_json(&found)?)
+}
+
+/// The tags most posts used in the last [`TRENDING_HOURS`] hours, as
+/// `[{"tag": "...", "posts": 12}]`.
+async fn compute(kv: &kv::KvStore) -> Result<Vec<Value>> {
+    let now = Utc::now();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for hours_ago in 0..TRENDING_HOURS {
+        let hour = (now - Duration::hours(hours_ago)).format("%Y-%m-%dT%H");
+        for rest in list_prefix(kv, format!("use/{}/", hour)).await? {
+            // <tag>/<post id>
+            if let Some((tag, _)) = rest.split_once('/') {
+                *counts.entry(tag.to_string()).or_default() += 1;
+            }
+        }
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(TRENDING_LIMIT);
+    Ok(counts
+        .into_iter()
+        .map(|(tag, posts)| json!({ "tag": tag, "posts": posts }))
+        .collect())
+}
+
+/// Computes the trending tags and stores them as the snapshot [`trending`] answers with,
+/// answering how many there are.
+pub async fn recompute(kv: &kv::KvStore) -> Result<usize> {
+    let snapshot = Snapshot {
+        computed_at: Utc::now().to_rfc3339(),
+        tags: compute(kv).await?,
+    };
+    kv.put("trending", &snapshot)?.execute().await?;
+    Ok(snapshot.tags.len())
+}
+
+/// `GET /tags/trending`: the tags most posts used in the last [`TRENDING_HOURS`] hours, as
+/// `[{"tag": "...", "posts": 12}]`. Read off the snapshot the scheduled job keeps, unless it
+/// has gone stale.
+pub async fn trending(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = ctx.kv(TAGS_KV)?;
+    let fresh_since = Utc::now() - Duration::minutes(SNAPSHOT_MAX_AGE_MINUTES);
+    if let Some(v) = kv.get("trending").await? {
+        let snapshot = v.as_json::<Snapshot>()?;
+        let fresh = DateTime::parse_from_rfc3339(&snapshot.computed_at)
+            .is_ok_and(|computed_at| computed_at > fresh_since);
+        if fresh {
+            return Ok(Response::from_json(&snapshot.tags)?);
+        }
+    }
+    Ok(Response::from_json(&compute(&kv).await?)?)
+}
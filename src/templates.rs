@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{communities, models, moderation};
+
+/// Most templates one community may have.
+const MAX_TEMPLATES: usize = 10;
+
+/// A shape posts in a community can opt into by naming it in their `template` field.
+#[derive(Serialize, Deserialize, Debug)]
+struct Template {
+    name: String,
+    /// What the title must look like, where `*` stands for any text, e.g. `[Question] *`.
+    #[serde(default)]
+    title_pattern: Option<String>,
+    /// Headings the content must have, e.g. `Steps to reproduce`.
+    #[serde(default)]
+    required_sections: Vec<String>,
+}
+
+fn templates_key(community: &str) -> String {
+    format!("templates/{}", community)
+}
+
+async fn load(ctx: &RouteContext<Session>, community: &str) -> Result<Vec<Template>> {
+    let kv = ctx.kv(communities::COMMUNITIES_KV)?;
+    match kv.get(&templates_key(community)).await? {
+        Some(v) => Ok(v.as_json::<Vec<Template>>()?),
+        None => Ok(vec![]),
+    }
+}
+
+/// Whether `text` matches `pattern`, in which `*` matches any run of characters.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text.ends_with(last) || text.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Text of the markdown headings in `content`, lowercased.
+fn headings(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim().to_lowercase())
+        .collect()
+}
+
+/// Checks a new post against the template it names, if any. Returns what is wrong with it, to
+/// be answered with a 400.
+pub async fn check(ctx: &RouteContext<Session>, post: &Value) -> Result<Option<String>> {
+    let name = match post.get("template").and_then(Value::as_str) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let community = match post.get("community").and_then(Value::as_str) {
+        Some(community) => community,
+        None => return Ok(Some("`template` needs a `community`".to_string())),
+    };
+    let template = match load(ctx, community)
+        .await?
+        .into_iter()
+        .find(|template| template.name == name)
+    {
+        Some(template) => template,
+        None => return Ok(Some(format!("c/{} has no template `{}`", community, name))),
+    };
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or_default();
+    if let Some(pattern) = &template.title_pattern {
+        if !matches_pattern(pattern, field("title")) {
+            return Ok(Some(format!("the title must look like `{}`", pattern)));
+        }
+    }
+    let headings = headings(field("content"));
+    let missing: Vec<&str> = template
+        .required_sections
+        .iter()
+        .filter(|section| !headings.contains(&section.trim().to_lowercase()))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Ok(Some(format!("missing sections: {}", missing.join(", "))));
+    }
+    Ok(None)
+}
+
+/// `GET /c/:name/templates`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    Ok(Response::from_json(&load(&ctx, &community).await?)?)
+}
+
+/// `PUT /c/:name/templates`, replacing the community's whole list. Moderators (and admins) only.
+pub async fn replace(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let templates = models::from_body::<Vec<Template>>(&mut req).await?;
+    if templates.len() > MAX_TEMPLATES {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} templates",
+            MAX_TEMPLATES
+        )));
+    }
+    for (i, template) in templates.iter().enumerate() {
+        if template.name.trim().is_empty() {
+            return Err(ApiError::BadRequest(format!(
+                "template {} needs a `name`",
+                i
+            )));
+        }
+        if templates[..i]
+            .iter()
+            .any(|other| other.name == template.name)
+        {
+            return Err(ApiError::BadRequest(format!(
+                "`{}` is named twice",
+                template.name
+            )));
+        }
+    }
+    ctx.kv(communities::COMMUNITIES_KV)?
+        .put(&templates_key(&community), &templates)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&templates)?)
+}
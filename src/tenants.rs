@@ -0,0 +1,457 @@
+use chrono::Utc;
+use js_sys::{Array, Function, Object, Promise, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::{self, Session};
+use crate::validation::Problems;
+use crate::{domains, isolate, models};
+
+/// Keys in the `tenants` namespace, which belongs to the deployment and is never scoped:
+///
+/// - `tenant/<id>`: a [`Tenant`]
+/// - `host/<hostname>`: the id of the tenant served on `hostname`
+///
+/// Hosts without a tenant are served as the deployment's own instance, from the namespaces as
+/// they are. A tenant's data lives in the same namespaces under `t/<id>/`, see [`scoped`].
+pub const TENANTS_KV: &str = "tenants";
+
+/// Var a scoped `Env` names its tenant in. Unset for the deployment's own instance.
+const TENANT_VAR: &str = "TENANT";
+
+/// How long an isolate keeps what a host resolves to, in milliseconds. A tenant provisioned or
+/// changed elsewhere takes at most that long to apply here.
+const HOST_TTL_MS: i64 = 60 * 1000;
+
+const MAX_ID_CHARS: usize = 32;
+
+/// A community hosted by this deployment next to its own instance.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    /// Hostnames it is served on.
+    pub hosts: Vec<String>,
+    /// Its auth server, in place of `AUTH_SERVER_URL`.
+    pub auth_server_url: String,
+    /// Origin of its frontend, the only one its CORS headers allow. Any origin when unset.
+    #[serde(default)]
+    pub frontend_url: Option<String>,
+    /// Who may moderate and use the `/admin/` routes there, in place of `ADMINS`.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    pub created_at: String,
+}
+
+/// Body of `PUT /admin/tenants/:id`.
+#[derive(Deserialize, Debug)]
+struct TenantConfig {
+    hosts: Vec<String>,
+    auth_server_url: String,
+    #[serde(default)]
+    frontend_url: Option<String>,
+    #[serde(default)]
+    admins: Vec<String>,
+}
+
+fn js_error(e: JsValue) -> Error {
+    Error::JsError(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+fn tenant_key(id: &str) -> String {
+    format!("tenant/{}", id)
+}
+
+fn host_key(host: &str) -> String {
+    format!("host/{}", host)
+}
+
+fn host_cache_key(host: &str) -> String {
+    format!("tenants/host/{}", host)
+}
+
+/// The namespace, or `None` on a deployment that doesn't bind it and so hosts no tenants.
+fn tenants_kv(env: &Env) -> Option<kv::KvStore> {
+    env.kv(TENANTS_KV).ok()
+}
+
+async fn load(kv: &kv::KvStore, id: &str) -> Result<Option<Tenant>> {
+    match kv.get(&tenant_key(id)).await? {
+        Some(v) => Ok(Some(v.as_json::<Tenant>()?)),
+        None => Ok(None),
+    }
+}
+
+/// The tenant `req`'s host belongs to, if any.
+pub async fn of(req: &Request, env: &Env) -> Result<Option<Tenant>> {
+    let host = req.url()?.host_str().unwrap_or_default().to_lowercase();
+    if let Some(tenant) = isolate::get::<Option<Tenant>>(&host_cache_key(&host)) {
+        return Ok(tenant);
+    }
+    let kv = match tenants_kv(env) {
+        Some(kv) => kv,
+        None => return Ok(None),
+    };
+    let tenant = match kv.get(&host_key(&host)).await? {
+        Some(id) => load(&kv, &id.as_string()).await?,
+        None => None,
+    };
+    isolate::put(&host_cache_key(&host), &tenant, HOST_TTL_MS);
+    Ok(tenant)
+}
+
+/// The tenant `id`, if it is still provisioned.
+pub async fn get(env: &Env, id: &str) -> Result<Option<Tenant>> {
+    match tenants_kv(env) {
+        Some(kv) => load(&kv, id).await,
+        None => Ok(None),
+    }
+}
+
+/// The tenant a scoped `Env`, or a route's bindings, belong to. `None` for the deployment's own
+/// instance.
+pub fn id(bindings: &JsValue) -> Option<String> {
+    Reflect::get(bindings, &JsValue::from(TENANT_VAR))
+        .ok()
+        .and_then(|id| id.as_string())
+}
+
+/// `key` for the isolate cache (see `isolate`), which every tenant shares.
+pub fn isolate_key(bindings: &JsValue, key: &str) -> String {
+    match id(bindings) {
+        Some(id) => format!("t/{}/{}", id, key),
+        None => key.to_string(),
+    }
+}
+
+/// Calls `target.method(args)`.
+fn call(target: &JsValue, method: &str, args: &Array) -> std::result::Result<JsValue, JsValue> {
+    let function: Function = Reflect::get(target, &JsValue::from(method))?.unchecked_into();
+    function.apply(target, args)
+}
+
+fn prefixed(prefix: &str, key: &JsValue) -> JsValue {
+    JsValue::from(format!("{}{}", prefix, key.as_string().unwrap_or_default()))
+}
+
+/// Sets `object.name` to `function`, which JavaScript then owns.
+fn define<F: ?Sized + wasm_bindgen::closure::WasmClosure>(
+    object: &Object,
+    name: &str,
+    function: Closure<F>,
+) -> Result<()> {
+    Reflect::set(object, &JsValue::from(name), &function.into_js_value()).map_err(js_error)?;
+    Ok(())
+}
+
+type Method2 = dyn FnMut(JsValue, JsValue) -> std::result::Result<JsValue, JsValue>;
+type Method3 = dyn FnMut(JsValue, JsValue, JsValue) -> std::result::Result<JsValue, JsValue>;
+
+/// A KV namespace that reads and writes `namespace` under `prefix`. Listings are limited to
+/// the prefix and answer names without it, so code using it can't tell the difference.
+fn scoped_kv(namespace: &JsValue, prefix: &str) -> Result<JsValue> {
+    let scoped = Object::new();
+    for method in ["get", "getWithMetadata", "delete"] {
+        let (namespace, prefix) = (namespace.clone(), prefix.to_string());
+        let function = Closure::wrap(Box::new(move |key: JsValue, options: JsValue| {
+            call(
+                &namespace,
+                method,
+                &Array::of2(&prefixed(&prefix, &key), &options),
+            )
+        }) as Box<Method2>);
+        define(&scoped, method, function)?;
+    }
+
+    let (namespace_put, prefix_put) = (namespace.clone(), prefix.to_string());
+    let put = Closure::wrap(
+        Box::new(move |key: JsValue, value: JsValue, options: JsValue| {
+            let key = prefixed(&prefix_put, &key);
+            call(&namespace_put, "put", &Array::of3(&key, &value, &options))
+        }) as Box<Method3>,
+    );
+    define(&scoped, "put", put)?;
+
+    let (namespace, prefix) = (namespace.clone(), prefix.to_string());
+    let list = Closure::wrap(Box::new(move |options: JsValue, _: JsValue| {
+        let scoped_options = Object::new();
+        if options.is_object() {
+            Object::assign(&scoped_options, options.unchecked_ref());
+        }
+        let wanted = Reflect::get(&scoped_options, &JsValue::from("prefix"))?;
+        let wanted = if wanted.is_string() {
+            wanted
+        } else {
+            JsValue::from("")
+        };
+        Reflect::set(
+            &scoped_options,
+            &JsValue::from("prefix"),
+            &prefixed(&prefix, &wanted),
+        )?;
+        let listed: Promise =
+            call(&namespace, "list", &Array::of1(&scoped_options))?.unchecked_into();
+        let prefix = prefix.clone();
+        Ok(JsValue::from(future_to_promise(async move {
+            let page = JsFuture::from(listed).await?;
+            let keys = Reflect::get(&page, &JsValue::from("keys"))?;
+            for key in Array::from(&keys).iter() {
+                let name = Reflect::get(&key, &JsValue::from("name"))?
+                    .as_string()
+                    .unwrap_or_default();
+                let name = name.strip_prefix(prefix.as_str()).unwrap_or(&name);
+                Reflect::set(&key, &JsValue::from("name"), &JsValue::from(name))?;
+            }
+            Ok(page)
+        })))
+    }) as Box<Method2>);
+    define(&scoped, "list", list)?;
+    Ok(scoped.into())
+}
+
+/// A Durable Object namespace whose objects are named under `prefix`, e.g. one live hub per
+/// tenant. It inherits from `namespace`, which `worker` checks bindings by.
+fn scoped_namespace(namespace: &JsValue, prefix: &str) -> Result<JsValue> {
+    let scoped: Object = Object::create(namespace.unchecked_ref::<Object>());
+    for method in ["get", "idFromString", "newUniqueId"] {
+        let function: Function = Reflect::get(namespace, &JsValue::from(method))
+            .map_err(js_error)?
+            .unchecked_into();
+        Reflect::set(&scoped, &JsValue::from(method), &function.bind0(namespace))
+            .map_err(js_error)?;
+    }
+    let (namespace, prefix) = (namespace.clone(), prefix.to_string());
+    let id_from_name = Closure::wrap(Box::new(move |name: JsValue, _: JsValue| {
+        call(
+            &namespace,
+            "idFromName",
+            &Array::of1(&prefixed(&prefix, &name)),
+        )
+    }) as Box<Method2>);
+    define(&scoped, "idFromName", id_from_name)?;
+    Ok(scoped.into())
+}
+
+/// Whether `binding` has a method called `name`, which is how the kind of a binding is told.
+fn has_method(binding: &JsValue, name: &str) -> bool {
+    Reflect::get(binding, &JsValue::from(name)).is_ok_and(|method| method.is_function())
+}
+
+/// `env` as `tenant` sees it:
+///
+/// - every KV namespace but [`TENANTS_KV`] and [`domains::DOMAINS_KV`] keeps its keys under
+///   `t/<id>/`
+/// - Durable Objects are named under `t/<id>/`, so like counters and live hubs are its own
+/// - D1 and the queues are left out: posts stay in KV, whose keys can be scoped, and work that
+///   would be queued is done right away instead (see `feeds::fan_out` and `bulk::delete`)
+/// - its auth server, frontend and admins replace the deployment's, and sessions are signed
+///   with a key of its own, so one minted by another instance doesn't check out
+///
+/// Everything else, R2 included, is shared with the deployment.
+pub fn scoped(env: &Env, tenant: &Tenant) -> Result<Env> {
+    let prefix = format!("t/{}/", tenant.id);
+    let scoped: Object = Object::create(env.unchecked_ref::<Object>());
+    for name in Object::keys(env.unchecked_ref::<Object>()).iter() {
+        if matches!(
+            name.as_string().as_deref(),
+            Some(TENANTS_KV | domains::DOMAINS_KV)
+        ) {
+            continue;
+        }
+        let binding = Reflect::get(env, &name).map_err(js_error)?;
+        if !binding.is_object() {
+            continue;
+        }
+        let replacement = if has_method(&binding, "getWithMetadata") {
+            scoped_kv(&binding, &prefix)?
+        } else if has_method(&binding, "idFromName") {
+            scoped_namespace(&binding, &prefix)?
+        } else if has_method(&binding, "send") || has_method(&binding, "prepare") {
+            JsValue::UNDEFINED
+        } else {
+            continue;
+        };
+        Reflect::set(&scoped, &name, &replacement).map_err(js_error)?;
+    }
+
+    let secret = env.secret("SESSION_SECRET")?.to_string();
+    let vars = [
+        (TENANT_VAR, JsValue::from(&tenant.id)),
+        ("AUTH_SERVER_URL", JsValue::from(&tenant.auth_server_url)),
+        ("ADMINS", JsValue::from(tenant.admins.join(","))),
+        ("POSTS_STORAGE", JsValue::from("kv")),
+        ("FRONTEND_URL", JsValue::from(tenant.frontend_url.clone())),
+        (
+            "SESSION_SECRET",
+            JsValue::from(session::seal(&format!("tenant|{}", tenant.id), &secret)),
+        ),
+        // Shared with the deployment's auth server; a tenant's has keys of its own in its JWKS.
+        ("AUTH_JWT_SECRET", JsValue::UNDEFINED),
+    ];
+    for (name, value) in vars {
+        Reflect::set(&scoped, &JsValue::from(name), &value).map_err(js_error)?;
+    }
+    Ok(scoped.unchecked_into())
+}
+
+/// `env` as the tenant `id` sees it, for code that only has its id, such as a like counter.
+/// `None` is the deployment's own instance.
+pub async fn scoped_by_id(env: &Env, id: Option<&str>) -> Result<Env> {
+    let id = match id {
+        Some(id) => id,
+        None => return Ok(JsValue::from(env).unchecked_into()),
+    };
+    let kv = tenants_kv(env).ok_or_else(|| Error::from("tenants are not bound"))?;
+    match load(&kv, id).await? {
+        Some(tenant) => scoped(env, &tenant),
+        None => Err(format!("tenant `{}` is gone", id).into()),
+    }
+}
+
+/// The deployment's own `env`, followed by every tenant's, for jobs that run across all of them.
+pub async fn every_env(env: &Env) -> Result<Vec<Env>> {
+    let mut envs: Vec<Env> = vec![JsValue::from(env).unchecked_into()];
+    let kv = match tenants_kv(env) {
+        Some(kv) => kv,
+        None => return Ok(envs),
+    };
+    for key in kv.list().prefix(tenant_key("")).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            envs.push(scoped(env, &v.as_json::<Tenant>()?)?);
+        }
+    }
+    Ok(envs)
+}
+
+/// Tenants are managed from the deployment's own instance; to a tenant's admins these routes
+/// don't exist.
+fn operator_only(ctx: &RouteContext<Session>) -> ApiResult<kv::KvStore> {
+    if id(ctx.data().bindings()).is_some() {
+        return Err(ApiError::NotFound);
+    }
+    Ok(ctx.kv(TENANTS_KV)?)
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_CHARS
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn valid_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.scheme() == "https" && url.host_str().is_some())
+}
+
+/// `GET /admin/tenants`: every tenant.
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = operator_only(&ctx)?;
+    let mut tenants = vec![];
+    for key in kv.list().prefix(tenant_key("")).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            tenants.push(v.as_json::<Tenant>()?);
+        }
+    }
+    Ok(Response::from_json(&tenants)?)
+}
+
+/// `PUT /admin/tenants/:id` with `{"hosts": ["..."], "auth_server_url": "https://...",
+/// "frontend_url": "https://...", "admins": ["..."]}` provisions a tenant, or changes one. Its
+/// namespaces need no setting up: its keys are written as it is used. Hosts have to be routed to
+/// the worker in Cloudflare as well.
+pub async fn provision(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = operator_only(&ctx)?;
+    let id = error::param(&ctx, "id")?;
+    let config = models::from_body::<TenantConfig>(&mut req).await?;
+    let hosts: Vec<String> = config
+        .hosts
+        .iter()
+        .map(|host| host.trim().to_lowercase())
+        .collect();
+
+    let mut problems = Problems::default();
+    if !valid_id(&id) {
+        problems.add(
+            "id",
+            format!(
+                "has to be 1 to {} lowercase letters, digits or dashes",
+                MAX_ID_CHARS
+            ),
+        );
+    }
+    if hosts.is_empty()
+        || hosts
+            .iter()
+            .any(|host| host.is_empty() || host.contains('/'))
+    {
+        problems.add(
+            "hosts",
+            "has to name at least one hostname, without a scheme",
+        );
+    }
+    if !valid_url(&config.auth_server_url) {
+        problems.add("auth_server_url", "has to be an https URL");
+    }
+    if config
+        .frontend_url
+        .as_deref()
+        .is_some_and(|url| !valid_url(url))
+    {
+        problems.add("frontend_url", "has to be an https URL");
+    }
+    problems.finish()?;
+    for host in &hosts {
+        if let Some(owner) = kv.get(&host_key(host)).await? {
+            if owner.as_string() != id {
+                return Err(ApiError::Conflict(format!(
+                    "{} is already served for another tenant",
+                    host
+                )));
+            }
+        }
+    }
+
+    let existing = load(&kv, &id).await?;
+    if let Some(existing) = &existing {
+        for host in existing.hosts.iter().filter(|host| !hosts.contains(host)) {
+            kv.delete(&host_key(host)).await?;
+            isolate::forget(&host_cache_key(host));
+        }
+    }
+    let tenant = Tenant {
+        id: id.clone(),
+        hosts,
+        auth_server_url: config.auth_server_url,
+        frontend_url: config.frontend_url,
+        admins: config.admins,
+        created_at: existing
+            .map(|existing| existing.created_at)
+            .unwrap_or_else(|| Utc::now().to_rfc3339()),
+    };
+    kv.put(&tenant_key(&id), &tenant)?.execute().await?;
+    for host in &tenant.hosts {
+        kv.put(&host_key(host), &id)?.execute().await?;
+        isolate::forget(&host_cache_key(host));
+    }
+    console_log!("tenants: provisioned {} on {}", id, tenant.hosts.join(", "));
+    Ok(Response::from_json(&tenant)?)
+}
+
+/// `DELETE /admin/tenants/:id`: stops serving a tenant. Its hosts fall back to the deployment's
+/// own instance; its data is left under `t/<id>/` in case it is provisioned again.
+pub async fn remove(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let kv = operator_only(&ctx)?;
+    let id = error::param(&ctx, "id")?;
+    let tenant = load(&kv, &id).await?.ok_or(ApiError::NotFound)?;
+    for host in &tenant.hosts {
+        kv.delete(&host_key(host)).await?;
+        isolate::forget(&host_cache_key(host));
+    }
+    kv.delete(&tenant_key(&id)).await?;
+    console_log!("tenants: removed {}", id);
+    Ok(Response::empty()?)
+}
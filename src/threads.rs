@@ -0,0 +1,134 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::withholding::Withheld;
+use crate::{bots, communities, models, posts, session, storage, utils, validation};
+
+/// Upper bound on how many segments one thread may be submitted with.
+const MAX_SEGMENTS: usize = 25;
+
+#[derive(Deserialize, Debug)]
+struct NewThread {
+    segments: Vec<Value>,
+}
+
+/// Segment `position` of thread `thread_id` is stored in the posts namespace under
+/// `<thread_id>.<position>`, so a whole thread can be listed by prefix.
+fn segment_id(thread_id: &str, position: usize) -> String {
+    format!("{}.{}", thread_id, position)
+}
+
+/// `POST /threads`
+///
+/// Stores every segment as an ordinary post carrying `thread_id` and `position`, so segments also
+/// show up in listings and search on their own. Each one is checked and prepared as
+/// `POST /posts` would a post of its own (see `posts::prepare`); the thread is throttled once.
+pub async fn create(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = session::authed(&ctx)?.username;
+    let body = models::from_body::<NewThread>(&mut req).await?;
+    bots::throttle(&req, &ctx).await?;
+    if body.segments.is_empty() || body.segments.len() > MAX_SEGMENTS {
+        return Err(ApiError::BadRequest(format!(
+            "a thread needs between 1 and {} segments",
+            MAX_SEGMENTS
+        )));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let thread_id = utils::ulid()?;
+    let mut segments = vec![];
+    let mut problems = validation::Problems::default();
+    for (position, mut segment) in body.segments.into_iter().enumerate() {
+        let id = segment_id(&thread_id, position);
+        let segment_obj = match segment.as_object_mut() {
+            Some(segment_obj) => segment_obj,
+            None => {
+                return Err(ApiError::BadRequest(format!(
+                    "segment {} is not an object",
+                    position
+                )))
+            }
+        };
+        for field in models::SERVER_FIELDS {
+            segment_obj.remove(*field);
+        }
+        segment_obj.insert("username".to_string(), json!(username));
+        segment_obj.insert("time".to_string(), json!(now));
+        segment_obj.insert("id".to_string(), json!(id));
+        segment_obj.insert("thread_id".to_string(), json!(thread_id));
+        segment_obj.insert("position".to_string(), json!(position));
+        match posts::prepare(&ctx, &mut segment).await {
+            Err(ApiError::BadRequest(problem)) => {
+                return Err(ApiError::BadRequest(format!(
+                    "segment {}: {}",
+                    position, problem
+                )))
+            }
+            result => result?,
+        }
+        if serde_json::from_value::<models::Post>(segment.clone()).is_err() {
+            return Err(ApiError::BadRequest(format!(
+                "segment {} needs a `title` and `content`",
+                position
+            )));
+        }
+        validation::post_fields(
+            &mut segment,
+            &format!("segments[{}].", position),
+            &mut problems,
+        );
+        segments.push((id, segment));
+    }
+    problems.finish()?;
+
+    for (id, segment) in &mut segments {
+        posts::insert(&ctx, id, segment).await?;
+    }
+    let segments: Vec<Value> = segments.into_iter().map(|(_, segment)| segment).collect();
+    Ok(Response::from_json(
+        &json!({ "thread_id": thread_id, "segments": segments }),
+    )?)
+}
+
+/// `GET /threads/:id`, segments in order. Segments a listing wouldn't show (archived, moderated
+/// or in a quarantined community) are left out, and pending co-author invites hidden.
+pub async fn show(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let thread_id = error::param(&ctx, "id")?;
+    let stored = storage::posts(&ctx)?
+        .list(&format!("{}.", thread_id), ctx.data().trace())
+        .await?;
+    let mut segments = vec![];
+    for (_, stored) in stored {
+        let mut segment: Value = serde_json::from_str(&stored)?;
+        if segment.get("thread_id").and_then(Value::as_str) != Some(thread_id.as_str())
+            || posts::is_archived(&segment)
+            || posts::is_moderated(&segment)
+            || posts::is_deleted(&segment)
+        {
+            continue;
+        }
+        if let Some(community) = segment.get("community").and_then(Value::as_str) {
+            if communities::is_quarantined(&ctx, community).await? {
+                continue;
+            }
+        }
+        posts::hide_pending_co_authors(&mut segment);
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+    // Keys sort as strings, so `.10` would come before `.2`.
+    segments.sort_by_key(|segment| segment.get("position").and_then(Value::as_u64));
+    let withheld = Withheld::for_request(&req, &ctx).await?;
+    for segment in &mut segments {
+        withheld.apply(segment);
+    }
+    Ok(Response::from_json(
+        &json!({ "thread_id": thread_id, "segments": segments }),
+    )?)
+}
@@ -0,0 +1,220 @@
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use worker::*;
+
+use crate::utils;
+
+/// The W3C trace context of a request (<https://www.w3.org/TR/trace-context/>). A request that
+/// arrives with a valid `traceparent` joins that trace; anything else starts a new one. Either
+/// way the worker is a span of its own, and that span is what outbound fetches name as their
+/// parent.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    trace_id: String,
+    span_id: String,
+    flags: String,
+    /// Vendor state from the caller, passed on untouched.
+    state: Option<String>,
+    /// Fetches made so far as part of the trace. Shared by every clone, so what routes fetch
+    /// through the session's copy shows up in `main`'s.
+    spans: Rc<RefCell<Vec<Span>>>,
+    /// Time spent per kind of dependency, in the order each was first used, shared like `spans`.
+    timings: Rc<RefCell<Vec<Timing>>>,
+    /// The most, in seconds, any edge-cached read may lag behind KV (see `replica`), shared
+    /// like `spans`.
+    staleness: Rc<Cell<Option<u64>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Timing {
+    name: &'static str,
+    total_ms: i64,
+    calls: u32,
+}
+
+/// A fetch the worker made to another service, see [`crate::outbound`].
+#[derive(Serialize, Debug, Clone)]
+pub struct Span {
+    /// Only the host: paths and queries may carry tokens.
+    pub host: String,
+    pub duration_ms: i64,
+    /// What the service answered; `None` when the fetch failed or was blocked.
+    pub status: Option<u16>,
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && s.bytes().any(|b| b != b'0')
+}
+
+/// The trace ID and flags of a version 00 `traceparent`, if it is well-formed.
+fn parse(traceparent: &str) -> Option<(String, String)> {
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || parts.next().is_some() {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || flags.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), flags.to_string()))
+}
+
+impl Trace {
+    pub fn of(req: &Request) -> Trace {
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+        // IDs only have to be unique, not secret: Cloudflare's ray ID already is per request.
+        let seed = utils::sha256_hex(&format!(
+            "{}|{}",
+            header("CF-Ray").unwrap_or_default(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let span_id = seed[32..48].to_string();
+        match header("traceparent").as_deref().and_then(parse) {
+            Some((trace_id, flags)) => Trace {
+                trace_id,
+                span_id,
+                flags,
+                state: header("tracestate"),
+                spans: Rc::default(),
+                timings: Rc::default(),
+                staleness: Rc::default(),
+            },
+            None => Trace {
+                trace_id: seed[..32].to_string(),
+                span_id,
+                flags: "01".to_string(),
+                state: None,
+                spans: Rc::default(),
+                timings: Rc::default(),
+                staleness: Rc::default(),
+            },
+        }
+    }
+
+    /// A new trace for work no request started, such as a scheduled job.
+    pub fn detached() -> Trace {
+        let seed = utils::sha256_hex(&format!(
+            "detached|{}",
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        Trace {
+            trace_id: seed[..32].to_string(),
+            span_id: seed[32..48].to_string(),
+            flags: "01".to_string(),
+            state: None,
+            spans: Rc::default(),
+            timings: Rc::default(),
+            staleness: Rc::default(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// The `traceparent` naming the worker's span, for outbound fetches and the response.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Sets the trace context headers on a fetch the worker makes.
+    pub fn propagate(&self, headers: &mut Headers) -> Result<()> {
+        headers.set("traceparent", &self.traceparent())?;
+        if let Some(state) = &self.state {
+            headers.set("tracestate", state)?;
+        }
+        Ok(())
+    }
+
+    pub fn record(&self, span: Span) {
+        self.spans.borrow_mut().push(span);
+    }
+
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.borrow().clone()
+    }
+
+    /// Adds the time since `started_at` (milliseconds since the epoch) to the dependency `name`,
+    /// e.g. `kv-get`. Calls to the same dependency add up.
+    pub fn time(&self, name: &'static str, started_at: i64) {
+        let elapsed = Utc::now().timestamp_millis() - started_at;
+        let mut timings = self.timings.borrow_mut();
+        match timings.iter_mut().find(|timing| timing.name == name) {
+            Some(timing) => {
+                timing.total_ms += elapsed;
+                timing.calls += 1;
+            }
+            None => timings.push(Timing {
+                name,
+                total_ms: elapsed,
+                calls: 1,
+            }),
+        }
+    }
+
+    /// The timings as a `Server-Timing` header value, e.g.
+    /// `kv-list;dur=12;desc="1 call", kv-get;dur=85;desc="40 calls"`, so browser devtools show
+    /// where a slow response spent its time. `None` before any dependency was used.
+    pub fn server_timing(&self) -> Option<String> {
+        let timings = self.timings.borrow();
+        if timings.is_empty() {
+            return None;
+        }
+        let metrics: Vec<String> = timings
+            .iter()
+            .map(|timing| {
+                format!(
+                    "{};dur={};desc=\"{} call{}\"",
+                    timing.name,
+                    timing.total_ms,
+                    timing.calls,
+                    if timing.calls == 1 { "" } else { "s" }
+                )
+            })
+            .collect();
+        Some(metrics.join(", "))
+    }
+
+    /// Notes that the response may rest on data up to `seconds` older than KV's.
+    pub fn may_be_stale(&self, seconds: u64) {
+        let most = self
+            .staleness
+            .get()
+            .map_or(seconds, |most| most.max(seconds));
+        self.staleness.set(Some(most));
+    }
+
+    /// The bound for the `X-Max-Staleness` header: how many seconds of writes the response may
+    /// miss beyond KV's own propagation delay. `None` when nothing was read from an edge cache.
+    pub fn max_staleness(&self) -> Option<u64> {
+        self.staleness.get()
+    }
+
+    /// Adds `"trace_id"` to an error envelope (see `error::ApiError`), so a client reporting a
+    /// failure can hand over the ID that finds it in the logs. Other responses pass untouched.
+    pub async fn stamp(&self, mut res: Response) -> Result<Response> {
+        let json = res
+            .headers()
+            .get("Content-Type")?
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        if res.status_code() < 400 || !json {
+            return Ok(res);
+        }
+        let mut body = res.json::<Value>().await?;
+        if let Some(body_obj) = body.as_object_mut().filter(|obj| obj.contains_key("error")) {
+            body_obj.insert("trace_id".to_string(), Value::from(self.id()));
+        }
+        let headers = res.headers().clone();
+        Ok(Response::from_json(&body)?
+            .with_status(res.status_code())
+            .with_headers(headers))
+    }
+}
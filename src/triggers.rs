@@ -0,0 +1,120 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{ApiError, ApiResult};
+use crate::session::Session;
+use crate::{apikeys, communities, models, posts, storage, utils, validation};
+
+/// Most items a polling trigger returns; Zapier only looks at the newest ones anyway.
+const MAX_ITEMS: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct NewPost {
+    title: String,
+    content: String,
+    community: Option<String>,
+}
+
+/// `GET /triggers/me`: lets automation platforms test a key when it is connected.
+pub async fn me(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    match apikeys::lookup(&req, &ctx).await? {
+        Some(api_key) => Ok(Response::from_json(&json!({
+            "id": api_key.owner,
+            "username": api_key.owner,
+            "scopes": api_key.scopes,
+        }))?),
+        None => Err(ApiError::Unauthorized),
+    }
+}
+
+/// `GET /triggers/new_posts[?since=<rfc3339>][&community=<name>]`, for a `read` key.
+///
+/// Public posts newest first, each with a stable `id`, which is the shape polling triggers
+/// deduplicate on. Without `since`, the last day is returned.
+pub async fn new_posts(req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if apikeys::authorize(&req, &ctx, apikeys::Scope::Read)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    let url = req.url()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let since = match param("since") {
+        Some(since) => match DateTime::parse_from_rfc3339(&since) {
+            Ok(since) => since.with_timezone(&Utc),
+            Err(_) => {
+                return Err(ApiError::BadRequest(
+                    "`since` must be an RFC 3339 timestamp".to_string(),
+                ))
+            }
+        },
+        None => Utc::now() - Duration::days(1),
+    };
+    let community = param("community");
+
+    let store = storage::posts(&ctx)?;
+    let mut items: Vec<(DateTime<Utc>, models::Post)> = vec![];
+    let listed = posts::list_public(&*store, ctx.data().trace()).await?;
+    for post in communities::without_quarantined(&ctx, listed).await? {
+        let time = match post.time.as_deref().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(time)) => time.with_timezone(&Utc),
+            _ => continue,
+        };
+        let in_community = match &community {
+            Some(community) => {
+                post.extra.get("community").and_then(Value::as_str) == Some(community)
+            }
+            None => true,
+        };
+        if time > since && in_community {
+            items.push((time, post));
+        }
+    }
+    items.sort_by(|(a, _), (b, _)| b.cmp(a));
+    items.truncate(MAX_ITEMS);
+    let items: Vec<models::Post> = items.into_iter().map(|(_, post)| post).collect();
+    Ok(Response::from_json(&items)?)
+}
+
+/// `POST /actions/create_post`, for a `post` key: posts as the key's owner.
+pub async fn create_post(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let api_key = apikeys::authorize(&req, &ctx, apikeys::Scope::Post)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let body = match req.json::<NewPost>().await {
+        Ok(body) => body,
+        Err(_) => {
+            return Err(ApiError::BadRequest(
+                "`title` and `content` are required".to_string(),
+            ))
+        }
+    };
+    if !api_key.allows_community(body.community.as_deref()) {
+        return Err(ApiError::Forbidden(
+            "This key can't post into that community".to_string(),
+        ));
+    }
+    let now = Utc::now().to_rfc3339();
+    let id = utils::ulid()?;
+    let mut post = json!({
+        "id": id,
+        "username": api_key.owner,
+        "title": body.title,
+        "content": body.content,
+        "time": now,
+    });
+    if let (Some(community), Some(post_obj)) = (body.community, post.as_object_mut()) {
+        post_obj.insert("community".to_string(), json!(community));
+    }
+    validation::post(&mut post)?;
+    posts::normalize_license(&mut post)?;
+    posts::insert(&ctx, &id, &mut post).await?;
+    Ok(Response::from_json(&post)?)
+}
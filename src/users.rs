@@ -0,0 +1,239 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{isolate, models, posts, profile_views, replica, session, storage};
+
+/// Keys in the `users` namespace:
+///
+/// - `<username>`: [`UserProfile`]. Users registered before profiles existed are stored as just
+///   the time they registered, which [`load`] reads as a profile with nothing else filled in.
+pub const USERS_KV: &str = "users";
+
+/// How long an isolate remembers that a username is taken, in milliseconds.
+const TAKEN_TTL_MS: i64 = 10 * 60 * 1000;
+
+/// How long an isolate remembers whether a user is banned, in milliseconds. A ban takes this
+/// long to reach every isolate.
+const BANNED_TTL_MS: i64 = 60 * 1000;
+
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserProfile {
+    /// When the user registered, in RFC 3339.
+    pub created_at: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// Set while an admin has the user banned, see `admin::ban`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banned: Option<Ban>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub by: String,
+    pub at: String,
+}
+
+impl UserProfile {
+    /// A profile for someone registering now.
+    pub fn new() -> UserProfile {
+        UserProfile {
+            created_at: Utc::now().to_rfc3339(),
+            ..UserProfile::default()
+        }
+    }
+}
+
+/// Body of `PATCH /users/:username`. Fields left out are kept; an empty string clears one.
+#[derive(Deserialize, Debug)]
+struct ProfileUpdate {
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+}
+
+fn parse(stored: String) -> UserProfile {
+    serde_json::from_str::<UserProfile>(&stored).unwrap_or(UserProfile {
+        created_at: stored,
+        ..UserProfile::default()
+    })
+}
+
+/// The stored profile of `username`, if they are registered.
+pub async fn load(kv: &kv::KvStore, username: &str) -> Result<Option<UserProfile>> {
+    Ok(kv.get(username).await?.map(|v| parse(v.as_string())))
+}
+
+/// Like [`load`], but from the nearest edge cache, so up to [`replica::PROFILE_TTL`] seconds
+/// stale. For showing a profile, not for updating one.
+pub async fn load_replicated(
+    ctx: &RouteContext<Session>,
+    username: &str,
+) -> Result<Option<UserProfile>> {
+    Ok(replica::get(ctx, USERS_KV, username, replica::PROFILE_TTL)
+        .await?
+        .map(parse))
+}
+
+fn taken_key(username: &str) -> String {
+    format!("users/taken/{}", username)
+}
+
+/// Whether `username` is registered. Only a yes is remembered in the isolate (see `isolate`):
+/// names are never freed, but a no goes stale as soon as someone takes the name elsewhere, and
+/// a stale no would register it a second time.
+pub async fn exists(kv: &kv::KvStore, username: &str) -> Result<bool> {
+    if isolate::get::<bool>(&taken_key(username)) == Some(true) {
+        return Ok(true);
+    }
+    let exists = kv.get(username).await?.is_some();
+    if exists {
+        isolate::put(&taken_key(username), &true, TAKEN_TTL_MS);
+    }
+    Ok(exists)
+}
+
+fn banned_key(username: &str) -> String {
+    format!("users/banned/{}", username)
+}
+
+/// Whether an admin has `username` banned, remembered in the isolate (see `isolate`) for
+/// [`BANNED_TTL_MS`] since every signed-in request asks.
+pub async fn is_banned(kv: &kv::KvStore, username: &str) -> Result<bool> {
+    if let Some(banned) = isolate::get::<bool>(&banned_key(username)) {
+        return Ok(banned);
+    }
+    let banned = load(kv, username)
+        .await?
+        .is_some_and(|profile| profile.banned.is_some());
+    isolate::put(&banned_key(username), &banned, BANNED_TTL_MS);
+    Ok(banned)
+}
+
+/// Drops what this isolate remembers of `username`'s ban, after it changed.
+pub fn forget_ban(username: &str) {
+    isolate::forget(&banned_key(username));
+}
+
+/// Stores a fresh profile for `username`. Callers check the name is free first.
+pub async fn register(kv: &kv::KvStore, username: &str) -> Result<UserProfile> {
+    let profile = UserProfile::new();
+    kv.put(username, &profile)?.execute().await?;
+    isolate::put(&taken_key(username), &true, TAKEN_TTL_MS);
+    Ok(profile)
+}
+
+/// `None` for an empty or blank value, so clients clear a field by sending `""`.
+fn cleared(value: String) -> Option<String> {
+    let value = value.trim().to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// How many posts `username` has that `viewer` may see: owners also count their archived posts.
+async fn post_count(
+    ctx: &RouteContext<Session>,
+    username: &str,
+    viewer: Option<&str>,
+) -> Result<usize> {
+    let owner = viewer == Some(username);
+    let mut count = 0;
+    for (_, stored) in storage::posts(ctx)?.list("", ctx.data().trace()).await? {
+        let post = match serde_json::from_str::<Value>(&stored) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        if post.get("username").and_then(Value::as_str) != Some(username)
+            || posts::is_moderated(&post)
+            || posts::is_deleted(&post)
+            || (posts::is_archived(&post) && !owner)
+        {
+            continue;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// `GET /users/:username`
+pub async fn show(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let username = error::param(&ctx, "username")?;
+    let profile = load_replicated(&ctx, &username)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let viewer = session::current_user(&ctx);
+    let post_count = post_count(&ctx, &username, viewer.as_deref()).await?;
+    if let Some(viewer) = &viewer {
+        profile_views::record(&ctx, &username, viewer).await;
+    }
+    Ok(Response::from_json(&json!({
+        "username": username,
+        "display_name": profile.display_name,
+        "bio": profile.bio,
+        "avatar_url": profile.avatar_url,
+        "created_at": profile.created_at,
+        "post_count": post_count,
+    }))?)
+}
+
+/// `PATCH /users/:username`, for the user themselves.
+pub async fn update(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let current = session::authed(&ctx)?.username;
+    let username = error::param(&ctx, "username")?;
+    if current != username {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let body = models::from_body::<ProfileUpdate>(&mut req).await?;
+    let kv = ctx.kv(USERS_KV)?;
+    let mut profile = load(&kv, &username).await?.ok_or(ApiError::NotFound)?;
+
+    if let Some(display_name) = body.display_name {
+        if display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+            return Err(ApiError::BadRequest(format!(
+                "`display_name` can be at most {} characters",
+                MAX_DISPLAY_NAME_LEN
+            )));
+        }
+        profile.display_name = cleared(display_name);
+    }
+    if let Some(bio) = body.bio {
+        if bio.chars().count() > MAX_BIO_LEN {
+            return Err(ApiError::BadRequest(format!(
+                "`bio` can be at most {} characters",
+                MAX_BIO_LEN
+            )));
+        }
+        profile.bio = cleared(bio);
+    }
+    if let Some(avatar_url) = body.avatar_url {
+        let avatar_url = cleared(avatar_url);
+        if let Some(url) = &avatar_url {
+            if Url::parse(url)
+                .map(|url| url.scheme() != "https")
+                .unwrap_or(true)
+            {
+                return Err(ApiError::BadRequest(
+                    "`avatar_url` must be an https URL".to_string(),
+                ));
+            }
+        }
+        profile.avatar_url = avatar_url;
+    }
+    kv.put(&username, &profile)?.execute().await?;
+    Ok(Response::from_json(&profile)?)
+}
@@ -1,4 +1,10 @@
 use cfg_if::cfg_if;
+use chrono::Utc;
+use futures::future::join_all;
+use js_sys::{Function, Reflect, Uint8Array};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use wasm_bindgen::{JsCast, JsValue};
 
 cfg_if! {
     // https://github.com/rustwasm/console_error_panic_hook#readme
@@ -10,3 +16,64 @@ cfg_if! {
         pub fn set_panic_hook() {}
     }
 }
+
+/// Lowercase hex SHA-256 of `input`.
+pub fn sha256_hex(input: &str) -> String {
+    Sha256::digest(input.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Crockford's base32, which ULIDs are written in.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// `len` random bytes from the runtime's `crypto.getRandomValues`.
+fn random_bytes(len: u32) -> worker::Result<Vec<u8>> {
+    let js_error = |e: JsValue| worker::Error::JsError(format!("{:?}", e));
+    let crypto = Reflect::get(&js_sys::global(), &JsValue::from("crypto")).map_err(js_error)?;
+    let fill: Function = Reflect::get(&crypto, &JsValue::from("getRandomValues"))
+        .map_err(js_error)?
+        .unchecked_into();
+    let bytes = Uint8Array::new_with_length(len);
+    fill.call1(&crypto, &bytes).map_err(js_error)?;
+    Ok(bytes.to_vec())
+}
+
+/// A new ULID: 26 characters, the first 10 of them the time in milliseconds, so ids made later
+/// sort after, and the other 16 random, so two made in the same millisecond don't collide.
+pub fn ulid() -> worker::Result<String> {
+    let millis = Utc::now().timestamp_millis() as u128 & ((1 << 48) - 1);
+    let value = random_bytes(10)?
+        .into_iter()
+        .fold(millis, |value, byte| (value << 8) | byte as u128);
+    Ok((0..26)
+        .rev()
+        .map(|i| ULID_ALPHABET[(value >> (5 * i)) as usize & 31] as char)
+        .collect())
+}
+
+/// Runs `futures` at most `limit` at a time, answering their outputs in the same order. Each
+/// wave is joined before the next starts, so `limit` caps the subrequests in flight.
+pub async fn bounded<F: Future>(
+    futures: impl IntoIterator<Item = F>,
+    limit: usize,
+) -> Vec<F::Output> {
+    let mut futures = futures.into_iter();
+    let mut outputs = vec![];
+    loop {
+        let wave: Vec<F> = futures.by_ref().take(limit.max(1)).collect();
+        if wave.is_empty() {
+            return outputs;
+        }
+        outputs.extend(join_all(wave).await);
+    }
+}
+
+/// The address a request came from, as Cloudflare saw it.
+pub fn client_ip(req: &worker::Request) -> worker::Result<String> {
+    Ok(req
+        .headers()
+        .get("CF-Connecting-IP")?
+        .unwrap_or_else(|| "unknown".to_string()))
+}
@@ -0,0 +1,195 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::{ApiError, ApiResult};
+use crate::{media, render};
+
+pub const MAX_TITLE_CHARS: usize = 300;
+pub const MAX_CONTENT_CHARS: usize = 40_000;
+pub const MAX_USERNAME_CHARS: usize = 32;
+pub const MAX_MEDIA_PER_POST: usize = 4;
+
+/// What is wrong with a request body, per field. Everything is collected before answering so a
+/// client can show every problem at once.
+#[derive(Debug, Default)]
+pub struct Problems(BTreeMap<String, String>);
+
+impl Problems {
+    pub fn add(&mut self, field: &str, problem: impl Into<String>) {
+        self.0
+            .entry(field.to_string())
+            .or_insert_with(|| problem.into());
+    }
+
+    /// Records a check's problem, if it had one, and passes its value on.
+    pub fn check<T>(&mut self, field: &str, checked: Result<T, String>) -> Option<T> {
+        match checked {
+            Ok(value) => Some(value),
+            Err(problem) => {
+                self.add(field, problem);
+                None
+            }
+        }
+    }
+
+    /// A 400 listing the problems, if there are any.
+    pub fn finish(self) -> ApiResult<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Invalid(self.0))
+        }
+    }
+}
+
+/// NFC-normalizes `text`, so the same words are stored as the same bytes whichever way a client
+/// composed them, with Windows line endings turned into `\n` and other control characters
+/// dropped.
+pub fn normalize(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .nfc()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+fn too_long(text: &str, max: usize) -> Result<(), String> {
+    if text.chars().count() > max {
+        return Err(format!("can be at most {} characters", max));
+    }
+    Ok(())
+}
+
+/// A title as it is stored: normalized, trimmed and HTML-escaped. Titles are plain text, so
+/// escaping them here makes them safe to drop into any page as they are.
+pub fn title(raw: &str) -> Result<String, String> {
+    let title = normalize(raw).trim().to_string();
+    too_long(&title, MAX_TITLE_CHARS)?;
+    Ok(render::escape_html(&title))
+}
+
+/// Content as it is stored: normalized. Content is markdown, which `render` already escapes
+/// when it makes `content_html`; escaping it here as well would show up literally in code.
+pub fn content(raw: &str) -> Result<String, String> {
+    let content = normalize(raw);
+    too_long(&content, MAX_CONTENT_CHARS)?;
+    Ok(content)
+}
+
+/// Usernames are ASCII letters, digits, `_`, `-` and `.`, so they are safe in keys, URLs and
+/// mentions alike.
+pub fn username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("is required".to_string());
+    }
+    too_long(username, MAX_USERNAME_CHARS)?;
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    if !username.chars().all(allowed) {
+        return Err("can only contain letters, digits, `_`, `-` and `.`".to_string());
+    }
+    Ok(())
+}
+
+/// `media` is a list of ids from `POST /media`.
+pub fn media(media: &Value) -> Result<(), String> {
+    let ids = media.as_array().ok_or("has to be a list of media ids")?;
+    if ids.len() > MAX_MEDIA_PER_POST {
+        return Err(format!("can have at most {} items", MAX_MEDIA_PER_POST));
+    }
+    if !ids
+        .iter()
+        .all(|id| id.as_str().is_some_and(media::is_media_id))
+    {
+        return Err("has to be a list of media ids".to_string());
+    }
+    Ok(())
+}
+
+/// Checks a new post and stores its text fields in their normalized form. `prefix` names the
+/// post in field names, e.g. `segments[2].` for a thread segment.
+pub fn post_fields(post: &mut Value, prefix: &str, problems: &mut Problems) {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).map(String::from);
+    let (title, content) = (field("title"), field("content"));
+    if let Some(name) = field("username") {
+        problems.check(&format!("{}username", prefix), username(&name));
+    }
+    if let Some(ids) = post.get("media") {
+        problems.check(&format!("{}media", prefix), media(ids));
+    }
+    let post_obj = match post.as_object_mut() {
+        Some(post_obj) => post_obj,
+        None => return,
+    };
+    if let Some(title) =
+        title.and_then(|raw| problems.check(&format!("{}title", prefix), self::title(&raw)))
+    {
+        post_obj.insert("title".to_string(), json!(title));
+    }
+    if let Some(content) =
+        content.and_then(|raw| problems.check(&format!("{}content", prefix), self::content(&raw)))
+    {
+        post_obj.insert("content".to_string(), json!(content));
+    }
+}
+
+/// [`post_fields`] for a request carrying a single post.
+pub fn post(post: &mut Value) -> ApiResult<()> {
+    let mut problems = Problems::default();
+    post_fields(post, "", &mut problems);
+    problems.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_composition_line_endings_and_control_characters() {
+        assert_eq!(normalize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(normalize("a\r\nb\u{7}\tc"), "a\nb\tc");
+    }
+
+    #[test]
+    fn titles_are_trimmed_escaped_and_bounded() {
+        assert_eq!(
+            title("  <b>Hi</b> & bye "),
+            Ok("&lt;b&gt;Hi&lt;/b&gt; &amp; bye".to_string())
+        );
+        assert!(title(&"x".repeat(MAX_TITLE_CHARS)).is_ok());
+        assert!(title(&"x".repeat(MAX_TITLE_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn content_is_left_unescaped() {
+        assert_eq!(content("`a < b`"), Ok("`a < b`".to_string()));
+        assert!(content(&"é".repeat(MAX_CONTENT_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn usernames_are_key_safe() {
+        assert!(username("alice.b-c_1").is_ok());
+        assert!(username("").is_err());
+        assert!(username("al ice").is_err());
+        assert!(username("alice/bob").is_err());
+        assert!(username(&"a".repeat(MAX_USERNAME_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn media_is_a_short_list_of_ids() {
+        let id = "a".repeat(media::MEDIA_ID_LEN);
+        assert!(self::media(&json!([id])).is_ok());
+        assert!(self::media(&json!(id)).is_err());
+        assert!(self::media(&json!(["nope"])).is_err());
+        assert!(self::media(&json!(vec![id; MAX_MEDIA_PER_POST + 1])).is_err());
+    }
+
+    #[test]
+    fn reports_every_bad_field_under_its_prefix() {
+        let mut post = json!({ "title": " <Hi> ", "username": "no spaces", "media": "x" });
+        let mut problems = Problems::default();
+        post_fields(&mut post, "segments[1].", &mut problems);
+        let fields: Vec<&String> = problems.0.keys().collect();
+        assert_eq!(fields, ["segments[1].media", "segments[1].username"]);
+        assert_eq!(post["title"], "&lt;Hi&gt;");
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::session::Session;
+use crate::{communities, models, moderation, outbound};
+
+/// Attempts per delivery before it is given up on.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Most webhooks one community may have.
+const MAX_WEBHOOKS: usize = 5;
+
+/// Longest excerpt of a post put in a message.
+const EXCERPT_CHARS: usize = 300;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    Slack,
+    Discord,
+}
+
+impl Service {
+    /// Hosts incoming webhooks of the service live on. Nothing else is accepted as a URL.
+    fn hosts(self) -> &'static [&'static str] {
+        match self {
+            Service::Slack => &["hooks.slack.com"],
+            Service::Discord => &["discord.com", "discordapp.com"],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A post was made in the community.
+    NewPost,
+    /// A post in the community was held for review.
+    ModQueue,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Webhook {
+    service: Service,
+    url: String,
+    events: Vec<Event>,
+}
+
+fn webhooks_key(community: &str) -> String {
+    format!("webhooks/{}", community)
+}
+
+async fn load(ctx: &RouteContext<Session>, community: &str) -> Result<Vec<Webhook>> {
+    let kv = ctx.kv(communities::COMMUNITIES_KV)?;
+    match kv.get(&webhooks_key(community)).await? {
+        Some(v) => Ok(v.as_json::<Vec<Webhook>>()?),
+        None => Ok(vec![]),
+    }
+}
+
+/// `GET /c/:name/webhooks`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    // Webhook URLs are secrets, so only the community's moderators (and admins) see or set them.
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    Ok(Response::from_json(&load(&ctx, &community).await?)?)
+}
+
+/// `PUT /c/:name/webhooks`, replacing the community's whole list.
+pub async fn replace(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let community = error::param(&ctx, "name")?;
+    // Webhook URLs are secrets, so only the community's moderators (and admins) see or set them.
+    if !moderation::may_moderate(&ctx, &community).await? {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let webhooks = models::from_body::<Vec<Webhook>>(&mut req).await?;
+    if webhooks.len() > MAX_WEBHOOKS {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} webhooks",
+            MAX_WEBHOOKS
+        )));
+    }
+    for webhook in &webhooks {
+        let host = Url::parse(&webhook.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+        let valid = host.is_some_and(|host| webhook.service.hosts().contains(&host.as_str()))
+            && webhook.url.starts_with("https://");
+        if !valid {
+            return Err(ApiError::BadRequest(format!(
+                "`{}` is not a {:?} webhook URL",
+                webhook.url, webhook.service
+            )));
+        }
+    }
+    ctx.kv(communities::COMMUNITIES_KV)?
+        .put(&webhooks_key(&community), &webhooks)?
+        .execute()
+        .await?;
+    Ok(Response::from_json(&webhooks)?)
+}
+
+fn message(service: Service, event: Event, community: &str, post: &Value) -> Value {
+    let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or_default();
+    let heading = match event {
+        Event::NewPost => format!("New post in c/{}", community),
+        Event::ModQueue => format!("Held for review in c/{}", community),
+    };
+    let excerpt: String = field("content").chars().take(EXCERPT_CHARS).collect();
+    let byline = format!("by @{} · {}", field("username"), field("id"));
+    let summary = format!("*{}*\n{}", field("title"), excerpt);
+    match service {
+        Service::Slack => json!({
+            "text": format!("{}: {}", heading, field("title")),
+            "blocks": [
+                { "type": "header", "text": { "type": "plain_text", "text": heading } },
+                {
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": summary },
+                },
+                { "type": "context", "elements": [{ "type": "mrkdwn", "text": byline }] },
+            ],
+        }),
+        Service::Discord => json!({
+            "content": heading,
+            "embeds": [{
+                "title": field("title"),
+                "description": excerpt,
+                "footer": { "text": byline },
+                "timestamp": field("time"),
+            }],
+        }),
+    }
+}
+
+/// Sends `event` about `post` to every webhook of `community` subscribed to it, retrying
+/// failures and 429/5xx answers. Delivery problems are logged, never returned.
+pub async fn notify(ctx: &RouteContext<Session>, community: &str, event: Event, post: &Value) {
+    let webhooks = match load(ctx, community).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => return console_log!("loading webhooks for {} failed: {}", community, e),
+    };
+    for webhook in webhooks
+        .iter()
+        .filter(|webhook| webhook.events.contains(&event))
+    {
+        let body = message(webhook.service, event, community, post);
+        let policy = outbound::Policy {
+            allowed_hosts: webhook
+                .service
+                .hosts()
+                .iter()
+                .map(|host| host.to_string())
+                .collect(),
+            ..Default::default()
+        };
+        for attempt in 1..=MAX_ATTEMPTS {
+            match outbound::post_json(&webhook.url, &body, &policy, ctx.data().trace()).await {
+                Ok(res) if res.status == 429 || res.status >= 500 => {
+                    console_log!(
+                        "webhook for {} answered {} (attempt {})",
+                        community,
+                        res.status,
+                        attempt
+                    )
+                }
+                Ok(res) if res.status >= 400 => {
+                    console_log!(
+                        "webhook for {} rejected the message: {}",
+                        community,
+                        res.status
+                    );
+                    break;
+                }
+                Ok(_) => break,
+                Err(e) => console_log!(
+                    "webhook for {} failed (attempt {}): {}",
+                    community,
+                    attempt,
+                    e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::error::{self, ApiError, ApiResult};
+use crate::models::{self, Post};
+use crate::session::Session;
+use crate::{moderation, session, storage};
+
+/// Most countries one withholding may name.
+const MAX_COUNTRIES: usize = 50;
+
+/// What is left out of a withheld post, on top of its title and content.
+const WITHHELD_FIELDS: &[&str] = &["content_html", "code_languages", "has_math", "has_spoilers"];
+
+/// A post an admin withholds in some countries, usually because a court or regulator there
+/// asked. Stored in the `moderation` namespace under `withhold/<post id>`, with `countries` also
+/// as the key's metadata so readers can be matched without fetching every withholding.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Withholding {
+    /// ISO 3166-1 alpha-2 codes, as Cloudflare reports them.
+    countries: Vec<String>,
+    reason: String,
+    by: String,
+    at: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewWithholding {
+    countries: Vec<String>,
+    reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Countries {
+    countries: Vec<String>,
+}
+
+fn withhold_key(id: &str) -> String {
+    format!("withhold/{}", id)
+}
+
+/// Every withholding in `kv`, by post id, for copies of the posts kept where the namespace
+/// can't be read (see `mirror`).
+pub async fn all_in(kv: &kv::KvStore) -> Result<HashMap<String, Withholding>> {
+    let prefix = withhold_key("");
+    let mut withholdings = HashMap::new();
+    for key in kv.list().prefix(prefix.clone()).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            let id = key.name[prefix.len()..].to_string();
+            withholdings.insert(id, v.as_json::<Withholding>()?);
+        }
+    }
+    Ok(withholdings)
+}
+
+/// The posts withheld in the country a request comes from, and why.
+pub struct Withheld {
+    country: String,
+    reasons: HashMap<String, String>,
+}
+
+impl Withheld {
+    /// Looks up what is withheld in the requester's country. Requests Cloudflare can't place
+    /// see everything.
+    pub async fn for_request(req: &Request, ctx: &RouteContext<Session>) -> Result<Withheld> {
+        let country = req.cf().country().unwrap_or_default();
+        let mut reasons = HashMap::new();
+        if country.is_empty() {
+            return Ok(Withheld { country, reasons });
+        }
+        let kv = ctx.kv(moderation::MODERATION_KV)?;
+        let prefix = withhold_key("");
+        for key in kv.list().prefix(prefix.clone()).execute().await?.keys {
+            let applies = key
+                .metadata
+                .and_then(|metadata| serde_json::from_value::<Countries>(metadata).ok())
+                .is_some_and(|metadata| metadata.countries.contains(&country));
+            if !applies {
+                continue;
+            }
+            if let Some(v) = kv.get(&key.name).await? {
+                let withholding = v.as_json::<Withholding>()?;
+                reasons.insert(key.name[prefix.len()..].to_string(), withholding.reason);
+            }
+        }
+        Ok(Withheld { country, reasons })
+    }
+
+    /// What `withholdings` (see [`all_in`]) withhold in the requester's country, without reading
+    /// the namespace.
+    pub fn from_withholdings(
+        req: &Request,
+        withholdings: &HashMap<String, Withholding>,
+    ) -> Withheld {
+        let country = req.cf().country().unwrap_or_default();
+        let reasons = withholdings
+            .iter()
+            .filter(|(_, withholding)| withholding.countries.contains(&country))
+            .map(|(id, withholding)| (id.clone(), withholding.reason.clone()))
+            .collect();
+        Withheld { country, reasons }
+    }
+
+    /// Swaps what is left out of a withheld post for a `withheld` marker.
+    fn mark(&self, id: &str, fields: &mut Map<String, Value>) -> bool {
+        let reason = match self.reasons.get(id) {
+            Some(reason) => reason,
+            None => return false,
+        };
+        for field in WITHHELD_FIELDS {
+            fields.remove(*field);
+        }
+        fields.insert(
+            "withheld".to_string(),
+            json!({ "country": self.country, "reason": reason }),
+        );
+        true
+    }
+
+    pub fn apply(&self, post: &mut Value) {
+        let id = match post.get("id").and_then(Value::as_str) {
+            Some(id) => id.to_string(),
+            None => return,
+        };
+        if let Some(post_obj) = post.as_object_mut() {
+            if self.mark(&id, post_obj) {
+                post_obj.insert("title".to_string(), json!(""));
+                post_obj.insert("content".to_string(), json!(""));
+            }
+        }
+    }
+
+    pub fn apply_to_posts(&self, posts: &mut [Post]) {
+        for post in posts {
+            let id = match post.extra.get("id").and_then(Value::as_str) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            if self.mark(&id, &mut post.extra) {
+                post.title.clear();
+                post.content.clear();
+            }
+        }
+    }
+}
+
+fn admin(ctx: &RouteContext<Session>) -> Result<Option<String>> {
+    Ok(match session::current_user(ctx) {
+        Some(username) if moderation::is_admin(ctx, &username)? => Some(username),
+        _ => None,
+    })
+}
+
+/// `GET /admin/withholdings`
+pub async fn list(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if admin(&ctx)?.is_none() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let kv = ctx.kv(moderation::MODERATION_KV)?;
+    let prefix = withhold_key("");
+    let mut withholdings = vec![];
+    for key in kv.list().prefix(prefix.clone()).execute().await?.keys {
+        if let Some(v) = kv.get(&key.name).await? {
+            let mut withholding = v.as_json::<Value>()?;
+            if let Some(withholding_obj) = withholding.as_object_mut() {
+                let id = &key.name[prefix.len()..];
+                withholding_obj.insert("post_id".to_string(), json!(id));
+            }
+            withholdings.push(withholding);
+        }
+    }
+    Ok(Response::from_json(&withholdings)?)
+}
+
+/// `countries` as stored: upper-case two-letter codes, sorted, each once.
+fn country_codes(countries: &[String]) -> ApiResult<Vec<String>> {
+    if countries.is_empty() || countries.len() > MAX_COUNTRIES {
+        return Err(ApiError::BadRequest(format!(
+            "`countries` needs between 1 and {} country codes",
+            MAX_COUNTRIES
+        )));
+    }
+    let mut codes = vec![];
+    for country in countries {
+        let country = country.trim().to_uppercase();
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(ApiError::BadRequest(format!(
+                "`{}` is not a two-letter country code",
+                country
+            )));
+        }
+        codes.push(country);
+    }
+    codes.sort();
+    codes.dedup();
+    Ok(codes)
+}
+
+/// `PUT /admin/withholdings/:id`, withholding a post in the given countries. Replaces whatever
+/// the post was withheld in before.
+pub async fn put(mut req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    let admin = admin(&ctx)?.ok_or_else(|| ApiError::Forbidden("Forbidden".to_string()))?;
+    let id = error::param(&ctx, "id")?;
+    let body = models::from_body::<NewWithholding>(&mut req).await?;
+    if body.reason.trim().is_empty() {
+        return Err(ApiError::BadRequest("`reason` is required".to_string()));
+    }
+    let countries = country_codes(&body.countries)?;
+    // Withholding a post that doesn't exist would go unnoticed.
+    if storage::posts(&ctx)?.get(&id).await?.is_none() {
+        return Err(ApiError::NotFound);
+    }
+
+    let withholding = Withholding {
+        countries: countries.clone(),
+        reason: body.reason,
+        by: admin,
+        at: Utc::now().to_rfc3339(),
+    };
+    ctx.kv(moderation::MODERATION_KV)?
+        .put(&withhold_key(&id), &withholding)?
+        .metadata(Countries { countries })?
+        .execute()
+        .await?;
+    console_log!(
+        "withholding: {} withheld {} in {:?}",
+        withholding.by,
+        id,
+        withholding.countries
+    );
+    Ok(Response::from_json(&withholding)?)
+}
+
+/// `DELETE /admin/withholdings/:id`
+pub async fn delete(_req: Request, ctx: RouteContext<Session>) -> ApiResult<Response> {
+    if admin(&ctx)?.is_none() {
+        return Err(ApiError::Forbidden("Forbidden".to_string()));
+    }
+    let id = error::param(&ctx, "id")?;
+    ctx.kv(moderation::MODERATION_KV)?
+        .delete(&withhold_key(&id))
+        .await?;
+    Ok(Response::empty()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn withheld() -> Withheld {
+        Withheld {
+            country: "DE".to_string(),
+            reasons: HashMap::from([("p1".to_string(), "court order".to_string())]),
+        }
+    }
+
+    #[test]
+    fn withheld_posts_lose_their_text_for_a_marker() {
+        let mut post = json!({
+            "id": "p1",
+            "title": "Title",
+            "content": "Content",
+            "content_html": "<p>Content</p>",
+            "likes": 3,
+        });
+        withheld().apply(&mut post);
+        assert_eq!(post["title"], "");
+        assert_eq!(post["content"], "");
+        assert!(post.get("content_html").is_none());
+        assert_eq!(post["likes"], 3);
+        assert_eq!(
+            post["withheld"],
+            json!({ "country": "DE", "reason": "court order" })
+        );
+    }
+
+    #[test]
+    fn other_posts_are_left_alone() {
+        let mut post = json!({ "id": "p2", "title": "Title", "content": "Content" });
+        let unchanged = post.clone();
+        withheld().apply(&mut post);
+        assert_eq!(post, unchanged);
+    }
+
+    #[test]
+    fn listed_posts_are_withheld_too() {
+        let post = |id: &str| -> Post {
+            serde_json::from_value(json!({
+                "id": id,
+                "title": "Title",
+                "username": "alice",
+                "content": "Content",
+            }))
+            .unwrap()
+        };
+        let mut posts = [post("p1"), post("p2")];
+        withheld().apply_to_posts(&mut posts);
+        assert!(posts[0].title.is_empty() && posts[0].extra.contains_key("withheld"));
+        assert_eq!(posts[1].title, "Title");
+    }
+
+    #[test]
+    fn country_codes_are_normalized() {
+        let codes = |codes: &[&str]| {
+            country_codes(
+                &codes
+                    .iter()
+                    .map(|code| code.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(codes(&["de", " FR ", "DE"]).unwrap(), ["DE", "FR"]);
+        assert!(codes(&[]).is_err());
+        assert!(codes(&["DEU"]).is_err());
+        assert!(codes(&["D1"]).is_err());
+        assert!(codes(&["DE"; MAX_COUNTRIES + 1]).is_err());
+    }
+}